@@ -15,7 +15,7 @@ use rand::rngs::OsRng;
 use safe_transmute::{
     guard::SingleManyGuard,
     to_bytes::{transmute_one_to_bytes, transmute_to_bytes},
-    transmute_many, transmute_many_pedantic, transmute_one_pedantic,
+    transmute_many, transmute_one_pedantic,
 };
 use sloggers::file::FileLoggerBuilder;
 use sloggers::types::Severity;
@@ -42,6 +42,9 @@ use serum_dex::state::gen_vault_signer_key;
 use serum_dex::state::Event;
 use serum_dex::state::EventQueueHeader;
 use serum_dex::state::MarketState;
+use serum_dex::state::MarketStateExt;
+use serum_dex::state::OpenOrders;
+use serum_dex::state::OpenOrdersExt;
 use serum_dex::state::QueueHeader;
 use serum_dex::state::Request;
 use serum_dex::state::RequestQueueHeader;
@@ -380,31 +383,7 @@ struct MarketPubkeys {
 
 #[cfg(target_endian = "little")]
 fn remove_dex_account_padding<'a>(data: &'a [u8]) -> Result<Cow<'a, [u64]>> {
-    use serum_dex::state::{ACCOUNT_HEAD_PADDING, ACCOUNT_TAIL_PADDING};
-    let head = &data[..ACCOUNT_HEAD_PADDING.len()];
-    if data.len() < ACCOUNT_HEAD_PADDING.len() + ACCOUNT_TAIL_PADDING.len() {
-        return Err(format_err!(
-            "dex account length {} is too small to contain valid padding",
-            data.len()
-        ));
-    }
-    if head != ACCOUNT_HEAD_PADDING {
-        return Err(format_err!("dex account head padding mismatch"));
-    }
-    let tail = &data[data.len() - ACCOUNT_TAIL_PADDING.len()..];
-    if tail != ACCOUNT_TAIL_PADDING {
-        return Err(format_err!("dex account tail padding mismatch"));
-    }
-    let inner_data_range = ACCOUNT_HEAD_PADDING.len()..(data.len() - ACCOUNT_TAIL_PADDING.len());
-    let inner: &'a [u8] = &data[inner_data_range];
-    let words: Cow<'a, [u64]> = match transmute_many_pedantic::<u64>(inner) {
-        Ok(word_slice) => Cow::Borrowed(word_slice),
-        Err(transmute_error) => {
-            let word_vec = transmute_error.copy().map_err(|e| e.without_src())?;
-            Cow::Owned(word_vec)
-        }
-    };
-    Ok(words)
+    Ok(serum_dex::state::strip_account_data_padding(data)?)
 }
 
 #[cfg(target_endian = "little")]
@@ -415,9 +394,13 @@ fn get_keys_for_market<'a>(
 ) -> Result<MarketPubkeys> {
     let account_data: Vec<u8> = client.get_account_data(&market)?;
     let words: Cow<[u64]> = remove_dex_account_padding(&account_data)?;
-    let market_state: MarketState =
-        transmute_one_pedantic::<MarketState>(transmute_to_bytes(&words))
-            .map_err(|e| e.without_src())?;
+    if words.len() < size_of::<MarketState>() >> 3 {
+        return Err(format_err!("{} is not a market account", market));
+    }
+    let market_state: MarketState = transmute_one_pedantic::<MarketState>(transmute_to_bytes(
+        &words[..size_of::<MarketState>() >> 3],
+    ))
+    .map_err(|e| e.without_src())?;
     market_state.check_flags()?;
     let vault_signer_key =
         gen_vault_signer_key(market_state.vault_signer_nonce, market, program_id)?;
@@ -511,7 +494,7 @@ fn consume_events_loop(
             .expect("Failed to retrieve account")
             .data;
         let inner: Cow<[u64]> = remove_dex_account_padding(&event_q_data)?;
-        let (_header, seg0, seg1) = parse_event_queue(&inner)?;
+        let (header, seg0, seg1) = parse_event_queue(&inner)?;
         let req_inner: Cow<[u64]> = remove_dex_account_padding(&req_q_data)?;
         let (_req_header, req_seg0, req_seg1) = parse_event_queue(&req_inner)?;
         let event_q_len = seg0.len() + seg1.len();
@@ -525,8 +508,13 @@ fn consume_events_loop(
             continue;
         } else {
             info!(
-                "Total event queue length: {}, market {}, coin {}, pc {}",
-                event_q_len, market, coin_wallet, pc_wallet
+                "Total event queue length: {}, events {}..{}, market {}, coin {}, pc {}",
+                event_q_len,
+                header.first_seq_num(),
+                header.seq_num(),
+                market,
+                coin_wallet,
+                pc_wallet
             );
             let accounts = seg0.iter().chain(seg1.iter()).map(|event| event.owner);
             let mut used_accounts = BTreeSet::new();
@@ -554,8 +542,8 @@ fn consume_events_loop(
             );
 
             let mut account_metas = Vec::with_capacity(orders_accounts.len() + 4);
-            for pubkey_words in orders_accounts {
-                let pubkey = Pubkey::new(transmute_to_bytes(&pubkey_words));
+            for pubkey_words in orders_accounts.iter() {
+                let pubkey = Pubkey::new(transmute_to_bytes(pubkey_words));
                 account_metas.push(AccountMeta::new(pubkey, false));
             }
             for pubkey in [
@@ -593,6 +581,8 @@ fn consume_events_loop(
                 });
             }
             pool.join();
+            let payer = read_keypair_file(&payer_path)?;
+            settle_registered_wallets(&client, program_id, &payer, &market_keys, &orders_accounts);
             let loop_end = std::time::Instant::now();
             info!(
                 "Total loop time took {}",
@@ -602,6 +592,65 @@ fn consume_events_loop(
     }
 }
 
+fn settle_registered_wallets(
+    client: &RpcClient,
+    program_id: &Pubkey,
+    payer: &Keypair,
+    state: &MarketPubkeys,
+    orders_accounts: &[[u64; 4]],
+) {
+    for pubkey_words in orders_accounts {
+        let orders = Pubkey::new(transmute_to_bytes(pubkey_words));
+        let result = get_open_orders(client, &orders).and_then(|(open_orders, ext)| {
+            let ext = match ext {
+                Some(ext) if ext.settle_coin_wallet != [0; 4] => ext,
+                _ => return Ok(()),
+            };
+            if open_orders.native_coin_free == 0 && open_orders.native_pc_free == 0 {
+                return Ok(());
+            }
+            settle_for(
+                client,
+                program_id,
+                payer,
+                state,
+                &orders,
+                &Pubkey::new(transmute_one_to_bytes(&ext.settle_coin_wallet)),
+                &Pubkey::new(transmute_one_to_bytes(&ext.settle_pc_wallet)),
+            )
+        });
+        if let Err(err) = result {
+            error!("Failed to settle {}: {:?}", orders, err);
+        }
+    }
+}
+
+fn get_open_orders(
+    client: &RpcClient,
+    orders: &Pubkey,
+) -> Result<(OpenOrders, Option<OpenOrdersExt>)> {
+    let account_data: Vec<u8> = client.get_account_data(orders)?;
+    let words: Cow<[u64]> = remove_dex_account_padding(&account_data)?;
+    let bytes = transmute_to_bytes(&words);
+    let (base, ext) = match bytes.len().checked_sub(size_of::<OpenOrders>()) {
+        Some(0) => (bytes, None),
+        Some(n) if n == size_of::<OpenOrdersExt>() => {
+            let (base, ext) = bytes.split_at(size_of::<OpenOrders>());
+            (base, Some(ext))
+        }
+        _ => return Err(format_err!("{} is not an OpenOrders account", orders)),
+    };
+    let open_orders: OpenOrders =
+        transmute_one_pedantic::<OpenOrders>(base).map_err(|e| e.without_src())?;
+    let ext = match ext {
+        Some(ext) => {
+            Some(transmute_one_pedantic::<OpenOrdersExt>(ext).map_err(|e| e.without_src())?)
+        }
+        None => None,
+    };
+    Ok((open_orders, ext))
+}
+
 fn consume_events_wrapper(
     client: &RpcClient,
     program_id: &Pubkey,
@@ -863,7 +912,8 @@ fn place_order(
                 client,
                 program_id,
                 &payer.pubkey(),
-                size_of::<serum_dex::state::OpenOrders>(),
+                size_of::<serum_dex::state::OpenOrders>()
+                    + size_of::<serum_dex::state::OpenOrdersExt>(),
             )?;
             orders_keypair = orders_key;
             signers.push(&orders_keypair);
@@ -903,6 +953,42 @@ fn place_order(
     Ok(())
 }
 
+fn settle_for(
+    client: &RpcClient,
+    program_id: &Pubkey,
+    payer: &Keypair,
+    state: &MarketPubkeys,
+    orders: &Pubkey,
+    coin_wallet: &Pubkey,
+    pc_wallet: &Pubkey,
+) -> Result<()> {
+    let data = MarketInstruction::SettleFor.pack();
+    let instruction = Instruction {
+        program_id: *program_id,
+        data,
+        accounts: vec![
+            AccountMeta::new(*state.market, false),
+            AccountMeta::new(*orders, false),
+            AccountMeta::new(*state.coin_vault, false),
+            AccountMeta::new(*state.pc_vault, false),
+            AccountMeta::new(*coin_wallet, false),
+            AccountMeta::new(*pc_wallet, false),
+            AccountMeta::new_readonly(*state.vault_signer_key, false),
+            AccountMeta::new_readonly(spl_token::ID, false),
+        ],
+    };
+    let (recent_hash, _fee_calc) = client.get_recent_blockhash()?;
+    let txn = Transaction::new_signed_with_payer(
+        &[instruction],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_hash,
+    );
+    info!("Settling {} to its registered wallets ...", orders);
+    send_txn(client, &txn, false)?;
+    Ok(())
+}
+
 fn settle_funds(
     client: &RpcClient,
     program_id: &Pubkey,
@@ -1067,7 +1153,12 @@ fn gen_listing_params(
     _coin_mint: &Pubkey,
     _pc_mint: &Pubkey,
 ) -> Result<(ListingKeys, Vec<Instruction>)> {
-    let (market_key, create_market) = create_dex_account(client, program_id, payer, 376)?;
+    let (market_key, create_market) = create_dex_account(
+        client,
+        program_id,
+        payer,
+        size_of::<MarketState>() + size_of::<MarketStateExt>(),
+    )?;
     let (req_q_key, create_req_q) = create_dex_account(client, program_id, payer, 640)?;
     let (event_q_key, create_event_q) = create_dex_account(client, program_id, payer, 1 << 20)?;
     let (bids_key, create_bids) = create_dex_account(client, program_id, payer, 1 << 16)?;
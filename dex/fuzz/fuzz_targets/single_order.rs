@@ -56,6 +56,8 @@ fn fuzz_place_order(data: SingleOrder) {
         ],
         &MarketInstruction::NewOrder(data.instruction.clone()).pack(),
     );
+    let order_can_rest = data.instruction.order_type != OrderType::ImmediateOrCancel
+        && data.instruction.order_type != OrderType::FillOrKill;
     if !data.correct_payer_account {
         assert!(place_order_result.is_err());
     } else if data.instruction.side == Side::Ask {
@@ -79,7 +81,7 @@ fn fuzz_place_order(data: SingleOrder) {
         }
     }
 
-    if place_order_result.is_ok() && data.instruction.order_type != OrderType::ImmediateOrCancel {
+    if place_order_result.is_ok() && order_can_rest {
         let (orders, _) = strip_header::<OpenOrders, u8>(&orders_account, false).unwrap();
         // println!("{:?}", orders);
         if data.instruction.side == Side::Bid {
@@ -110,7 +112,7 @@ fn fuzz_place_order(data: SingleOrder) {
     )
     .unwrap();
 
-    if place_order_result.is_ok() && data.instruction.order_type != OrderType::ImmediateOrCancel {
+    if place_order_result.is_ok() && order_can_rest {
         let (orders, _) = strip_header::<OpenOrders, u8>(&orders_account, false).unwrap();
         if data.instruction.side == Side::Bid {
             assert_eq!(orders.native_coin_free, 0);
@@ -138,7 +140,7 @@ fn fuzz_place_order(data: SingleOrder) {
     )
     .unwrap();
 
-    if place_order_result.is_ok() && data.instruction.order_type != OrderType::ImmediateOrCancel {
+    if place_order_result.is_ok() && order_can_rest {
         let (orders, _) = strip_header::<OpenOrders, u8>(&orders_account, false).unwrap();
         // println!("{:?}", orders);
         if data.instruction.side == Side::Bid {
@@ -165,7 +167,7 @@ fn fuzz_place_order(data: SingleOrder) {
         &MarketInstruction::CancelOrderByClientId(data.instruction.client_id).pack(),
     );
 
-    if place_order_result.is_ok() && data.instruction.order_type != OrderType::ImmediateOrCancel {
+    if place_order_result.is_ok() && order_can_rest {
         assert!(cancel_order_result.is_ok());
     } else {
         assert!(cancel_order_result.is_err())
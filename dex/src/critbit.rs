@@ -1,9 +1,13 @@
 use crate::{
     error::{DexErrorCode, DexResult},
     fees::FeeTier,
+    matching::{OrderExpiry, Side},
 };
 use arrayref::{array_refs, mut_array_refs};
-use bytemuck::{cast, cast_mut, cast_ref, cast_slice, cast_slice_mut, Pod, Zeroable};
+use bytemuck::{
+    cast, cast_mut, cast_ref, cast_slice, cast_slice_mut, try_cast_slice, try_from_bytes, Pod,
+    Zeroable,
+};
 
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use static_assertions::const_assert_eq;
@@ -51,7 +55,9 @@ pub struct LeafNode {
     tag: u32,
     owner_slot: u8,
     fee_tier: u8,
-    padding: [u8; 2],
+    // Leaves from before OpenOrders had more than 256 slots have zeroes here
+    owner_slot_hi: u8,
+    flags: u8,
     key: u128,
     owner: [u64; 4],
     quantity: u64,
@@ -60,21 +66,25 @@ pub struct LeafNode {
 unsafe impl Zeroable for LeafNode {}
 unsafe impl Pod for LeafNode {}
 
+const LEAF_FLAG_HAS_EXT: u8 = 1;
+
 impl LeafNode {
     #[inline]
     pub fn new(
-        owner_slot: u8,
+        owner_slot: u16,
         key: &u128,
         owner: &[u64; 4],
         quantity: u64,
         fee_tier: FeeTier,
         client_order_id: u64,
     ) -> Self {
+        let [owner_slot_lo, owner_slot_hi] = owner_slot.to_le_bytes();
         LeafNode {
             tag: NodeTag::LeafNode.into(),
-            owner_slot,
+            owner_slot: owner_slot_lo,
             fee_tier: fee_tier.into(),
-            padding: [0; 2],
+            owner_slot_hi,
+            flags: 0,
             key: *key,
             owner: *owner,
             quantity,
@@ -97,6 +107,13 @@ impl LeafNode {
         &self.key
     }
 
+    /// Gives the order a new key, e.g. to requeue it at the back of its price level. The leaf
+    /// must not be in a slab while its key changes.
+    #[inline]
+    pub fn set_order_id(&mut self, key: &u128) {
+        self.key = *key;
+    }
+
     #[inline]
     pub fn quantity(&self) -> u64 {
         self.quantity
@@ -113,14 +130,29 @@ impl LeafNode {
     }
 
     #[inline]
-    pub fn owner_slot(&self) -> u8 {
-        self.owner_slot
+    pub fn owner_slot(&self) -> u16 {
+        u16::from_le_bytes([self.owner_slot, self.owner_slot_hi])
     }
 
     #[inline]
     pub fn client_order_id(&self) -> u64 {
         self.client_order_id
     }
+
+    /// Whether the order has an entry in its book's `OrderExtTable`.
+    #[inline]
+    pub fn has_ext(&self) -> bool {
+        self.flags & LEAF_FLAG_HAS_EXT != 0
+    }
+
+    #[inline]
+    pub(crate) fn set_has_ext(&mut self, has_ext: bool) {
+        if has_ext {
+            self.flags |= LEAF_FLAG_HAS_EXT;
+        } else {
+            self.flags &= !LEAF_FLAG_HAS_EXT;
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -183,7 +215,6 @@ impl AnyNode {
         }
     }
 
-    #[cfg(test)]
     fn prefix_len(&self) -> u32 {
         match self.case().unwrap() {
             NodeRef::Inner(&InnerNode { prefix_len, .. }) => prefix_len,
@@ -214,6 +245,14 @@ impl AnyNode {
         }
     }
 
+    #[inline]
+    pub fn as_leaf(&self) -> Option<&LeafNode> {
+        match self.case() {
+            Some(NodeRef::Leaf(leaf_ref)) => Some(leaf_ref),
+            _ => None,
+        }
+    }
+
     #[inline]
     pub fn as_leaf_mut(&mut self) -> Option<&mut LeafNode> {
         match self.case_mut() {
@@ -252,7 +291,7 @@ struct SlabHeader {
 unsafe impl Zeroable for SlabHeader {}
 unsafe impl Pod for SlabHeader {}
 
-const SLAB_HEADER_LEN: usize = size_of::<SlabHeader>();
+pub(crate) const SLAB_HEADER_LEN: usize = size_of::<SlabHeader>();
 
 #[cfg(debug_assertions)]
 unsafe fn invariant(check: bool) {
@@ -292,6 +331,23 @@ impl Slab {
         slab
     }
 
+    /// Read-only counterpart of `new`, e.g. for order book data fetched by a client
+    #[inline]
+    pub fn new_ref(bytes: &[u8]) -> DexResult<&Self> {
+        let len_without_header = bytes
+            .len()
+            .checked_sub(SLAB_HEADER_LEN)
+            .ok_or(DexErrorCode::SlabTooSmall)?;
+        let slop = len_without_header % size_of::<AnyNode>();
+        let bytes = &bytes[..bytes.len() - slop];
+        let (header_bytes, nodes_bytes) = bytes.split_at(SLAB_HEADER_LEN);
+        try_from_bytes::<SlabHeader>(header_bytes)
+            .map_err(|_| DexErrorCode::WrongAccountDataAlignment)?;
+        try_cast_slice::<u8, AnyNode>(nodes_bytes)
+            .map_err(|_| DexErrorCode::WrongAccountDataAlignment)?;
+        Ok(unsafe { &*(bytes as *const [u8] as *const Slab) })
+    }
+
     #[inline]
     pub fn assert_minimum_capacity(&self, capacity: u32) -> DexResult {
         if self.nodes().len() <= (capacity as usize) * 2 {
@@ -475,6 +531,78 @@ impl SlabView<AnyNode> for Slab {
     }
 }
 
+// Inner nodes on a path have strictly increasing prefix lengths below 128, so the stack holds
+// at most one pending sibling for each of them plus the two children of the current node. A
+// corrupt slab that nests deeper or loops back on itself ends the iteration instead.
+const SLAB_ITER_STACK_LEN: usize = 129;
+
+/// In-order iterator over the leaves of a `Slab` that doesn't allocate.
+pub struct SlabIter<'a> {
+    slab: &'a Slab,
+    stack: [NodeHandle; SLAB_ITER_STACK_LEN],
+    stack_len: usize,
+    // a tree visits each of the slab's nodes at most once
+    nodes_remaining: u64,
+    ascending: bool,
+}
+
+impl<'a> Iterator for SlabIter<'a> {
+    type Item = &'a LeafNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.stack_len > 0 && self.nodes_remaining > 0 {
+            self.stack_len -= 1;
+            self.nodes_remaining -= 1;
+            match self.slab.get(self.stack[self.stack_len])?.case()? {
+                NodeRef::Leaf(leaf) => return Some(leaf),
+                NodeRef::Inner(&InnerNode { children, .. }) => {
+                    if self.stack_len + 2 > SLAB_ITER_STACK_LEN {
+                        return None;
+                    }
+                    let (first, second) = if self.ascending {
+                        (children[0], children[1])
+                    } else {
+                        (children[1], children[0])
+                    };
+                    self.stack[self.stack_len] = second;
+                    self.stack[self.stack_len + 1] = first;
+                    self.stack_len += 2;
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Iterator over `(price, quantity)` levels of one side of the book, see `Slab::depth`.
+pub struct DepthIter<'a> {
+    orders: SlabIter<'a>,
+    next: Option<&'a LeafNode>,
+    levels_remaining: usize,
+}
+
+impl<'a> Iterator for DepthIter<'a> {
+    type Item = (NonZeroU64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.levels_remaining == 0 {
+            return None;
+        }
+        let first = self.next.take().or_else(|| self.orders.next())?;
+        let price = first.price();
+        let mut quantity = first.quantity();
+        for leaf in &mut self.orders {
+            if leaf.price() != price {
+                self.next = Some(leaf);
+                break;
+            }
+            quantity += leaf.quantity();
+        }
+        self.levels_remaining -= 1;
+        Some((price, quantity))
+    }
+}
+
 #[derive(Debug)]
 pub enum SlabTreeError {
     OutOfSpace,
@@ -513,6 +641,40 @@ impl Slab {
         self.find_min_max(true)
     }
 
+    /// Iterates over the orders in key order, which is price order.
+    pub fn iter(&self, ascending: bool) -> SlabIter<'_> {
+        let mut iter = SlabIter {
+            slab: self,
+            stack: [0; SLAB_ITER_STACK_LEN],
+            stack_len: 0,
+            nodes_remaining: self
+                .header()
+                .bump_index
+                .saturating_sub(self.header().free_list_len),
+            ascending,
+        };
+        if let Some(root) = self.root() {
+            iter.stack[0] = root;
+            iter.stack_len = 1;
+        }
+        iter
+    }
+
+    /// Iterates over the orders of one side of the book, best price first.
+    pub fn iter_best_first(&self, side: Side) -> SlabIter<'_> {
+        self.iter(side == Side::Ask)
+    }
+
+    /// Aggregates the visible quantity of the orders by price, for up to `levels` prices
+    /// starting at the best one.
+    pub fn depth(&self, side: Side, levels: usize) -> DepthIter<'_> {
+        DepthIter {
+            orders: self.iter_best_first(side),
+            next: None,
+            levels_remaining: levels,
+        }
+    }
+
     #[inline]
     pub fn insert_leaf(
         &mut self,
@@ -588,8 +750,7 @@ impl Slab {
         }
     }
 
-    #[cfg(test)]
-    fn find_by_key(&self, search_key: &u128) -> Option<NodeHandle> {
+    pub fn find_by_key(&self, search_key: &u128) -> Option<NodeHandle> {
         let mut node_handle: NodeHandle = self.root()?;
         loop {
             let node_ref = self.get(node_handle).unwrap();
@@ -765,6 +926,219 @@ impl Slab {
     }
 }
 
+/// The state of a resting order that doesn't fit in its `LeafNode`: its expiry and, for
+/// iceberg orders, its hidden reserve. Extended order books keep these in an `OrderExtTable`
+/// next to their slab, found by the owner and slot of the order.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct OrderExt {
+    owner: [u64; 4],
+    key: u128,
+    expiry: u64,
+    hidden_quantity: u64,
+    display_quantity: u64,
+    owner_slot: u16,
+    expiry_kind: u8,
+    occupied: u8,
+    _padding: [u8; 4],
+}
+unsafe impl Zeroable for OrderExt {}
+unsafe impl Pod for OrderExt {}
+
+impl OrderExt {
+    #[inline]
+    pub fn new(expiry: Option<OrderExpiry>) -> Self {
+        let (expiry_kind, expiry) = OrderExpiry::encode(expiry);
+        OrderExt {
+            expiry_kind,
+            expiry,
+            ..Zeroable::zeroed()
+        }
+    }
+
+    /// Turns `leaf` into an iceberg order that only shows up to `display_quantity` at a time,
+    /// keeping the rest of its quantity hidden.
+    #[inline]
+    pub fn hide_quantity(&mut self, leaf: &mut LeafNode, display_quantity: NonZeroU64) {
+        if display_quantity.get() < leaf.quantity {
+            self.hidden_quantity = leaf.quantity - display_quantity.get();
+            self.display_quantity = display_quantity.get();
+            leaf.quantity = display_quantity.get();
+        }
+    }
+
+    /// Moves the next display quantity of an iceberg order out of its hidden reserve.
+    #[inline]
+    pub fn refill(&mut self, leaf: &mut LeafNode) {
+        let refill_quantity = self.display_quantity.min(self.hidden_quantity);
+        leaf.quantity += refill_quantity;
+        self.hidden_quantity -= refill_quantity;
+    }
+
+    #[inline]
+    pub fn order_id(&self) -> &u128 {
+        &self.key
+    }
+
+    #[inline]
+    pub fn expiry(&self) -> Option<OrderExpiry> {
+        OrderExpiry::decode(self.expiry_kind, self.expiry).unwrap()
+    }
+
+    #[inline]
+    pub fn hidden_quantity(&self) -> u64 {
+        self.hidden_quantity
+    }
+
+    /// The quantity an iceberg order shows each time it is refilled, or 0 for other orders.
+    #[inline]
+    pub fn display_quantity(&self) -> u64 {
+        self.display_quantity
+    }
+}
+
+/// The length of the slab at the start of an extended order book's data. The rest holds an
+/// `OrderExtTable` with an entry for every leaf the slab has room for.
+pub fn extended_slab_len(data_len: usize) -> usize {
+    let node_size = size_of::<AnyNode>();
+    let leaf_capacity =
+        data_len.saturating_sub(SLAB_HEADER_LEN) / (2 * node_size + size_of::<OrderExt>());
+    SLAB_HEADER_LEN + 2 * leaf_capacity * node_size
+}
+
+/// Open addressing hash table from (owner, owner slot) to the `OrderExt` of the order resting
+/// in that slot. A leaf has an entry exactly when its `has_ext` flag is set.
+#[repr(transparent)]
+pub struct OrderExtTable([OrderExt]);
+
+impl OrderExtTable {
+    #[inline]
+    pub fn new(bytes: &mut [u8]) -> &mut Self {
+        let truncated_len = bytes.len() - bytes.len() % size_of::<OrderExt>();
+        let entries: &mut [OrderExt] = cast_slice_mut(&mut bytes[..truncated_len]);
+        unsafe { &mut *(entries as *mut [OrderExt] as *mut OrderExtTable) }
+    }
+
+    /// Read-only counterpart of `new`, e.g. for order book data fetched by a client
+    #[inline]
+    pub fn new_ref(bytes: &[u8]) -> DexResult<&Self> {
+        let truncated_len = bytes.len() - bytes.len() % size_of::<OrderExt>();
+        let entries: &[OrderExt] =
+            try_cast_slice(&bytes[..truncated_len]).map_err(|_| DexErrorCode::SlabTooSmall)?;
+        Ok(unsafe { &*(entries as *const [OrderExt] as *const OrderExtTable) })
+    }
+
+    fn home_index(&self, owner: &[u64; 4], owner_slot: u16) -> usize {
+        let hash = (owner[0] ^ owner[1].rotate_left(32) ^ owner_slot as u64)
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        (hash >> 32) as usize % self.0.len()
+    }
+
+    fn position(&self, owner: &[u64; 4], owner_slot: u16) -> Option<usize> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let mut index = self.home_index(owner, owner_slot);
+        for _ in 0..self.0.len() {
+            let entry = &self.0[index];
+            if entry.occupied == 0 {
+                return None;
+            }
+            if entry.owner == *owner && entry.owner_slot == owner_slot {
+                return Some(index);
+            }
+            index = (index + 1) % self.0.len();
+        }
+        None
+    }
+
+    /// The entry of the order resting in the owner's slot, whatever its current key.
+    pub fn find(&self, owner: &[u64; 4], owner_slot: u16) -> Option<&OrderExt> {
+        Some(&self.0[self.position(owner, owner_slot)?])
+    }
+
+    pub fn get(&self, leaf: &LeafNode) -> Option<&OrderExt> {
+        if !leaf.has_ext() {
+            return None;
+        }
+        self.find(leaf.owner(), leaf.owner_slot())
+            .filter(|ext| ext.key == leaf.key)
+    }
+
+    /// Stores `ext` as the entry of `leaf`, replacing the entry of its slot if there is one,
+    /// and flags the leaf as having an entry.
+    pub fn insert(&mut self, leaf: &mut LeafNode, ext: &OrderExt) -> Result<(), SlabTreeError> {
+        if self.0.is_empty() {
+            return Err(SlabTreeError::OutOfSpace);
+        }
+        let owner_slot = leaf.owner_slot();
+        let mut index = self.home_index(leaf.owner(), owner_slot);
+        for _ in 0..self.0.len() {
+            let entry = &mut self.0[index];
+            if entry.occupied == 0 || (entry.owner == leaf.owner && entry.owner_slot == owner_slot)
+            {
+                *entry = OrderExt {
+                    owner: leaf.owner,
+                    key: leaf.key,
+                    owner_slot,
+                    occupied: 1,
+                    ..*ext
+                };
+                leaf.set_has_ext(true);
+                return Ok(());
+            }
+            index = (index + 1) % self.0.len();
+        }
+        Err(SlabTreeError::OutOfSpace)
+    }
+
+    /// Removes the entry of `leaf`, shifting back the entries probed past it so that lookups
+    /// never stop early.
+    pub fn remove(&mut self, leaf: &LeafNode) -> Option<OrderExt> {
+        if !leaf.has_ext() {
+            return None;
+        }
+        let mut hole = self.position(leaf.owner(), leaf.owner_slot())?;
+        let removed = self.0[hole];
+        let len = self.0.len();
+        let mut index = hole;
+        loop {
+            index = (index + 1) % len;
+            let entry = self.0[index];
+            if entry.occupied == 0 {
+                break;
+            }
+            let home = self.home_index(&entry.owner, entry.owner_slot);
+            // the entry can fill the hole unless its home lies cyclically in (hole, index]
+            let stays = if hole <= index {
+                hole < home && home <= index
+            } else {
+                hole < home || home <= index
+            };
+            if !stays {
+                self.0[hole] = entry;
+                hole = index;
+            }
+        }
+        self.0[hole] = Zeroable::zeroed();
+        Some(removed)
+    }
+}
+
+/// One side of an order book as stored in a bids or asks account, for reading it off chain.
+#[derive(Copy, Clone)]
+pub struct OrderBookSide<'a> {
+    pub orders: &'a Slab,
+    /// Only extended order books have one
+    pub exts: Option<&'a OrderExtTable>,
+}
+
+impl<'a> OrderBookSide<'a> {
+    pub fn ext(&self, leaf: &LeafNode) -> Option<&'a OrderExt> {
+        self.exts?.get(leaf)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -826,6 +1200,14 @@ mod tests {
                 let slab_max = slab.get(slab.find_max().unwrap()).unwrap();
                 let model_max = model.iter().next_back().unwrap().1;
                 assert_eq!(bytes_of(slab_max), bytes_of(model_max));
+
+                // test iter
+                let slab_keys: Vec<u128> = slab.iter(true).map(|l| *l.order_id()).collect();
+                let model_keys: Vec<u128> = model.keys().copied().collect();
+                assert_eq!(slab_keys, model_keys);
+                let slab_keys_rev: Vec<u128> = slab.iter(false).map(|l| *l.order_id()).collect();
+                let model_keys_rev: Vec<u128> = model.keys().rev().copied().collect();
+                assert_eq!(slab_keys_rev, model_keys_rev);
             }
         }
     }
@@ -941,6 +1323,46 @@ mod tests {
         }
     }
 
+    #[test]
+    fn simulate_order_ext_table() {
+        use std::collections::HashMap;
+
+        let mut entries = vec![OrderExt::zeroed(); 64];
+        let table = OrderExtTable::new(cast_slice_mut(entries.as_mut_slice()));
+        let mut model: HashMap<([u64; 4], u16), LeafNode> = HashMap::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        let owners: Vec<[u64; 4]> = (0..2).map(|_| rng.gen()).collect();
+
+        for i in 1..10_000u64 {
+            let owner = *owners.choose(&mut rng).unwrap();
+            let owner_slot = rng.gen_range(0, 24);
+            match model.remove(&(owner, owner_slot)) {
+                Some(leaf) => {
+                    let ext = table.remove(&leaf).unwrap();
+                    assert_eq!(ext.order_id(), leaf.order_id());
+                    assert!(table.find(&owner, owner_slot).is_none());
+                }
+                None => {
+                    let key = i as u128;
+                    let mut leaf = LeafNode::new(owner_slot, &key, &owner, 1, FeeTier::Base, 0);
+                    let mut ext = OrderExt::new(Some(OrderExpiry::Slot(i)));
+                    ext.hidden_quantity = i;
+                    table.insert(&mut leaf, &ext).unwrap();
+                    assert!(leaf.has_ext());
+                    model.insert((owner, owner_slot), leaf);
+                }
+            }
+
+            // every entry can still be found after the deletions shifted the others around
+            for leaf in model.values() {
+                let ext = table.get(leaf).unwrap();
+                let i = *leaf.order_id() as u64;
+                assert_eq!(ext.hidden_quantity(), i);
+                assert_eq!(ext.expiry(), Some(OrderExpiry::Slot(i)));
+            }
+        }
+    }
+
     #[test]
     #[should_panic]
     fn panics_unaligned() {
@@ -949,4 +1371,33 @@ mod tests {
 
         Slab::new(&mut bytes[1..]);
     }
+
+    #[test]
+    fn iter_stops_on_cyclic_slab() {
+        let mut aligned_buf = vec![0u64; 10_000];
+        let bytes: &mut [u8] = cast_slice_mut(aligned_buf.as_mut_slice());
+        let slab: &mut Slab = Slab::new(bytes);
+        for key in 0..3u128 {
+            let leaf = LeafNode::new(0, &key, &[0; 4], 1, FeeTier::Base, 0);
+            slab.insert_leaf(&leaf).unwrap();
+        }
+        let root = slab.root().unwrap();
+        match slab.get_mut(root).unwrap().case_mut().unwrap() {
+            NodeRefMut::Inner(inner) => inner.children[1] = root,
+            NodeRefMut::Leaf(_) => unreachable!(),
+        }
+
+        assert!(slab.iter(true).count() <= 3);
+        assert!(slab.iter(false).count() <= 3);
+    }
+
+    #[test]
+    fn new_ref_rejects_short_or_unaligned() {
+        let aligned_buf = vec![0u64; 10_000];
+        let bytes: &[u8] = cast_slice(aligned_buf.as_slice());
+
+        assert!(Slab::new_ref(bytes).is_ok());
+        assert!(Slab::new_ref(&bytes[..SLAB_HEADER_LEN - 1]).is_err());
+        assert!(Slab::new_ref(&bytes[1..]).is_err());
+    }
 }
@@ -106,6 +106,31 @@ pub enum DexErrorCode {
     WrongRentSysvarAccount,
     RentNotProvided,
     OrdersNotRentExempt,
+    WrongTriggerQueueAccount,
+
+    TriggerQueueFull = 60,
+    MarketHasNoTriggerQueue,
+    ClockNotProvided,
+    OrderNotFound,
+    ReplacedOrderSideMismatch,
+    InvalidFeeSchedule,
+    EventQueueNotEmpty,
+    InvalidMarketParams,
+    OrderBookNotEmpty,
+    MarketIsCancelOnly,
+
+    MarketIsPaused = 70,
+    OrdersAccountNotEmpty,
+    OrdersAlreadyInitialized,
+    AutoSettleNotEnabled,
+    WrongSettleWallet,
+    WrongTradeLogAccount,
+    WrongMarketStatsAccount,
+    MarketNotExtended,
+    WrongMarketAuthorityRecord,
+    OrdersAccountNotV2,
+
+    IcebergReplacedAtSamePrice = 80,
 
     Unknown = 1000,
 
@@ -124,6 +149,8 @@ pub enum SourceFileId {
     Matching = 2,
     #[error("src/critbit.rs")]
     Critbit = 3,
+    #[error("src/snapshot.rs")]
+    Snapshot = 4,
 }
 
 #[macro_export]
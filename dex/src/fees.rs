@@ -1,4 +1,6 @@
+use bytemuck::{Pod, Zeroable};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 
 #[cfg(test)]
@@ -17,6 +19,27 @@ pub enum FeeTier {
     MSRM,
 }
 
+const FEE_TIER_COUNT: usize = 7;
+
+/// The fees a market charges, stored in the market account. Rates are in basis points and
+/// indexed by `FeeTier`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(C)]
+pub struct FeeSchedule {
+    pub taker_fee_bps: [u64; FEE_TIER_COUNT],
+    pub maker_rebate_bps: [u64; FEE_TIER_COUNT],
+    // the SRM balances, in native units, needed for the SRM2 through SRM6 tiers
+    pub srm_tier_thresholds: [u64; 5],
+    // the percentage of the taker fee paid out to the referrer
+    pub referrer_rebate_pct: u64,
+}
+unsafe impl Zeroable for FeeSchedule {}
+unsafe impl Pod for FeeSchedule {}
+
+const ONE_SRM: u64 = 1_000_000;
+
 #[repr(transparent)]
 #[derive(Copy, Clone)]
 struct U64F64(u128);
@@ -65,64 +88,85 @@ const fn rebate_bps(bps: u64) -> U64F64 {
     U64F64(fee_bps(bps).0 + 1)
 }
 
-impl FeeTier {
+impl FeeSchedule {
+    pub const DEFAULT: FeeSchedule = FeeSchedule {
+        taker_fee_bps: [22, 20, 18, 16, 14, 12, 10],
+        maker_rebate_bps: [3, 3, 3, 3, 3, 3, 5],
+        srm_tier_thresholds: [
+            ONE_SRM * 100,
+            ONE_SRM * 1_000,
+            ONE_SRM * 10_000,
+            ONE_SRM * 100_000,
+            ONE_SRM * 1_000_000,
+        ],
+        referrer_rebate_pct: 20,
+    };
+
+    /// A schedule is valid if its tier thresholds are ascending, no fee exceeds 100%, and
+    /// the exchange keeps a share of every taker fee after paying out the maker and
+    /// referrer rebates, whichever tiers are trading.
+    pub fn is_valid(&self) -> bool {
+        if self.referrer_rebate_pct > 100 {
+            return false;
+        }
+        let thresholds_ascending = self
+            .srm_tier_thresholds
+            .windows(2)
+            .all(|pair| pair[0] <= pair[1]);
+        let max_maker_rebate_bps = *self.maker_rebate_bps.iter().max().unwrap();
+        let fees_cover_rebates = self.taker_fee_bps.iter().all(|&taker_fee_bps| {
+            taker_fee_bps <= 10_000
+                && (max_maker_rebate_bps == 0
+                    || (max_maker_rebate_bps as u128) * 100
+                        < (taker_fee_bps as u128) * (100 - self.referrer_rebate_pct as u128))
+        });
+        thresholds_ascending && fees_cover_rebates
+    }
+
     #[inline]
-    pub fn from_srm_and_msrm_balances(srm_held: u64, msrm_held: u64) -> FeeTier {
-        let one_srm = 1_000_000;
+    pub fn fee_tier(&self, srm_held: u64, msrm_held: u64) -> FeeTier {
+        let [srm2, srm3, srm4, srm5, srm6] = self.srm_tier_thresholds;
         match () {
             () if msrm_held >= 1 => FeeTier::MSRM,
-            () if srm_held >= one_srm * 1_000_000 => FeeTier::SRM6,
-            () if srm_held >= one_srm * 100_000 => FeeTier::SRM5,
-            () if srm_held >= one_srm * 10_000 => FeeTier::SRM4,
-            () if srm_held >= one_srm * 1_000 => FeeTier::SRM3,
-            () if srm_held >= one_srm * 100 => FeeTier::SRM2,
+            () if srm_held >= srm6 => FeeTier::SRM6,
+            () if srm_held >= srm5 => FeeTier::SRM5,
+            () if srm_held >= srm4 => FeeTier::SRM4,
+            () if srm_held >= srm3 => FeeTier::SRM3,
+            () if srm_held >= srm2 => FeeTier::SRM2,
             () => FeeTier::Base,
         }
     }
 
     #[inline]
-    pub fn maker_rebate(self, pc_qty: u64) -> u64 {
-        use FeeTier::*;
-        let rate: U64F64 = match self {
-            MSRM => rebate_bps(5),
-            Base | SRM2 | SRM3 | SRM4 | SRM5 | SRM6 => rebate_bps(3),
-        };
+    pub fn maker_rebate(&self, tier: FeeTier, pc_qty: u64) -> u64 {
+        let rate = rebate_bps(self.maker_rebate_bps[u8::from(tier) as usize]);
         rate.mul_u64(pc_qty).floor()
     }
 
-    fn taker_rate(self) -> U64F64 {
-        use FeeTier::*;
-        match self {
-            Base => fee_bps(22),
-            SRM2 => fee_bps(20),
-            SRM3 => fee_bps(18),
-            SRM4 => fee_bps(16),
-            SRM5 => fee_bps(14),
-            SRM6 => fee_bps(12),
-            MSRM => fee_bps(10),
-        }
+    fn taker_rate(&self, tier: FeeTier) -> U64F64 {
+        fee_bps(self.taker_fee_bps[u8::from(tier) as usize])
     }
 
     #[inline]
-    pub fn taker_fee(self, pc_qty: u64) -> u64 {
-        let rate = self.taker_rate();
+    pub fn taker_fee(&self, tier: FeeTier, pc_qty: u64) -> u64 {
+        let rate = self.taker_rate(tier);
         let exact_fee: U64F64 = rate.mul_u64(pc_qty);
         exact_fee.floor() + ((exact_fee.frac_part() != 0) as u64)
     }
 
     #[inline]
-    pub fn remove_taker_fee(self, pc_qty_incl_fee: u64) -> u64 {
-        let rate = self.taker_rate();
+    pub fn remove_taker_fee(&self, tier: FeeTier, pc_qty_incl_fee: u64) -> u64 {
+        let rate = self.taker_rate(tier);
         U64F64::from_int(pc_qty_incl_fee)
             .div(U64F64::ONE.add(rate))
             .try_into()
             .unwrap()
     }
-}
 
-#[inline]
-pub fn referrer_rebate(amount: u64) -> u64 {
-    amount / 5
+    #[inline]
+    pub fn referrer_rebate(&self, amount: u64) -> u64 {
+        ((amount as u128) * (self.referrer_rebate_pct as u128) / 100) as u64
+    }
 }
 
 #[cfg(test)]
@@ -133,8 +177,9 @@ mod tests {
     proptest! {
         #[test]
         fn positive_net_fees(tt: FeeTier, mt: FeeTier, qty in 1..=std::u64::MAX) {
-            let fee = tt.taker_fee(qty);
-            let rebate = mt.maker_rebate(qty) + referrer_rebate(fee);
+            let fees = FeeSchedule::DEFAULT;
+            let fee = fees.taker_fee(tt, qty);
+            let rebate = fees.maker_rebate(mt, qty) + fees.referrer_rebate(fee);
             assert!(fee > rebate);
             let net_bps_u64f64 = (fee - rebate) as u128 * 10_000;
             let three_bps = (qty as u128) * 3;
@@ -154,8 +199,9 @@ mod tests {
 
         #[test]
         fn market_order_cannot_cheat(tier: FeeTier, qty: u64) {
-            let qty_without_fees = tier.remove_taker_fee(qty);
-            let required_fee = tier.taker_fee(qty_without_fees) as i128;
+            let fees = FeeSchedule::DEFAULT;
+            let qty_without_fees = fees.remove_taker_fee(tier, qty);
+            let required_fee = fees.taker_fee(tier, qty_without_fees) as i128;
             let actual_fee = qty as i128 - qty_without_fees as i128;
             assert!([required_fee + 1, required_fee].contains(&actual_fee),
                     "actual_fee = {}, required_fee = {}",
@@ -164,9 +210,40 @@ mod tests {
 
         #[test]
         fn test_add_remove_fees(tier: FeeTier, qty in 1..=(std::u64::MAX >> 1)) {
-            let qty_with_fees = qty + tier.taker_fee(qty);
-            let qty2 = tier.remove_taker_fee(qty_with_fees);
+            let fees = FeeSchedule::DEFAULT;
+            let qty_with_fees = qty + fees.taker_fee(tier, qty);
+            let qty2 = fees.remove_taker_fee(tier, qty_with_fees);
             assert!([-1, 0, 1].contains(&(qty as i128 - qty2 as i128)))
         }
+
+        #[test]
+        fn referrer_rebate_above_100_pct_invalid(referrer_rebate_pct in 101..=std::u64::MAX) {
+            let fees = FeeSchedule {
+                referrer_rebate_pct,
+                ..FeeSchedule::DEFAULT
+            };
+            assert!(!fees.is_valid());
+        }
+
+        #[test]
+        fn valid_schedule_nonnegative_net_fees(
+            taker_fee_bps in prop::array::uniform7(0..=10_000u64),
+            maker_rebate_bps in prop::array::uniform7(0..=100u64),
+            referrer_rebate_pct in 0..=100u64,
+            tt: FeeTier,
+            mt: FeeTier,
+            qty in 1..=std::u64::MAX,
+        ) {
+            let fees = FeeSchedule {
+                taker_fee_bps,
+                maker_rebate_bps,
+                referrer_rebate_pct,
+                ..FeeSchedule::DEFAULT
+            };
+            prop_assume!(fees.is_valid());
+            let fee = fees.taker_fee(tt, qty);
+            let rebate = fees.maker_rebate(mt, qty) + fees.referrer_rebate(fee);
+            assert!(fee >= rebate);
+        }
     }
 }
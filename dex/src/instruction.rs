@@ -1,6 +1,7 @@
 #![cfg_attr(not(feature = "program"), allow(unused))]
 use crate::error::DexError;
-use crate::matching::{OrderType, Side};
+use crate::fees::FeeSchedule;
+use crate::matching::{OrderExpiry, OrderType, Side, TriggerCondition};
 use bytemuck::cast;
 use serde::{Deserialize, Serialize};
 use solana_sdk::{
@@ -18,6 +19,8 @@ use proptest::prelude::*;
 #[cfg(test)]
 use proptest_derive::Arbitrary;
 
+pub const MAX_BATCH_ORDERS: usize = 16;
+
 pub mod srm_token {
     use solana_sdk::declare_id;
     declare_id!("SRMuApVNdxXokk5GT7XD5cUUgXMBCoAz2LHeuAoKWRt");
@@ -53,9 +56,67 @@ pub struct InitializeMarketInstruction {
     // If these assumptions are problematic, rejigger the lot sizes.
     pub coin_lot_size: u64,
     pub pc_lot_size: u64,
+    // Deprecated and ignored, fees come from the fee config. Still part of the instruction
+    // data and stored in the market for older clients.
     pub fee_rate_bps: u16,
     pub vault_signer_nonce: u64,
     pub pc_dust_threshold: u64,
+    // Markets initialized without a fee config charge `FeeSchedule::DEFAULT` for good.
+    pub fee_config: Option<FeeConfig>,
+    // Can disable the market, sweep fees and change its status and params alongside the
+    // program-wide keys. Omitted by older clients, in which case only the global keys work.
+    pub market_authority: Option<[u64; 4]>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct UpdateMarketParamsInstruction {
+    pub coin_lot_size: u64,
+    pub pc_lot_size: u64,
+    pub pc_dust_threshold: u64,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct CreateOpenOrdersInstruction {
+    pub index: u64,
+    pub delegate: Option<[u64; 4]>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct FeeConfig {
+    pub fee_authority: [u64; 4],
+    pub fee_schedule: FeeSchedule,
+}
+
+fn unpack_fee_schedule(data: &[u8; 160]) -> FeeSchedule {
+    let mut words = [0u64; 20];
+    for (word, bytes) in words.iter_mut().zip(data.chunks_exact(8)) {
+        *word = u64::from_le_bytes(*array_ref![bytes, 0, 8]);
+    }
+    let (&taker_fee_bps, &maker_rebate_bps, &srm_tier_thresholds, &[referrer_rebate_pct]) =
+        array_refs![&words, 7, 7, 5, 1];
+    FeeSchedule {
+        taker_fee_bps,
+        maker_rebate_bps,
+        srm_tier_thresholds,
+        referrer_rebate_pct,
+    }
+}
+
+// An optional delegate is a 0 tag, or a 1 tag followed by the 32 byte key
+fn unpack_delegate(data: &[u8]) -> Option<Option<[u64; 4]>> {
+    match data.split_first() {
+        Some((&0, [])) => Some(None),
+        Some((&1, delegate)) if delegate.len() == 32 => {
+            Some(Some(cast(*array_ref![delegate, 0, 32])))
+        }
+        _ => None,
+    }
 }
 
 #[derive(
@@ -67,6 +128,19 @@ pub struct InitializeMarketInstruction {
 pub enum SelfTradeBehavior {
     DecrementTake = 0,
     CancelProvide = 1,
+    CancelBoth = 2,
+}
+
+#[derive(
+    PartialEq, Eq, Copy, Clone, Debug, TryFromPrimitive, IntoPrimitive, Serialize, Deserialize,
+)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum MarketStatus {
+    Active = 0,
+    CancelOnly = 1,
+    Paused = 2,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
@@ -88,6 +162,26 @@ pub struct NewOrderInstructionV2 {
     pub self_trade_behavior: SelfTradeBehavior,
 }
 
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct NewOrderInstructionV3 {
+    pub side: Side,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub limit_price: NonZeroU64,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub max_qty: NonZeroU64,
+    pub order_type: OrderType,
+    pub client_id: u64,
+    pub self_trade_behavior: SelfTradeBehavior,
+    pub expiry: Option<OrderExpiry>,
+}
+
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 pub struct NewOrderInstructionV1 {
@@ -106,6 +200,87 @@ pub struct NewOrderInstructionV1 {
     pub client_id: u64,
 }
 
+impl NewOrderInstructionV2 {
+    fn unpack(data: &[u8; 36]) -> Option<Self> {
+        let (v1_data_arr, v2_data_arr) = array_refs![data, 32, 4];
+        let v1_instr = NewOrderInstructionV1::unpack(v1_data_arr)?;
+        let self_trade_behavior = SelfTradeBehavior::try_from_primitive(
+            u32::from_le_bytes(*v2_data_arr).try_into().ok()?,
+        )
+        .ok()?;
+        Some(v1_instr.add_self_trade_behavior(self_trade_behavior))
+    }
+
+    pub fn add_expiry(self, expiry: Option<OrderExpiry>) -> NewOrderInstructionV3 {
+        let NewOrderInstructionV2 {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+            self_trade_behavior,
+        } = self;
+        NewOrderInstructionV3 {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+            self_trade_behavior,
+            expiry,
+        }
+    }
+}
+
+impl NewOrderInstructionV3 {
+    fn unpack(data: &[u8]) -> Option<Self> {
+        let (v2_data_arr, &[expiry_tag], expiry_data) = array_refs![data, 36, 1; ..;];
+        let v2_instr = NewOrderInstructionV2::unpack(v2_data_arr)?;
+        let expiry = match (expiry_tag, expiry_data.len()) {
+            (0, 0) => None,
+            (1, 12) => {
+                let (&kind_arr, &value_arr) = array_refs![array_ref![expiry_data, 0, 12], 4, 8];
+                Some(match u32::from_le_bytes(kind_arr) {
+                    0 => OrderExpiry::Slot(u64::from_le_bytes(value_arr)),
+                    1 => OrderExpiry::UnixTimestamp(i64::from_le_bytes(value_arr)),
+                    _ => return None,
+                })
+            }
+            _ => return None,
+        };
+        Some(v2_instr.add_expiry(expiry))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct NewTriggerOrderInstruction {
+    pub order: NewOrderInstructionV2,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub trigger_price: NonZeroU64,
+    pub trigger_condition: TriggerCondition,
+}
+
+impl NewTriggerOrderInstruction {
+    fn unpack(data: &[u8; 48]) -> Option<Self> {
+        let (order_arr, price_arr, condition_arr) = array_refs![data, 36, 8, 4];
+        let order = NewOrderInstructionV2::unpack(order_arr)?;
+        let trigger_price = NonZeroU64::new(u64::from_le_bytes(*price_arr))?;
+        let trigger_condition = TriggerCondition::try_from_primitive(
+            u32::from_le_bytes(*condition_arr).try_into().ok()?,
+        )
+        .ok()?;
+        Some(NewTriggerOrderInstruction {
+            order,
+            trigger_price,
+            trigger_condition,
+        })
+    }
+}
+
 impl NewOrderInstructionV1 {
     pub fn add_self_trade_behavior(
         self,
@@ -145,6 +320,8 @@ impl NewOrderInstructionV1 {
             0 => OrderType::Limit,
             1 => OrderType::ImmediateOrCancel,
             2 => OrderType::PostOnly,
+            3 => OrderType::FillOrKill,
+            4 => OrderType::PostOnlySlide,
             _ => return None,
         };
         Some(NewOrderInstructionV1 {
@@ -157,6 +334,103 @@ impl NewOrderInstructionV1 {
     }
 }
 
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct NewOrdersBatchInstruction {
+    #[cfg_attr(
+        test,
+        proptest(
+            strategy = "prop::collection::vec(any::<NewOrderInstructionV2>(), 1..=MAX_BATCH_ORDERS)"
+        )
+    )]
+    pub orders: Vec<NewOrderInstructionV2>,
+}
+
+impl NewOrdersBatchInstruction {
+    fn unpack(data: &[u8]) -> Option<Self> {
+        let (&len_arr, orders_data) = array_refs![data, 8; ..;];
+        let len = u64::from_le_bytes(len_arr);
+        if len == 0 || len > MAX_BATCH_ORDERS as u64 || orders_data.len() as u64 != len * 36 {
+            return None;
+        }
+        let orders = orders_data
+            .chunks_exact(36)
+            .map(|order_data| NewOrderInstructionV2::unpack(array_ref![order_data, 0, 36]))
+            .collect::<Option<Vec<_>>>()?;
+        Some(NewOrdersBatchInstruction { orders })
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub enum OrderToReplace {
+    OrderId(u128),
+    ClientId(u64),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct ReplaceOrderInstruction {
+    pub replaces: OrderToReplace,
+    pub order: NewOrderInstructionV3,
+}
+
+impl ReplaceOrderInstruction {
+    fn unpack(data: &[u8]) -> Option<Self> {
+        let (&discrim, rest) = array_refs![data, 4; ..;];
+        let (replaces, order_data) = match u32::from_le_bytes(discrim) {
+            0 => {
+                let (&order_id, order_data) = array_refs![rest, 16; ..;];
+                (
+                    OrderToReplace::OrderId(u128::from_le_bytes(order_id)),
+                    order_data,
+                )
+            }
+            1 => {
+                let (&client_id, order_data) = array_refs![rest, 8; ..;];
+                (
+                    OrderToReplace::ClientId(u64::from_le_bytes(client_id)),
+                    order_data,
+                )
+            }
+            _ => return None,
+        };
+        match order_data.len() {
+            37 | 49 => (),
+            _ => return None,
+        };
+        let order = NewOrderInstructionV3::unpack(order_data)?;
+        Some(ReplaceOrderInstruction { replaces, order })
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct NewIcebergOrderInstruction {
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub display_qty: NonZeroU64,
+    pub order: NewOrderInstructionV3,
+}
+
+impl NewIcebergOrderInstruction {
+    fn unpack(data: &[u8]) -> Option<Self> {
+        let (&display_qty_arr, order_data) = array_refs![data, 8; ..;];
+        let display_qty = NonZeroU64::new(u64::from_le_bytes(display_qty_arr))?;
+        match order_data.len() {
+            37 | 49 => (),
+            _ => return None,
+        };
+        let order = NewOrderInstructionV3::unpack(order_data)?;
+        Some(NewIcebergOrderInstruction { display_qty, order })
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 #[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
@@ -164,7 +438,7 @@ pub struct CancelOrderInstruction {
     pub side: Side,
     pub order_id: u128,
     pub owner: [u64; 4], // Unused
-    pub owner_slot: u8,
+    pub owner_slot: u16,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
@@ -180,6 +454,7 @@ pub enum MarketInstruction {
     /// 6. `[writable]` spl-token account for the price currency
     /// 7. `[]` coin currency Mint
     /// 8. `[]` price currency Mint
+    /// 9. `[writable]` (optional) zeroed out trigger queue
     InitializeMarket(InitializeMarketInstruction),
     /// 0. `[writable]` the market
     /// 1. `[writable]` the OpenOrders account to use
@@ -199,6 +474,8 @@ pub enum MarketInstruction {
     /// 4. `[writable]` asks
     /// 5. `[writable]` coin fee receivable account
     /// 6. `[writable]` pc fee receivable account
+    /// 7. `[writable]` the trigger queue, required if the market has one
+    /// 8. `[]` the clock sysvar, required unless the market has the original layout
     MatchOrders(u16),
     /// ... `[writable]` OpenOrders
     /// accounts.len() - 4 `[writable]` market
@@ -211,9 +488,12 @@ pub enum MarketInstruction {
     /// 2. `[writable]` the request queue
     /// 3. `[signer]` the OpenOrders owner
     CancelOrder(CancelOrderInstruction),
+    /// Without the owner's signature funds can only go to the wallets registered with
+    /// `SetAutoSettle`, no referrer may be passed and referrer rebates stay accrued.
+    ///
     /// 0. `[writable]` market
     /// 1. `[writable]` OpenOrders
-    /// 2. `[signer]` the OpenOrders owner
+    /// 2. `[signer]` the OpenOrders owner, or any account if settling to the registered wallets
     /// 3. `[writable]` coin vault
     /// 4. `[writable]` pc vault
     /// 5. `[writable]` coin wallet
@@ -228,14 +508,16 @@ pub enum MarketInstruction {
     /// 3. `[signer]` the OpenOrders owner
     CancelOrderByClientId(u64),
     /// 0. `[writable]` market
-    /// 1. `[signer]` disable authority
+    /// 1. `[signer]` disable authority or market authority
+    /// 2. `[]` (optional) the market's authority record, see `SetMarketAuthority`
     DisableMarket,
     /// 0. `[writable]` market
     /// 1. `[writable]` pc vault
-    /// 2. `[signer]` fee sweeping authority
+    /// 2. `[signer]` fee sweeping authority or market authority
     /// 3. `[writable]` fee receivable account
     /// 4. `[]` vault signer
     /// 5. `[]` spl token program
+    /// 6. `[]` (optional) the market's authority record, see `SetMarketAuthority`
     SweepFees,
     /// 0. `[writable]` the market
     /// 1. `[writable]` the OpenOrders account to use
@@ -248,6 +530,213 @@ pub enum MarketInstruction {
     /// 8. `[]` the rent sysvar
     /// 9. `[writable]` (optional) the (M)SRM account used for fee discounts
     NewOrderV2(NewOrderInstructionV2),
+    /// Places an order that waits in the market's trigger queue until the last
+    /// trade price meets its trigger condition, at which point it is matched
+    /// like a regular order of its order type.
+    ///
+    /// 0. `[writable]` the market
+    /// 1. `[writable]` the OpenOrders account to use
+    /// 2. `[writable]` the request queue
+    /// 3. `[writable]` the (coin or price currency) account paying for the order
+    /// 4. `[signer]` owner of the OpenOrders account
+    /// 5. `[writable]` coin vault
+    /// 6. `[writable]` pc vault
+    /// 7. `[]` spl token program
+    /// 8. `[]` the rent sysvar
+    /// 9. `[writable]` the trigger queue
+    /// 10. `[writable]` (optional) the (M)SRM account used for fee discounts
+    NewTriggerOrder(NewTriggerOrderInstruction),
+    /// Same as NewOrderV2, but the order may carry an expiry slot or unix timestamp.
+    /// Once it has passed, the order is removed from the book and its funds unlocked
+    /// the next time it would be matched against by a MatchOrders.
+    /// Markets with the original layout reject orders with an expiry and post-only slide
+    /// orders.
+    ///
+    /// 0. `[writable]` the market
+    /// 1. `[writable]` the OpenOrders account to use
+    /// 2. `[writable]` the request queue
+    /// 3. `[writable]` the (coin or price currency) account paying for the order
+    /// 4. `[signer]` owner of the OpenOrders account
+    /// 5. `[writable]` coin vault
+    /// 6. `[writable]` pc vault
+    /// 7. `[]` spl token program
+    /// 8. `[]` the rent sysvar
+    /// 9. `[writable]` (optional) the (M)SRM account used for fee discounts
+    NewOrderV3(NewOrderInstructionV3),
+    /// Cancels one of the owner's orders and places a new order in its stead, as a single
+    /// request. The replacement takes over the OpenOrders slot of the original and the funds
+    /// the original has locked, so only the difference is deposited. If the original is partly
+    /// filled before the replacement is matched, the replacement is reduced accordingly.
+    /// If the replacement is on the same side, at the same price, with the same expiry and
+    /// client order id and for no more than the remaining size of the original, the original
+    /// is reduced in place and keeps its time priority and order id.
+    /// If the original is no longer in the book, the replacement is not placed.
+    /// Markets with the original layout have no room in their request queue for the order
+    /// being replaced and reject this instruction.
+    ///
+    /// 0. `[writable]` the market
+    /// 1. `[writable]` the OpenOrders account to use
+    /// 2. `[writable]` the request queue
+    /// 3. `[writable]` the (coin or price currency) account paying for the order
+    /// 4. `[signer]` owner of the OpenOrders account
+    /// 5. `[writable]` coin vault
+    /// 6. `[writable]` pc vault
+    /// 7. `[]` spl token program
+    /// 8. `[]` the rent sysvar
+    /// 9. `[]` bids for a bid, asks for an ask
+    /// 10. `[writable]` (optional) the (M)SRM account used for fee discounts
+    ReplaceOrder(ReplaceOrderInstruction),
+    /// Cancels every order of the OpenOrders account, or only those on the given side.
+    ///
+    /// Queues a cancel request for each order slot in use, as many as the request queue has room
+    /// for. Any orders left over can be cancelled by calling it again once the queued requests
+    /// have been matched and their events consumed.
+    ///
+    /// 0. `[]` market
+    /// 1. `[writable]` OpenOrders
+    /// 2. `[writable]` the request queue
+    /// 3. `[signer]` the OpenOrders owner
+    CancelAllOrders(Option<Side>),
+    /// Places up to `MAX_BATCH_ORDERS` orders with a single deposit per currency.
+    ///
+    /// 0. `[writable]` the market
+    /// 1. `[writable]` the OpenOrders account to use
+    /// 2. `[writable]` the request queue
+    /// 3. `[writable]` the coin account paying for asks
+    /// 4. `[writable]` the price currency account paying for bids
+    /// 5. `[signer]` owner of the OpenOrders account
+    /// 6. `[writable]` coin vault
+    /// 7. `[writable]` pc vault
+    /// 8. `[]` spl token program
+    /// 9. `[]` the rent sysvar
+    /// 10. `[writable]` (optional) the (M)SRM account used for fee discounts
+    NewOrdersBatch(NewOrdersBatchInstruction),
+    /// Same as NewOrderV3, but only up to `display_qty` of the order is shown in the book at
+    /// a time. Whenever the shown quantity is filled, it is refilled from the rest of the
+    /// order, which then goes to the back of the queue at its price under a new order id.
+    /// Markets with the original layout reject iceberg orders.
+    ///
+    /// 0. `[writable]` the market
+    /// 1. `[writable]` the OpenOrders account to use
+    /// 2. `[writable]` the request queue
+    /// 3. `[writable]` the (coin or price currency) account paying for the order
+    /// 4. `[signer]` owner of the OpenOrders account
+    /// 5. `[writable]` coin vault
+    /// 6. `[writable]` pc vault
+    /// 7. `[]` spl token program
+    /// 8. `[]` the rent sysvar
+    /// 9. `[writable]` (optional) the (M)SRM account used for fee discounts
+    NewIcebergOrder(NewIcebergOrderInstruction),
+    /// Replaces the fee schedule of a market initialized with a fee config. The event queue
+    /// must be empty, so that every fill is settled at the rates it was matched at.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[]` event queue
+    /// 2. `[signer]` the market's fee authority
+    UpdateFeeSchedule(FeeSchedule),
+    /// Changes the lot sizes and dust threshold of a market. Since orders are stored in lots,
+    /// the lot sizes can only change while the request queue, the order book and the trigger
+    /// queue are all empty. The dust threshold can change at any time.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[]` request queue
+    /// 2. `[]` bids
+    /// 3. `[]` asks
+    /// 4. `[signer]` disable authority or market authority
+    /// 5. `[]` the trigger queue, required if the market has one, or for markets with the
+    ///    original layout, (optional) the market's authority record
+    UpdateMarketParams(UpdateMarketParamsInstruction),
+    /// Moves a market between active, cancel-only and paused. Cancel-only markets reject new
+    /// orders but still match queued requests, cancel, consume events and settle. Paused markets
+    /// additionally reject cancels and matching until they are resumed.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[signer]` disable authority or market authority
+    /// 2. `[]` (optional) the market's authority record, see `SetMarketAuthority`
+    SetMarketStatus(MarketStatus),
+    /// Closes an OpenOrders account with no open orders and no unsettled funds, sending its
+    /// lamports to the destination.
+    ///
+    /// 0. `[writable]` OpenOrders
+    /// 1. `[signer]` the OpenOrders owner
+    /// 2. `[writable]` the destination for the reclaimed lamports
+    /// 3. `[]` market
+    CloseOpenOrders,
+    /// Initializes an OpenOrders account up front instead of on the first order. The optional
+    /// delegate can place and cancel orders on the owner's behalf, but cannot settle funds.
+    /// Only 512-slot accounts can have a delegate.
+    ///
+    /// 0. `[writable]` zeroed out OpenOrders
+    /// 1. `[signer]` the OpenOrders owner
+    /// 2. `[]` market
+    /// 3. `[]` the rent sysvar
+    InitOpenOrders(Option<[u64; 4]>),
+    /// Creates and initializes the OpenOrders account at the address given by
+    /// `find_open_orders_address(market, owner, index)`, so clients can locate it without
+    /// keeping track of it.
+    ///
+    /// 0. `[writable]` the OpenOrders address to create
+    /// 1. `[writable, signer]` the OpenOrders owner, who pays for the account
+    /// 2. `[]` market
+    /// 3. `[]` the rent sysvar
+    /// 4. `[]` the system program
+    CreateOpenOrders(CreateOpenOrdersInstruction),
+    /// Moves the balances of a 128-slot OpenOrders account with no open orders into a
+    /// 512-slot one owned by the same owner, then closes the old account and returns its
+    /// lamports to the owner. The new account is initialized if it is still zeroed out.
+    ///
+    /// 0. `[writable]` the 128-slot OpenOrders
+    /// 1. `[writable]` the 512-slot OpenOrders
+    /// 2. `[writable, signer]` the OpenOrders owner
+    /// 3. `[]` market
+    /// 4. `[]` the rent sysvar
+    MigrateOpenOrders,
+    /// Registers the wallets `SettleFor` pays free funds out to, or clears them. Once set,
+    /// anyone (typically the crank after consuming events) can settle the account to them.
+    /// Only 512-slot accounts can register wallets.
+    ///
+    /// 0. `[writable]` OpenOrders
+    /// 1. `[signer]` the OpenOrders owner
+    /// 2. `[]` market
+    /// 3. `[]` coin wallet, only when enabling
+    /// 4. `[]` pc wallet, only when enabling
+    SetAutoSettle(bool),
+    /// Sends all free funds of an OpenOrders account with auto-settle enabled to its registered
+    /// wallets. Needs no signature. Referrer rebates are left accrued.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[writable]` OpenOrders
+    /// 2. `[writable]` coin vault
+    /// 3. `[writable]` pc vault
+    /// 4. `[writable]` the registered coin wallet
+    /// 5. `[writable]` the registered pc wallet
+    /// 6. `[]` vault signer
+    /// 7. `[]` spl token program
+    SettleFor,
+    /// Attaches a trade log to the market. From then on MatchOrders records every fill in it
+    /// and requires the trade log and the clock sysvar among its optional accounts.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[writable]` zeroed out trade log
+    /// 2. `[signer]` disable authority or market authority
+    InitTradeLog,
+    /// Attaches a stats account to the market, see `MarketStats`. From then on MatchOrders
+    /// updates it on every fill and requires it and the clock sysvar among its optional
+    /// accounts.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[writable]` zeroed out stats account
+    /// 2. `[signer]` disable authority or market authority
+    InitMarketStats,
+    /// Sets the admin authority of a market, or removes it if all zero. Markets with the
+    /// original layout have no room for it and keep it in an authority record instead, which
+    /// the admin instructions then take as their last account.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[signer]` disable authority
+    /// 2. `[writable]` the market's authority record, zeroed out the first time; only for
+    ///    markets with the original layout
+    SetMarketAuthority([u64; 4]),
 }
 
 impl MarketInstruction {
@@ -256,7 +745,7 @@ impl MarketInstruction {
     }
 
     pub fn unpack(versioned_bytes: &[u8]) -> Option<Self> {
-        if versioned_bytes.len() < 5 || versioned_bytes.len() > 58 {
+        if versioned_bytes.len() < 5 || versioned_bytes.len() > 13 + 36 * MAX_BATCH_ORDERS {
             return None;
         }
         let (&[version], &discrim, data) = array_refs![versioned_bytes, 1, 4; ..;];
@@ -265,15 +754,39 @@ impl MarketInstruction {
         }
         let discrim = u32::from_le_bytes(discrim);
         Some(match (discrim, data.len()) {
-            (0, 34) => MarketInstruction::InitializeMarket({
-                let data_array = array_ref![data, 0, 34];
+            (0, len) if len >= 34 => MarketInstruction::InitializeMarket({
+                let (data_array, fee_config_data) = array_refs![data, 34; ..;];
                 let fields = array_refs![data_array, 8, 8, 2, 8, 8];
+                let (fee_config, authority_data) = match fee_config_data.split_first() {
+                    None => (None, fee_config_data),
+                    Some((&0, rest)) => (None, rest),
+                    Some((&1, rest)) if rest.len() >= 192 => {
+                        let (fee_config_data, rest) = rest.split_at(192);
+                        let (authority_data, schedule_data) =
+                            array_refs![array_ref![fee_config_data, 0, 192], 32, 160];
+                        let fee_config = FeeConfig {
+                            fee_authority: cast(*authority_data),
+                            fee_schedule: unpack_fee_schedule(schedule_data),
+                        };
+                        (Some(fee_config), rest)
+                    }
+                    _ => return None,
+                };
+                let market_authority = match authority_data.split_first() {
+                    None | Some((&0, [])) => None,
+                    Some((&1, authority_data)) if authority_data.len() == 32 => {
+                        Some(cast(*array_ref![authority_data, 0, 32]))
+                    }
+                    _ => return None,
+                };
                 InitializeMarketInstruction {
                     coin_lot_size: u64::from_le_bytes(*fields.0),
                     pc_lot_size: u64::from_le_bytes(*fields.1),
                     fee_rate_bps: u16::from_le_bytes(*fields.2),
                     vault_signer_nonce: u64::from_le_bytes(*fields.3),
                     pc_dust_threshold: u64::from_le_bytes(*fields.4),
+                    fee_config,
+                    market_authority,
                 }
             }),
             (1, 32) => MarketInstruction::NewOrder({
@@ -288,9 +801,9 @@ impl MarketInstruction {
                 let limit = array_ref![data, 0, 2];
                 MarketInstruction::ConsumeEvents(u16::from_le_bytes(*limit))
             }
-            (4, 53) => MarketInstruction::CancelOrder({
-                let data_array = array_ref![data, 0, 53];
-                let fields = array_refs![data_array, 4, 16, 32, 1];
+            (4, 53) | (4, 54) => MarketInstruction::CancelOrder({
+                let (data_array, owner_slot_data) = array_refs![data, 52; ..;];
+                let fields = array_refs![data_array, 4, 16, 32];
                 let side = match u32::from_le_bytes(*fields.0) {
                     0 => Side::Bid,
                     1 => Side::Ask,
@@ -298,7 +811,12 @@ impl MarketInstruction {
                 };
                 let order_id = u128::from_le_bytes(*fields.1);
                 let owner = cast(*fields.2);
-                let &[owner_slot] = fields.3;
+                // Clients from before OpenOrders had more than 256 slots send a single byte
+                let owner_slot = match owner_slot_data {
+                    &[owner_slot] => owner_slot.into(),
+                    &[lo, hi] => u16::from_le_bytes([lo, hi]),
+                    _ => return None,
+                };
                 CancelOrderInstruction {
                     side,
                     order_id,
@@ -315,13 +833,69 @@ impl MarketInstruction {
             (8, 0) => MarketInstruction::SweepFees,
             (9, 36) => MarketInstruction::NewOrderV2({
                 let data_arr = array_ref![data, 0, 36];
-                let (v1_data_arr, v2_data_arr) = array_refs![data_arr, 32, 4];
-                let v1_instr = NewOrderInstructionV1::unpack(v1_data_arr)?;
-                let self_trade_behavior = SelfTradeBehavior::try_from_primitive(
-                    u32::from_le_bytes(*v2_data_arr).try_into().ok()?,
-                )
-                .ok()?;
-                v1_instr.add_self_trade_behavior(self_trade_behavior)
+                NewOrderInstructionV2::unpack(data_arr)?
+            }),
+            (10, 48) => MarketInstruction::NewTriggerOrder({
+                let data_arr = array_ref![data, 0, 48];
+                NewTriggerOrderInstruction::unpack(data_arr)?
+            }),
+            (11, 37) | (11, 49) => {
+                MarketInstruction::NewOrderV3(NewOrderInstructionV3::unpack(data)?)
+            }
+            (12, 49) | (12, 57) | (12, 61) | (12, 69) => {
+                MarketInstruction::ReplaceOrder(ReplaceOrderInstruction::unpack(data)?)
+            }
+            (13, 1) | (13, 5) => MarketInstruction::CancelAllOrders({
+                let (&[tag], side_data) = array_refs![data, 1; ..;];
+                match (tag, side_data.len()) {
+                    (0, 0) => None,
+                    (1, 4) => match u32::from_le_bytes(*array_ref![side_data, 0, 4]) {
+                        0 => Some(Side::Bid),
+                        1 => Some(Side::Ask),
+                        _ => return None,
+                    },
+                    _ => return None,
+                }
+            }),
+            (14, len) if len >= 8 => {
+                MarketInstruction::NewOrdersBatch(NewOrdersBatchInstruction::unpack(data)?)
+            }
+            (15, 45) | (15, 57) => {
+                MarketInstruction::NewIcebergOrder(NewIcebergOrderInstruction::unpack(data)?)
+            }
+            (17, 24) => MarketInstruction::UpdateMarketParams({
+                let fields = array_refs![array_ref![data, 0, 24], 8, 8, 8];
+                UpdateMarketParamsInstruction {
+                    coin_lot_size: u64::from_le_bytes(*fields.0),
+                    pc_lot_size: u64::from_le_bytes(*fields.1),
+                    pc_dust_threshold: u64::from_le_bytes(*fields.2),
+                }
+            }),
+            (16, 160) => {
+                MarketInstruction::UpdateFeeSchedule(unpack_fee_schedule(array_ref![data, 0, 160]))
+            }
+            (18, 4) => MarketInstruction::SetMarketStatus({
+                let status = u32::from_le_bytes(*array_ref![data, 0, 4]);
+                MarketStatus::try_from_primitive(status.try_into().ok()?).ok()?
+            }),
+            (19, 0) => MarketInstruction::CloseOpenOrders,
+            (20, 1) | (20, 33) => MarketInstruction::InitOpenOrders(unpack_delegate(data)?),
+            (22, 0) => MarketInstruction::MigrateOpenOrders,
+            (23, 1) => MarketInstruction::SetAutoSettle(match data[0] {
+                0 => false,
+                1 => true,
+                _ => return None,
+            }),
+            (24, 0) => MarketInstruction::SettleFor,
+            (25, 0) => MarketInstruction::InitTradeLog,
+            (26, 0) => MarketInstruction::InitMarketStats,
+            (27, 32) => MarketInstruction::SetMarketAuthority(cast(*array_ref![data, 0, 32])),
+            (21, 9) | (21, 41) => MarketInstruction::CreateOpenOrders({
+                let (index_data, delegate_data) = array_refs![data, 8; ..;];
+                CreateOpenOrdersInstruction {
+                    index: u64::from_le_bytes(*index_data),
+                    delegate: unpack_delegate(delegate_data)?,
+                }
             }),
             _ => return None,
         })
@@ -361,6 +935,8 @@ pub fn initialize_market(
         fee_rate_bps: 0,
         vault_signer_nonce,
         pc_dust_threshold,
+        fee_config: None,
+        market_authority: None,
     })
     .pack();
 
@@ -414,13 +990,41 @@ mod tests {
                 serialized, unpack_serde_result, unpack_result
             );
         }
+
+        #[test]
+        fn test_unpack_truncated(inst: MarketInstruction) {
+            let serialized = inst.pack();
+            for len in 0..serialized.len() {
+                MarketInstruction::unpack(&serialized[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unpack_truncated_delegate() {
+        let delegate = Some([1, 2, 3, 4]);
+        let init = MarketInstruction::InitOpenOrders(delegate).pack();
+        let create =
+            MarketInstruction::CreateOpenOrders(CreateOpenOrdersInstruction { index: 7, delegate })
+                .pack();
+        // a set tag with the key cut off entirely
+        assert_eq!(MarketInstruction::unpack(&init[..6]), None);
+        assert_eq!(MarketInstruction::unpack(&create[..14]), None);
+        // an unset tag followed by a key
+        let mut init_unset = init.clone();
+        init_unset[5] = 0;
+        assert_eq!(MarketInstruction::unpack(&init_unset), None);
+        assert_eq!(
+            MarketInstruction::unpack(&init),
+            Some(MarketInstruction::InitOpenOrders(delegate))
+        );
     }
 }
 
 #[cfg(feature = "fuzz")]
 mod fuzzing {
     use super::*;
-    use crate::matching::{OrderType, Side};
+    use crate::matching::{OrderExpiry, OrderType, Side, TriggerCondition};
     use arbitrary::Unstructured;
     use std::convert::{TryFrom, TryInto};
 
@@ -489,6 +1093,146 @@ mod fuzzing {
         }
     }
 
+    #[derive(arbitrary::Arbitrary)]
+    struct NewTriggerOrderInstructionU64 {
+        pub order: NewOrderInstructionV2,
+        pub trigger_price: u64,
+        pub trigger_condition: TriggerCondition,
+    }
+
+    impl TryFrom<NewTriggerOrderInstructionU64> for NewTriggerOrderInstruction {
+        type Error = std::num::TryFromIntError;
+
+        fn try_from(value: NewTriggerOrderInstructionU64) -> Result<Self, Self::Error> {
+            Ok(Self {
+                order: value.order,
+                trigger_price: value.trigger_price.try_into()?,
+                trigger_condition: value.trigger_condition,
+            })
+        }
+    }
+
+    impl From<&NewTriggerOrderInstruction> for NewTriggerOrderInstructionU64 {
+        fn from(value: &NewTriggerOrderInstruction) -> Self {
+            Self {
+                order: value.order.clone(),
+                trigger_price: value.trigger_price.get(),
+                trigger_condition: value.trigger_condition,
+            }
+        }
+    }
+
+    impl arbitrary::Arbitrary for NewTriggerOrderInstruction {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            <NewTriggerOrderInstructionU64 as arbitrary::Arbitrary>::arbitrary(u)?
+                .try_into()
+                .map_err(|_| arbitrary::Error::IncorrectFormat)
+        }
+
+        fn size_hint(depth: usize) -> (usize, Option<usize>) {
+            <NewTriggerOrderInstructionU64 as arbitrary::Arbitrary>::size_hint(depth)
+        }
+
+        fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+            let x: NewTriggerOrderInstructionU64 = self.into();
+            Box::new(
+                x.shrink()
+                    .map(NewTriggerOrderInstructionU64::try_into)
+                    .filter_map(Result::ok),
+            )
+        }
+    }
+
+    #[derive(arbitrary::Arbitrary)]
+    struct NewIcebergOrderInstructionU64 {
+        pub display_qty: u64,
+        pub order: NewOrderInstructionV3,
+    }
+
+    impl TryFrom<NewIcebergOrderInstructionU64> for NewIcebergOrderInstruction {
+        type Error = std::num::TryFromIntError;
+
+        fn try_from(value: NewIcebergOrderInstructionU64) -> Result<Self, Self::Error> {
+            Ok(Self {
+                display_qty: value.display_qty.try_into()?,
+                order: value.order,
+            })
+        }
+    }
+
+    impl From<&NewIcebergOrderInstruction> for NewIcebergOrderInstructionU64 {
+        fn from(value: &NewIcebergOrderInstruction) -> Self {
+            Self {
+                display_qty: value.display_qty.get(),
+                order: value.order.clone(),
+            }
+        }
+    }
+
+    impl arbitrary::Arbitrary for NewIcebergOrderInstruction {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            <NewIcebergOrderInstructionU64 as arbitrary::Arbitrary>::arbitrary(u)?
+                .try_into()
+                .map_err(|_| arbitrary::Error::IncorrectFormat)
+        }
+
+        fn size_hint(depth: usize) -> (usize, Option<usize>) {
+            <NewIcebergOrderInstructionU64 as arbitrary::Arbitrary>::size_hint(depth)
+        }
+
+        fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+            let x: NewIcebergOrderInstructionU64 = self.into();
+            Box::new(
+                x.shrink()
+                    .map(NewIcebergOrderInstructionU64::try_into)
+                    .filter_map(Result::ok),
+            )
+        }
+    }
+
+    #[derive(arbitrary::Arbitrary)]
+    struct NewOrderInstructionV3Parts {
+        pub order: NewOrderInstructionV2,
+        pub expiry: Option<OrderExpiry>,
+    }
+
+    impl From<NewOrderInstructionV3Parts> for NewOrderInstructionV3 {
+        fn from(value: NewOrderInstructionV3Parts) -> Self {
+            value.order.add_expiry(value.expiry)
+        }
+    }
+
+    impl From<&NewOrderInstructionV3> for NewOrderInstructionV3Parts {
+        fn from(value: &NewOrderInstructionV3) -> Self {
+            Self {
+                order: NewOrderInstructionV2 {
+                    side: value.side,
+                    limit_price: value.limit_price,
+                    max_qty: value.max_qty,
+                    order_type: value.order_type,
+                    client_id: value.client_id,
+                    self_trade_behavior: value.self_trade_behavior,
+                },
+                expiry: value.expiry,
+            }
+        }
+    }
+
+    impl arbitrary::Arbitrary for NewOrderInstructionV3 {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            Ok(<NewOrderInstructionV3Parts as arbitrary::Arbitrary>::arbitrary(u)?.into())
+        }
+
+        fn size_hint(depth: usize) -> (usize, Option<usize>) {
+            <NewOrderInstructionV3Parts as arbitrary::Arbitrary>::size_hint(depth)
+        }
+
+        fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+            let x: NewOrderInstructionV3Parts = self.into();
+            Box::new(x.shrink().map(NewOrderInstructionV3::from))
+        }
+    }
+
     impl arbitrary::Arbitrary for NewOrderInstructionV1 {
         fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
             <NewOrderInstructionU64 as arbitrary::Arbitrary>::arbitrary(u)?
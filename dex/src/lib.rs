@@ -7,9 +7,11 @@ pub mod error;
 mod tests;
 
 pub mod critbit;
-mod fees;
+pub mod fees;
 pub mod instruction;
 pub mod matching;
+pub mod simulation;
+pub mod snapshot;
 pub mod state;
 
 #[cfg(feature = "program")]
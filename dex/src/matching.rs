@@ -1,20 +1,24 @@
 use std::num::NonZeroU64;
 
-use crate::instruction::SelfTradeBehavior;
+use crate::instruction::{MarketStatus, SelfTradeBehavior};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 #[cfg(test)]
 use proptest_derive::Arbitrary;
 use serde::{Deserialize, Serialize};
+use solana_sdk::clock::Clock;
 #[cfg(feature = "program")]
 use solana_sdk::info;
 
 use crate::critbit::SlabTreeError;
 use crate::error::{DexErrorCode, DexResult, SourceFileId};
 use crate::{
-    critbit::{LeafNode, NodeHandle, Slab, SlabView},
+    critbit::{LeafNode, NodeHandle, OrderBookSide, OrderExt, OrderExtTable, Slab, SlabView},
     error::DexError,
-    fees::{self, FeeTier},
-    state::{Event, EventQueue, EventView, MarketState, Request, RequestQueue, RequestView},
+    fees::FeeTier,
+    state::{
+        Event, EventQueue, EventView, MarketStateMut, MarketStats, ReplacedOrder, Request,
+        RequestExt, RequestQueue, RequestView, TradeLog, TriggerOrder, TriggerQueue,
+    },
 };
 
 #[cfg(not(feature = "program"))]
@@ -44,17 +48,93 @@ pub enum OrderType {
     Limit = 0,
     ImmediateOrCancel = 1,
     PostOnly = 2,
+    FillOrKill = 3,
+    PostOnlySlide = 4,
+}
+
+#[derive(
+    Eq, PartialEq, Copy, Clone, TryFromPrimitive, IntoPrimitive, Debug, Serialize, Deserialize,
+)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum TriggerCondition {
+    // buy stops and sell take-profits
+    PriceAtOrAbove = 0,
+    // sell stops and buy take-profits
+    PriceAtOrBelow = 1,
+}
+
+impl TriggerCondition {
+    #[inline]
+    pub fn is_met(self, trigger_price: NonZeroU64, last_trade_price: NonZeroU64) -> bool {
+        match self {
+            TriggerCondition::PriceAtOrAbove => last_trade_price >= trigger_price,
+            TriggerCondition::PriceAtOrBelow => last_trade_price <= trigger_price,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub enum OrderExpiry {
+    Slot(u64),
+    UnixTimestamp(i64),
+}
+
+impl OrderExpiry {
+    #[inline]
+    pub fn has_passed(self, clock: &Clock) -> bool {
+        match self {
+            OrderExpiry::Slot(slot) => clock.slot >= slot,
+            OrderExpiry::UnixTimestamp(timestamp) => clock.unix_timestamp >= timestamp,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn encode(expiry: Option<OrderExpiry>) -> (u8, u64) {
+        match expiry {
+            None => (0, 0),
+            Some(OrderExpiry::Slot(slot)) => (1, slot),
+            Some(OrderExpiry::UnixTimestamp(timestamp)) => (2, timestamp as u64),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn decode(kind: u8, value: u64) -> Option<Option<OrderExpiry>> {
+        match kind {
+            0 => Some(None),
+            1 => Some(Some(OrderExpiry::Slot(value))),
+            2 => Some(Some(OrderExpiry::UnixTimestamp(value as i64))),
+            _ => None,
+        }
+    }
 }
 
 fn extract_price_from_order_id(order_id: &u128) -> u64 {
     (order_id >> 64) as u64
 }
 
+// Orders placed later have higher sequence numbers, which bids store inverted.
+pub(crate) fn extract_seq_num_from_order_id(order_id: &u128, side: Side) -> u64 {
+    match side {
+        Side::Bid => !(*order_id as u64),
+        Side::Ask => *order_id as u64,
+    }
+}
+
 pub struct OrderBookState<'a> {
     // first byte of a key is 0xaa or 0xbb, disambiguating bids and asks
     pub bids: &'a mut Slab,
     pub asks: &'a mut Slab,
-    pub market_state: &'a mut MarketState,
+    // expiry and iceberg state of the resting orders, only present on extended markets
+    pub bid_exts: Option<&'a mut OrderExtTable>,
+    pub ask_exts: Option<&'a mut OrderExtTable>,
+    pub market_state: MarketStateMut<'a>,
+    pub clock: Option<Clock>,
+    pub trade_log: Option<TradeLog<'a>>,
+    pub stats: Option<&'a mut MarketStats>,
 }
 
 impl<'ob> OrderBookState<'ob> {
@@ -65,6 +145,130 @@ impl<'ob> OrderBookState<'ob> {
         }
     }
 
+    fn side(&self, side: Side) -> OrderBookSide<'_> {
+        match side {
+            Side::Bid => OrderBookSide {
+                orders: &*self.bids,
+                exts: self.bid_exts.as_deref(),
+            },
+            Side::Ask => OrderBookSide {
+                orders: &*self.asks,
+                exts: self.ask_exts.as_deref(),
+            },
+        }
+    }
+
+    fn exts_mut(&mut self, side: Side) -> Option<&mut OrderExtTable> {
+        match side {
+            Side::Bid => self.bid_exts.as_deref_mut(),
+            Side::Ask => self.ask_exts.as_deref_mut(),
+        }
+    }
+
+    fn order_ext(&self, side: Side, handle: NodeHandle) -> Option<OrderExt> {
+        let side = self.side(side);
+        let leaf = side.orders.get(handle)?.as_leaf()?;
+        side.ext(leaf).copied()
+    }
+
+    // Builds the side table entry of a new order, if it needs one. Only extended markets can
+    // take orders that expire, hide part of their quantity or rest under a slid or triggered
+    // order id.
+    fn new_order_ext(
+        &self,
+        leaf: &mut LeafNode,
+        expiry: Option<OrderExpiry>,
+        display_qty: Option<NonZeroU64>,
+        slid: bool,
+    ) -> DexResult<Option<OrderExt>> {
+        let mut ext = OrderExt::new(expiry);
+        if let Some(display_qty) = display_qty {
+            ext.hide_quantity(leaf, display_qty);
+        }
+        if expiry.is_none() && ext.hidden_quantity() == 0 && !slid {
+            return Ok(None);
+        }
+        check_assert!(self.bid_exts.is_some() && self.ask_exts.is_some())?;
+        Ok(Some(ext))
+    }
+
+    // Rests an order on the book together with its side table entry.
+    fn insert_order(
+        &mut self,
+        side: Side,
+        mut leaf: LeafNode,
+        ext: Option<OrderExt>,
+    ) -> Result<(), SlabTreeError> {
+        leaf.set_has_ext(false);
+        if let Some(ext) = ext {
+            self.exts_mut(side)
+                .ok_or(SlabTreeError::OutOfSpace)?
+                .insert(&mut leaf, &ext)?;
+        }
+        if let Err(err) = self.orders_mut(side).insert_leaf(&leaf) {
+            if let Some(exts) = self.exts_mut(side) {
+                exts.remove(&leaf);
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // Takes an order off the book together with its side table entry.
+    fn remove_order(
+        &mut self,
+        side: Side,
+        order_id: &u128,
+    ) -> Option<(LeafNode, Option<OrderExt>)> {
+        let leaf = self.orders_mut(side).remove_by_key(order_id)?;
+        let ext = self.exts_mut(side).and_then(|exts| exts.remove(&leaf));
+        Some((leaf, ext))
+    }
+
+    // The id an open orders account knows an order by goes stale when the order is repriced by
+    // a post-only slide, requeued from its iceberg reserve or activated from the trigger queue,
+    // until the event carrying the new id is consumed. The side table entry of the owner's slot
+    // always has the current one.
+    fn resting_order_id(
+        &self,
+        side: Side,
+        order_id: &u128,
+        owner: &[u64; 4],
+        owner_slot: u16,
+    ) -> u128 {
+        self.side(side)
+            .exts
+            .and_then(|exts| exts.find(owner, owner_slot))
+            .map_or(*order_id, |ext| *ext.order_id())
+    }
+
+    // Takes the least aggressive order off a full side of the book to make room for a new one.
+    fn remove_worst_order(&mut self, side: Side) -> Option<(LeafNode, Option<OrderExt>)> {
+        let orders = &*self.orders_mut(side);
+        let handle = match side {
+            Side::Bid => orders.find_min(),
+            Side::Ask => orders.find_max(),
+        }?;
+        let order_id = *orders.get(handle)?.as_leaf()?.order_id();
+        self.remove_order(side, &order_id)
+    }
+
+    fn record_trade(&mut self, price: u64, quantity: u64, taker_side: Side) {
+        if let Ok(market_ext) = self.market_state.ext_mut() {
+            market_ext.last_trade_price = price;
+        }
+        // MatchOrders only loads a trade log or stats together with the clock
+        if let Some(trade_log) = self.trade_log.as_mut() {
+            let slot = self.clock.as_ref().map_or(0, |clock| clock.slot);
+            trade_log.record(price, quantity, taker_side, slot);
+        }
+        if let (Some(stats), Some(clock)) = (self.stats.as_mut(), self.clock.as_ref()) {
+            let native_coin_qty = quantity * self.market_state.coin_lot_size;
+            let native_pc_qty = quantity * price * self.market_state.pc_lot_size;
+            stats.record_trade(price, native_coin_qty, native_pc_qty, clock);
+        }
+    }
+
     fn find_bbo(&self, side: Side) -> Option<NodeHandle> {
         match side {
             Side::Bid => self.bids.find_max(),
@@ -72,21 +276,142 @@ impl<'ob> OrderBookState<'ob> {
         }
     }
 
+    // Moves the price of a post-only slide order that would cross to one tick behind the
+    // opposite best order. The low 64 bits of the order id, which hold the sequence number,
+    // are kept.
+    fn slide_order_id(&self, side: Side, order_id: u128) -> u128 {
+        let limit_price = extract_price_from_order_id(&order_id);
+        let best_price = |orders: &Slab, handle: Option<NodeHandle>| {
+            orders
+                .get(handle?)
+                .and_then(|node| node.as_leaf())
+                .map(|leaf| leaf.price().get())
+        };
+        let slid_price = match side {
+            Side::Bid => best_price(self.asks, self.asks.find_min())
+                .filter(|&best_ask| limit_price >= best_ask)
+                .map(|best_ask| best_ask - 1),
+            Side::Ask => best_price(self.bids, self.bids.find_max())
+                .filter(|&best_bid| limit_price <= best_bid)
+                .and_then(|best_bid| best_bid.checked_add(1)),
+        };
+        match slid_price {
+            Some(price) if price > 0 => ((price as u128) << 64) | (order_id as u64 as u128),
+            _ => order_id,
+        }
+    }
+
+    fn order_expired(&self, expiry: Option<OrderExpiry>) -> DexResult<bool> {
+        match (expiry, self.clock.as_ref()) {
+            (None, _) => Ok(false),
+            (Some(expiry), Some(clock)) => Ok(expiry.has_passed(clock)),
+            // MatchOrders requires the clock on extended markets, the only ones whose orders
+            // can expire
+            (Some(_), None) => Err(DexErrorCode::ClockNotProvided.into()),
+        }
+    }
+
+    fn best_order_expired(&self, side: Side, handle: NodeHandle) -> DexResult<bool> {
+        check_assert!(self.side(side).orders.get(handle).is_some())?;
+        let expiry = self.order_ext(side, handle).and_then(|ext| ext.expiry());
+        self.order_expired(expiry)
+    }
+
+    fn remove_expired_order(
+        &mut self,
+        side: Side,
+        handle: NodeHandle,
+        event_q: &mut EventQueue,
+    ) -> DexResult {
+        let order_id = *self
+            .orders_mut(side)
+            .get(handle)
+            .and_then(|node| node.as_leaf())
+            .ok_or(assertion_error!())?
+            .order_id();
+        let (leaf_node, ext) = self
+            .remove_order(side, &order_id)
+            .ok_or(assertion_error!())?;
+        let total_quantity = leaf_node.quantity() + ext.map_or(0, |ext| ext.hidden_quantity());
+        let native_qty_unlocked = match side {
+            Side::Bid => total_quantity * leaf_node.price().get() * self.market_state.pc_lot_size,
+            Side::Ask => total_quantity * self.market_state.coin_lot_size,
+        };
+        event_q
+            .push_back(Event::new(EventView::Out {
+                side,
+                native_qty_unlocked,
+                native_qty_still_locked: 0,
+                order_id: &order_id,
+                owner: leaf_node.owner(),
+                owner_slot: leaf_node.owner_slot(),
+                client_order_id: NonZeroU64::new(leaf_node.client_order_id()),
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
+        Ok(())
+    }
+
+    // Requeues an iceberg order whose visible quantity has been filled behind the other orders
+    // at its price, under a new order id, with the next display quantity from its reserve.
+    fn refill_iceberg_order(
+        &mut self,
+        side: Side,
+        order_id: &u128,
+        req_q: &mut RequestQueue,
+        event_q: &mut EventQueue,
+    ) -> DexResult {
+        let (mut leaf_node, ext) = self
+            .remove_order(side, order_id)
+            .ok_or(assertion_error!())?;
+        let mut ext = ext.ok_or(assertion_error!())?;
+        let refilled_order_id = req_q.gen_order_id(leaf_node.price().get(), side);
+        leaf_node.set_order_id(&refilled_order_id);
+        ext.refill(&mut leaf_node);
+        let total_quantity = leaf_node.quantity() + ext.hidden_quantity();
+        self.insert_order(side, leaf_node, Some(ext))
+            .map_err(|_| assertion_error!())?;
+        let native_qty_still_locked = match side {
+            Side::Bid => total_quantity * leaf_node.price().get() * self.market_state.pc_lot_size,
+            Side::Ask => total_quantity * self.market_state.coin_lot_size,
+        };
+        event_q
+            .push_back(Event::new(EventView::Out {
+                side,
+                native_qty_unlocked: 0,
+                native_qty_still_locked,
+                order_id: &refilled_order_id,
+                owner: leaf_node.owner(),
+                owner_slot: leaf_node.owner_slot(),
+                client_order_id: NonZeroU64::new(leaf_node.client_order_id()),
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
+        Ok(())
+    }
+
     pub fn process_requests(
         &mut self,
         req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
+        mut trigger_q: Option<&mut TriggerQueue>,
         limit: u16,
     ) -> Result<(), DexError> {
         let mut limit_remaining = limit;
         while limit_remaining > 0 {
-            let request = match req_q.peek_front_mut() {
+            // the request is copied out so that matching can generate new order ids
+            let (request, request_ext) = match req_q.peek_front() {
                 Some(r) => r,
                 None => break,
             };
-            match self.process_orderbook_request(request, event_q, &mut limit_remaining)? {
+            match self.process_orderbook_request(
+                &request,
+                &request_ext,
+                req_q,
+                event_q,
+                trigger_q.as_deref_mut(),
+                &mut limit_remaining,
+            )? {
                 Some(remaining_request) => {
-                    *request = remaining_request;
+                    req_q.set_front(remaining_request)?;
                 }
                 None => {
                     req_q.pop_front().unwrap();
@@ -94,16 +419,65 @@ impl<'ob> OrderBookState<'ob> {
             };
         }
 
+        // Queued requests, including cancels of trigger orders, still drain in cancel-only
+        // mode, but trigger orders stay parked.
+        if let Some(trigger_q) = trigger_q {
+            if self.market_state.status() == MarketStatus::Active {
+                self.activate_trigger_orders(trigger_q, req_q)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Moves every trigger order whose condition is met by the last trade price to the back of
+    // the request queue, where it is matched like any other new order. Orders that are not
+    // triggered keep their relative order in the trigger queue, and aren't checked again until
+    // the price changes or a new order is queued.
+    fn activate_trigger_orders(
+        &mut self,
+        trigger_q: &mut TriggerQueue,
+        req_q: &mut RequestQueue,
+    ) -> DexResult {
+        let last_trade_price = match NonZeroU64::new(self.market_state.ext().last_trade_price) {
+            Some(price) => price,
+            None => return Ok(()),
+        };
+        if trigger_q.checked_price() == Some(last_trade_price) {
+            return Ok(());
+        }
+        let mut left_behind = false;
+        for _ in 0..trigger_q.len() {
+            let trigger_order = trigger_q.pop_front().or(check_unreachable!())?;
+            let triggered = trigger_order
+                .trigger_condition()?
+                .is_met(trigger_order.trigger_price(), last_trade_price);
+            if triggered && !req_q.full() {
+                let request = trigger_order.activate(req_q);
+                req_q.push_back(request)?;
+            } else {
+                left_behind |= triggered;
+                trigger_q
+                    .push_back(trigger_order)
+                    .or(check_unreachable!())?;
+            }
+        }
+        if !left_behind {
+            trigger_q.set_checked_price(Some(last_trade_price));
+        }
         Ok(())
     }
 
     fn process_orderbook_request(
         &mut self,
         request: &Request,
+        request_ext: &RequestExt,
+        req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
+        trigger_q: Option<&mut TriggerQueue>,
         limit: &mut u16,
-    ) -> DexResult<Option<Request>> {
-        Ok(match request.as_view()? {
+    ) -> DexResult<Option<(Request, RequestExt)>> {
+        Ok(match request.as_view(request_ext)? {
             RequestView::NewOrder {
                 side,
                 order_type,
@@ -115,37 +489,52 @@ impl<'ob> OrderBookState<'ob> {
                 native_pc_qty_locked,
                 client_order_id,
                 self_trade_behavior,
-            } => self
-                .new_order(
-                    NewOrderParams {
-                        side,
-                        order_type,
-                        order_id,
-                        owner,
-                        owner_slot,
-                        fee_tier,
-                        max_coin_qty,
-                        native_pc_qty_locked,
-                        client_order_id: client_order_id.map_or(0, NonZeroU64::get),
-                        self_trade_behavior,
-                    },
-                    event_q,
-                    limit,
-                )?
-                .map(|remaining| {
-                    Request::new(RequestView::NewOrder {
-                        side,
-                        order_type,
-                        order_id,
-                        owner_slot,
-                        fee_tier,
-                        owner,
-                        max_coin_qty: remaining.coin_qty_remaining,
-                        native_pc_qty_locked: remaining.native_pc_qty_remaining,
-                        client_order_id,
-                        self_trade_behavior,
+                expiry,
+                display_qty,
+                replaces,
+                triggered,
+            } => {
+                let mut params = NewOrderParams {
+                    side,
+                    order_type,
+                    order_id,
+                    owner,
+                    owner_slot,
+                    fee_tier,
+                    max_coin_qty,
+                    native_pc_qty_locked,
+                    client_order_id: client_order_id.map_or(0, NonZeroU64::get),
+                    self_trade_behavior,
+                    expiry,
+                    display_qty,
+                    triggered,
+                };
+                if let Some(replaced_order) = replaces {
+                    if !self.replace_order(replaced_order, &mut params, event_q)? {
+                        *limit -= 1;
+                        return Ok(None);
+                    }
+                }
+                self.new_order(params, req_q, event_q, limit)?
+                    .map(|remaining| {
+                        Request::new(RequestView::NewOrder {
+                            side,
+                            order_type,
+                            order_id,
+                            owner_slot,
+                            fee_tier,
+                            owner,
+                            max_coin_qty: remaining.coin_qty_remaining,
+                            native_pc_qty_locked: remaining.native_pc_qty_remaining,
+                            client_order_id,
+                            self_trade_behavior,
+                            expiry,
+                            display_qty,
+                            replaces: None,
+                            triggered,
+                        })
                     })
-                }),
+            }
             RequestView::CancelOrder {
                 side,
                 order_id,
@@ -155,7 +544,7 @@ impl<'ob> OrderBookState<'ob> {
                 cancel_id: _,
             } => {
                 *limit -= 1;
-                self.cancel_order(
+                let cancelled = self.cancel_order(
                     side,
                     order_id,
                     expected_owner,
@@ -163,6 +552,16 @@ impl<'ob> OrderBookState<'ob> {
                     client_order_id,
                     event_q,
                 )?;
+                if let (false, Some(trigger_q)) = (cancelled, trigger_q) {
+                    self.cancel_trigger_order(
+                        trigger_q,
+                        side,
+                        order_id,
+                        expected_owner,
+                        expected_owner_slot,
+                        event_q,
+                    )?;
+                }
                 None
             }
         })
@@ -174,12 +573,224 @@ struct NewOrderParams<'a> {
     order_type: OrderType,
     order_id: &'a u128,
     owner: &'a [u64; 4],
-    owner_slot: u8,
+    owner_slot: u16,
     fee_tier: FeeTier,
     max_coin_qty: NonZeroU64,
     native_pc_qty_locked: Option<NonZeroU64>,
     client_order_id: u64,
     self_trade_behavior: SelfTradeBehavior,
+    expiry: Option<OrderExpiry>,
+    display_qty: Option<NonZeroU64>,
+    triggered: bool,
+}
+
+impl<'ob> OrderBookState<'ob> {
+    // Cancels the order being replaced, returning whether the replacement still has to be
+    // matched. The funds the original has locked are handed over to the replacement up to the
+    // amount it was credited with, and if the original has since been partly filled, the
+    // replacement is reduced by what's missing. A replacement that reuses the id of the original
+    // only reduces it in place so that it keeps its priority. If the original is gone, the
+    // replacement is dropped.
+    fn replace_order(
+        &mut self,
+        replaced_order: ReplacedOrder,
+        params: &mut NewOrderParams,
+        event_q: &mut EventQueue,
+    ) -> DexResult<bool> {
+        let side = params.side;
+        let owner = params.owner;
+        let owner_slot = params.owner_slot;
+        let pc_lot_size = self.market_state.pc_lot_size;
+        let coin_lot_size = self.market_state.coin_lot_size;
+        let native_qty_locked = |quantity: u64, price: NonZeroU64| match side {
+            Side::Bid => quantity * price.get() * pc_lot_size,
+            Side::Ask => quantity * coin_lot_size,
+        };
+        let native_qty_credited = replaced_order.native_qty_credited;
+        let amend_in_place = *params.order_id == replaced_order.order_id;
+
+        let replaced_order_id =
+            self.resting_order_id(side, &replaced_order.order_id, owner, owner_slot);
+        let replaced_leaf = match self.remove_order(side, &replaced_order_id) {
+            Some((leaf, ext)) if leaf.owner() == owner && leaf.owner_slot() == owner_slot => {
+                Some((leaf, ext))
+            }
+            Some((leaf, ext)) => {
+                self.insert_order(side, leaf, ext).unwrap();
+                None
+            }
+            None => None,
+        };
+        let (mut leaf, ext) = match replaced_leaf {
+            Some(replaced_leaf) => replaced_leaf,
+            // an amendment has nothing locked of its own
+            None if amend_in_place => return Ok(false),
+            None => {
+                let native_qty_locked = match side {
+                    Side::Bid => params.native_pc_qty_locked.map_or(0, NonZeroU64::get),
+                    Side::Ask => params.max_coin_qty.get() * coin_lot_size,
+                };
+                event_q
+                    .push_back(Event::new(EventView::Out {
+                        side,
+                        native_qty_unlocked: native_qty_locked - native_qty_credited,
+                        native_qty_still_locked: 0,
+                        order_id: params.order_id,
+                        owner,
+                        owner_slot,
+                        client_order_id: NonZeroU64::new(params.client_order_id),
+                    }))
+                    .map_err(|_| DexErrorCode::EventQueueFull)?;
+                return Ok(false);
+            }
+        };
+
+        let total_quantity = leaf.quantity() + ext.map_or(0, |ext| ext.hidden_quantity());
+        let native_qty_released = native_qty_locked(total_quantity, leaf.price());
+        let quantity_kept = if amend_in_place {
+            params.max_coin_qty.get().min(leaf.quantity())
+        } else {
+            0
+        };
+        let native_qty_kept = native_qty_locked(quantity_kept, leaf.price());
+        let native_qty_handed_over = if amend_in_place {
+            0
+        } else {
+            native_qty_released.min(native_qty_credited)
+        };
+        event_q
+            .push_back(Event::new(EventView::Out {
+                side,
+                native_qty_unlocked: native_qty_released - native_qty_kept - native_qty_handed_over,
+                native_qty_still_locked: native_qty_kept,
+                order_id: &replaced_order_id,
+                owner,
+                owner_slot,
+                client_order_id: NonZeroU64::new(leaf.client_order_id()),
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
+        if amend_in_place {
+            *leaf.quantity_mut() = quantity_kept;
+            self.insert_order(side, leaf, ext).unwrap();
+            return Ok(false);
+        }
+
+        let native_qty_missing = native_qty_credited - native_qty_handed_over;
+        match side {
+            Side::Bid => {
+                let native_pc_qty_locked = params.native_pc_qty_locked.unwrap().get();
+                params.native_pc_qty_locked =
+                    NonZeroU64::new(native_pc_qty_locked - native_qty_missing);
+            }
+            Side::Ask => {
+                let max_coin_qty = params.max_coin_qty.get() - native_qty_missing / coin_lot_size;
+                params.max_coin_qty = NonZeroU64::new(max_coin_qty).unwrap();
+            }
+        };
+        Ok(true)
+    }
+
+    // Drops a new order without matching it, unlocking all of its funds.
+    fn unlock_new_order(&mut self, params: &NewOrderParams, event_q: &mut EventQueue) -> DexResult {
+        let native_qty_unlocked = match params.side {
+            Side::Bid => params.native_pc_qty_locked.map_or(0, NonZeroU64::get),
+            Side::Ask => params.max_coin_qty.get() * self.market_state.coin_lot_size,
+        };
+        event_q
+            .push_back(Event::new(EventView::Out {
+                side: params.side,
+                native_qty_unlocked,
+                native_qty_still_locked: 0,
+                order_id: params.order_id,
+                owner: params.owner,
+                owner_slot: params.owner_slot,
+                client_order_id: NonZeroU64::new(params.client_order_id),
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
+        Ok(())
+    }
+
+    // Walks the opposite side of the book the same way new_bid / new_ask would, without
+    // modifying it, and returns whether the whole order would be filled within its limit price
+    // in at most `limit` matching steps.
+    fn can_fill_completely(&self, params: &NewOrderParams, limit: u16) -> DexResult<bool> {
+        let &NewOrderParams {
+            side,
+            order_id,
+            owner,
+            fee_tier,
+            max_coin_qty,
+            native_pc_qty_locked,
+            self_trade_behavior,
+            ..
+        } = params;
+        let limit_price = extract_price_from_order_id(order_id);
+        let mut coin_qty_remaining = max_coin_qty.get();
+        let mut pc_qty_remaining = match side {
+            Side::Bid => {
+                self.market_state
+                    .ext()
+                    .fee_schedule
+                    .remove_taker_fee(fee_tier, native_pc_qty_locked.map_or(0, NonZeroU64::get))
+                    / self.market_state.pc_lot_size
+            }
+            Side::Ask => std::u64::MAX,
+        };
+        let maker_side = match side {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        };
+        let makers = self.side(maker_side);
+
+        // new_bid / new_ask match one resting order, or one visible part of an iceberg order, per
+        // step, and removing an expired or self-trading order takes a step of its own
+        let mut steps_remaining = limit;
+        for maker in makers.orders.iter_best_first(maker_side) {
+            let maker_ext = makers.ext(maker);
+            let trade_price = maker.price().get();
+            let crossed = match side {
+                Side::Bid => limit_price >= trade_price,
+                Side::Ask => limit_price <= trade_price,
+            };
+            if !crossed {
+                break;
+            }
+            let expired = self.order_expired(maker_ext.and_then(|ext| ext.expiry()))?;
+            if expired || maker.owner() == owner {
+                let skipped = expired || self_trade_behavior == SelfTradeBehavior::CancelProvide;
+                if !skipped || steps_remaining == 0 {
+                    break;
+                }
+                steps_remaining -= 1;
+                continue;
+            }
+            let maker_qty = maker.quantity() + maker_ext.map_or(0, |ext| ext.hidden_quantity());
+            let trade_qty = maker_qty
+                .min(coin_qty_remaining)
+                .min(pc_qty_remaining / trade_price);
+            if trade_qty == 0 {
+                break;
+            }
+            let steps = match maker_ext {
+                Some(ext) if trade_qty > maker.quantity() => {
+                    let refilled_qty = trade_qty - maker.quantity();
+                    let display_qty = ext.display_quantity();
+                    1 + (refilled_qty + display_qty - 1) / display_qty
+                }
+                _ => 1,
+            };
+            if steps > steps_remaining as u64 {
+                break;
+            }
+            steps_remaining -= steps as u16;
+            coin_qty_remaining -= trade_qty;
+            pc_qty_remaining -= trade_qty * trade_price;
+            if coin_qty_remaining == 0 || trade_qty < maker_qty {
+                break;
+            }
+        }
+        Ok(coin_qty_remaining == 0)
+    }
 }
 
 struct OrderRemaining {
@@ -193,9 +804,19 @@ impl<'ob> OrderBookState<'ob> {
 
         params: NewOrderParams,
 
+        req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
         limit: &mut u16,
     ) -> DexResult<Option<OrderRemaining>> {
+        let fill_or_kill = params.order_type == OrderType::FillOrKill;
+        if self.order_expired(params.expiry)?
+            || (fill_or_kill && !self.can_fill_completely(&params, *limit)?)
+        {
+            *limit -= 1;
+            self.unlock_new_order(&params, event_q)?;
+            return Ok(None);
+        }
+
         let NewOrderParams {
             side,
             order_type,
@@ -207,15 +828,26 @@ impl<'ob> OrderBookState<'ob> {
             mut native_pc_qty_locked,
             client_order_id,
             self_trade_behavior,
+            expiry,
+            display_qty,
+            triggered,
         } = params;
         let (post_only, post_allowed) = match order_type {
             OrderType::Limit => (false, true),
-            OrderType::ImmediateOrCancel => (false, false),
-            OrderType::PostOnly => (true, true),
+            OrderType::ImmediateOrCancel | OrderType::FillOrKill => (false, false),
+            OrderType::PostOnly | OrderType::PostOnlySlide => (true, true),
         };
-        let limit_price = extract_price_from_order_id(order_id);
+        // a fill-or-kill order that got this far completes within the limit
         while *limit > 0 {
             *limit -= 1;
+            let slid_order_id = match order_type {
+                OrderType::PostOnlySlide => self.slide_order_id(side, *order_id),
+                _ => *order_id,
+            };
+            // an activated trigger order rests under a new id just like a slid one
+            let slid = triggered || slid_order_id != *order_id;
+            let order_id = &slid_order_id;
+            let limit_price = extract_price_from_order_id(order_id);
             let remaining_order = match side {
                 Side::Bid => self.new_bid(
                     NewBidParams {
@@ -230,7 +862,11 @@ impl<'ob> OrderBookState<'ob> {
                         post_allowed,
                         client_order_id,
                         self_trade_behavior,
+                        expiry,
+                        display_qty,
+                        slid,
                     },
+                    req_q,
                     event_q,
                 ),
                 Side::Ask => {
@@ -247,11 +883,30 @@ impl<'ob> OrderBookState<'ob> {
                             post_allowed,
                             client_order_id,
                             self_trade_behavior,
+                            expiry,
+                            display_qty,
+                            slid,
                         },
+                        req_q,
                         event_q,
                     )
                 }
             }?;
+            if side == Side::Ask && remaining_order.is_none() && slid {
+                // resting asks emit no event, so tell the open orders account about the new id
+                event_q
+                    .push_back(Event::new(EventView::Out {
+                        side,
+                        native_qty_unlocked: 0,
+                        native_qty_still_locked: max_coin_qty.get()
+                            * self.market_state.coin_lot_size,
+                        order_id,
+                        owner,
+                        owner_slot,
+                        client_order_id: NonZeroU64::new(client_order_id),
+                    }))
+                    .map_err(|_| DexErrorCode::EventQueueFull)?;
+            }
             if *limit == 0 {
                 return Ok(remaining_order);
             }
@@ -272,18 +927,22 @@ struct NewAskParams<'a> {
     limit_price: NonZeroU64,
     order_id: &'a u128,
     owner: &'a [u64; 4],
-    owner_slot: u8,
+    owner_slot: u16,
     fee_tier: FeeTier,
     post_only: bool,
     post_allowed: bool,
     client_order_id: u64,
     self_trade_behavior: SelfTradeBehavior,
+    expiry: Option<OrderExpiry>,
+    display_qty: Option<NonZeroU64>,
+    slid: bool,
 }
 
 impl<'ob> OrderBookState<'ob> {
     fn new_ask(
         &mut self,
         params: NewAskParams,
+        req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
     ) -> DexResult<Option<OrderRemaining>> {
         let NewAskParams {
@@ -297,12 +956,16 @@ impl<'ob> OrderBookState<'ob> {
             post_allowed,
             client_order_id,
             self_trade_behavior,
+            expiry,
+            display_qty,
+            slid,
         } = params;
         let mut unfilled_qty = max_qty.get();
         let mut accum_fill_price = 0;
 
         let pc_lot_size = self.market_state.pc_lot_size;
         let coin_lot_size = self.market_state.coin_lot_size;
+        let fee_schedule = self.market_state.ext().fee_schedule;
 
         let mut accum_maker_rebates = 0;
         let crossed;
@@ -315,6 +978,15 @@ impl<'ob> OrderBookState<'ob> {
                 Some(h) => h,
             };
 
+            if self.best_order_expired(Side::Bid, best_bid_h)? {
+                self.remove_expired_order(Side::Bid, best_bid_h, event_q)?;
+                crossed = true;
+                break false;
+            }
+
+            let best_bid_hidden_qty = self
+                .order_ext(Side::Bid, best_bid_h)
+                .map_or(0, |ext| ext.hidden_quantity());
             let best_bid_ref = self
                 .orders_mut(Side::Bid)
                 .get_mut(best_bid_h)
@@ -339,6 +1011,7 @@ impl<'ob> OrderBookState<'ob> {
             let order_would_self_trade = owner == best_bid_ref.owner();
             if order_would_self_trade {
                 let best_bid_id = *best_bid_ref.order_id();
+                let best_bid_total_qty = best_bid_ref.quantity() + best_bid_hidden_qty;
                 let cancelled_provide_qty;
                 let cancelled_take_qty;
 
@@ -348,12 +1021,16 @@ impl<'ob> OrderBookState<'ob> {
                         cancelled_take_qty = trade_qty;
                     }
                     SelfTradeBehavior::CancelProvide => {
-                        cancelled_provide_qty = best_bid_ref.quantity();
+                        cancelled_provide_qty = best_bid_total_qty;
                         cancelled_take_qty = 0;
                     }
+                    SelfTradeBehavior::CancelBoth => {
+                        cancelled_provide_qty = best_bid_total_qty;
+                        cancelled_take_qty = unfilled_qty;
+                    }
                 };
 
-                let remaining_provide_size = bid_size - cancelled_provide_qty;
+                let remaining_provide_size = best_bid_total_qty - cancelled_provide_qty;
                 let provide_out = Event::new(EventView::Out {
                     side: Side::Bid,
                     native_qty_unlocked: cancelled_provide_qty * trade_price.get() * pc_lot_size,
@@ -369,11 +1046,12 @@ impl<'ob> OrderBookState<'ob> {
                     .push_back(provide_out)
                     .map_err(|_| DexErrorCode::EventQueueFull)?;
                 if remaining_provide_size == 0 {
-                    self.orders_mut(Side::Bid)
-                        .remove_by_key(&best_bid_id)
-                        .unwrap();
+                    self.remove_order(Side::Bid, &best_bid_id).unwrap();
                 } else {
-                    *best_bid_ref.quantity_mut() = remaining_provide_size;
+                    *best_bid_ref.quantity_mut() -= cancelled_provide_qty;
+                    if best_bid_ref.quantity() == 0 {
+                        self.refill_iceberg_order(Side::Bid, &best_bid_id, req_q, event_q)?;
+                    }
                 }
 
                 unfilled_qty -= cancelled_take_qty;
@@ -400,7 +1078,8 @@ impl<'ob> OrderBookState<'ob> {
 
             let maker_fee_tier = best_bid_ref.fee_tier();
             let native_maker_pc_qty = trade_qty * trade_price.get() * pc_lot_size;
-            let native_maker_rebate = maker_fee_tier.maker_rebate(native_maker_pc_qty);
+            let native_maker_rebate =
+                fee_schedule.maker_rebate(maker_fee_tier, native_maker_pc_qty);
             accum_maker_rebates += native_maker_rebate;
 
             let maker_fill = Event::new(EventView::Fill {
@@ -423,7 +1102,10 @@ impl<'ob> OrderBookState<'ob> {
             unfilled_qty -= trade_qty;
             accum_fill_price += trade_qty * trade_price.get();
 
-            if best_bid_ref.quantity() == 0 {
+            if best_bid_ref.quantity() == 0 && best_bid_hidden_qty > 0 {
+                let best_bid_id = *best_bid_ref.order_id();
+                self.refill_iceberg_order(Side::Bid, &best_bid_id, req_q, event_q)?;
+            } else if best_bid_ref.quantity() == 0 {
                 let best_bid_id = *best_bid_ref.order_id();
                 event_q
                     .push_back(Event::new(EventView::Out {
@@ -436,16 +1118,15 @@ impl<'ob> OrderBookState<'ob> {
                         client_order_id: NonZeroU64::new(best_bid_ref.client_order_id()),
                     }))
                     .map_err(|_| DexErrorCode::EventQueueFull)?;
-                self.orders_mut(Side::Bid)
-                    .remove_by_key(&best_bid_id)
-                    .unwrap();
+                self.remove_order(Side::Bid, &best_bid_id).unwrap();
             }
 
+            self.record_trade(trade_price.get(), trade_qty, Side::Ask);
             break false;
         };
 
         let native_taker_pc_qty = accum_fill_price * pc_lot_size;
-        let native_taker_fee = fee_tier.taker_fee(native_taker_pc_qty);
+        let native_taker_fee = fee_schedule.taker_fee(fee_tier, native_taker_pc_qty);
         if native_taker_pc_qty > 0 {
             let taker_fill = Event::new(EventView::Fill {
                 side: Side::Ask,
@@ -465,7 +1146,7 @@ impl<'ob> OrderBookState<'ob> {
         }
 
         let net_fees_before_referrer_rebate = native_taker_fee - accum_maker_rebates;
-        let referrer_rebate = fees::referrer_rebate(native_taker_fee);
+        let referrer_rebate = fee_schedule.referrer_rebate(native_taker_fee);
         let net_fees = net_fees_before_referrer_rebate - referrer_rebate;
 
         self.market_state.referrer_rebates_accrued += referrer_rebate;
@@ -482,8 +1163,7 @@ impl<'ob> OrderBookState<'ob> {
         }
 
         if post_allowed && !crossed && unfilled_qty > 0 {
-            let offers = self.orders_mut(Side::Ask);
-            let new_order = LeafNode::new(
+            let mut new_order = LeafNode::new(
                 owner_slot,
                 order_id,
                 owner,
@@ -491,14 +1171,16 @@ impl<'ob> OrderBookState<'ob> {
                 fee_tier,
                 client_order_id,
             );
-            let insert_result = offers.insert_leaf(&new_order);
+            let new_order_ext = self.new_order_ext(&mut new_order, expiry, display_qty, slid)?;
+            let insert_result = self.insert_order(Side::Ask, new_order, new_order_ext);
             if let Err(SlabTreeError::OutOfSpace) = insert_result {
                 // boot out the least aggressive offer
                 info!("offers full! booting...");
-                let order = offers.remove_max().unwrap();
+                let (order, order_ext) = self.remove_worst_order(Side::Ask).unwrap();
+                let order_qty = order.quantity() + order_ext.map_or(0, |ext| ext.hidden_quantity());
                 let out = Event::new(EventView::Out {
                     side: Side::Ask,
-                    native_qty_unlocked: order.quantity() * coin_lot_size,
+                    native_qty_unlocked: order_qty * coin_lot_size,
                     native_qty_still_locked: 0,
                     order_id: order.order_id(),
                     owner: order.owner(),
@@ -508,7 +1190,8 @@ impl<'ob> OrderBookState<'ob> {
                 event_q
                     .push_back(out)
                     .map_err(|_| DexErrorCode::EventQueueFull)?;
-                offers.insert_leaf(&new_order).unwrap();
+                self.insert_order(Side::Ask, new_order, new_order_ext)
+                    .unwrap();
             } else {
                 insert_result.unwrap();
             }
@@ -537,18 +1220,22 @@ struct NewBidParams<'a> {
     limit_price: Option<NonZeroU64>,
     order_id: &'a u128,
     owner: &'a [u64; 4],
-    owner_slot: u8,
+    owner_slot: u16,
     fee_tier: FeeTier,
     post_only: bool,
     post_allowed: bool,
     client_order_id: u64,
     self_trade_behavior: SelfTradeBehavior,
+    expiry: Option<OrderExpiry>,
+    display_qty: Option<NonZeroU64>,
+    slid: bool,
 }
 
 impl<'ob> OrderBookState<'ob> {
     fn new_bid(
         &mut self,
         params: NewBidParams,
+        req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
     ) -> DexResult<Option<OrderRemaining>> {
         let NewBidParams {
@@ -563,6 +1250,9 @@ impl<'ob> OrderBookState<'ob> {
             post_allowed,
             client_order_id,
             self_trade_behavior,
+            expiry,
+            display_qty,
+            slid,
         } = params;
         if post_allowed {
             check_assert!(limit_price.is_some())?;
@@ -570,8 +1260,10 @@ impl<'ob> OrderBookState<'ob> {
 
         let pc_lot_size = self.market_state.pc_lot_size;
         let coin_lot_size = self.market_state.coin_lot_size;
+        let fee_schedule = self.market_state.ext().fee_schedule;
 
-        let max_pc_qty = fee_tier.remove_taker_fee(native_pc_qty_locked.get()) / pc_lot_size;
+        let max_pc_qty =
+            fee_schedule.remove_taker_fee(fee_tier, native_pc_qty_locked.get()) / pc_lot_size;
 
         let mut coin_qty_remaining = max_coin_qty.get();
         let mut pc_qty_remaining = max_pc_qty;
@@ -587,6 +1279,15 @@ impl<'ob> OrderBookState<'ob> {
                 Some(h) => h,
             };
 
+            if self.best_order_expired(Side::Ask, best_offer_h)? {
+                self.remove_expired_order(Side::Ask, best_offer_h, event_q)?;
+                crossed = true;
+                break false;
+            }
+
+            let best_offer_hidden_qty = self
+                .order_ext(Side::Ask, best_offer_h)
+                .map_or(0, |ext| ext.hidden_quantity());
             let best_offer_ref = self
                 .orders_mut(Side::Ask)
                 .get_mut(best_offer_h)
@@ -614,6 +1315,7 @@ impl<'ob> OrderBookState<'ob> {
             let order_would_self_trade = owner == best_offer_ref.owner();
             if order_would_self_trade {
                 let best_offer_id = *best_offer_ref.order_id();
+                let best_offer_total_qty = best_offer_ref.quantity() + best_offer_hidden_qty;
 
                 let cancelled_take_qty;
                 let cancelled_provide_qty;
@@ -621,15 +1323,19 @@ impl<'ob> OrderBookState<'ob> {
                 match self_trade_behavior {
                     SelfTradeBehavior::CancelProvide => {
                         cancelled_take_qty = 0;
-                        cancelled_provide_qty = best_offer_ref.quantity();
+                        cancelled_provide_qty = best_offer_total_qty;
                     }
                     SelfTradeBehavior::DecrementTake => {
                         cancelled_take_qty = trade_qty;
                         cancelled_provide_qty = trade_qty;
                     }
+                    SelfTradeBehavior::CancelBoth => {
+                        cancelled_take_qty = coin_qty_remaining;
+                        cancelled_provide_qty = best_offer_total_qty;
+                    }
                 };
 
-                let remaining_provide_qty = best_offer_ref.quantity() - cancelled_provide_qty;
+                let remaining_provide_qty = best_offer_total_qty - cancelled_provide_qty;
                 let provide_out = Event::new(EventView::Out {
                     side: Side::Ask,
                     native_qty_unlocked: cancelled_provide_qty * coin_lot_size,
@@ -643,11 +1349,12 @@ impl<'ob> OrderBookState<'ob> {
                     .push_back(provide_out)
                     .map_err(|_| DexErrorCode::EventQueueFull)?;
                 if remaining_provide_qty == 0 {
-                    self.orders_mut(Side::Ask)
-                        .remove_by_key(&best_offer_id)
-                        .unwrap();
+                    self.remove_order(Side::Ask, &best_offer_id).unwrap();
                 } else {
-                    *best_offer_ref.quantity_mut() = remaining_provide_qty;
+                    *best_offer_ref.quantity_mut() -= cancelled_provide_qty;
+                    if best_offer_ref.quantity() == 0 {
+                        self.refill_iceberg_order(Side::Ask, &best_offer_id, req_q, event_q)?;
+                    }
                 }
 
                 let native_taker_pc_unlocked = cancelled_take_qty * trade_price.get() * pc_lot_size;
@@ -696,7 +1403,8 @@ impl<'ob> OrderBookState<'ob> {
             }
             let maker_fee_tier = best_offer_ref.fee_tier();
             let native_maker_pc_qty = trade_qty * trade_price.get() * pc_lot_size;
-            let native_maker_rebate = maker_fee_tier.maker_rebate(native_maker_pc_qty);
+            let native_maker_rebate =
+                fee_schedule.maker_rebate(maker_fee_tier, native_maker_pc_qty);
             accum_maker_rebates += native_maker_rebate;
 
             let maker_fill = Event::new(EventView::Fill {
@@ -719,7 +1427,10 @@ impl<'ob> OrderBookState<'ob> {
             coin_qty_remaining -= trade_qty;
             pc_qty_remaining -= trade_qty * trade_price.get();
 
-            if best_offer_ref.quantity() == 0 {
+            if best_offer_ref.quantity() == 0 && best_offer_hidden_qty > 0 {
+                let best_offer_id = *best_offer_ref.order_id();
+                self.refill_iceberg_order(Side::Ask, &best_offer_id, req_q, event_q)?;
+            } else if best_offer_ref.quantity() == 0 {
                 let best_offer_id = *best_offer_ref.order_id();
                 event_q
                     .push_back(Event::new(EventView::Out {
@@ -732,16 +1443,15 @@ impl<'ob> OrderBookState<'ob> {
                         client_order_id: NonZeroU64::new(best_offer_ref.client_order_id()),
                     }))
                     .map_err(|_| DexErrorCode::EventQueueFull)?;
-                self.orders_mut(Side::Ask)
-                    .remove_by_key(&best_offer_id)
-                    .unwrap();
+                self.remove_order(Side::Ask, &best_offer_id).unwrap();
             }
 
+            self.record_trade(trade_price.get(), trade_qty, Side::Bid);
             break false;
         };
 
         let native_accum_fill_price = (max_pc_qty - pc_qty_remaining) * pc_lot_size;
-        let native_taker_fee = fee_tier.taker_fee(native_accum_fill_price);
+        let native_taker_fee = fee_schedule.taker_fee(fee_tier, native_accum_fill_price);
         let native_pc_qty_remaining =
             native_pc_qty_locked.get() - native_accum_fill_price - native_taker_fee;
 
@@ -764,7 +1474,7 @@ impl<'ob> OrderBookState<'ob> {
         }
 
         let net_fees_before_referrer_rebate = native_taker_fee - accum_maker_rebates;
-        let referrer_rebate = fees::referrer_rebate(native_taker_fee);
+        let referrer_rebate = fee_schedule.referrer_rebate(native_taker_fee);
         let net_fees = net_fees_before_referrer_rebate - referrer_rebate;
 
         self.market_state.referrer_rebates_accrued += referrer_rebate;
@@ -809,8 +1519,7 @@ impl<'ob> OrderBookState<'ob> {
             .map_err(|_| DexErrorCode::EventQueueFull)?;
 
         if pc_qty_to_keep_locked > 0 {
-            let bids = self.orders_mut(Side::Bid);
-            let new_leaf = LeafNode::new(
+            let mut new_leaf = LeafNode::new(
                 owner_slot,
                 order_id,
                 owner,
@@ -818,14 +1527,16 @@ impl<'ob> OrderBookState<'ob> {
                 fee_tier,
                 client_order_id,
             );
-            let insert_result = bids.insert_leaf(&new_leaf);
+            let new_leaf_ext = self.new_order_ext(&mut new_leaf, expiry, display_qty, slid)?;
+            let insert_result = self.insert_order(Side::Bid, new_leaf, new_leaf_ext);
             if let Err(SlabTreeError::OutOfSpace) = insert_result {
                 // boot out the least aggressive bid
                 info!("bids full! booting...");
-                let order = bids.remove_min().unwrap();
+                let (order, order_ext) = self.remove_worst_order(Side::Bid).unwrap();
+                let order_qty = order.quantity() + order_ext.map_or(0, |ext| ext.hidden_quantity());
                 let out = Event::new(EventView::Out {
                     side: Side::Bid,
-                    native_qty_unlocked: order.quantity() * order.price().get() * pc_lot_size,
+                    native_qty_unlocked: order_qty * order.price().get() * pc_lot_size,
                     native_qty_still_locked: 0,
                     order_id: order.order_id(),
                     owner: order.owner(),
@@ -835,7 +1546,8 @@ impl<'ob> OrderBookState<'ob> {
                 event_q
                     .push_back(out)
                     .map_err(|_| DexErrorCode::EventQueueFull)?;
-                bids.insert_leaf(&new_leaf).unwrap();
+                self.insert_order(Side::Bid, new_leaf, new_leaf_ext)
+                    .unwrap();
             } else {
                 insert_result.unwrap();
             }
@@ -849,24 +1561,26 @@ impl<'ob> OrderBookState<'ob> {
         side: Side,
         order_id: &u128,
         expected_owner: &[u64; 4],
-        expected_owner_slot: u8,
+        expected_owner_slot: u16,
         client_order_id: Option<NonZeroU64>,
 
         event_q: &mut EventQueue,
-    ) -> DexResult<()> {
-        if let Some(leaf_node) = self.orders_mut(side).remove_by_key(order_id) {
+    ) -> DexResult<bool> {
+        let order_id = &self.resting_order_id(side, order_id, expected_owner, expected_owner_slot);
+        let mut cancelled = false;
+        if let Some((leaf_node, ext)) = self.remove_order(side, order_id) {
             if leaf_node.owner() == expected_owner && leaf_node.owner_slot() == expected_owner_slot
             {
                 if let Some(client_id) = client_order_id {
                     debug_assert_eq!(client_id.get(), leaf_node.client_order_id());
                 }
+                let total_quantity =
+                    leaf_node.quantity() + ext.map_or(0, |ext| ext.hidden_quantity());
                 let native_qty_unlocked = match side {
                     Side::Bid => {
-                        leaf_node.quantity()
-                            * leaf_node.price().get()
-                            * self.market_state.pc_lot_size
+                        total_quantity * leaf_node.price().get() * self.market_state.pc_lot_size
                     }
-                    Side::Ask => leaf_node.quantity() * self.market_state.coin_lot_size,
+                    Side::Ask => total_quantity * self.market_state.coin_lot_size,
                 };
                 event_q
                     .push_back(Event::new(EventView::Out {
@@ -879,10 +1593,85 @@ impl<'ob> OrderBookState<'ob> {
                         client_order_id: NonZeroU64::new(leaf_node.client_order_id()),
                     }))
                     .map_err(|_| DexErrorCode::EventQueueFull)?;
+                cancelled = true;
             } else {
-                self.orders_mut(side).insert_leaf(&leaf_node).unwrap();
+                self.insert_order(side, leaf_node, ext).unwrap();
             }
         }
+        Ok(cancelled)
+    }
+
+    fn cancel_trigger_order(
+        &mut self,
+        trigger_q: &mut TriggerQueue,
+        side: Side,
+        order_id: &u128,
+        expected_owner: &[u64; 4],
+        expected_owner_slot: u16,
+
+        event_q: &mut EventQueue,
+    ) -> DexResult {
+        for _ in 0..trigger_q.len() {
+            let trigger_order = trigger_q.pop_front().or(check_unreachable!())?;
+            let cancelled = match trigger_order.as_view()? {
+                RequestView::NewOrder {
+                    side: order_side,
+                    order_id: trigger_order_id,
+                    owner,
+                    owner_slot,
+                    ..
+                } => {
+                    order_side == side
+                        && trigger_order_id == order_id
+                        && owner == expected_owner
+                        && owner_slot == expected_owner_slot
+                }
+                _ => false,
+            };
+            if cancelled {
+                self.cancel_untriggered_order(&trigger_order, event_q)?;
+            } else {
+                trigger_q
+                    .push_back(trigger_order)
+                    .or(check_unreachable!())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Releases the funds locked for a trigger order taken out of the trigger queue.
+    fn cancel_untriggered_order(
+        &self,
+        trigger_order: &TriggerOrder,
+        event_q: &mut EventQueue,
+    ) -> DexResult {
+        if let RequestView::NewOrder {
+            side,
+            order_id,
+            owner,
+            owner_slot,
+            max_coin_qty,
+            native_pc_qty_locked,
+            client_order_id,
+            ..
+        } = trigger_order.as_view()?
+        {
+            let native_qty_unlocked = match side {
+                Side::Bid => native_pc_qty_locked.map_or(0, NonZeroU64::get),
+                Side::Ask => max_coin_qty.get() * self.market_state.coin_lot_size,
+            };
+            event_q
+                .push_back(Event::new(EventView::Out {
+                    side,
+                    native_qty_unlocked,
+                    native_qty_still_locked: 0,
+                    order_id,
+                    owner,
+                    owner_slot,
+                    client_order_id,
+                }))
+                .map_err(|_| DexErrorCode::EventQueueFull)?;
+        }
         Ok(())
     }
 }
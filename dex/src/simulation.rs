@@ -0,0 +1,231 @@
+//! Off-chain simulation of a taker order against the order book, for estimating the fills,
+//! fees and slippage of an order before sending it.
+//!
+//! The matching engine fills a taker order against one resting order per step. Each step
+//! derives the pc lots a bid can pay for from what the bid still has locked, and charges the
+//! taker fee, rounded up, on the pc that step filled. The simulation goes through the same
+//! steps, so its amounts match what the market would settle to the native unit, including
+//! the rounding of every fee. Self trades are not simulated: the taker's own orders are
+//! matched like anyone else's.
+
+use std::collections::VecDeque;
+use std::num::NonZeroU64;
+
+use serde::{Deserialize, Serialize};
+use solana_sdk::clock::Clock;
+
+use crate::critbit::{LeafNode, OrderBookSide, OrderExt};
+use crate::fees::FeeTier;
+use crate::matching::Side;
+use crate::state::{MarketState, MarketStateExt};
+
+#[derive(Copy, Clone, Debug)]
+pub struct SimulatedOrder {
+    pub side: Side,
+    pub limit_price: Option<NonZeroU64>,
+    pub max_coin_qty: NonZeroU64,
+    /// The pc budget of a bid, including the taker fee. Asks ignore it.
+    pub max_native_pc_qty_including_fees: Option<NonZeroU64>,
+    pub fee_tier: FeeTier,
+}
+
+/// A fill against a single resting order. Prices are in pc lots per coin lot and quantities
+/// in coin lots.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimulatedFill {
+    pub price: u64,
+    pub quantity: u64,
+    pub native_pc_qty: u64,
+    pub native_taker_fee: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Simulation {
+    pub side: Side,
+    pub fills: Vec<SimulatedFill>,
+    pub coin_qty_filled: u64,
+    pub native_coin_qty: u64,
+    pub native_pc_qty: u64,
+    pub native_taker_fee: u64,
+}
+
+impl Simulation {
+    /// The pc the taker pays for a bid, or receives for an ask, after the taker fee.
+    pub fn native_pc_qty_including_fees(&self) -> u64 {
+        match self.side {
+            Side::Bid => self.native_pc_qty + self.native_taker_fee,
+            Side::Ask => self.native_pc_qty - self.native_taker_fee,
+        }
+    }
+
+    /// The average fill price in pc lots per coin lot, before fees.
+    pub fn average_price(&self) -> Option<f64> {
+        if self.coin_qty_filled == 0 {
+            return None;
+        }
+        let notional: u128 = self
+            .fills
+            .iter()
+            .map(|fill| fill.quantity as u128 * fill.price as u128)
+            .sum();
+        Some(notional as f64 / self.coin_qty_filled as f64)
+    }
+
+    /// How much worse the fills are than the best price on the book, in basis points,
+    /// rounded down. Fees are not included.
+    pub fn slippage_bps(&self) -> Option<u64> {
+        let best_price = self.fills.first()?.price as u128;
+        let price_impact: u128 = self
+            .fills
+            .iter()
+            .map(|fill| {
+                let price = fill.price as u128;
+                let diff = match self.side {
+                    Side::Bid => price - best_price,
+                    Side::Ask => best_price - price,
+                };
+                fill.quantity as u128 * diff
+            })
+            .sum();
+        let bps = price_impact * 10_000 / (best_price * self.coin_qty_filled as u128);
+        Some(bps as u64)
+    }
+}
+
+struct RestingOrder {
+    quantity: u64,
+    hidden_quantity: u64,
+    display_quantity: u64,
+}
+
+impl RestingOrder {
+    fn from_leaf(leaf: &LeafNode, ext: Option<&OrderExt>) -> Self {
+        RestingOrder {
+            quantity: leaf.quantity(),
+            hidden_quantity: ext.map_or(0, |ext| ext.hidden_quantity()),
+            display_quantity: ext.map_or(0, |ext| ext.display_quantity()),
+        }
+    }
+}
+
+/// Simulates `order` as an immediate-or-cancel order against `bids` and `asks`, without
+/// modifying them. Iceberg orders are refilled to the back of their price level, as on chain.
+/// Orders that have expired at `clock` are skipped; without a clock, expiries are ignored.
+/// Markets with the original layout are simulated with `LEGACY_MARKET_EXT`.
+pub fn simulate_taker_order(
+    bids: OrderBookSide,
+    asks: OrderBookSide,
+    market: &MarketState,
+    market_ext: &MarketStateExt,
+    order: &SimulatedOrder,
+    clock: Option<&Clock>,
+) -> Simulation {
+    let SimulatedOrder {
+        side,
+        limit_price,
+        max_coin_qty,
+        max_native_pc_qty_including_fees,
+        fee_tier,
+    } = *order;
+
+    let pc_lot_size = market.pc_lot_size;
+    let coin_lot_size = market.coin_lot_size;
+    let fee_schedule = market_ext.fee_schedule;
+
+    let (book, book_side) = match side {
+        Side::Bid => (asks, Side::Ask),
+        Side::Ask => (bids, Side::Bid),
+    };
+    let is_live = |leaf: &LeafNode| match (book.ext(leaf).and_then(|ext| ext.expiry()), clock) {
+        (Some(expiry), Some(clock)) => !expiry.has_passed(clock),
+        _ => true,
+    };
+    let mut orders = book
+        .orders
+        .iter_best_first(book_side)
+        .filter(|leaf| is_live(leaf))
+        .peekable();
+
+    let mut coin_qty_remaining = max_coin_qty.get();
+    let mut native_pc_qty_locked =
+        max_native_pc_qty_including_fees.map_or(u64::MAX, NonZeroU64::get);
+    let mut fills = Vec::new();
+
+    'levels: while let Some(first) = orders.next() {
+        let price = first.price();
+        let crossed = match side {
+            Side::Bid => limit_price.map_or(true, |limit_price| limit_price >= price),
+            Side::Ask => limit_price.map_or(true, |limit_price| limit_price <= price),
+        };
+        if !crossed {
+            break;
+        }
+        let price = price.get();
+
+        let mut level = VecDeque::new();
+        level.push_back(RestingOrder::from_leaf(first, book.ext(first)));
+        while let Some(leaf) = orders.peek() {
+            if leaf.price().get() != price {
+                break;
+            }
+            level.push_back(RestingOrder::from_leaf(leaf, book.ext(leaf)));
+            orders.next();
+        }
+
+        // one step of the matching engine per resting order
+        while let Some(mut maker) = level.pop_front() {
+            let trade_qty = match side {
+                Side::Bid => {
+                    let max_pc_qty =
+                        fee_schedule.remove_taker_fee(fee_tier, native_pc_qty_locked) / pc_lot_size;
+                    maker
+                        .quantity
+                        .min(coin_qty_remaining)
+                        .min(max_pc_qty / price)
+                }
+                Side::Ask => maker.quantity.min(coin_qty_remaining),
+            };
+            if trade_qty == 0 {
+                break 'levels;
+            }
+
+            let native_pc_qty = trade_qty * price * pc_lot_size;
+            let native_taker_fee = fee_schedule.taker_fee(fee_tier, native_pc_qty);
+            fills.push(SimulatedFill {
+                price,
+                quantity: trade_qty,
+                native_pc_qty,
+                native_taker_fee,
+            });
+
+            coin_qty_remaining -= trade_qty;
+            if side == Side::Bid {
+                native_pc_qty_locked -= native_pc_qty + native_taker_fee;
+            }
+
+            maker.quantity -= trade_qty;
+            if maker.quantity > 0 {
+                level.push_front(maker);
+            } else if maker.hidden_quantity > 0 {
+                let refill_quantity = maker.display_quantity.min(maker.hidden_quantity);
+                maker.quantity = refill_quantity;
+                maker.hidden_quantity -= refill_quantity;
+                level.push_back(maker);
+            }
+
+            if coin_qty_remaining == 0 || native_pc_qty_locked == 0 {
+                break 'levels;
+            }
+        }
+    }
+
+    let coin_qty_filled = max_coin_qty.get() - coin_qty_remaining;
+    Simulation {
+        side,
+        coin_qty_filled,
+        native_coin_qty: coin_qty_filled * coin_lot_size,
+        native_pc_qty: fills.iter().map(|fill| fill.native_pc_qty).sum(),
+        native_taker_fee: fills.iter().map(|fill| fill.native_taker_fee).sum(),
+        fills,
+    }
+}
@@ -0,0 +1,300 @@
+//! Versioned snapshots of a market's order book and queues, decoded from the raw data of its
+//! bids, asks, request queue and event queue accounts.
+//!
+//! `MarketSnapshot` is the L3 view, with every order and its owner. `L2Snapshot` aggregates
+//! the orders by price. Both derive serde's traits, so they can be encoded as JSON, and have
+//! a compact binary encoding through `to_bytes` / `from_bytes`.
+
+use std::convert::TryInto;
+
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::critbit::{LeafNode, OrderBookSide};
+use crate::error::{DexErrorCode, DexResult, SourceFileId};
+use crate::matching::Side;
+use crate::state::{
+    load_order_book_side, read_event_queue, read_request_queue, strip_account_data_padding,
+    EventView, RequestView,
+};
+
+declare_check_assert_macros!(SourceFileId::Snapshot);
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Order {
+    pub order_id: u128,
+    pub price: u64,
+    pub quantity: u64,
+    pub hidden_quantity: u64,
+    pub owner: Pubkey,
+    pub owner_slot: u16,
+    pub client_order_id: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QueuedRequest {
+    NewOrder {
+        side: Side,
+        order_id: u128,
+        max_coin_qty: u64,
+        native_pc_qty_locked: u64,
+        owner: Pubkey,
+        owner_slot: u16,
+        client_order_id: u64,
+    },
+    CancelOrder {
+        side: Side,
+        order_id: u128,
+        owner: Pubkey,
+        owner_slot: u16,
+        client_order_id: u64,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QueuedEvent {
+    Fill {
+        seq_num: u64,
+        side: Side,
+        maker: bool,
+        order_id: u128,
+        owner: Pubkey,
+        owner_slot: u16,
+        native_qty_paid: u64,
+        native_qty_received: u64,
+        native_fee_or_rebate: u64,
+        client_order_id: u64,
+    },
+    Out {
+        seq_num: u64,
+        side: Side,
+        order_id: u128,
+        owner: Pubkey,
+        owner_slot: u16,
+        native_qty_unlocked: u64,
+        native_qty_still_locked: u64,
+        client_order_id: u64,
+    },
+}
+
+/// Orders are listed best price first. Prices are in pc lots per coin lot and quantities in
+/// coin lots, as on the book.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarketSnapshot {
+    pub version: u32,
+    pub bids: Vec<Order>,
+    pub asks: Vec<Order>,
+    pub requests: Vec<QueuedRequest>,
+    pub events: Vec<QueuedEvent>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Level {
+    pub price: u64,
+    pub quantity: u64,
+}
+
+/// Visible quantity by price, best price first.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct L2Snapshot {
+    pub version: u32,
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+}
+
+fn owner_pubkey(owner: &[u64; 4]) -> Pubkey {
+    Pubkey::new(bytemuck::bytes_of(owner))
+}
+
+impl Order {
+    fn from_leaf(book: OrderBookSide, leaf: &LeafNode) -> Self {
+        Order {
+            order_id: *leaf.order_id(),
+            price: leaf.price().get(),
+            quantity: leaf.quantity(),
+            hidden_quantity: book.ext(leaf).map_or(0, |ext| ext.hidden_quantity()),
+            owner: owner_pubkey(leaf.owner()),
+            owner_slot: leaf.owner_slot(),
+            client_order_id: leaf.client_order_id(),
+        }
+    }
+}
+
+impl MarketSnapshot {
+    /// Decodes the snapshot from the account data of the market's bids, asks, request queue
+    /// and event queue, including the dex's account padding.
+    pub fn from_account_data(
+        bids: &[u8],
+        asks: &[u8],
+        req_q: &[u8],
+        event_q: &[u8],
+    ) -> DexResult<Self> {
+        let bids_words = strip_account_data_padding(bids)?;
+        let (bids_side, bids_book) = load_order_book_side(&bids_words)?;
+        check_assert_eq!(bids_side, Side::Bid).map_err(|_| DexErrorCode::WrongBidsAccount)?;
+        let asks_words = strip_account_data_padding(asks)?;
+        let (asks_side, asks_book) = load_order_book_side(&asks_words)?;
+        check_assert_eq!(asks_side, Side::Ask).map_err(|_| DexErrorCode::WrongAsksAccount)?;
+
+        let (_, raw_requests) = read_request_queue(&strip_account_data_padding(req_q)?)?;
+        let requests = raw_requests
+            .iter()
+            .map(|(request, ext)| {
+                Ok(match request.as_view(ext)? {
+                    RequestView::NewOrder {
+                        side,
+                        order_id,
+                        max_coin_qty,
+                        native_pc_qty_locked,
+                        owner,
+                        owner_slot,
+                        client_order_id,
+                        ..
+                    } => QueuedRequest::NewOrder {
+                        side,
+                        order_id: *order_id,
+                        max_coin_qty: max_coin_qty.get(),
+                        native_pc_qty_locked: native_pc_qty_locked.map_or(0, |qty| qty.get()),
+                        owner: owner_pubkey(owner),
+                        owner_slot,
+                        client_order_id: client_order_id.map_or(0, |id| id.get()),
+                    },
+                    RequestView::CancelOrder {
+                        side,
+                        order_id,
+                        expected_owner,
+                        expected_owner_slot,
+                        client_order_id,
+                        ..
+                    } => QueuedRequest::CancelOrder {
+                        side,
+                        order_id: *order_id,
+                        owner: owner_pubkey(expected_owner),
+                        owner_slot: expected_owner_slot,
+                        client_order_id: client_order_id.map_or(0, |id| id.get()),
+                    },
+                })
+            })
+            .collect::<DexResult<_>>()?;
+
+        let (event_q_header, raw_events) = read_event_queue(&strip_account_data_padding(event_q)?)?;
+        let first_seq_num = event_q_header.first_seq_num();
+        let events = raw_events
+            .iter()
+            .zip(first_seq_num..)
+            .map(|(event, seq_num)| {
+                Ok(match event.as_view()? {
+                    EventView::Fill {
+                        side,
+                        maker,
+                        native_qty_paid,
+                        native_qty_received,
+                        native_fee_or_rebate,
+                        order_id,
+                        owner,
+                        owner_slot,
+                        client_order_id,
+                        ..
+                    } => QueuedEvent::Fill {
+                        seq_num,
+                        side,
+                        maker,
+                        order_id: *order_id,
+                        owner: owner_pubkey(owner),
+                        owner_slot,
+                        native_qty_paid,
+                        native_qty_received,
+                        native_fee_or_rebate,
+                        client_order_id: client_order_id.map_or(0, |id| id.get()),
+                    },
+                    EventView::Out {
+                        side,
+                        native_qty_unlocked,
+                        native_qty_still_locked,
+                        order_id,
+                        owner,
+                        owner_slot,
+                        client_order_id,
+                    } => QueuedEvent::Out {
+                        seq_num,
+                        side,
+                        order_id: *order_id,
+                        owner: owner_pubkey(owner),
+                        owner_slot,
+                        native_qty_unlocked,
+                        native_qty_still_locked,
+                        client_order_id: client_order_id.map_or(0, |id| id.get()),
+                    },
+                })
+            })
+            .collect::<DexResult<_>>()?;
+
+        Ok(MarketSnapshot {
+            version: SNAPSHOT_VERSION,
+            bids: bids_book
+                .orders
+                .iter_best_first(Side::Bid)
+                .map(|leaf| Order::from_leaf(bids_book, leaf))
+                .collect(),
+            asks: asks_book
+                .orders
+                .iter_best_first(Side::Ask)
+                .map(|leaf| Order::from_leaf(asks_book, leaf))
+                .collect(),
+            requests,
+            events,
+        })
+    }
+
+    /// Aggregates the visible quantity of the orders by price.
+    pub fn l2(&self) -> L2Snapshot {
+        fn levels(orders: &[Order]) -> Vec<Level> {
+            let mut levels: Vec<Level> = Vec::new();
+            for order in orders {
+                match levels.last_mut() {
+                    Some(level) if level.price == order.price => level.quantity += order.quantity,
+                    _ => levels.push(Level {
+                        price: order.price,
+                        quantity: order.quantity,
+                    }),
+                }
+            }
+            levels
+        }
+        L2Snapshot {
+            version: self.version,
+            bids: levels(&self.bids),
+            asks: levels(&self.asks),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// Decodes a snapshot encoded by `to_bytes`, if it has the current version.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let version = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?);
+        if version != SNAPSHOT_VERSION {
+            return None;
+        }
+        bincode::deserialize(bytes).ok()
+    }
+}
+
+impl L2Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// Decodes a snapshot encoded by `to_bytes`, if it has the current version.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let version = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?);
+        if version != SNAPSHOT_VERSION {
+            return None;
+        }
+        bincode::deserialize(bytes).ok()
+    }
+}
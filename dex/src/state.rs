@@ -1,12 +1,20 @@
 #![cfg_attr(not(feature = "program"), allow(unused))]
 use num_enum::TryFromPrimitive;
-use std::{cell::RefMut, convert::TryInto, mem::size_of, num::NonZeroU64, ops::DerefMut};
+use std::{
+    borrow::Cow,
+    cell::RefMut,
+    convert::TryInto,
+    mem::size_of,
+    num::NonZeroU64,
+    ops::{Deref, DerefMut},
+};
 
 use arrayref::{array_ref, array_refs, mut_array_refs};
 
 use bytemuck::{
-    bytes_of, bytes_of_mut, cast, cast_slice, cast_slice_mut, from_bytes_mut, try_cast_mut,
-    try_cast_slice_mut, try_from_bytes_mut, Pod, Zeroable,
+    bytes_of, bytes_of_mut, cast, cast_slice, cast_slice_mut, from_bytes, from_bytes_mut,
+    try_cast_mut, try_cast_slice, try_cast_slice_mut, try_from_bytes, try_from_bytes_mut, Pod,
+    Zeroable,
 };
 use enumflags2::BitFlags;
 use num_traits::FromPrimitive;
@@ -22,6 +30,7 @@ macro_rules! info {
 
 use solana_sdk::{
     account_info::AccountInfo,
+    clock::Clock,
     program_error::ProgramError,
     program_pack::Pack,
     pubkey::Pubkey,
@@ -31,14 +40,19 @@ use solana_sdk::{
 use spl_token::error::TokenError;
 
 use crate::{
-    critbit::Slab,
+    critbit::{extended_slab_len, OrderBookSide, OrderExtTable, Slab, SlabView, SLAB_HEADER_LEN},
     error::{DexErrorCode, DexResult, SourceFileId},
-    fees::{self, FeeTier},
+    fees::{FeeSchedule, FeeTier},
     instruction::{
         disable_authority, fee_sweeper, msrm_token, srm_token, CancelOrderInstruction,
-        InitializeMarketInstruction, MarketInstruction, NewOrderInstructionV2, SelfTradeBehavior,
+        CreateOpenOrdersInstruction, InitializeMarketInstruction, MarketInstruction, MarketStatus,
+        NewIcebergOrderInstruction, NewOrderInstructionV3, NewTriggerOrderInstruction,
+        OrderToReplace, ReplaceOrderInstruction, SelfTradeBehavior, UpdateMarketParamsInstruction,
+    },
+    matching::{
+        extract_seq_num_from_order_id, OrderBookState, OrderExpiry, OrderType, Side,
+        TriggerCondition,
     },
-    matching::{OrderBookState, OrderType, Side},
 };
 
 declare_check_assert_macros!(SourceFileId::State);
@@ -65,6 +79,13 @@ pub enum AccountFlag {
     Bids = 1u64 << 5,
     Asks = 1u64 << 6,
     Disabled = 1u64 << 7,
+    TriggerQueue = 1u64 << 8,
+    CancelOnly = 1u64 << 9,
+    Paused = 1u64 << 10,
+    TradeLog = 1u64 << 11,
+    MarketStats = 1u64 << 12,
+    Extended = 1u64 << 13,
+    MarketAuthority = 1u64 << 14,
 }
 
 #[cfg_attr(target_endian = "little", derive(Debug))]
@@ -116,7 +137,8 @@ pub struct MarketState {
     // 44
     pub pc_lot_size: u64,
 
-    // 45
+    // 45, deprecated: recorded at initialization but never charged, the fee schedule in the
+    // market extension sets the fees
     pub fee_rate_bps: u64,
     // 46
     pub referrer_rebates_accrued: u64,
@@ -128,6 +150,264 @@ unsafe impl Pod for MarketState {}
 #[cfg(target_endian = "little")]
 unsafe impl TriviallyTransmutable for MarketState {}
 
+/// The fields an extended market has on top of the original layout, stored right after it.
+/// Extended markets have the `Extended` account flag set; older markets behave as if they
+/// had `LEGACY_MARKET_EXT`.
+#[cfg_attr(target_endian = "little", derive(Debug))]
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct MarketStateExt {
+    // 0
+    pub last_trade_price: u64,
+    // 1
+    pub trigger_q: [u64; 4],
+
+    // 5
+    pub fee_authority: [u64; 4],
+    // 9
+    pub fee_schedule: FeeSchedule,
+    // 29
+    pub market_authority: [u64; 4],
+    // 33
+    pub trade_log: [u64; 4],
+    // 37
+    pub stats: [u64; 4],
+}
+#[cfg(target_endian = "little")]
+unsafe impl Zeroable for MarketStateExt {}
+#[cfg(target_endian = "little")]
+unsafe impl Pod for MarketStateExt {}
+#[cfg(target_endian = "little")]
+unsafe impl TriviallyTransmutable for MarketStateExt {}
+
+pub const LEGACY_MARKET_EXT: MarketStateExt = MarketStateExt {
+    last_trade_price: 0,
+    trigger_q: [0; 4],
+    fee_authority: [0; 4],
+    fee_schedule: FeeSchedule::DEFAULT,
+    market_authority: [0; 4],
+    trade_log: [0; 4],
+    stats: [0; 4],
+};
+
+impl MarketStateExt {
+    #[inline]
+    pub fn has_trade_log(&self) -> bool {
+        self.trade_log != [0; 4]
+    }
+
+    #[inline]
+    pub fn has_stats(&self) -> bool {
+        self.stats != [0; 4]
+    }
+
+    #[inline]
+    pub fn has_trigger_queue(&self) -> bool {
+        self.trigger_q != [0; 4]
+    }
+
+    fn load_trade_log_mut<'a>(&self, log: &'a AccountInfo) -> DexResult<TradeLog<'a>> {
+        check_assert_eq!(&log.key.to_aligned_bytes(), &self.trade_log)
+            .map_err(|_| DexErrorCode::WrongTradeLogAccount)?;
+        let (header, buf) = strip_header::<TradeLogHeader, TradeRecord>(log, false)?;
+
+        let flags = BitFlags::from_bits(header.account_flags).unwrap();
+        check_assert_eq!(&flags, &(AccountFlag::Initialized | AccountFlag::TradeLog))?;
+        Ok(Queue { header, buf })
+    }
+
+    fn load_stats_mut<'a>(
+        &self,
+        stats: &'a AccountInfo,
+        market: &[u64; 4],
+    ) -> DexResult<RefMut<'a, MarketStats>> {
+        check_assert_eq!(&stats.key.to_aligned_bytes(), &self.stats)
+            .map_err(|_| DexErrorCode::WrongMarketStatsAccount)?;
+        let (header, _) = strip_header::<MarketStats, u8>(stats, false)?;
+        header.check_flags()?;
+        check_assert_eq!(&header.market, market)
+            .map_err(|_| DexErrorCode::WrongMarketStatsAccount)?;
+        Ok(header)
+    }
+
+    fn load_trigger_queue_mut<'a>(&self, queue: &'a AccountInfo) -> DexResult<TriggerQueue<'a>> {
+        if !self.has_trigger_queue() {
+            Err(DexErrorCode::MarketHasNoTriggerQueue)?
+        }
+        check_assert_eq!(&queue.key.to_aligned_bytes(), &self.trigger_q)
+            .map_err(|_| DexErrorCode::WrongTriggerQueueAccount)?;
+        let (header, buf) = strip_header::<TriggerQueueHeader, TriggerOrder>(queue, false)?;
+
+        let flags = BitFlags::from_bits(header.account_flags).unwrap();
+        check_assert_eq!(
+            &flags,
+            &(AccountFlag::Initialized | AccountFlag::TriggerQueue)
+        )?;
+        Ok(Queue { header, buf })
+    }
+
+    #[inline]
+    fn load_fee_tier(
+        &self,
+        expected_owner: &[u64; 4],
+        srm_or_msrm_account: Option<account_parser::TokenAccount>,
+    ) -> DexResult<FeeTier> {
+        let srm_or_msrm_account = match srm_or_msrm_account {
+            Some(a) => a,
+            None => return Ok(FeeTier::Base),
+        };
+        let data = srm_or_msrm_account.inner().try_borrow_data()?;
+
+        let mut aligned_data: [u64; 9] = Zeroable::zeroed();
+        bytes_of_mut(&mut aligned_data).copy_from_slice(&data[..72]);
+        let (mint, owner, &[balance]) = array_refs![&aligned_data, 4, 4, 1];
+
+        check_assert_eq!(owner, expected_owner)?;
+        if mint == &srm_token::ID.to_aligned_bytes() {
+            return Ok(self.fee_schedule.fee_tier(balance, 0));
+        }
+
+        if mint == &msrm_token::ID.to_aligned_bytes() {
+            return Ok(self.fee_schedule.fee_tier(0, balance));
+        }
+
+        Ok(self.fee_schedule.fee_tier(0, 0))
+    }
+}
+
+/// A loaded market account, holding the borrow of its data.
+pub struct MarketStateRefMut<'a> {
+    base: RefMut<'a, MarketState>,
+    ext: Option<RefMut<'a, MarketStateExt>>,
+}
+
+impl<'a> MarketStateRefMut<'a> {
+    pub fn ext(&self) -> &MarketStateExt {
+        self.ext.as_deref().unwrap_or(&LEGACY_MARKET_EXT)
+    }
+
+    /// The market's admin authority, all zero if it has none. Markets with the original layout
+    /// keep it in their authority record, which only counts when passed.
+    fn load_market_authority(
+        &self,
+        record: Option<&AccountInfo>,
+        program_id: &Pubkey,
+    ) -> DexResult<[u64; 4]> {
+        if self.is_extended() {
+            return Ok(self.ext().market_authority);
+        }
+        Ok(match record {
+            Some(record) => {
+                MarketAuthorityRecord::load_mut(record, program_id, &self.own_address, false)?
+                    .authority
+            }
+            None => [0; 4],
+        })
+    }
+
+    pub fn ext_mut(&mut self) -> DexResult<&mut MarketStateExt> {
+        Ok(self
+            .ext
+            .as_deref_mut()
+            .ok_or(DexErrorCode::MarketNotExtended)?)
+    }
+
+    pub fn as_market_mut(&mut self) -> MarketStateMut<'_> {
+        MarketStateMut {
+            base: &mut self.base,
+            ext: self.ext.as_deref_mut(),
+        }
+    }
+}
+
+impl<'a> Deref for MarketStateRefMut<'a> {
+    type Target = MarketState;
+
+    fn deref(&self) -> &MarketState {
+        &self.base
+    }
+}
+
+impl<'a> DerefMut for MarketStateRefMut<'a> {
+    fn deref_mut(&mut self) -> &mut MarketState {
+        &mut self.base
+    }
+}
+
+/// Holds the admin authority of a market with the original layout, which has no room for it.
+/// Only `SetMarketAuthority` initializes these accounts.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct MarketAuthorityRecord {
+    pub account_flags: u64, // Initialized, MarketAuthority
+    pub market: [u64; 4],
+    pub authority: [u64; 4],
+}
+unsafe impl Zeroable for MarketAuthorityRecord {}
+unsafe impl Pod for MarketAuthorityRecord {}
+
+impl MarketAuthorityRecord {
+    fn load_mut<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        market: &[u64; 4],
+        init_allowed: bool,
+    ) -> DexResult<RefMut<'a, Self>> {
+        check_assert_eq!(account.owner, program_id)?;
+        let (mut record, _) = strip_header::<Self, u8>(account, init_allowed)?;
+        let flags = (AccountFlag::Initialized | AccountFlag::MarketAuthority).bits();
+        if init_allowed && record.account_flags == 0 {
+            *record = MarketAuthorityRecord {
+                account_flags: flags,
+                market: *market,
+                authority: [0; 4],
+            };
+        }
+        if record.account_flags != flags || &record.market != market {
+            Err(DexErrorCode::WrongMarketAuthorityRecord)?
+        }
+        Ok(record)
+    }
+}
+
+#[inline]
+fn is_market_authority(market_authority: &[u64; 4], key: &Pubkey) -> bool {
+    market_authority != &[0; 4] && &key.to_aligned_bytes() == market_authority
+}
+
+/// Gives access to a market's state, whichever its layout.
+pub struct MarketStateMut<'a> {
+    base: &'a mut MarketState,
+    ext: Option<&'a mut MarketStateExt>,
+}
+
+impl<'a> MarketStateMut<'a> {
+    pub fn ext(&self) -> &MarketStateExt {
+        self.ext.as_deref().unwrap_or(&LEGACY_MARKET_EXT)
+    }
+
+    pub fn ext_mut(&mut self) -> DexResult<&mut MarketStateExt> {
+        Ok(self
+            .ext
+            .as_deref_mut()
+            .ok_or(DexErrorCode::MarketNotExtended)?)
+    }
+}
+
+impl<'a> Deref for MarketStateMut<'a> {
+    type Target = MarketState;
+
+    fn deref(&self) -> &MarketState {
+        self.base
+    }
+}
+
+impl<'a> DerefMut for MarketStateMut<'a> {
+    fn deref_mut(&mut self) -> &mut MarketState {
+        self.base
+    }
+}
+
 pub const ACCOUNT_HEAD_PADDING: &[u8; 5] = b"serum";
 pub const ACCOUNT_TAIL_PADDING: &[u8; 7] = b"padding";
 
@@ -198,21 +478,42 @@ impl MarketState {
     pub fn load<'a>(
         market_account: &'a AccountInfo,
         program_id: &Pubkey,
-    ) -> DexResult<RefMut<'a, Self>> {
+    ) -> DexResult<MarketStateRefMut<'a>> {
         check_assert_eq!(market_account.owner, program_id)?;
         let mut account_data: RefMut<'a, [u8]>;
-        let state: RefMut<'a, Self>;
 
         account_data = RefMut::map(market_account.try_borrow_mut_data()?, |data| *data);
         check_account_padding(&mut account_data)?;
-        state = RefMut::map(account_data, |data| {
-            from_bytes_mut(cast_slice_mut(
-                check_account_padding(data).unwrap_or_else(|_| unreachable!()),
-            ))
+        let data: RefMut<'a, [u8]> = RefMut::map(account_data, |data| {
+            cast_slice_mut(check_account_padding(data).unwrap_or_else(|_| unreachable!()))
         });
 
-        state.check_flags()?;
-        Ok(state)
+        let base_size = size_of::<MarketState>();
+        check_assert!(data.len() >= base_size)?;
+        let (base, ext) = RefMut::map_split(data, |data| data.split_at_mut(base_size));
+        let base: RefMut<'a, Self> = RefMut::map(base, |data| from_bytes_mut(data));
+        base.check_flags()?;
+        let ext = if base.is_extended() {
+            check_assert_eq!(ext.len(), size_of::<MarketStateExt>())?;
+            Some(RefMut::map(ext, |data| from_bytes_mut(data)))
+        } else {
+            check_assert!(ext.is_empty())?;
+            None
+        };
+        Ok(MarketStateRefMut { base, ext })
+    }
+
+    /// The data length, padding included, of a market account with the extended layout.
+    pub fn account_size() -> usize {
+        size_of::<MarketState>()
+            + size_of::<MarketStateExt>()
+            + ACCOUNT_HEAD_PADDING.len()
+            + ACCOUNT_TAIL_PADDING.len()
+    }
+
+    #[inline]
+    pub fn is_extended(&self) -> bool {
+        self.account_flags & (AccountFlag::Extended as u64) != 0
     }
 
     #[inline]
@@ -220,26 +521,90 @@ impl MarketState {
         let flags = BitFlags::from_bits(self.account_flags)
             .map_err(|_| DexErrorCode::InvalidMarketFlags)?;
         let required_flags = AccountFlag::Initialized | AccountFlag::Market;
-        if flags != required_flags {
+        let status_flags = AccountFlag::CancelOnly | AccountFlag::Paused;
+        let optional_flags = status_flags | AccountFlag::Extended;
+        if flags & !optional_flags != required_flags || flags.contains(status_flags) {
             Err(DexErrorCode::InvalidMarketFlags)?
         }
         Ok(())
     }
 
+    pub fn status(&self) -> MarketStatus {
+        let flags = BitFlags::from_bits(self.account_flags).unwrap();
+        if flags.contains(AccountFlag::Paused) {
+            MarketStatus::Paused
+        } else if flags.contains(AccountFlag::CancelOnly) {
+            MarketStatus::CancelOnly
+        } else {
+            MarketStatus::Active
+        }
+    }
+
+    fn check_not_paused(&self) -> DexResult {
+        if self.status() == MarketStatus::Paused {
+            Err(DexErrorCode::MarketIsPaused)?
+        }
+        Ok(())
+    }
+
     pub fn load_orders_mut<'a>(
         &self,
         orders_account: &'a AccountInfo,
         owner_account: Option<&AccountInfo>,
         program_id: &Pubkey,
         rent: Option<Rent>,
-    ) -> DexResult<RefMut<'a, OpenOrders>> {
+    ) -> DexResult<OpenOrdersRefMut<'a>> {
+        self.load_orders_mut_inner(orders_account, owner_account, false, program_id, rent)
+    }
+
+    /// Like `load_orders_mut`, but the signer may also be the account's trading delegate.
+    pub fn load_orders_mut_as_trader<'a>(
+        &self,
+        orders_account: &'a AccountInfo,
+        trader_account: &AccountInfo,
+        program_id: &Pubkey,
+        rent: Option<Rent>,
+    ) -> DexResult<OpenOrdersRefMut<'a>> {
+        self.load_orders_mut_inner(orders_account, Some(trader_account), true, program_id, rent)
+    }
+
+    pub fn init_orders_mut<'a>(
+        &self,
+        orders_account: &'a AccountInfo,
+        owner_account: &AccountInfo,
+        program_id: &Pubkey,
+        rent: Rent,
+    ) -> DexResult<OpenOrdersRefMut<'a>> {
         check_assert_eq!(orders_account.owner, program_id)?;
-        let mut open_orders: RefMut<'a, OpenOrders>;
+        let open_orders_data_len = orders_account.data_len();
+        let open_orders_lamports = orders_account.lamports();
+        let (_, data) = strip_header::<[u8; 0], u8>(orders_account, true)?;
+        let mut open_orders = OpenOrdersRefMut::new(data)?;
+        if open_orders.account_flags != 0 {
+            Err(DexErrorCode::OrdersAlreadyInitialized)?
+        }
+        if !rent.is_exempt(open_orders_lamports, open_orders_data_len) {
+            Err(DexErrorCode::OrdersNotRentExempt)?
+        }
+        open_orders
+            .as_orders_mut()
+            .init(&self.own_address, &owner_account.key.to_aligned_bytes())?;
+        Ok(open_orders)
+    }
 
+    fn load_orders_mut_inner<'a>(
+        &self,
+        orders_account: &'a AccountInfo,
+        owner_account: Option<&AccountInfo>,
+        allow_delegate: bool,
+        program_id: &Pubkey,
+        rent: Option<Rent>,
+    ) -> DexResult<OpenOrdersRefMut<'a>> {
+        check_assert_eq!(orders_account.owner, program_id)?;
         let open_orders_data_len = orders_account.data_len();
         let open_orders_lamports = orders_account.lamports();
         let (_, data) = strip_header::<[u8; 0], u8>(orders_account, true)?;
-        open_orders = RefMut::map(data, |data| from_bytes_mut(data));
+        let mut open_orders = OpenOrdersRefMut::new(data)?;
 
         if open_orders.account_flags == 0 {
             let rent = rent.ok_or(DexErrorCode::RentNotProvided)?;
@@ -247,48 +612,61 @@ impl MarketState {
             if !rent.is_exempt(open_orders_lamports, open_orders_data_len) {
                 return Err(DexErrorCode::OrdersNotRentExempt)?;
             }
-            open_orders.init(&self.own_address, &owner_account.key.to_aligned_bytes())?;
+            open_orders
+                .as_orders_mut()
+                .init(&self.own_address, &owner_account.key.to_aligned_bytes())?;
         }
         open_orders.check_flags()?;
         check_assert_eq!(&open_orders.market, &self.own_address)
             .map_err(|_| DexErrorCode::WrongOrdersAccount)?;
         if let Some(owner) = owner_account {
-            check_assert_eq!(&open_orders.owner, &owner.key.to_aligned_bytes())
-                .map_err(|_| DexErrorCode::WrongOrdersAccount)?;
+            let signer = owner.key.to_aligned_bytes();
+            let is_delegate =
+                allow_delegate && open_orders.as_orders_mut().delegate() == Some(&signer);
+            if !is_delegate {
+                check_assert_eq!(&open_orders.owner, &signer)
+                    .map_err(|_| DexErrorCode::WrongOrdersAccount)?;
+            }
         }
 
         Ok(open_orders)
     }
 
-    fn load_bids_mut<'a>(&self, bids: &'a AccountInfo) -> DexResult<RefMut<'a, Slab>> {
+    fn load_bids_mut<'a>(&self, bids: &'a AccountInfo) -> DexResult<OrderBookSideMut<'a>> {
         check_assert_eq!(&bids.key.to_aligned_bytes(), &self.bids)
             .map_err(|_| DexErrorCode::WrongBidsAccount)?;
-        let (header, buf) = strip_header::<OrderBookStateHeader, u8>(bids, false)?;
-        let flags = BitFlags::from_bits(header.account_flags).unwrap();
-        check_assert_eq!(&flags, &(AccountFlag::Initialized | AccountFlag::Bids))?;
-        Ok(RefMut::map(buf, Slab::new))
+        load_order_book_side_mut(bids, AccountFlag::Bids)
     }
 
-    fn load_asks_mut<'a>(&self, asks: &'a AccountInfo) -> DexResult<RefMut<'a, Slab>> {
+    fn load_asks_mut<'a>(&self, asks: &'a AccountInfo) -> DexResult<OrderBookSideMut<'a>> {
         check_assert_eq!(&asks.key.to_aligned_bytes(), &self.asks)
             .map_err(|_| DexErrorCode::WrongAsksAccount)?;
-        let (header, buf) = strip_header::<OrderBookStateHeader, u8>(asks, false)?;
-        let flags = BitFlags::from_bits(header.account_flags).unwrap();
-        check_assert_eq!(&flags, &(AccountFlag::Initialized | AccountFlag::Asks))?;
-        Ok(RefMut::map(buf, Slab::new))
+        load_order_book_side_mut(asks, AccountFlag::Asks)
     }
 
     fn load_request_queue_mut<'a>(&self, queue: &'a AccountInfo) -> DexResult<RequestQueue<'a>> {
         check_assert_eq!(&queue.key.to_aligned_bytes(), &self.req_q)
             .map_err(|_| DexErrorCode::WrongRequestQueueAccount)?;
 
-        let (header, buf) = strip_header::<RequestQueueHeader, Request>(queue, false)?;
-        let flags = BitFlags::from_bits(header.account_flags).unwrap();
-        check_assert_eq!(
-            &flags,
-            &(AccountFlag::Initialized | AccountFlag::RequestQueue)
-        )?;
-        Ok(Queue { header, buf })
+        let (header, buf) = strip_header::<RequestQueueHeader, u8>(queue, false)?;
+        let extended = request_queue_flags(header.account_flags)?;
+        check_assert_eq!(extended, self.is_extended())?;
+        if !extended {
+            let buf = RefMut::map(buf, remove_slop_mut);
+            let queue = Queue { header, buf };
+            return Ok(RequestQueue { queue, exts: None });
+        }
+        let (buf, exts) = RefMut::map_split(buf, |bytes| {
+            let capacity = extended_request_queue_capacity(bytes.len());
+            let (request_bytes, ext_bytes) = bytes.split_at_mut(capacity * size_of::<Request>());
+            let ext_bytes = &mut ext_bytes[..capacity * size_of::<RequestExt>()];
+            (cast_slice_mut(request_bytes), cast_slice_mut(ext_bytes))
+        });
+        let queue = Queue { header, buf };
+        Ok(RequestQueue {
+            queue,
+            exts: Some(exts),
+        })
     }
 
     fn load_event_queue_mut<'a>(&self, queue: &'a AccountInfo) -> DexResult<EventQueue<'a>> {
@@ -336,40 +714,16 @@ impl MarketState {
         Ok(())
     }
 
-    #[inline]
-    fn load_fee_tier(
-        &self,
-        expected_owner: &[u64; 4],
-        srm_or_msrm_account: Option<account_parser::TokenAccount>,
-    ) -> DexResult<FeeTier> {
-        let srm_or_msrm_account = match srm_or_msrm_account {
-            Some(a) => a,
-            None => return Ok(FeeTier::Base),
-        };
-        let data = srm_or_msrm_account.inner().try_borrow_data()?;
-
-        let mut aligned_data: [u64; 9] = Zeroable::zeroed();
-        bytes_of_mut(&mut aligned_data).copy_from_slice(&data[..72]);
-        let (mint, owner, &[balance]) = array_refs![&aligned_data, 4, 4, 1];
-
-        check_assert_eq!(owner, expected_owner)?;
-        if mint == &srm_token::ID.to_aligned_bytes() {
-            return Ok(FeeTier::from_srm_and_msrm_balances(balance, 0));
-        }
-
-        if mint == &msrm_token::ID.to_aligned_bytes() {
-            return Ok(FeeTier::from_srm_and_msrm_balances(0, balance));
-        }
-
-        Ok(FeeTier::from_srm_and_msrm_balances(0, 0))
-    }
-
     fn check_enabled(&self) -> DexResult {
         let flags = BitFlags::from_bits(self.account_flags).unwrap();
         if flags.contains(AccountFlag::Disabled) {
             return Err(DexErrorCode::MarketIsDisabled.into());
         }
-        Ok(())
+        match self.status() {
+            MarketStatus::Active => Ok(()),
+            MarketStatus::CancelOnly => Err(DexErrorCode::MarketIsCancelOnly.into()),
+            MarketStatus::Paused => Err(DexErrorCode::MarketIsPaused.into()),
+        }
     }
 
     fn pubkey(&self) -> Pubkey {
@@ -400,6 +754,29 @@ pub struct OpenOrders {
 }
 unsafe impl Pod for OpenOrders {}
 unsafe impl Zeroable for OpenOrders {}
+unsafe impl TriviallyTransmutable for OpenOrders {}
+
+/// The slots and settings a v2 OpenOrders account has on top of the v1 layout, stored right
+/// after it. Which version an account is follows from its data length.
+#[cfg_attr(feature = "fuzz", derive(Debug))]
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct OpenOrdersExt {
+    pub free_slot_bits: [u128; 3],
+    pub is_bid_bits: [u128; 3],
+    pub orders: [u128; 384],
+    pub client_order_ids: [u64; 384],
+    pub delegate: [u64; 4],
+    // Wallets `SettleFor` pays out to, all zero unless auto-settle is enabled
+    pub settle_coin_wallet: [u64; 4],
+    pub settle_pc_wallet: [u64; 4],
+}
+unsafe impl Pod for OpenOrdersExt {}
+unsafe impl Zeroable for OpenOrdersExt {}
+unsafe impl TriviallyTransmutable for OpenOrdersExt {}
+
+pub const OPEN_ORDERS_V1_SLOTS: u16 = 128;
+pub const OPEN_ORDERS_V2_SLOTS: u16 = 512;
 
 impl OpenOrders {
     fn check_flags(&self) -> DexResult {
@@ -412,17 +789,10 @@ impl OpenOrders {
         Ok(())
     }
 
-    fn init(&mut self, market: &[u64; 4], owner: &[u64; 4]) -> DexResult<()> {
-        check_assert_eq!(&self.account_flags, &0)?;
-        self.account_flags = (AccountFlag::Initialized | AccountFlag::OpenOrders).bits();
-        self.market = *market;
-        self.owner = *owner;
-        self.native_coin_total = 0;
-        self.native_coin_free = 0;
-        self.native_pc_total = 0;
-        self.native_pc_free = 0;
-        self.free_slot_bits = std::u128::MAX;
-        Ok(())
+    /// The data length, padding included, of an OpenOrders account with the given layout.
+    pub fn account_size(v2: bool) -> usize {
+        let ext_size = if v2 { size_of::<OpenOrdersExt>() } else { 0 };
+        size_of::<OpenOrders>() + ext_size + ACCOUNT_HEAD_PADDING.len() + ACCOUNT_TAIL_PADDING.len()
     }
 
     fn credit_locked_coin(&mut self, native_coin_amount: u64) {
@@ -446,72 +816,287 @@ impl OpenOrders {
     fn lock_free_pc(&mut self, native_pc_amount: u64) {
         self.native_pc_free = self.native_pc_free.checked_sub(native_pc_amount).unwrap();
     }
+}
 
-    fn slot_is_free(&self, slot: u8) -> bool {
-        let slot_mask = 1u128 << slot;
-        self.free_slot_bits & slot_mask != 0
-    }
+/// A loaded OpenOrders account, holding the borrow of its data.
+pub struct OpenOrdersRefMut<'a> {
+    base: RefMut<'a, OpenOrders>,
+    ext: Option<RefMut<'a, OpenOrdersExt>>,
+}
 
-    pub fn slot_side(&self, slot: u8) -> Option<Side> {
-        let slot_mask = 1u128 << slot;
-        if self.free_slot_bits & slot_mask != 0 {
+impl<'a> OpenOrdersRefMut<'a> {
+    fn new(data: RefMut<'a, [u8]>) -> DexResult<Self> {
+        let v1_size = size_of::<OpenOrders>();
+        let v2_size = v1_size + size_of::<OpenOrdersExt>();
+        if data.len() != v1_size && data.len() != v2_size {
+            Err(DexErrorCode::WrongOrdersAccount)?
+        }
+        let (base, ext) = RefMut::map_split(data, |data| data.split_at_mut(v1_size));
+        let base = RefMut::map(base, |data| from_bytes_mut(data));
+        let ext = if ext.is_empty() {
             None
-        } else if self.is_bid_bits & slot_mask != 0 {
-            Some(Side::Bid)
         } else {
-            Some(Side::Ask)
-        }
+            Some(RefMut::map(ext, |data| from_bytes_mut(data)))
+        };
+        Ok(OpenOrdersRefMut { base, ext })
     }
 
-    fn remove_order(&mut self, slot: u8) -> DexResult {
-        check_assert!(slot < 128)?;
-        check_assert!(!self.slot_is_free(slot))?;
+    pub fn as_orders_mut(&mut self) -> OpenOrdersMut<'_> {
+        OpenOrdersMut {
+            base: &mut self.base,
+            ext: self.ext.as_deref_mut(),
+        }
+    }
+}
 
-        let slot_mask = 1u128 << slot;
-        self.orders[slot as usize] = 0;
-        self.client_order_ids[slot as usize] = 0;
-        self.free_slot_bits |= slot_mask;
-        self.is_bid_bits &= !slot_mask;
+impl<'a> Deref for OpenOrdersRefMut<'a> {
+    type Target = OpenOrders;
 
-        Ok(())
+    fn deref(&self) -> &OpenOrders {
+        &self.base
     }
+}
 
-    fn add_order(&mut self, id: u128, side: Side) -> DexResult<u8> {
-        if self.free_slot_bits == 0 {
-            Err(DexErrorCode::TooManyOpenOrders)?;
-        }
-        let slot = self.free_slot_bits.trailing_zeros();
-        check_assert!(self.slot_is_free(slot as u8))?;
-        let slot_mask = 1u128 << slot;
-        self.free_slot_bits &= !slot_mask;
-        match side {
-            Side::Bid => {
-                self.is_bid_bits |= slot_mask;
-            }
-            Side::Ask => {
-                self.is_bid_bits &= !slot_mask;
-            }
-        };
-        self.orders[slot as usize] = id;
-        Ok(slot as u8)
+impl<'a> DerefMut for OpenOrdersRefMut<'a> {
+    fn deref_mut(&mut self) -> &mut OpenOrders {
+        &mut self.base
     }
 }
 
-pub trait QueueHeader: Pod {
-    type Item: Pod + Copy;
+/// Gives access to all the order slots of an OpenOrders account, whichever its layout.
+pub struct OpenOrdersMut<'a> {
+    base: &'a mut OpenOrders,
+    ext: Option<&'a mut OpenOrdersExt>,
+}
 
-    fn head(&self) -> u64;
-    fn head_mut(&mut self) -> &mut u64;
-    fn count(&self) -> u64;
-    fn count_mut(&mut self) -> &mut u64;
+impl<'a> Deref for OpenOrdersMut<'a> {
+    type Target = OpenOrders;
 
-    fn incr_event_id(&mut self);
-    fn decr_event_id(&mut self, n: u64);
+    fn deref(&self) -> &OpenOrders {
+        self.base
+    }
 }
 
-pub struct Queue<'a, H: QueueHeader> {
-    header: RefMut<'a, H>,
-    buf: RefMut<'a, [H::Item]>,
+impl<'a> DerefMut for OpenOrdersMut<'a> {
+    fn deref_mut(&mut self) -> &mut OpenOrders {
+        self.base
+    }
+}
+
+impl<'a> OpenOrdersMut<'a> {
+    fn init(&mut self, market: &[u64; 4], owner: &[u64; 4]) -> DexResult<()> {
+        check_assert_eq!(&self.account_flags, &0)?;
+        self.account_flags = (AccountFlag::Initialized | AccountFlag::OpenOrders).bits();
+        self.market = *market;
+        self.owner = *owner;
+        self.native_coin_total = 0;
+        self.native_coin_free = 0;
+        self.native_pc_total = 0;
+        self.native_pc_free = 0;
+        self.free_slot_bits = std::u128::MAX;
+        if let Some(ext) = self.ext.as_mut() {
+            ext.free_slot_bits = [std::u128::MAX; 3];
+            ext.delegate = [0; 4];
+            ext.settle_coin_wallet = [0; 4];
+            ext.settle_pc_wallet = [0; 4];
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        *self.base = Zeroable::zeroed();
+        if let Some(ext) = self.ext.as_mut() {
+            **ext = Zeroable::zeroed();
+        }
+    }
+
+    /// The trading delegate, if one is set. Only v2 accounts can have one.
+    pub fn delegate(&self) -> Option<&[u64; 4]> {
+        self.ext
+            .as_ref()
+            .map(|ext| &ext.delegate)
+            .filter(|&delegate| delegate != &[0; 4])
+    }
+
+    fn set_delegate(&mut self, delegate: Option<[u64; 4]>) -> DexResult {
+        match (&mut self.ext, delegate) {
+            (Some(ext), delegate) => ext.delegate = delegate.unwrap_or([0; 4]),
+            (None, None) => (),
+            (None, Some(_)) => Err(DexErrorCode::OrdersAccountNotV2)?,
+        }
+        Ok(())
+    }
+
+    /// The registered (coin, pc) wallets, if auto-settle is enabled. Only v2 accounts can have
+    /// them.
+    pub fn settle_wallets(&self) -> Option<(&[u64; 4], &[u64; 4])> {
+        self.ext
+            .as_ref()
+            .filter(|ext| ext.settle_coin_wallet != [0; 4])
+            .map(|ext| (&ext.settle_coin_wallet, &ext.settle_pc_wallet))
+    }
+
+    fn set_settle_wallets(&mut self, wallets: Option<([u64; 4], [u64; 4])>) -> DexResult {
+        match (&mut self.ext, wallets) {
+            (Some(ext), wallets) => {
+                let (coin_wallet, pc_wallet) = wallets.unwrap_or(([0; 4], [0; 4]));
+                ext.settle_coin_wallet = coin_wallet;
+                ext.settle_pc_wallet = pc_wallet;
+            }
+            (None, None) => (),
+            (None, Some(_)) => Err(DexErrorCode::OrdersAccountNotV2)?,
+        }
+        Ok(())
+    }
+
+    fn check_settle_wallets(
+        &self,
+        coin_wallet: &AccountInfo,
+        pc_wallet: &AccountInfo,
+    ) -> DexResult {
+        let (settle_coin_wallet, settle_pc_wallet) = self
+            .settle_wallets()
+            .ok_or(DexErrorCode::AutoSettleNotEnabled)?;
+        check_assert_eq!(settle_coin_wallet, &coin_wallet.key.to_aligned_bytes())
+            .map_err(|_| DexErrorCode::WrongSettleWallet)?;
+        check_assert_eq!(settle_pc_wallet, &pc_wallet.key.to_aligned_bytes())
+            .map_err(|_| DexErrorCode::WrongSettleWallet)?;
+        Ok(())
+    }
+
+    pub fn slot_count(&self) -> u16 {
+        match self.ext {
+            Some(_) => OPEN_ORDERS_V2_SLOTS,
+            None => OPEN_ORDERS_V1_SLOTS,
+        }
+    }
+
+    pub fn all_slots_free(&self) -> bool {
+        (0..self.slot_count() / 128).all(|word| self.slot_words(word).0 == std::u128::MAX)
+    }
+
+    // Returns the free and is-bid bitmaps covering slots `128 * word..128 * (word + 1)`.
+    fn slot_words(&self, word: u16) -> (u128, u128) {
+        match (word, &self.ext) {
+            (0, _) => (self.base.free_slot_bits, self.base.is_bid_bits),
+            (word, Some(ext)) => (
+                ext.free_slot_bits[word as usize - 1],
+                ext.is_bid_bits[word as usize - 1],
+            ),
+            (_, None) => unreachable!(),
+        }
+    }
+
+    fn slot_words_mut(&mut self, word: u16) -> (&mut u128, &mut u128) {
+        match (word, &mut self.ext) {
+            (0, _) => (&mut self.base.free_slot_bits, &mut self.base.is_bid_bits),
+            (word, Some(ext)) => (
+                &mut ext.free_slot_bits[word as usize - 1],
+                &mut ext.is_bid_bits[word as usize - 1],
+            ),
+            (_, None) => unreachable!(),
+        }
+    }
+
+    fn slot_is_free(&self, slot: u16) -> bool {
+        let (free_slot_bits, _) = self.slot_words(slot / 128);
+        free_slot_bits & (1u128 << (slot % 128)) != 0
+    }
+
+    pub fn slot_side(&self, slot: u16) -> Option<Side> {
+        if slot >= self.slot_count() {
+            return None;
+        }
+        let (free_slot_bits, is_bid_bits) = self.slot_words(slot / 128);
+        let slot_mask = 1u128 << (slot % 128);
+        if free_slot_bits & slot_mask != 0 {
+            None
+        } else if is_bid_bits & slot_mask != 0 {
+            Some(Side::Bid)
+        } else {
+            Some(Side::Ask)
+        }
+    }
+
+    pub fn order_id(&self, slot: u16) -> u128 {
+        match slot.checked_sub(OPEN_ORDERS_V1_SLOTS) {
+            None => self.base.orders[slot as usize],
+            Some(i) => self.ext.as_ref().unwrap().orders[i as usize],
+        }
+    }
+
+    fn order_id_mut(&mut self, slot: u16) -> &mut u128 {
+        match slot.checked_sub(OPEN_ORDERS_V1_SLOTS) {
+            None => &mut self.base.orders[slot as usize],
+            Some(i) => &mut self.ext.as_mut().unwrap().orders[i as usize],
+        }
+    }
+
+    pub fn client_order_id(&self, slot: u16) -> u64 {
+        match slot.checked_sub(OPEN_ORDERS_V1_SLOTS) {
+            None => self.base.client_order_ids[slot as usize],
+            Some(i) => self.ext.as_ref().unwrap().client_order_ids[i as usize],
+        }
+    }
+
+    fn client_order_id_mut(&mut self, slot: u16) -> &mut u64 {
+        match slot.checked_sub(OPEN_ORDERS_V1_SLOTS) {
+            None => &mut self.base.client_order_ids[slot as usize],
+            Some(i) => &mut self.ext.as_mut().unwrap().client_order_ids[i as usize],
+        }
+    }
+
+    fn remove_order(&mut self, slot: u16) -> DexResult {
+        check_assert!(slot < self.slot_count())?;
+        check_assert!(!self.slot_is_free(slot))?;
+
+        *self.order_id_mut(slot) = 0;
+        *self.client_order_id_mut(slot) = 0;
+        let slot_mask = 1u128 << (slot % 128);
+        let (free_slot_bits, is_bid_bits) = self.slot_words_mut(slot / 128);
+        *free_slot_bits |= slot_mask;
+        *is_bid_bits &= !slot_mask;
+
+        Ok(())
+    }
+
+    fn add_order(&mut self, id: u128, side: Side) -> DexResult<u16> {
+        let word = (0..self.slot_count() / 128)
+            .find(|&word| self.slot_words(word).0 != 0)
+            .ok_or(DexErrorCode::TooManyOpenOrders)?;
+        let slot = word * 128 + self.slot_words(word).0.trailing_zeros() as u16;
+        check_assert!(self.slot_is_free(slot))?;
+        let slot_mask = 1u128 << (slot % 128);
+        let (free_slot_bits, is_bid_bits) = self.slot_words_mut(word);
+        *free_slot_bits &= !slot_mask;
+        match side {
+            Side::Bid => {
+                *is_bid_bits |= slot_mask;
+            }
+            Side::Ask => {
+                *is_bid_bits &= !slot_mask;
+            }
+        };
+        *self.order_id_mut(slot) = id;
+        Ok(slot)
+    }
+}
+
+pub trait QueueHeader: Pod {
+    type Item: Pod + Copy;
+
+    fn head(&self) -> u64;
+    fn head_mut(&mut self) -> &mut u64;
+    fn count(&self) -> u64;
+    fn count_mut(&mut self) -> &mut u64;
+
+    fn incr_event_id(&mut self);
+    fn decr_event_id(&mut self, n: u64);
+}
+
+pub struct Queue<'a, H: QueueHeader> {
+    header: RefMut<'a, H>,
+    buf: RefMut<'a, [H::Item]>,
 }
 
 impl<'a, H: QueueHeader> Queue<'a, H> {
@@ -613,7 +1198,7 @@ impl<'a, 'b, H: QueueHeader> Iterator for QueueIterator<'a, 'b, H> {
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct RequestQueueHeader {
-    account_flags: u64, // Initialized, RequestQueue
+    account_flags: u64, // Initialized, RequestQueue, optionally Extended
     head: u64,
     count: u64,
     next_seq_num: u64,
@@ -642,10 +1227,79 @@ impl QueueHeader for RequestQueueHeader {
     fn decr_event_id(&mut self, _n: u64) {}
 }
 
-pub type RequestQueue<'a> = Queue<'a, RequestQueueHeader>;
+/// A market's request queue. The queues of extended markets keep the `RequestExt` of each
+/// request in a second ring of the same capacity, after the requests.
+pub struct RequestQueue<'a> {
+    queue: Queue<'a, RequestQueueHeader>,
+    exts: Option<RefMut<'a, [RequestExt]>>,
+}
+
+impl<'a> RequestQueue<'a> {
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.queue.len()
+    }
+
+    #[inline]
+    pub fn full(&self) -> bool {
+        self.queue.full()
+    }
+
+    #[inline]
+    pub fn empty(&self) -> bool {
+        self.queue.empty()
+    }
+
+    #[inline]
+    fn ext(&self, index: usize) -> RequestExt {
+        self.exts
+            .as_ref()
+            .map_or_else(Zeroable::zeroed, |exts| exts[index])
+    }
+
+    /// Queues a request with its extension, which has to be all zero if the queue has no room
+    /// for it.
+    #[inline]
+    pub fn push_back(&mut self, (request, ext): (Request, RequestExt)) -> DexResult {
+        if self.full() {
+            Err(DexErrorCode::RequestQueueFull)?
+        }
+        let index =
+            ((self.queue.header.head + self.queue.header.count) as usize) % self.queue.buf.len();
+        match self.exts.as_deref_mut() {
+            Some(exts) => exts[index] = ext,
+            None if ext != RequestExt::zeroed() => Err(DexErrorCode::MarketNotExtended)?,
+            None => (),
+        }
+        self.queue.push_back(request).or(check_unreachable!())?;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn peek_front(&self) -> Option<(Request, RequestExt)> {
+        let request = *self.queue.peek_front()?;
+        Some((request, self.ext(self.queue.header.head as usize)))
+    }
+
+    /// Overwrites the request at the front of the queue, e.g. with what is left of it after
+    /// matching.
+    #[inline]
+    pub fn set_front(&mut self, (request, ext): (Request, RequestExt)) -> DexResult {
+        let head = self.queue.header.head as usize;
+        *self.queue.peek_front_mut().ok_or(assertion_error!())? = request;
+        if let Some(exts) = self.exts.as_deref_mut() {
+            exts[head] = ext;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn pop_front(&mut self) -> Result<(Request, RequestExt), ()> {
+        let ext = self.ext(self.queue.header.head as usize);
+        Ok((self.queue.pop_front()?, ext))
+    }
 
-impl RequestQueue<'_> {
-    fn gen_order_id(&mut self, limit_price: u64, side: Side) -> u128 {
+    pub(crate) fn gen_order_id(&mut self, limit_price: u64, side: Side) -> u128 {
         let seq_num = self.gen_seq_num();
         let upper = (limit_price as u128) << 64;
         let lower = match side {
@@ -656,14 +1310,20 @@ impl RequestQueue<'_> {
     }
 
     fn gen_seq_num(&mut self) -> u64 {
-        let seq_num = self.header.next_seq_num;
-        self.header.next_seq_num += 1;
+        let seq_num = self.queue.header.next_seq_num;
+        self.queue.header.next_seq_num += 1;
         seq_num
     }
 }
 
+/// The number of requests an extended request queue with `buf_len` bytes after its header
+/// holds. Its buffer starts with the requests, followed by a `RequestExt` for each of them.
+pub fn extended_request_queue_capacity(buf_len: usize) -> usize {
+    buf_len / (size_of::<Request>() + size_of::<RequestExt>())
+}
+
 #[derive(Copy, Clone, BitFlags, Debug)]
-#[repr(u8)]
+#[repr(u16)]
 enum RequestFlag {
     NewOrder = 0x01,
     CancelOrder = 0x02,
@@ -671,6 +1331,10 @@ enum RequestFlag {
     PostOnly = 0x08,
     ImmediateOrCancel = 0x10,
     DecrementTakeOnSelfTrade = 0x20,
+    ReplaceOrder = 0x40,
+    FillOrKill = 0x80,
+    PostOnlySlide = 0x100,
+    Triggered = 0x200,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -680,7 +1344,11 @@ pub struct Request {
     owner_slot: u8,
     fee_tier: u8,
     self_trade_behavior: u8,
-    padding: [u8; 4],
+    // the high bytes of the flags and owner slot, zero in requests queued before they were
+    // widened
+    request_flags_hi: u8,
+    owner_slot_hi: u8,
+    padding: [u8; 2],
     max_coin_qty_or_cancel_id: u64,
     native_pc_qty_locked: u64,
     order_id: u128,
@@ -690,12 +1358,40 @@ pub struct Request {
 unsafe impl Zeroable for Request {}
 unsafe impl Pod for Request {}
 
+/// The state of a new order request that doesn't fit in its `Request`: its expiry, its display
+/// quantity if it is an iceberg order, and the order it replaces. The request queues of extended
+/// markets keep one for every request, after the requests. Other requests have an all zero one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct RequestExt {
+    expiry_kind: u8,
+    padding: [u8; 7],
+    expiry: u64,
+    display_qty: u64,
+    replaced_native_qty_credited: u64,
+    replaced_order_id: u128,
+}
+unsafe impl Zeroable for RequestExt {}
+unsafe impl Pod for RequestExt {}
+
+/// The order a new order replaces in its OpenOrders slot. If the replacement reuses the order id
+/// of the original, it only reduces the original in place.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ReplacedOrder {
+    pub order_id: u128,
+    /// Always the slot of the replacement, which takes over the slot of the original
+    pub owner_slot: u16,
+    /// The part of the funds locked for the replacement that the original still had locked when
+    /// the replacement was placed, and which wasn't deposited again.
+    pub native_qty_credited: u64,
+}
+
 #[derive(Debug)]
 pub enum RequestView<'a> {
     NewOrder {
         side: Side,
         order_type: OrderType,
-        owner_slot: u8,
+        owner_slot: u16,
         fee_tier: FeeTier,
         order_id: &'a u128,
         max_coin_qty: NonZeroU64,
@@ -703,12 +1399,18 @@ pub enum RequestView<'a> {
         owner: &'a [u64; 4],
         client_order_id: Option<NonZeroU64>,
         self_trade_behavior: SelfTradeBehavior,
+        expiry: Option<OrderExpiry>,
+        display_qty: Option<NonZeroU64>,
+        replaces: Option<ReplacedOrder>,
+        /// Whether the order was activated from the trigger queue, under an id that its
+        /// OpenOrders slot doesn't know yet
+        triggered: bool,
     },
     CancelOrder {
         side: Side,
         order_id: &'a u128,
         cancel_id: u64,
-        expected_owner_slot: u8,
+        expected_owner_slot: u16,
         expected_owner: &'a [u64; 4],
         client_order_id: Option<NonZeroU64>,
     },
@@ -716,7 +1418,7 @@ pub enum RequestView<'a> {
 
 impl Request {
     #[inline(always)]
-    pub fn new(view: RequestView) -> Self {
+    pub fn new(view: RequestView) -> (Self, RequestExt) {
         match view {
             RequestView::NewOrder {
                 side,
@@ -729,20 +1431,39 @@ impl Request {
                 native_pc_qty_locked,
                 client_order_id,
                 self_trade_behavior,
+                expiry,
+                display_qty,
+                replaces,
+                triggered,
             } => {
                 let mut flags = BitFlags::from_flag(RequestFlag::NewOrder);
                 if side == Side::Bid {
                     flags.insert(RequestFlag::Bid);
                 }
+                if replaces.is_some() {
+                    flags.insert(RequestFlag::ReplaceOrder);
+                }
+                if triggered {
+                    flags.insert(RequestFlag::Triggered);
+                }
                 match order_type {
                     OrderType::PostOnly => flags |= RequestFlag::PostOnly,
                     OrderType::ImmediateOrCancel => flags |= RequestFlag::ImmediateOrCancel,
+                    OrderType::FillOrKill => flags |= RequestFlag::FillOrKill,
+                    OrderType::PostOnlySlide => flags |= RequestFlag::PostOnlySlide,
                     OrderType::Limit => (),
                 };
-
-                Request {
-                    request_flags: flags.bits(),
+                let (expiry_kind, expiry) = OrderExpiry::encode(expiry);
+                let (replaced_order_id, replaced_native_qty_credited) =
+                    replaces.map_or((0, 0), |r| (r.order_id, r.native_qty_credited));
+                let [request_flags, request_flags_hi] = flags.bits().to_le_bytes();
+                let [owner_slot, owner_slot_hi] = owner_slot.to_le_bytes();
+
+                let request = Request {
+                    request_flags,
+                    request_flags_hi,
                     owner_slot,
+                    owner_slot_hi,
                     fee_tier: fee_tier.into(),
                     self_trade_behavior: self_trade_behavior.into(),
                     padding: Zeroable::zeroed(),
@@ -751,7 +1472,16 @@ impl Request {
                     max_coin_qty_or_cancel_id: max_coin_qty.get(),
                     native_pc_qty_locked: native_pc_qty_locked.map_or(0, NonZeroU64::get),
                     client_order_id: client_order_id.map_or(0, NonZeroU64::get),
-                }
+                };
+                let ext = RequestExt {
+                    expiry_kind,
+                    padding: Zeroable::zeroed(),
+                    expiry,
+                    display_qty: display_qty.map_or(0, NonZeroU64::get),
+                    replaced_native_qty_credited,
+                    replaced_order_id,
+                };
+                (request, ext)
             }
             RequestView::CancelOrder {
                 side,
@@ -765,25 +1495,41 @@ impl Request {
                 if side == Side::Bid {
                     flags.insert(RequestFlag::Bid);
                 }
-                Request {
-                    request_flags: flags.bits(),
+                let [request_flags, request_flags_hi] = flags.bits().to_le_bytes();
+                let [owner_slot, owner_slot_hi] = expected_owner_slot.to_le_bytes();
+                let request = Request {
+                    request_flags,
+                    request_flags_hi,
                     max_coin_qty_or_cancel_id: cancel_id,
                     order_id: *order_id,
-                    owner_slot: expected_owner_slot,
+                    owner_slot,
+                    owner_slot_hi,
                     fee_tier: 0,
                     self_trade_behavior: 0,
                     owner: *expected_owner,
                     native_pc_qty_locked: 0,
                     padding: Zeroable::zeroed(),
                     client_order_id: client_order_id.map_or(0, NonZeroU64::get),
-                }
+                };
+                (request, Zeroable::zeroed())
             }
         }
     }
 
     #[inline(always)]
-    pub fn as_view(&self) -> DexResult<RequestView> {
-        let flags = BitFlags::from_bits(self.request_flags).unwrap();
+    fn owner_slot(&self) -> u16 {
+        u16::from_le_bytes([self.owner_slot, self.owner_slot_hi])
+    }
+
+    /// Decodes the request along with `ext`, the extension stored with it, which is all zero
+    /// for requests of markets that don't have them.
+    #[inline(always)]
+    pub fn as_view(&self, ext: &RequestExt) -> DexResult<RequestView> {
+        let flags = BitFlags::from_bits(u16::from_le_bytes([
+            self.request_flags,
+            self.request_flags_hi,
+        ]))
+        .unwrap();
         let side = if flags.contains(RequestFlag::Bid) {
             Side::Bid
         } else {
@@ -792,27 +1538,53 @@ impl Request {
         if flags.contains(RequestFlag::NewOrder) {
             let allowed_flags = {
                 use RequestFlag::*;
-                NewOrder | Bid | PostOnly | ImmediateOrCancel
+                NewOrder
+                    | Bid
+                    | PostOnly
+                    | ImmediateOrCancel
+                    | FillOrKill
+                    | PostOnlySlide
+                    | ReplaceOrder
+                    | Triggered
             };
             check_assert!(allowed_flags.contains(flags))?;
             let post_only = flags.contains(RequestFlag::PostOnly);
             let ioc = flags.contains(RequestFlag::ImmediateOrCancel);
-            let order_type = match (post_only, ioc) {
-                (true, false) => OrderType::PostOnly,
-                (false, true) => OrderType::ImmediateOrCancel,
-                (false, false) => OrderType::Limit,
-                (true, true) => unreachable!(),
+            let fok = flags.contains(RequestFlag::FillOrKill);
+            let slide = flags.contains(RequestFlag::PostOnlySlide);
+            let order_type = match (post_only, ioc, fok, slide) {
+                (true, false, false, false) => OrderType::PostOnly,
+                (false, true, false, false) => OrderType::ImmediateOrCancel,
+                (false, false, true, false) => OrderType::FillOrKill,
+                (false, false, false, true) => OrderType::PostOnlySlide,
+                (false, false, false, false) => OrderType::Limit,
+                _ => unreachable!(),
             };
             let fee_tier = FeeTier::try_from_primitive(self.fee_tier).or(check_unreachable!())?;
             let self_trade_behavior =
                 SelfTradeBehavior::try_from_primitive(self.self_trade_behavior)
                     .or(check_unreachable!())?;
+            let expiry =
+                OrderExpiry::decode(ext.expiry_kind, ext.expiry).ok_or(assertion_error!())?;
+            let replaces = if flags.contains(RequestFlag::ReplaceOrder) {
+                Some(ReplacedOrder {
+                    order_id: ext.replaced_order_id,
+                    owner_slot: self.owner_slot(),
+                    native_qty_credited: ext.replaced_native_qty_credited,
+                })
+            } else {
+                None
+            };
             Ok(RequestView::NewOrder {
                 side,
                 order_type,
-                owner_slot: self.owner_slot,
+                owner_slot: self.owner_slot(),
                 fee_tier,
                 self_trade_behavior,
+                expiry,
+                display_qty: NonZeroU64::new(ext.display_qty),
+                replaces,
+                triggered: flags.contains(RequestFlag::Triggered),
                 order_id: &self.order_id,
                 owner: &self.owner,
                 max_coin_qty: NonZeroU64::new(self.max_coin_qty_or_cancel_id).unwrap(),
@@ -830,7 +1602,7 @@ impl Request {
                 side,
                 cancel_id: self.max_coin_qty_or_cancel_id,
                 order_id: &self.order_id,
-                expected_owner_slot: self.owner_slot,
+                expected_owner_slot: self.owner_slot(),
                 expected_owner: &self.owner,
                 client_order_id: NonZeroU64::new(self.client_order_id),
             })
@@ -838,6 +1610,122 @@ impl Request {
     }
 }
 
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct TriggerQueueHeader {
+    account_flags: u64, // Initialized, TriggerQueue
+    head: u64,
+    count: u64,
+    // the last trade price the queued orders were all checked against, zero if an order was
+    // queued since or a triggered one was left behind
+    checked_price: u64,
+}
+unsafe impl Zeroable for TriggerQueueHeader {}
+unsafe impl Pod for TriggerQueueHeader {}
+
+impl QueueHeader for TriggerQueueHeader {
+    type Item = TriggerOrder;
+
+    fn head(&self) -> u64 {
+        self.head
+    }
+    fn head_mut(&mut self) -> &mut u64 {
+        &mut self.head
+    }
+    fn count(&self) -> u64 {
+        self.count
+    }
+    fn count_mut(&mut self) -> &mut u64 {
+        &mut self.count
+    }
+    #[inline(always)]
+    fn incr_event_id(&mut self) {}
+    #[inline(always)]
+    fn decr_event_id(&mut self, _n: u64) {}
+}
+
+pub type TriggerQueue<'a> = Queue<'a, TriggerQueueHeader>;
+
+impl<'a> Queue<'a, TriggerQueueHeader> {
+    /// The last trade price at which no queued order was triggered, or at which every triggered
+    /// one was moved to the request queue.
+    #[inline]
+    pub fn checked_price(&self) -> Option<NonZeroU64> {
+        NonZeroU64::new(self.header.checked_price)
+    }
+
+    #[inline]
+    pub fn set_checked_price(&mut self, price: Option<NonZeroU64>) {
+        self.header.checked_price = price.map_or(0, NonZeroU64::get);
+    }
+}
+
+/// A new order request that is held back from the request queue until the
+/// market's last trade price meets its trigger condition.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct TriggerOrder {
+    trigger_price: u64,
+    trigger_condition: u8,
+    padding: [u8; 7],
+    request: Request,
+    request_ext: RequestExt,
+}
+unsafe impl Zeroable for TriggerOrder {}
+unsafe impl Pod for TriggerOrder {}
+
+impl TriggerOrder {
+    #[inline]
+    pub fn new(
+        (request, request_ext): (Request, RequestExt),
+        trigger_price: NonZeroU64,
+        trigger_condition: TriggerCondition,
+    ) -> Self {
+        TriggerOrder {
+            trigger_price: trigger_price.get(),
+            trigger_condition: trigger_condition.into(),
+            padding: Zeroable::zeroed(),
+            request,
+            request_ext,
+        }
+    }
+
+    #[inline]
+    pub fn trigger_price(&self) -> NonZeroU64 {
+        NonZeroU64::new(self.trigger_price).unwrap()
+    }
+
+    #[inline]
+    pub fn trigger_condition(&self) -> DexResult<TriggerCondition> {
+        let trigger_condition = TriggerCondition::try_from_primitive(self.trigger_condition);
+        Ok(trigger_condition.or(check_unreachable!())?)
+    }
+
+    /// The new order request to queue once the order is triggered. It gets a fresh order id from
+    /// `req_q`, so it only takes its place in the book's time priority when it activates.
+    #[inline]
+    pub fn activate(&self, req_q: &mut RequestQueue) -> (Request, RequestExt) {
+        let mut request = self.request;
+        let flags = u16::from_le_bytes([request.request_flags, request.request_flags_hi])
+            | RequestFlag::Triggered as u16;
+        let side = if flags & RequestFlag::Bid as u16 != 0 {
+            Side::Bid
+        } else {
+            Side::Ask
+        };
+        let [request_flags, request_flags_hi] = flags.to_le_bytes();
+        request.request_flags = request_flags;
+        request.request_flags_hi = request_flags_hi;
+        request.order_id = req_q.gen_order_id((request.order_id >> 64) as u64, side);
+        (request, self.request_ext)
+    }
+
+    #[inline]
+    pub fn as_view(&self) -> DexResult<RequestView<'_>> {
+        self.request.as_view(&self.request_ext)
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct EventQueueHeader {
@@ -851,6 +1739,7 @@ unsafe impl Pod for EventQueueHeader {}
 
 unsafe impl TriviallyTransmutable for EventQueueHeader {}
 unsafe impl TriviallyTransmutable for RequestQueueHeader {}
+unsafe impl TriviallyTransmutable for TriggerQueueHeader {}
 
 impl QueueHeader for EventQueueHeader {
     type Item = Event;
@@ -877,47 +1766,355 @@ impl QueueHeader for EventQueueHeader {
 
 pub type EventQueue<'a> = Queue<'a, EventQueueHeader>;
 
-#[derive(Copy, Clone, BitFlags, Debug)]
-#[repr(u8)]
-enum EventFlag {
-    Fill = 0x1,
-    Out = 0x2,
-    Bid = 0x4,
-    Maker = 0x8,
-}
-
-impl EventFlag {
+impl EventQueueHeader {
+    /// Sequence number the next pushed event will get. Events are numbered consecutively from
+    /// zero over the lifetime of the queue, so this is also the number of events ever pushed.
     #[inline]
-    fn from_side(side: Side) -> BitFlags<Self> {
-        match side {
-            Side::Bid => EventFlag::Bid.into(),
-            Side::Ask => BitFlags::empty(),
-        }
+    pub fn seq_num(&self) -> u64 {
+        self.seq_num
     }
 
+    /// Sequence number of the oldest event still in the queue.
     #[inline]
-    fn flags_to_side(flags: BitFlags<Self>) -> Side {
-        if flags.contains(EventFlag::Bid) {
-            Side::Bid
-        } else {
-            Side::Ask
-        }
+    pub fn first_seq_num(&self) -> u64 {
+        self.seq_num - self.count
+    }
+
+    /// Reads the events still in the queue from sequence number `seq_num` on, given the
+    /// queue's ring buffer as stored after the header. Events before the oldest one in the
+    /// queue have already been consumed; the returned cursor reports how many were missed.
+    pub fn cursor<'a>(&self, buf: &'a [Event], seq_num: u64) -> DexResult<EventQueueCursor<'a>> {
+        QueueCursor::new(buf, self.head, self.count, self.seq_num, seq_num)
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-#[repr(C)]
-pub struct Event {
-    event_flags: u8,
-    owner_slot: u8,
+impl<'a> EventQueue<'a> {
+    /// See `EventQueueHeader::cursor`.
+    pub fn cursor(&self, seq_num: u64) -> DexResult<EventQueueCursor<'_>> {
+        self.header.cursor(&self.buf, seq_num)
+    }
+}
 
-    fee_tier: u8,
+/// Iterates over `(seq_num, item)` pairs of a queue whose items are numbered consecutively,
+/// in order. Readers that keep the cursor's `seq_num` between reads see every item exactly
+/// once, as long as `missed` is zero.
+pub struct QueueCursor<'a, T> {
+    buf: &'a [T],
+    head: u64,
+    first_seq_num: u64,
+    end_seq_num: u64,
+    seq_num: u64,
+    missed: u64,
+}
 
-    _padding: [u8; 5],
+pub type EventQueueCursor<'a> = QueueCursor<'a, Event>;
+
+impl<'a, T> QueueCursor<'a, T> {
+    fn new(buf: &'a [T], head: u64, count: u64, end_seq_num: u64, seq_num: u64) -> DexResult<Self> {
+        check_assert!(head < buf.len() as u64)?;
+        check_assert!(count <= buf.len() as u64)?;
+        check_assert!(seq_num <= end_seq_num)?;
+        let first_seq_num = end_seq_num - count;
+        let start = seq_num.max(first_seq_num);
+        Ok(QueueCursor {
+            buf,
+            head,
+            first_seq_num,
+            end_seq_num,
+            seq_num: start,
+            missed: start - seq_num,
+        })
+    }
 
-    native_qty_released: u64,
-    native_qty_paid: u64,
-    native_fee_or_rebate: u64,
+    /// Sequence number of the next item the cursor returns, or the queue's `seq_num` once
+    /// it has read everything.
+    #[inline]
+    pub fn seq_num(&self) -> u64 {
+        self.seq_num
+    }
+
+    /// Number of items between the requested sequence number and the oldest item still
+    /// in the queue. These were removed before they could be read.
+    #[inline]
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+}
+
+impl<'a, T> Iterator for QueueCursor<'a, T> {
+    type Item = (u64, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.seq_num == self.end_seq_num {
+            return None;
+        }
+        let index = (self.head + self.seq_num - self.first_seq_num) % self.buf.len() as u64;
+        let item = (self.seq_num, &self.buf[index as usize]);
+        self.seq_num += 1;
+        Some(item)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct TradeLogHeader {
+    account_flags: u64, // Initialized, TradeLog
+    head: u64,
+    count: u64,
+    seq_num: u64,
+}
+unsafe impl Zeroable for TradeLogHeader {}
+unsafe impl Pod for TradeLogHeader {}
+unsafe impl TriviallyTransmutable for TradeLogHeader {}
+
+impl QueueHeader for TradeLogHeader {
+    type Item = TradeRecord;
+
+    fn head(&self) -> u64 {
+        self.head
+    }
+    fn head_mut(&mut self) -> &mut u64 {
+        &mut self.head
+    }
+    fn count(&self) -> u64 {
+        self.count
+    }
+    fn count_mut(&mut self) -> &mut u64 {
+        &mut self.count
+    }
+    fn incr_event_id(&mut self) {
+        self.seq_num += 1;
+    }
+    fn decr_event_id(&mut self, n: u64) {
+        self.seq_num -= n;
+    }
+}
+
+impl TradeLogHeader {
+    /// Sequence number the next recorded trade will get, i.e. the number of trades ever
+    /// recorded.
+    #[inline]
+    pub fn seq_num(&self) -> u64 {
+        self.seq_num
+    }
+
+    /// Reads the trades still in the log from sequence number `seq_num` on, given the log's
+    /// ring buffer as stored after the header. Older trades have been overwritten; the
+    /// returned cursor reports how many were missed.
+    pub fn cursor<'a>(
+        &self,
+        buf: &'a [TradeRecord],
+        seq_num: u64,
+    ) -> DexResult<TradeLogCursor<'a>> {
+        QueueCursor::new(buf, self.head, self.count, self.seq_num, seq_num)
+    }
+}
+
+/// A fixed-size ring of the market's most recent trades. Once full, each new trade overwrites
+/// the oldest one.
+pub type TradeLog<'a> = Queue<'a, TradeLogHeader>;
+
+pub type TradeLogCursor<'a> = QueueCursor<'a, TradeRecord>;
+
+impl<'a> TradeLog<'a> {
+    pub fn record(&mut self, price: u64, quantity: u64, taker_side: Side, slot: u64) {
+        if self.full() {
+            self.pop_front().unwrap();
+        }
+        let record = TradeRecord {
+            price,
+            quantity,
+            slot,
+            seq_num: self.header.seq_num,
+            taker_side: taker_side.into(),
+            padding: Zeroable::zeroed(),
+        };
+        self.push_back(record).unwrap();
+    }
+
+    /// See `TradeLogHeader::cursor`.
+    pub fn cursor(&self, seq_num: u64) -> DexResult<TradeLogCursor<'_>> {
+        self.header.cursor(&self.buf, seq_num)
+    }
+}
+
+/// A fill between a taker and one resting order, with the price and quantity in lots.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct TradeRecord {
+    pub price: u64,
+    pub quantity: u64,
+    pub slot: u64,
+    pub seq_num: u64,
+    taker_side: u8,
+    padding: [u8; 7],
+}
+unsafe impl Zeroable for TradeRecord {}
+unsafe impl Pod for TradeRecord {}
+unsafe impl TriviallyTransmutable for TradeRecord {}
+
+impl TradeRecord {
+    #[inline]
+    pub fn taker_side(&self) -> DexResult<Side> {
+        Ok(Side::try_from_primitive(self.taker_side).or(check_unreachable!())?)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct VolumeBucket {
+    pub hour: u64,
+    pub native_coin_volume: u64,
+    pub native_pc_volume: u64,
+}
+unsafe impl Zeroable for VolumeBucket {}
+unsafe impl Pod for VolumeBucket {}
+
+/// Price and volume statistics kept up to date by matching, for other programs to read
+/// through `MarketStats::load`.
+///
+/// The totals are cumulative and wrap around on overflow, so readers take differences between
+/// two snapshots with `wrapping_sub`. Over such a window the volume-weighted average price is
+/// the change in pc volume over the change in coin volume, and the time-weighted average price
+/// in lots is the change in `price_time_total_at` over the elapsed seconds.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct MarketStats {
+    pub account_flags: u64, // Initialized, MarketStats
+    pub market: [u64; 4],
+    // in pc lots per coin lot
+    pub last_trade_price: u64,
+    pub last_trade_slot: u64,
+    pub last_trade_unix_timestamp: i64,
+    pub native_coin_volume_total: u64,
+    pub native_pc_volume_total: u64,
+    pub price_time_total: u64,
+    pub hourly_volume: [VolumeBucket; 24],
+}
+unsafe impl Zeroable for MarketStats {}
+unsafe impl Pod for MarketStats {}
+
+impl MarketStats {
+    fn check_flags(&self) -> DexResult {
+        let flags = BitFlags::from_bits(self.account_flags)
+            .map_err(|_| DexErrorCode::WrongMarketStatsAccount)?;
+        if flags != AccountFlag::Initialized | AccountFlag::MarketStats {
+            Err(DexErrorCode::WrongMarketStatsAccount)?
+        }
+        Ok(())
+    }
+
+    /// Copies the stats out of a stats account, checking that it belongs to `market`.
+    /// Needs no write access, so it can be called from other programs.
+    pub fn load(account: &AccountInfo, program_id: &Pubkey, market: &Pubkey) -> DexResult<Self> {
+        check_assert_eq!(account.owner, program_id)?;
+        let data = account.try_borrow_data()?;
+        check_assert!(data.len() >= 12 + size_of::<MarketStats>())?;
+        let (head, body, tail) = array_refs![&data, 5; ..; 7];
+        check_assert_eq!(head, ACCOUNT_HEAD_PADDING)?;
+        check_assert_eq!(tail, ACCOUNT_TAIL_PADDING)?;
+        let stats: &MarketStats =
+            try_from_bytes(&body[..size_of::<MarketStats>()]).or(check_unreachable!())?;
+        stats.check_flags()?;
+        check_assert_eq!(stats.market, market.to_aligned_bytes())
+            .map_err(|_| DexErrorCode::WrongMarketStatsAccount)?;
+        Ok(*stats)
+    }
+
+    pub fn record_trade(
+        &mut self,
+        price: u64,
+        native_coin_qty: u64,
+        native_pc_qty: u64,
+        clock: &Clock,
+    ) {
+        self.price_time_total = self.price_time_total_at(clock.unix_timestamp);
+        self.last_trade_price = price;
+        self.last_trade_slot = clock.slot;
+        self.last_trade_unix_timestamp = clock.unix_timestamp;
+        self.native_coin_volume_total = self.native_coin_volume_total.wrapping_add(native_coin_qty);
+        self.native_pc_volume_total = self.native_pc_volume_total.wrapping_add(native_pc_qty);
+
+        let hour = clock.unix_timestamp.max(0) as u64 / 3600;
+        let bucket = &mut self.hourly_volume[(hour % 24) as usize];
+        if bucket.hour != hour {
+            *bucket = VolumeBucket {
+                hour,
+                native_coin_volume: 0,
+                native_pc_volume: 0,
+            };
+        }
+        bucket.native_coin_volume = bucket.native_coin_volume.wrapping_add(native_coin_qty);
+        bucket.native_pc_volume = bucket.native_pc_volume.wrapping_add(native_pc_qty);
+    }
+
+    /// `price_time_total` carried forward to `unix_timestamp` at the last trade price.
+    pub fn price_time_total_at(&self, unix_timestamp: i64) -> u64 {
+        let elapsed = unix_timestamp
+            .saturating_sub(self.last_trade_unix_timestamp)
+            .max(0) as u64;
+        self.price_time_total
+            .wrapping_add(self.last_trade_price.wrapping_mul(elapsed))
+    }
+
+    /// Native coin and pc volume traded in the 24 hours up to and including the current one.
+    pub fn volume_24h(&self, unix_timestamp: i64) -> (u64, u64) {
+        let hour = unix_timestamp.max(0) as u64 / 3600;
+        self.hourly_volume
+            .iter()
+            .filter(|bucket| bucket.hour <= hour && bucket.hour + 24 > hour)
+            .fold((0, 0), |(coin, pc), bucket| {
+                (
+                    coin.wrapping_add(bucket.native_coin_volume),
+                    pc.wrapping_add(bucket.native_pc_volume),
+                )
+            })
+    }
+}
+
+#[derive(Copy, Clone, BitFlags, Debug)]
+#[repr(u8)]
+enum EventFlag {
+    Fill = 0x1,
+    Out = 0x2,
+    Bid = 0x4,
+    Maker = 0x8,
+}
+
+impl EventFlag {
+    #[inline]
+    fn from_side(side: Side) -> BitFlags<Self> {
+        match side {
+            Side::Bid => EventFlag::Bid.into(),
+            Side::Ask => BitFlags::empty(),
+        }
+    }
+
+    #[inline]
+    fn flags_to_side(flags: BitFlags<Self>) -> Side {
+        if flags.contains(EventFlag::Bid) {
+            Side::Bid
+        } else {
+            Side::Ask
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct Event {
+    event_flags: u8,
+    owner_slot: u8,
+
+    fee_tier: u8,
+    // zero in events queued before owner slots were widened
+    owner_slot_hi: u8,
+
+    _padding: [u8; 4],
+
+    native_qty_released: u64,
+    native_qty_paid: u64,
+    native_fee_or_rebate: u64,
 
     order_id: u128,
     pub owner: [u64; 4],
@@ -928,6 +2125,7 @@ unsafe impl Pod for Event {}
 
 unsafe impl TriviallyTransmutable for Event {}
 unsafe impl TriviallyTransmutable for Request {}
+unsafe impl TriviallyTransmutable for TriggerOrder {}
 
 impl Event {
     #[inline(always)]
@@ -952,10 +2150,12 @@ impl Event {
                 };
                 let event_flags =
                     (EventFlag::from_side(side) | EventFlag::Fill).bits() | maker_flag;
+                let [owner_slot, owner_slot_hi] = owner_slot.to_le_bytes();
                 Event {
                     event_flags,
                     owner_slot,
                     fee_tier: fee_tier.into(),
+                    owner_slot_hi,
 
                     _padding: Zeroable::zeroed(),
 
@@ -980,10 +2180,12 @@ impl Event {
                 client_order_id,
             } => {
                 let event_flags = (EventFlag::from_side(side) | EventFlag::Out).bits();
+                let [owner_slot, owner_slot_hi] = owner_slot.to_le_bytes();
                 Event {
                     event_flags,
                     owner_slot,
                     fee_tier: 0,
+                    owner_slot_hi,
 
                     _padding: Zeroable::zeroed(),
 
@@ -999,6 +2201,11 @@ impl Event {
         }
     }
 
+    #[inline(always)]
+    fn owner_slot(&self) -> u16 {
+        u16::from_le_bytes([self.owner_slot, self.owner_slot_hi])
+    }
+
     #[inline(always)]
     pub fn as_view(&self) -> DexResult<EventView> {
         let flags = BitFlags::from_bits(self.event_flags).unwrap();
@@ -1021,7 +2228,7 @@ impl Event {
                 order_id: &self.order_id,
                 owner: &self.owner,
 
-                owner_slot: self.owner_slot,
+                owner_slot: self.owner_slot(),
                 fee_tier: self.fee_tier.try_into().or(check_unreachable!())?,
                 client_order_id,
             });
@@ -1039,7 +2246,7 @@ impl Event {
             order_id: &self.order_id,
             owner: &self.owner,
 
-            owner_slot: self.owner_slot,
+            owner_slot: self.owner_slot(),
             client_order_id,
         })
     }
@@ -1055,7 +2262,7 @@ pub enum EventView<'a> {
         native_fee_or_rebate: u64,
         order_id: &'a u128,
         owner: &'a [u64; 4],
-        owner_slot: u8,
+        owner_slot: u16,
         fee_tier: FeeTier,
         client_order_id: Option<NonZeroU64>,
     },
@@ -1065,7 +2272,7 @@ pub enum EventView<'a> {
         native_qty_still_locked: u64,
         order_id: &'a u128,
         owner: &'a [u64; 4],
-        owner_slot: u8,
+        owner_slot: u16,
         client_order_id: Option<NonZeroU64>,
     },
 }
@@ -1081,11 +2288,167 @@ impl<'a> EventView<'a> {
 #[derive(Copy, Clone)]
 #[repr(C)]
 struct OrderBookStateHeader {
-    account_flags: u64, // Initialized, (Bids or Asks)
+    account_flags: u64, // Initialized, (Bids or Asks), optionally Extended
 }
 unsafe impl Zeroable for OrderBookStateHeader {}
 unsafe impl Pod for OrderBookStateHeader {}
 
+// The slab of a bids or asks account and, for books of extended markets, the table holding
+// the expiry and iceberg state of its orders.
+type OrderBookSideMut<'a> = (RefMut<'a, Slab>, Option<RefMut<'a, OrderExtTable>>);
+
+fn order_book_side_flags(account_flags: u64, side_flag: AccountFlag) -> DexResult<bool> {
+    let flags = BitFlags::from_bits(account_flags).or(check_unreachable!())?;
+    check_assert_eq!(
+        flags & !AccountFlag::Extended,
+        AccountFlag::Initialized | side_flag
+    )?;
+    Ok(flags.contains(AccountFlag::Extended))
+}
+
+// Whether the request queue has the extended layout
+fn request_queue_flags(account_flags: u64) -> DexResult<bool> {
+    let flags = BitFlags::from_bits(account_flags).or(check_unreachable!())?;
+    check_assert_eq!(
+        flags & !AccountFlag::Extended,
+        AccountFlag::Initialized | AccountFlag::RequestQueue
+    )?;
+    Ok(flags.contains(AccountFlag::Extended))
+}
+
+fn load_order_book_side_mut<'a>(
+    account: &'a AccountInfo,
+    side_flag: AccountFlag,
+) -> DexResult<OrderBookSideMut<'a>> {
+    let (header, buf) = strip_header::<OrderBookStateHeader, u8>(account, false)?;
+    if !order_book_side_flags(header.account_flags, side_flag)? {
+        return Ok((RefMut::map(buf, Slab::new), None));
+    }
+    let (slab, exts) = RefMut::map_split(buf, |bytes| {
+        let (slab_bytes, exts_bytes) = bytes.split_at_mut(extended_slab_len(bytes.len()));
+        (Slab::new(slab_bytes), OrderExtTable::new(exts_bytes))
+    });
+    Ok((slab, Some(exts)))
+}
+
+/// Strips the padding off a dex account's data as fetched by a client, copying the data if it
+/// isn't 8-byte aligned.
+pub fn strip_account_data_padding(data: &[u8]) -> DexResult<Cow<'_, [u64]>> {
+    check_assert!(data.len() >= 12)?;
+    let (head, inner, tail) = array_refs![data, 5; ..; 7];
+    if head != ACCOUNT_HEAD_PADDING {
+        Err(DexErrorCode::WrongAccountHeadPadding)?
+    }
+    if tail != ACCOUNT_TAIL_PADDING {
+        Err(DexErrorCode::WrongAccountTailPadding)?
+    }
+    if inner.len() % 8 != 0 {
+        Err(DexErrorCode::WrongAccountDataPaddingLength)?
+    }
+    Ok(match try_cast_slice(inner) {
+        Ok(words) => Cow::Borrowed(words),
+        Err(_) => {
+            let mut words = vec![0u64; inner.len() / 8];
+            cast_slice_mut(&mut words).copy_from_slice(inner);
+            Cow::Owned(words)
+        }
+    })
+}
+
+/// Views the data of a bids or asks account, with its padding stripped, as the side of the
+/// book it holds.
+pub fn load_order_book_side(words: &[u64]) -> DexResult<(Side, OrderBookSide<'_>)> {
+    const HEADER_WORDS: usize = size_of::<OrderBookStateHeader>() / size_of::<u64>();
+    check_assert!(words.len() >= HEADER_WORDS)?;
+    let (header_words, slab_words) = words.split_at(HEADER_WORDS);
+    let header: &OrderBookStateHeader = from_bytes(cast_slice(header_words));
+    let flags: BitFlags<AccountFlag> =
+        BitFlags::from_bits(header.account_flags).or(check_unreachable!())?;
+    let (side, side_flag) = if flags.contains(AccountFlag::Bids) {
+        (Side::Bid, AccountFlag::Bids)
+    } else {
+        (Side::Ask, AccountFlag::Asks)
+    };
+    let extended = order_book_side_flags(header.account_flags, side_flag)?;
+    let slab_bytes: &[u8] = cast_slice(slab_words);
+    check_assert!(slab_bytes.len() >= SLAB_HEADER_LEN)?;
+    if !extended {
+        let orders = Slab::new_ref(slab_bytes)?;
+        return Ok((side, OrderBookSide { orders, exts: None }));
+    }
+    let (slab_bytes, exts_bytes) = slab_bytes.split_at(extended_slab_len(slab_bytes.len()));
+    let side_view = OrderBookSide {
+        orders: Slab::new_ref(slab_bytes)?,
+        exts: Some(OrderExtTable::new_ref(exts_bytes)?),
+    };
+    Ok((side, side_view))
+}
+
+fn read_queue_header<H: QueueHeader>(words: &[u64]) -> DexResult<(H, &[u8])> {
+    let bytes: &[u8] = cast_slice(words);
+    check_assert!(bytes.len() >= size_of::<H>())?;
+    let (header_bytes, item_bytes) = bytes.split_at(size_of::<H>());
+    let mut header = H::zeroed();
+    bytes_of_mut(&mut header).copy_from_slice(header_bytes);
+    Ok((header, item_bytes))
+}
+
+// Items are copied out since they need not be aligned within the account data
+fn copy_queue_items<T: Pod>(item_bytes: &[u8], head: u64, count: u64) -> DexResult<Vec<T>> {
+    let capacity = item_bytes.len() / size_of::<T>();
+    check_assert!(head < capacity as u64)?;
+    check_assert!(count <= capacity as u64)?;
+    let items = (0..count)
+        .map(|i| {
+            let index = (head + i) as usize % capacity;
+            let mut item = T::zeroed();
+            bytes_of_mut(&mut item)
+                .copy_from_slice(&item_bytes[index * size_of::<T>()..(index + 1) * size_of::<T>()]);
+            item
+        })
+        .collect();
+    Ok(items)
+}
+
+fn read_queue<H: QueueHeader>(words: &[u64], flag: AccountFlag) -> DexResult<(H, Vec<H::Item>)> {
+    let (header, item_bytes) = read_queue_header::<H>(words)?;
+    // Every queue header starts with its account flags
+    check_assert_eq!(words[0], (AccountFlag::Initialized | flag).bits())?;
+    let items = copy_queue_items(item_bytes, header.head(), header.count())?;
+    Ok((header, items))
+}
+
+/// Copies the requests out of a request queue account's data, with its padding stripped,
+/// oldest first, along with their extensions. Requests of queues without extensions come with
+/// all zero ones.
+pub fn read_request_queue(
+    words: &[u64],
+) -> DexResult<(RequestQueueHeader, Vec<(Request, RequestExt)>)> {
+    let (header, item_bytes) = read_queue_header::<RequestQueueHeader>(words)?;
+    let extended = request_queue_flags(header.account_flags)?;
+    let (head, count) = (header.head, header.count);
+    if !extended {
+        let requests: Vec<Request> = copy_queue_items(item_bytes, head, count)?;
+        let requests = requests
+            .into_iter()
+            .map(|request| (request, Zeroable::zeroed()))
+            .collect();
+        return Ok((header, requests));
+    }
+    let capacity = extended_request_queue_capacity(item_bytes.len());
+    let (request_bytes, ext_bytes) = item_bytes.split_at(capacity * size_of::<Request>());
+    let ext_bytes = &ext_bytes[..capacity * size_of::<RequestExt>()];
+    let requests: Vec<Request> = copy_queue_items(request_bytes, head, count)?;
+    let exts: Vec<RequestExt> = copy_queue_items(ext_bytes, head, count)?;
+    Ok((header, requests.into_iter().zip(exts).collect()))
+}
+
+/// Copies the events out of an event queue account's data, with its padding stripped,
+/// oldest first.
+pub fn read_event_queue(words: &[u64]) -> DexResult<(EventQueueHeader, Vec<Event>)> {
+    read_queue(words, AccountFlag::EventQueue)
+}
+
 pub enum State {}
 
 fn gen_vault_signer_seeds<'a>(nonce: &'a u64, market: &'a Pubkey) -> [&'a [u8]; 2] {
@@ -1113,6 +2476,38 @@ pub fn gen_vault_signer_key(
     Ok(Pubkey::default())
 }
 
+fn gen_open_orders_seeds<'a>(
+    market: &'a Pubkey,
+    owner: &'a Pubkey,
+    index: &'a u64,
+    nonce: &'a u64,
+) -> [&'a [u8]; 4] {
+    [
+        market.as_ref(),
+        owner.as_ref(),
+        bytes_of(index),
+        bytes_of(nonce),
+    ]
+}
+
+/// Returns the OpenOrders address for `(market, owner, index)` along with the nonce used to
+/// derive it, which is always the smallest nonce that yields a valid program address.
+pub fn find_open_orders_address(
+    market: &Pubkey,
+    owner: &Pubkey,
+    index: u64,
+    program_id: &Pubkey,
+) -> (Pubkey, u64) {
+    let mut nonce = 0;
+    loop {
+        let seeds = gen_open_orders_seeds(market, owner, &index, &nonce);
+        if let Ok(address) = Pubkey::create_program_address(&seeds, program_id) {
+            return (address, nonce);
+        }
+        nonce += 1;
+    }
+}
+
 #[cfg(not(feature = "fuzz"))]
 #[cfg(feature = "program")]
 fn invoke_spl_token(
@@ -1151,6 +2546,68 @@ fn invoke_spl_token(
     Ok(())
 }
 
+#[cfg(feature = "program")]
+fn settle_free_funds<'a, 'b: 'a>(
+    market: &mut MarketState,
+    open_orders: &mut OpenOrders,
+    coin_vault: account_parser::CoinVault<'a, 'b>,
+    pc_vault: account_parser::PcVault<'a, 'b>,
+    coin_wallet: account_parser::CoinWallet<'a, 'b>,
+    pc_wallet: account_parser::PcWallet<'a, 'b>,
+    vault_signer: account_parser::VaultSigner<'a, 'b>,
+    spl_token_program: account_parser::SplTokenProgram<'a, 'b>,
+) -> DexResult {
+    let native_coin_amount = open_orders.native_coin_free;
+    let native_pc_amount = open_orders.native_pc_free;
+
+    market.coin_deposits_total -= native_coin_amount;
+    market.pc_deposits_total -= native_pc_amount;
+
+    open_orders.native_coin_free = 0;
+    open_orders.native_pc_free = 0;
+
+    open_orders.native_coin_total = open_orders
+        .native_coin_total
+        .checked_sub(native_coin_amount)
+        .unwrap();
+    open_orders.native_pc_total = open_orders
+        .native_pc_total
+        .checked_sub(native_pc_amount)
+        .unwrap();
+
+    let token_infos: [(
+        u64,
+        account_parser::TokenAccount,
+        account_parser::TokenAccount,
+    ); 2] = [
+        (
+            native_coin_amount,
+            coin_wallet.token_account(),
+            coin_vault.token_account(),
+        ),
+        (
+            native_pc_amount,
+            pc_wallet.token_account(),
+            pc_vault.token_account(),
+        ),
+    ];
+
+    let market_pubkey = market.pubkey();
+    let vault_signer_seeds = gen_vault_signer_seeds(&market.vault_signer_nonce, &market_pubkey);
+
+    for &(token_amount, wallet_account, vault) in token_infos.iter() {
+        send_from_vault(
+            token_amount,
+            wallet_account,
+            vault,
+            spl_token_program,
+            vault_signer,
+            &vault_signer_seeds,
+        )?;
+    }
+    Ok(())
+}
+
 #[cfg(not(feature = "client"))]
 fn send_from_vault<'a, 'b: 'a>(
     native_amount: u64,
@@ -1260,22 +2717,40 @@ pub mod account_parser {
         Ok(())
     });
 
-    declare_validated_account_wrapper!(SignerAccount, |account: &AccountInfo| {
+    declare_validated_account_wrapper!(SigningProgramDisableAuthority, |account: &AccountInfo| {
         check_assert!(account.is_signer)?;
+        check_assert!(account.key == &disable_authority::ID)?;
         Ok(())
     });
 
-    declare_validated_account_wrapper!(SigningFeeSweeper, |account: &AccountInfo| {
+    declare_validated_account_wrapper!(SignerAccount, |account: &AccountInfo| {
         check_assert!(account.is_signer)?;
-        check_assert_eq!(account.key, &fee_sweeper::ID)?;
         Ok(())
     });
 
-    declare_validated_account_wrapper!(SigningDisableAuthority, |account: &AccountInfo| {
-        check_assert!(account.is_signer)?;
-        check_assert_eq!(account.key, &disable_authority::ID)?;
-        Ok(())
-    });
+    declare_validated_account_wrapper!(
+        SigningFeeSweeper,
+        |account: &AccountInfo, market_authority: &[u64; 4]| {
+            check_assert!(account.is_signer)?;
+            check_assert!(
+                account.key == &fee_sweeper::ID || is_market_authority(market_authority, account.key)
+            )?;
+            Ok(())
+        },
+        market_authority: &[u64; 4]
+    );
+
+    declare_validated_account_wrapper!(
+        SigningDisableAuthority,
+        |account: &AccountInfo, market_authority: &[u64; 4]| {
+            check_assert!(account.is_signer)?;
+            check_assert!(
+                account.key == &disable_authority::ID || is_market_authority(market_authority, account.key)
+            )?;
+            Ok(())
+        },
+        market_authority: &[u64; 4]
+    );
 
     declare_validated_token_account_wrapper!(
         CoinVault,
@@ -1355,6 +2830,7 @@ pub mod account_parser {
         serum_dex_accounts: &'a [AccountInfo<'b>; 5],
         pub coin_vault_and_mint: TokenAccountAndMint<'a, 'b>,
         pub pc_vault_and_mint: TokenAccountAndMint<'a, 'b>,
+        trigger_q: Option<&'a AccountInfo<'b>>,
     }
 
     impl<'a, 'b: 'a> InitializeMarketArgs<'a, 'b> {
@@ -1363,12 +2839,15 @@ pub mod account_parser {
             instruction: &'a InitializeMarketInstruction,
             accounts: &'a [AccountInfo<'b>],
         ) -> DexResult<Self> {
-            check_assert_eq!(accounts.len(), 9)?;
-            let accounts = array_ref![accounts, 0, 9];
+            check_assert!(accounts.len() == 9 || accounts.len() == 10)?;
+            let (accounts, unchecked_trigger_q) = array_refs![accounts, 9; .. ;];
             let (unchecked_serum_dex_accounts, unchecked_vaults, unchecked_mints) =
                 array_refs![accounts, 5, 2, 2];
             let mut checked_vaults = [None, None];
-            for account in unchecked_serum_dex_accounts {
+            for account in unchecked_serum_dex_accounts
+                .iter()
+                .chain(unchecked_trigger_q.iter())
+            {
                 check_assert_eq!(account.owner, program_id)?;
                 let data = account.try_borrow_data()?;
                 check_assert_eq!(data.len() % 8, 4)?;
@@ -1379,6 +2858,7 @@ pub mod account_parser {
                 check_assert_eq!(*padding7, [0u8; 7])?;
             }
             let serum_dex_accounts = unchecked_serum_dex_accounts;
+            let trigger_q = unchecked_trigger_q.first();
             let vault_owner_key_bytes = gen_vault_signer_key(
                 instruction.vault_signer_nonce,
                 serum_dex_accounts[0].key,
@@ -1411,6 +2891,7 @@ pub mod account_parser {
                 serum_dex_accounts,
                 coin_vault_and_mint,
                 pc_vault_and_mint,
+                trigger_q,
             })
         }
 
@@ -1437,12 +2918,16 @@ pub mod account_parser {
         pub fn get_asks(&self) -> &'a AccountInfo<'b> {
             &self.serum_dex_accounts[4]
         }
+
+        pub fn get_trigger_q(&self) -> Option<&'a AccountInfo<'b>> {
+            self.trigger_q
+        }
     }
 
     pub struct NewOrderArgs<'a, 'b: 'a> {
-        pub instruction: &'a NewOrderInstructionV2,
-        pub market: &'a mut MarketState,
-        pub open_orders: &'a mut OpenOrders,
+        pub instruction: &'a NewOrderInstructionV3,
+        pub market: MarketStateMut<'a>,
+        pub open_orders: OpenOrdersMut<'a>,
         pub open_orders_address: &'a [u64; 4],
         pub owner: SignerAccount<'a, 'b>,
         pub req_q: RequestQueue<'a>,
@@ -1455,7 +2940,7 @@ pub mod account_parser {
     impl<'a, 'b: 'a> NewOrderArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
             program_id: &'a Pubkey,
-            instruction: &'a NewOrderInstructionV2,
+            instruction: &'a NewOrderInstructionV3,
             accounts: &'a [AccountInfo<'b>],
             f: impl FnOnce(NewOrderArgs) -> DexResult<T>,
         ) -> DexResult<T> {
@@ -1464,6 +2949,22 @@ pub mod account_parser {
                 &'a [AccountInfo<'b>; 9],
                 &'a [AccountInfo<'b>],
             ) = array_refs![accounts, 9; .. ;];
+            Self::with_fixed_accounts(
+                program_id,
+                instruction,
+                fixed_accounts,
+                fee_discount_account,
+                f,
+            )
+        }
+
+        fn with_fixed_accounts<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a NewOrderInstructionV3,
+            fixed_accounts: &'a [AccountInfo<'b>; 9],
+            fee_discount_account: &'a [AccountInfo<'b>],
+            f: impl FnOnce(NewOrderArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
             let &[
                 ref market_acc,
                 ref open_orders_acc,
@@ -1481,17 +2982,18 @@ pub mod account_parser {
                 _ => check_unreachable!()?,
             };
 
-            let mut market: RefMut<'a, MarketState> = MarketState::load(market_acc, program_id)?;
+            let mut market: MarketStateRefMut<'a> = MarketState::load(market_acc, program_id)?;
             let rent = {
                 let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
                 Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
             };
             let owner = SignerAccount::new(owner_acc)?;
-            let fee_tier =
-                market.load_fee_tier(&owner.inner().key.to_aligned_bytes(), srm_or_msrm_account)?;
-            let mut open_orders = market.load_orders_mut(
+            let fee_tier = market
+                .ext()
+                .load_fee_tier(&owner.inner().key.to_aligned_bytes(), srm_or_msrm_account)?;
+            let mut open_orders = market.load_orders_mut_as_trader(
                 open_orders_acc,
-                Some(owner.inner()),
+                owner.inner(),
                 program_id,
                 Some(rent),
             )?;
@@ -1509,8 +3011,8 @@ pub mod account_parser {
             let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
             let args = NewOrderArgs {
                 instruction,
-                market: market.deref_mut(),
-                open_orders: open_orders.deref_mut(),
+                market: market.as_market_mut(),
+                open_orders: open_orders.as_orders_mut(),
                 open_orders_address,
                 owner,
                 req_q,
@@ -1524,42 +3026,282 @@ pub mod account_parser {
         }
     }
 
-    pub struct MatchOrdersArgs<'a> {
-        pub limit: u16,
-        pub order_book_state: OrderBookState<'a>,
-        pub req_q: RequestQueue<'a>,
-        pub event_q: EventQueue<'a>,
+    pub struct NewTriggerOrderArgs<'a, 'b: 'a> {
+        pub trigger_price: NonZeroU64,
+        pub trigger_condition: TriggerCondition,
+        pub trigger_q: TriggerQueue<'a>,
+        pub new_order: NewOrderArgs<'a, 'b>,
     }
-    impl<'a> MatchOrdersArgs<'a> {
-        pub fn with_parsed_args<'b, T>(
+    impl<'a, 'b: 'a> NewTriggerOrderArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
             program_id: &'a Pubkey,
+            instruction: &'a NewTriggerOrderInstruction,
             accounts: &'a [AccountInfo<'b>],
-            limit: u16,
-            f: impl FnOnce(MatchOrdersArgs) -> DexResult<T>,
+            f: impl FnOnce(NewTriggerOrderArgs) -> DexResult<T>,
         ) -> DexResult<T> {
-            check_assert!(accounts.len() >= 5)?;
-            #[rustfmt::skip]
-            let &[
-                ref market_acc,
-                ref req_q_acc,
-                ref event_q_acc,
-                ref bids_acc,
-                ref asks_acc
-            ] = array_ref![accounts, 0, 5];
-            let mut market = MarketState::load(market_acc, program_id).or(check_unreachable!())?;
-            let req_q = market
+            check_assert!(accounts.len() == 10 || accounts.len() == 11)?;
+            let (new_order_accounts, &[ref trigger_q_acc], fee_discount_account) =
+                array_refs![accounts, 9, 1; .. ;];
+            let order = instruction.order.clone().add_expiry(None);
+            NewOrderArgs::with_fixed_accounts(
+                program_id,
+                &order,
+                new_order_accounts,
+                fee_discount_account,
+                |new_order| {
+                    let trigger_q = new_order
+                        .market
+                        .ext()
+                        .load_trigger_queue_mut(trigger_q_acc)?;
+                    let args = NewTriggerOrderArgs {
+                        trigger_price: instruction.trigger_price,
+                        trigger_condition: instruction.trigger_condition,
+                        trigger_q,
+                        new_order,
+                    };
+                    f(args)
+                },
+            )
+        }
+    }
+
+    pub struct NewOrdersBatchArgs<'a, 'b: 'a> {
+        pub instructions: &'a [NewOrderInstructionV3],
+        pub market: MarketStateMut<'a>,
+        pub open_orders: OpenOrdersMut<'a>,
+        pub open_orders_address: &'a [u64; 4],
+        pub owner: SignerAccount<'a, 'b>,
+        pub req_q: RequestQueue<'a>,
+        pub coin_payer: TokenAccount<'a, 'b>,
+        pub pc_payer: TokenAccount<'a, 'b>,
+        pub coin_vault: CoinVault<'a, 'b>,
+        pub pc_vault: PcVault<'a, 'b>,
+        pub spl_token_program: SplTokenProgram<'a, 'b>,
+        pub fee_tier: FeeTier,
+    }
+    impl<'a, 'b: 'a> NewOrdersBatchArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instructions: &'a [NewOrderInstructionV3],
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(NewOrdersBatchArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() == 10 || accounts.len() == 11)?;
+            let (fixed_accounts, fee_discount_account): (
+                &'a [AccountInfo<'b>; 10],
+                &'a [AccountInfo<'b>],
+            ) = array_refs![accounts, 10; .. ;];
+            let &[
+                ref market_acc,
+                ref open_orders_acc,
+                ref req_q_acc,
+                ref coin_payer_acc,
+                ref pc_payer_acc,
+                ref owner_acc,
+                ref coin_vault_acc,
+                ref pc_vault_acc,
+                ref spl_token_program_acc,
+                ref rent_sysvar_acc,
+            ]: &'a [AccountInfo<'b>; 10] = fixed_accounts;
+            let srm_or_msrm_account = match fee_discount_account {
+                &[] => None,
+                &[ref account] => Some(TokenAccount::new(account)?),
+                _ => check_unreachable!()?,
+            };
+
+            let mut market: MarketStateRefMut<'a> = MarketState::load(market_acc, program_id)?;
+            let rent = {
+                let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
+                Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
+            };
+            let owner = SignerAccount::new(owner_acc)?;
+            let fee_tier = market
+                .ext()
+                .load_fee_tier(&owner.inner().key.to_aligned_bytes(), srm_or_msrm_account)?;
+            let mut open_orders = market.load_orders_mut_as_trader(
+                open_orders_acc,
+                owner.inner(),
+                program_id,
+                Some(rent),
+            )?;
+            let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
+            let req_q = market.load_request_queue_mut(req_q_acc)?;
+
+            let coin_payer = TokenAccount::new(coin_payer_acc)?;
+            market.check_coin_payer(coin_payer)?;
+            let pc_payer = TokenAccount::new(pc_payer_acc)?;
+            market.check_pc_payer(pc_payer)?;
+            let coin_vault = CoinVault::from_account(coin_vault_acc, &market)?;
+            let pc_vault = PcVault::from_account(pc_vault_acc, &market)?;
+            market.check_enabled()?;
+            let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
+            let args = NewOrdersBatchArgs {
+                instructions,
+                market: market.as_market_mut(),
+                open_orders: open_orders.as_orders_mut(),
+                open_orders_address,
+                owner,
+                req_q,
+                coin_payer,
+                pc_payer,
+                coin_vault,
+                pc_vault,
+                spl_token_program,
+                fee_tier,
+            };
+            f(args)
+        }
+    }
+
+    pub struct NewIcebergOrderArgs<'a, 'b: 'a> {
+        pub display_qty: NonZeroU64,
+        pub new_order: NewOrderArgs<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> NewIcebergOrderArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a NewIcebergOrderInstruction,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(NewIcebergOrderArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            NewOrderArgs::with_parsed_args(program_id, &instruction.order, accounts, |new_order| {
+                let args = NewIcebergOrderArgs {
+                    display_qty: instruction.display_qty,
+                    new_order,
+                };
+                f(args)
+            })
+        }
+    }
+
+    pub struct ReplaceOrderArgs<'a, 'b: 'a> {
+        pub replaces: OrderToReplace,
+        pub orders: RefMut<'a, Slab>,
+        pub exts: Option<RefMut<'a, OrderExtTable>>,
+        pub new_order: NewOrderArgs<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> ReplaceOrderArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a ReplaceOrderInstruction,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(ReplaceOrderArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() == 10 || accounts.len() == 11)?;
+            let (new_order_accounts, &[ref order_book_acc], fee_discount_account) =
+                array_refs![accounts, 9, 1; .. ;];
+            NewOrderArgs::with_fixed_accounts(
+                program_id,
+                &instruction.order,
+                new_order_accounts,
+                fee_discount_account,
+                |new_order| {
+                    let (orders, exts) = match instruction.order.side {
+                        Side::Bid => new_order.market.load_bids_mut(order_book_acc)?,
+                        Side::Ask => new_order.market.load_asks_mut(order_book_acc)?,
+                    };
+                    let args = ReplaceOrderArgs {
+                        replaces: instruction.replaces,
+                        orders,
+                        exts,
+                        new_order,
+                    };
+                    f(args)
+                },
+            )
+        }
+    }
+
+    pub struct MatchOrdersArgs<'a> {
+        pub limit: u16,
+        pub order_book_state: OrderBookState<'a>,
+        pub req_q: RequestQueue<'a>,
+        pub event_q: EventQueue<'a>,
+        pub trigger_q: Option<TriggerQueue<'a>>,
+    }
+    impl<'a> MatchOrdersArgs<'a> {
+        pub fn with_parsed_args<'b, T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            limit: u16,
+            f: impl FnOnce(MatchOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() >= 5)?;
+            #[rustfmt::skip]
+            let (&[
+                ref market_acc,
+                ref req_q_acc,
+                ref event_q_acc,
+                ref bids_acc,
+                ref asks_acc
+            ], remaining_accounts) = array_refs![accounts, 5; .. ;];
+            let mut market = MarketState::load(market_acc, program_id).or(check_unreachable!())?;
+            market.check_not_paused()?;
+            let req_q = market
                 .load_request_queue_mut(req_q_acc)
                 .or(check_unreachable!())?;
             let event_q = market
                 .load_event_queue_mut(event_q_acc)
                 .or(check_unreachable!())?;
-            let mut bids = market.load_bids_mut(bids_acc).or(check_unreachable!())?;
-            let mut asks = market.load_asks_mut(asks_acc).or(check_unreachable!())?;
+            let (mut bids, mut bid_exts) =
+                market.load_bids_mut(bids_acc).or(check_unreachable!())?;
+            let (mut asks, mut ask_exts) =
+                market.load_asks_mut(asks_acc).or(check_unreachable!())?;
+            let trigger_q = if market.ext().has_trigger_queue() {
+                let trigger_q_acc = remaining_accounts
+                    .iter()
+                    .find(|account| account.key.to_aligned_bytes() == market.ext().trigger_q)
+                    .ok_or(DexErrorCode::WrongTriggerQueueAccount)?;
+                Some(market.ext().load_trigger_queue_mut(trigger_q_acc)?)
+            } else {
+                None
+            };
+            let clock = match remaining_accounts
+                .iter()
+                .find(|account| Clock::check_id(account.key))
+            {
+                Some(clock_acc) => {
+                    Some(Clock::from_account_info(clock_acc).or(check_unreachable!())?)
+                }
+                None => None,
+            };
+            let trade_log = if market.ext().has_trade_log() {
+                let trade_log_acc = remaining_accounts
+                    .iter()
+                    .find(|account| account.key.to_aligned_bytes() == market.ext().trade_log)
+                    .ok_or(DexErrorCode::WrongTradeLogAccount)?;
+                Some(market.ext().load_trade_log_mut(trade_log_acc)?)
+            } else {
+                None
+            };
+            let mut stats = if market.ext().has_stats() {
+                let stats_acc = remaining_accounts
+                    .iter()
+                    .find(|account| account.key.to_aligned_bytes() == market.ext().stats)
+                    .ok_or(DexErrorCode::WrongMarketStatsAccount)?;
+                Some(
+                    market
+                        .ext()
+                        .load_stats_mut(stats_acc, &market.own_address)?,
+                )
+            } else {
+                None
+            };
+            // Orders of extended markets may expire, so they can't be matched without the time,
+            // and trades are recorded with it.
+            if market.is_extended() && clock.is_none() {
+                Err(DexErrorCode::ClockNotProvided)?
+            }
 
             let order_book_state = OrderBookState {
                 bids: bids.deref_mut(),
                 asks: asks.deref_mut(),
-                market_state: market.deref_mut(),
+                bid_exts: bid_exts.as_deref_mut(),
+                ask_exts: ask_exts.as_deref_mut(),
+                market_state: market.as_market_mut(),
+                clock,
+                trade_log,
+                stats: stats.as_deref_mut(),
             };
 
             let args = MatchOrdersArgs {
@@ -1567,6 +3309,7 @@ pub mod account_parser {
                 order_book_state,
                 req_q,
                 event_q,
+                trigger_q,
             };
             f(args)
         }
@@ -1576,7 +3319,7 @@ pub mod account_parser {
         pub limit: u16,
         pub program_id: &'a Pubkey,
         pub open_orders_accounts: &'a [AccountInfo<'b>],
-        pub market: &'a mut MarketState,
+        pub market: MarketStateMut<'a>,
         pub event_q: EventQueue<'a>,
     }
     impl<'a, 'b: 'a> ConsumeEventsArgs<'a, 'b> {
@@ -1601,7 +3344,7 @@ pub mod account_parser {
                 limit,
                 program_id,
                 open_orders_accounts,
-                market: market.deref_mut(),
+                market: market.as_market_mut(),
                 event_q,
             };
             f(args)
@@ -1610,7 +3353,7 @@ pub mod account_parser {
 
     pub struct CancelOrderArgs<'a, 'b: 'a> {
         pub instruction: &'a CancelOrderInstruction,
-        pub open_orders: &'a mut OpenOrders,
+        pub open_orders: OpenOrdersMut<'a>,
         pub open_orders_address: &'a [u64; 4],
         pub req_q: RequestQueue<'a>,
         pub orders_owner: SignerAccount<'a, 'b>,
@@ -1630,15 +3373,20 @@ pub mod account_parser {
                 ref req_q_acc,
                 ref owner_acc
             ] = array_ref![accounts, 0, 4];
-            let market: RefMut<'a, MarketState> = MarketState::load(market_acc, program_id)?;
+            let market: MarketStateRefMut<'a> = MarketState::load(market_acc, program_id)?;
+            market.check_not_paused()?;
             let owner = SignerAccount::new(owner_acc)?;
-            let mut open_orders =
-                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+            let mut open_orders = market.load_orders_mut_as_trader(
+                open_orders_acc,
+                owner.inner(),
+                program_id,
+                None,
+            )?;
             let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
             let req_q = market.load_request_queue_mut(req_q_acc)?;
             let args = CancelOrderArgs {
                 instruction,
-                open_orders: open_orders.deref_mut(),
+                open_orders: open_orders.as_orders_mut(),
                 open_orders_address,
                 req_q,
                 orders_owner: owner,
@@ -1649,7 +3397,7 @@ pub mod account_parser {
 
     pub struct CancelOrderByClientIdArgs<'a, 'b: 'a> {
         pub client_order_id: NonZeroU64,
-        pub open_orders: &'a mut OpenOrders,
+        pub open_orders: OpenOrdersMut<'a>,
         pub open_orders_address: &'a [u64; 4],
         pub req_q: RequestQueue<'a>,
         pub orders_owner: SignerAccount<'a, 'b>,
@@ -1670,15 +3418,64 @@ pub mod account_parser {
                 ref req_q_acc,
                 ref owner_acc
             ] = array_ref![accounts, 0, 4];
-            let market: RefMut<'a, MarketState> = MarketState::load(market_acc, program_id)?;
+            let market: MarketStateRefMut<'a> = MarketState::load(market_acc, program_id)?;
+            market.check_not_paused()?;
             let owner = SignerAccount::new(owner_acc)?;
-            let mut open_orders =
-                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+            let mut open_orders = market.load_orders_mut_as_trader(
+                open_orders_acc,
+                owner.inner(),
+                program_id,
+                None,
+            )?;
             let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
             let req_q = market.load_request_queue_mut(req_q_acc)?;
             let args = CancelOrderByClientIdArgs {
                 client_order_id,
-                open_orders: open_orders.deref_mut(),
+                open_orders: open_orders.as_orders_mut(),
+                open_orders_address,
+                req_q,
+                orders_owner: owner,
+            };
+            f(args)
+        }
+    }
+
+    pub struct CancelAllOrdersArgs<'a, 'b: 'a> {
+        pub side: Option<Side>,
+        pub open_orders: OpenOrdersMut<'a>,
+        pub open_orders_address: &'a [u64; 4],
+        pub req_q: RequestQueue<'a>,
+        pub orders_owner: SignerAccount<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> CancelAllOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            side: Option<Side>,
+            f: impl FnOnce(CancelAllOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 4)?;
+            #[rustfmt::skip]
+            let &[
+                ref market_acc,
+                ref open_orders_acc,
+                ref req_q_acc,
+                ref owner_acc
+            ] = array_ref![accounts, 0, 4];
+            let market: MarketStateRefMut<'a> = MarketState::load(market_acc, program_id)?;
+            market.check_not_paused()?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let mut open_orders = market.load_orders_mut_as_trader(
+                open_orders_acc,
+                owner.inner(),
+                program_id,
+                None,
+            )?;
+            let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
+            let req_q = market.load_request_queue_mut(req_q_acc)?;
+            let args = CancelAllOrdersArgs {
+                side,
+                open_orders: open_orders.as_orders_mut(),
                 open_orders_address,
                 req_q,
                 orders_owner: owner,
@@ -1689,7 +3486,7 @@ pub mod account_parser {
 
     pub struct SettleFundsArgs<'a, 'b: 'a> {
         pub market: &'a mut MarketState,
-        pub open_orders: &'a mut OpenOrders,
+        pub open_orders: OpenOrdersMut<'a>,
         pub coin_vault: CoinVault<'a, 'b>,
         pub pc_vault: PcVault<'a, 'b>,
         pub coin_wallet: CoinWallet<'a, 'b>,
@@ -1697,6 +3494,7 @@ pub mod account_parser {
         pub vault_signer: VaultSigner<'a, 'b>,
         pub spl_token_program: SplTokenProgram<'a, 'b>,
         pub referrer: Option<PcWallet<'a, 'b>>,
+        pub signed_by_owner: bool,
     }
     impl<'a, 'b: 'a> SettleFundsArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
@@ -1719,7 +3517,6 @@ pub mod account_parser {
             ], remaining_accounts) = array_refs![accounts, 9; ..;];
             let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
             let mut market = MarketState::load(market_acc, program_id)?;
-            let owner = SignerAccount::new(owner_acc).or(check_unreachable!())?;
 
             let coin_vault =
                 CoinVault::from_account(coin_vault_acc, &market).or(check_unreachable!())?;
@@ -1740,11 +3537,18 @@ pub mod account_parser {
             let vault_signer = VaultSigner::new(vault_signer_acc, &market, program_id)?;
 
             let mut open_orders =
-                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+                market.load_orders_mut(open_orders_acc, None, program_id, None)?;
+            let open_orders = open_orders.as_orders_mut();
+            let signed_by_owner =
+                owner_acc.is_signer && open_orders.owner == owner_acc.key.to_aligned_bytes();
+            if !signed_by_owner {
+                open_orders.check_settle_wallets(coin_wallet_acc, pc_wallet_acc)?;
+                check_assert!(referrer.is_none())?;
+            }
 
             let args = SettleFundsArgs {
                 market: market.deref_mut(),
-                open_orders: open_orders.deref_mut(),
+                open_orders,
                 coin_vault,
                 pc_vault,
                 coin_wallet,
@@ -1752,95 +3556,594 @@ pub mod account_parser {
                 vault_signer,
                 spl_token_program,
                 referrer,
+                signed_by_owner,
             };
             f(args)
         }
     }
 
-    pub struct DisableMarketArgs<'a, 'b: 'a> {
-        pub market: &'a mut MarketState,
-        pub authorization: SigningDisableAuthority<'a, 'b>,
+    pub struct SetAutoSettleArgs<'a, 'b: 'a> {
+        pub open_orders: OpenOrdersMut<'a>,
+        pub wallets: Option<(CoinWallet<'a, 'b>, PcWallet<'a, 'b>)>,
     }
-    impl<'a, 'b: 'a> DisableMarketArgs<'a, 'b> {
+    impl<'a, 'b: 'a> SetAutoSettleArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
             program_id: &'a Pubkey,
+            enable: bool,
             accounts: &'a [AccountInfo<'b>],
-            f: impl FnOnce(DisableMarketArgs) -> DexResult<T>,
+            f: impl FnOnce(SetAutoSettleArgs) -> DexResult<T>,
         ) -> DexResult<T> {
-            check_assert_eq!(accounts.len(), 2)?;
-            let &[ref market_acc, ref signer_acc] = array_ref![accounts, 0, 2];
-            let mut market = MarketState::load(market_acc, program_id)?;
-            let authorization = SigningDisableAuthority::new(signer_acc)?;
+            check_assert!(accounts.len() >= 3)?;
+            #[rustfmt::skip]
+            let (&[
+                ref open_orders_acc,
+                ref owner_acc,
+                ref market_acc,
+            ], remaining_accounts) = array_refs![accounts, 3; ..;];
+            let market = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let wallets = match (enable, remaining_accounts) {
+                (false, &[]) => None,
+                (true, &[ref coin_wallet_acc, ref pc_wallet_acc]) => Some((
+                    CoinWallet::from_account(coin_wallet_acc, &market)?,
+                    PcWallet::from_account(pc_wallet_acc, &market)?,
+                )),
+                _ => check_unreachable!()?,
+            };
+            let mut open_orders =
+                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
 
-            let args = DisableMarketArgs {
-                market: market.deref_mut(),
-                authorization,
+            let args = SetAutoSettleArgs {
+                open_orders: open_orders.as_orders_mut(),
+                wallets,
             };
             f(args)
         }
     }
 
-    pub struct SweepFeesArgs<'a, 'b: 'a> {
+    pub struct SettleForArgs<'a, 'b: 'a> {
         pub market: &'a mut MarketState,
+        pub open_orders: OpenOrdersMut<'a>,
+        pub coin_vault: CoinVault<'a, 'b>,
         pub pc_vault: PcVault<'a, 'b>,
-        pub fee_receiver: PcWallet<'a, 'b>,
+        pub coin_wallet: CoinWallet<'a, 'b>,
+        pub pc_wallet: PcWallet<'a, 'b>,
         pub vault_signer: VaultSigner<'a, 'b>,
         pub spl_token_program: SplTokenProgram<'a, 'b>,
-        pub authorization: SigningFeeSweeper<'a, 'b>,
     }
-    impl<'a, 'b: 'a> SweepFeesArgs<'a, 'b> {
+    impl<'a, 'b: 'a> SettleForArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
             program_id: &'a Pubkey,
             accounts: &'a [AccountInfo<'b>],
-            f: impl FnOnce(SweepFeesArgs) -> DexResult<T>,
+            f: impl FnOnce(SettleForArgs) -> DexResult<T>,
         ) -> DexResult<T> {
-            check_assert_eq!(accounts.len(), 6)?;
+            check_assert_eq!(accounts.len(), 8)?;
             #[rustfmt::skip]
             let &[
                 ref market_acc,
+                ref open_orders_acc,
+                ref coin_vault_acc,
                 ref pc_vault_acc,
-                ref sweep_authority_acc,
+                ref coin_wallet_acc,
                 ref pc_wallet_acc,
                 ref vault_signer_acc,
-                ref spl_token_program
-            ] = array_ref![accounts, 0, 6];
-
+                ref spl_token_program_acc,
+            ] = array_ref![accounts, 0, 8];
+            let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
             let mut market = MarketState::load(market_acc, program_id)?;
-            let pc_vault = PcVault::from_account(pc_vault_acc, &market)?;
-            let fee_receiver = PcWallet::from_account(pc_wallet_acc, &market)?;
+
+            let coin_vault =
+                CoinVault::from_account(coin_vault_acc, &market).or(check_unreachable!())?;
+            let pc_vault = PcVault::from_account(pc_vault_acc, &market).or(check_unreachable!())?;
+            let coin_wallet =
+                CoinWallet::from_account(coin_wallet_acc, &market).or(check_unreachable!())?;
+            let pc_wallet =
+                PcWallet::from_account(pc_wallet_acc, &market).or(check_unreachable!())?;
+
             let vault_signer = VaultSigner::new(vault_signer_acc, &market, program_id)?;
-            let spl_token_program = SplTokenProgram::new(spl_token_program)?;
-            let authorization = SigningFeeSweeper::new(sweep_authority_acc)?;
 
-            let args = SweepFeesArgs {
+            let mut open_orders =
+                market.load_orders_mut(open_orders_acc, None, program_id, None)?;
+            let open_orders = open_orders.as_orders_mut();
+            open_orders.check_settle_wallets(coin_wallet_acc, pc_wallet_acc)?;
+
+            let args = SettleForArgs {
                 market: market.deref_mut(),
+                open_orders,
+                coin_vault,
                 pc_vault,
-                fee_receiver,
+                coin_wallet,
+                pc_wallet,
                 vault_signer,
                 spl_token_program,
-                authorization,
             };
             f(args)
         }
     }
-}
 
-#[inline]
-fn remove_slop<T: Pod>(bytes: &[u8]) -> &[T] {
-    let slop = bytes.len() % size_of::<T>();
-    let new_len = bytes.len() - slop;
-    cast_slice(&bytes[..new_len])
-}
+    pub struct CreateOpenOrdersArgs<'a, 'b: 'a> {
+        pub program_id: &'a Pubkey,
+        pub index: u64,
+        pub nonce: u64,
+        pub delegate: Option<[u64; 4]>,
+        pub market: &'a MarketState,
+        pub open_orders_acc: &'a AccountInfo<'b>,
+        pub owner: SignerAccount<'a, 'b>,
+        pub rent: Rent,
+        pub system_program: &'a AccountInfo<'b>,
+    }
+    impl<'a, 'b: 'a> CreateOpenOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a CreateOpenOrdersInstruction,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(CreateOpenOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 5)?;
+            #[rustfmt::skip]
+            let &[
+                ref open_orders_acc,
+                ref owner_acc,
+                ref market_acc,
+                ref rent_sysvar_acc,
+                ref system_program_acc,
+            ] = array_ref![accounts, 0, 5];
+            let market = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let rent = {
+                let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
+                Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
+            };
+            check_assert!(solana_sdk::system_program::check_id(system_program_acc.key))?;
+            let (address, nonce) = find_open_orders_address(
+                market_acc.key,
+                owner_acc.key,
+                instruction.index,
+                program_id,
+            );
+            check_assert_eq!(open_orders_acc.key, &address)
+                .map_err(|_| DexErrorCode::WrongOrdersAccount)?;
 
-#[inline]
-fn remove_slop_mut<T: Pod>(bytes: &mut [u8]) -> &mut [T] {
-    let slop = bytes.len() % size_of::<T>();
-    let new_len = bytes.len() - slop;
-    cast_slice_mut(&mut bytes[..new_len])
-}
+            let args = CreateOpenOrdersArgs {
+                program_id,
+                index: instruction.index,
+                nonce,
+                delegate: instruction.delegate,
+                market: &market,
+                open_orders_acc,
+                owner,
+                rent,
+                system_program: system_program_acc,
+            };
+            f(args)
+        }
+    }
 
-#[cfg_attr(not(feature = "program"), allow(unused))]
-impl State {
+    pub struct InitOpenOrdersArgs<'a> {
+        pub delegate: Option<[u64; 4]>,
+        pub open_orders: OpenOrdersMut<'a>,
+    }
+    impl<'a> InitOpenOrdersArgs<'a> {
+        pub fn with_parsed_args<'b, T>(
+            program_id: &'a Pubkey,
+            delegate: Option<[u64; 4]>,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(InitOpenOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 4)?;
+            #[rustfmt::skip]
+            let &[
+                ref open_orders_acc,
+                ref owner_acc,
+                ref market_acc,
+                ref rent_sysvar_acc,
+            ] = array_ref![accounts, 0, 4];
+            let market = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let rent = {
+                let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
+                Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
+            };
+            let mut open_orders =
+                market.init_orders_mut(open_orders_acc, owner.inner(), program_id, rent)?;
+
+            let args = InitOpenOrdersArgs {
+                delegate,
+                open_orders: open_orders.as_orders_mut(),
+            };
+            f(args)
+        }
+    }
+
+    pub struct MigrateOpenOrdersArgs<'a, 'b: 'a> {
+        pub old_open_orders: OpenOrdersMut<'a>,
+        pub new_open_orders: OpenOrdersMut<'a>,
+        pub old_open_orders_acc: &'a AccountInfo<'b>,
+        pub owner: SignerAccount<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> MigrateOpenOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(MigrateOpenOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 5)?;
+            #[rustfmt::skip]
+            let &[
+                ref old_open_orders_acc,
+                ref new_open_orders_acc,
+                ref owner_acc,
+                ref market_acc,
+                ref rent_sysvar_acc,
+            ] = array_ref![accounts, 0, 5];
+            check_assert!(old_open_orders_acc.key != new_open_orders_acc.key)?;
+            let market = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let rent = {
+                let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
+                Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
+            };
+            let mut old_open_orders = market.load_orders_mut(
+                old_open_orders_acc,
+                Some(owner.inner()),
+                program_id,
+                None,
+            )?;
+            let mut new_open_orders = market.load_orders_mut(
+                new_open_orders_acc,
+                Some(owner.inner()),
+                program_id,
+                Some(rent),
+            )?;
+            let old_open_orders = old_open_orders.as_orders_mut();
+            let new_open_orders = new_open_orders.as_orders_mut();
+            check_assert_eq!(old_open_orders.slot_count(), OPEN_ORDERS_V1_SLOTS)
+                .map_err(|_| DexErrorCode::WrongOrdersAccount)?;
+            check_assert_eq!(new_open_orders.slot_count(), OPEN_ORDERS_V2_SLOTS)
+                .map_err(|_| DexErrorCode::WrongOrdersAccount)?;
+
+            let args = MigrateOpenOrdersArgs {
+                old_open_orders,
+                new_open_orders,
+                old_open_orders_acc,
+                owner,
+            };
+            f(args)
+        }
+    }
+
+    pub struct CloseOpenOrdersArgs<'a, 'b: 'a> {
+        pub open_orders: OpenOrdersMut<'a>,
+        pub open_orders_acc: &'a AccountInfo<'b>,
+        pub destination: &'a AccountInfo<'b>,
+    }
+    impl<'a, 'b: 'a> CloseOpenOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(CloseOpenOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 4)?;
+            #[rustfmt::skip]
+            let &[
+                ref open_orders_acc,
+                ref owner_acc,
+                ref destination_acc,
+                ref market_acc,
+            ] = array_ref![accounts, 0, 4];
+            check_assert!(open_orders_acc.key != destination_acc.key)?;
+            let market = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let mut open_orders =
+                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+
+            let args = CloseOpenOrdersArgs {
+                open_orders: open_orders.as_orders_mut(),
+                open_orders_acc,
+                destination: destination_acc,
+            };
+            f(args)
+        }
+    }
+
+    pub struct DisableMarketArgs<'a, 'b: 'a> {
+        pub market: &'a mut MarketState,
+        pub authorization: SigningDisableAuthority<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> DisableMarketArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(DisableMarketArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() == 2 || accounts.len() == 3)?;
+            let (&[ref market_acc, ref signer_acc], record_acc) = array_refs![accounts, 2; .. ;];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let market_authority = market.load_market_authority(record_acc.first(), program_id)?;
+            let authorization = SigningDisableAuthority::new(signer_acc, &market_authority)?;
+
+            let args = DisableMarketArgs {
+                market: market.deref_mut(),
+                authorization,
+            };
+            f(args)
+        }
+    }
+
+    pub struct SetMarketStatusArgs<'a, 'b: 'a> {
+        pub status: MarketStatus,
+        pub market: &'a mut MarketState,
+        pub authorization: SigningDisableAuthority<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> SetMarketStatusArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            status: MarketStatus,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SetMarketStatusArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() == 2 || accounts.len() == 3)?;
+            let (&[ref market_acc, ref signer_acc], record_acc) = array_refs![accounts, 2; .. ;];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let market_authority = market.load_market_authority(record_acc.first(), program_id)?;
+            let authorization = SigningDisableAuthority::new(signer_acc, &market_authority)?;
+
+            let args = SetMarketStatusArgs {
+                status,
+                market: market.deref_mut(),
+                authorization,
+            };
+            f(args)
+        }
+    }
+
+    pub struct InitTradeLogArgs<'a, 'b: 'a> {
+        pub market: MarketStateMut<'a>,
+        pub trade_log_acc: &'a AccountInfo<'b>,
+        pub authorization: SigningDisableAuthority<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> InitTradeLogArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(InitTradeLogArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 3)?;
+            let &[ref market_acc, ref trade_log_acc, ref signer_acc] = array_ref![accounts, 0, 3];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let market_authority = market.load_market_authority(None, program_id)?;
+            let authorization = SigningDisableAuthority::new(signer_acc, &market_authority)?;
+            if market.ext().has_trade_log() {
+                Err(DexErrorCode::AlreadyInitialized)?
+            }
+            check_assert_eq!(trade_log_acc.owner, program_id)?;
+
+            let args = InitTradeLogArgs {
+                market: market.as_market_mut(),
+                trade_log_acc,
+                authorization,
+            };
+            f(args)
+        }
+    }
+
+    pub struct InitMarketStatsArgs<'a, 'b: 'a> {
+        pub market: MarketStateMut<'a>,
+        pub stats_acc: &'a AccountInfo<'b>,
+        pub authorization: SigningDisableAuthority<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> InitMarketStatsArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(InitMarketStatsArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 3)?;
+            let &[ref market_acc, ref stats_acc, ref signer_acc] = array_ref![accounts, 0, 3];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let market_authority = market.load_market_authority(None, program_id)?;
+            let authorization = SigningDisableAuthority::new(signer_acc, &market_authority)?;
+            if market.ext().has_stats() {
+                Err(DexErrorCode::AlreadyInitialized)?
+            }
+            check_assert_eq!(stats_acc.owner, program_id)?;
+
+            let args = InitMarketStatsArgs {
+                market: market.as_market_mut(),
+                stats_acc,
+                authorization,
+            };
+            f(args)
+        }
+    }
+
+    pub struct UpdateFeeScheduleArgs<'a, 'b: 'a> {
+        pub fee_schedule: FeeSchedule,
+        pub market: MarketStateMut<'a>,
+        pub fee_authority: SignerAccount<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> UpdateFeeScheduleArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            fee_schedule: FeeSchedule,
+            f: impl FnOnce(UpdateFeeScheduleArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 3)?;
+            let &[ref market_acc, ref event_q_acc, ref signer_acc] = array_ref![accounts, 0, 3];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let fee_authority = SignerAccount::new(signer_acc)?;
+            let expected_fee_authority = market.ext().fee_authority;
+            if expected_fee_authority == [0; 4]
+                || fee_authority.inner().key.to_aligned_bytes() != expected_fee_authority
+            {
+                Err(DexErrorCode::WrongSigner)?
+            }
+            if !market.load_event_queue_mut(event_q_acc)?.empty() {
+                Err(DexErrorCode::EventQueueNotEmpty)?
+            }
+
+            let args = UpdateFeeScheduleArgs {
+                fee_schedule,
+                market: market.as_market_mut(),
+                fee_authority,
+            };
+            f(args)
+        }
+    }
+
+    pub struct UpdateMarketParamsArgs<'a, 'b: 'a> {
+        pub instruction: &'a UpdateMarketParamsInstruction,
+        pub market: MarketStateMut<'a>,
+        pub book_is_empty: bool,
+        pub authorization: SigningDisableAuthority<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> UpdateMarketParamsArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            instruction: &'a UpdateMarketParamsInstruction,
+            f: impl FnOnce(UpdateMarketParamsArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() == 5 || accounts.len() == 6)?;
+            #[rustfmt::skip]
+            let (&[
+                ref market_acc,
+                ref req_q_acc,
+                ref bids_acc,
+                ref asks_acc,
+                ref signer_acc,
+            ], optional_acc) = array_refs![accounts, 5; .. ;];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            // markets with the original layout have no trigger queue, but may have a record
+            let (trigger_q_acc, record_acc) = if market.is_extended() {
+                (optional_acc, &[][..])
+            } else {
+                (&[][..], optional_acc)
+            };
+            let market_authority = market.load_market_authority(record_acc.first(), program_id)?;
+            let authorization = SigningDisableAuthority::new(signer_acc, &market_authority)?;
+
+            let trigger_q_is_empty = match (market.ext().has_trigger_queue(), trigger_q_acc) {
+                (_, [trigger_q_acc]) => market.ext().load_trigger_queue_mut(trigger_q_acc)?.empty(),
+                (false, []) => true,
+                _ => Err(DexErrorCode::WrongTriggerQueueAccount)?,
+            };
+            let book_is_empty = trigger_q_is_empty
+                && market.load_request_queue_mut(req_q_acc)?.empty()
+                && market.load_bids_mut(bids_acc)?.0.find_min().is_none()
+                && market.load_asks_mut(asks_acc)?.0.find_min().is_none();
+
+            let args = UpdateMarketParamsArgs {
+                instruction,
+                market: market.as_market_mut(),
+                book_is_empty,
+                authorization,
+            };
+            f(args)
+        }
+    }
+
+    pub struct SetMarketAuthorityArgs<'a, 'b: 'a> {
+        pub market_authority: [u64; 4],
+        pub market: MarketStateMut<'a>,
+        pub record: Option<RefMut<'a, MarketAuthorityRecord>>,
+        pub authorization: SigningProgramDisableAuthority<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> SetMarketAuthorityArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            market_authority: [u64; 4],
+            f: impl FnOnce(SetMarketAuthorityArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() == 2 || accounts.len() == 3)?;
+            let (&[ref market_acc, ref signer_acc], record_acc) = array_refs![accounts, 2; .. ;];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let authorization = SigningProgramDisableAuthority::new(signer_acc)?;
+            let record = match (market.is_extended(), record_acc) {
+                (true, []) => None,
+                (false, [record_acc]) => Some(MarketAuthorityRecord::load_mut(
+                    record_acc,
+                    program_id,
+                    &market.own_address,
+                    true,
+                )?),
+                _ => Err(DexErrorCode::WrongMarketAuthorityRecord)?,
+            };
+
+            let args = SetMarketAuthorityArgs {
+                market_authority,
+                market: market.as_market_mut(),
+                record,
+                authorization,
+            };
+            f(args)
+        }
+    }
+
+    pub struct SweepFeesArgs<'a, 'b: 'a> {
+        pub market: &'a mut MarketState,
+        pub pc_vault: PcVault<'a, 'b>,
+        pub fee_receiver: PcWallet<'a, 'b>,
+        pub vault_signer: VaultSigner<'a, 'b>,
+        pub spl_token_program: SplTokenProgram<'a, 'b>,
+        pub authorization: SigningFeeSweeper<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> SweepFeesArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SweepFeesArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() == 6 || accounts.len() == 7)?;
+            #[rustfmt::skip]
+            let (&[
+                ref market_acc,
+                ref pc_vault_acc,
+                ref sweep_authority_acc,
+                ref pc_wallet_acc,
+                ref vault_signer_acc,
+                ref spl_token_program
+            ], record_acc) = array_refs![accounts, 6; .. ;];
+
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let pc_vault = PcVault::from_account(pc_vault_acc, &market)?;
+            let fee_receiver = PcWallet::from_account(pc_wallet_acc, &market)?;
+            let vault_signer = VaultSigner::new(vault_signer_acc, &market, program_id)?;
+            let spl_token_program = SplTokenProgram::new(spl_token_program)?;
+            let market_authority = market.load_market_authority(record_acc.first(), program_id)?;
+            let authorization = SigningFeeSweeper::new(sweep_authority_acc, &market_authority)?;
+
+            let args = SweepFeesArgs {
+                market: market.deref_mut(),
+                pc_vault,
+                fee_receiver,
+                vault_signer,
+                spl_token_program,
+                authorization,
+            };
+            f(args)
+        }
+    }
+}
+
+#[inline]
+fn remove_slop<T: Pod>(bytes: &[u8]) -> &[T] {
+    let slop = bytes.len() % size_of::<T>();
+    let new_len = bytes.len() - slop;
+    cast_slice(&bytes[..new_len])
+}
+
+#[inline]
+fn remove_slop_mut<T: Pod>(bytes: &mut [u8]) -> &mut [T] {
+    let slop = bytes.len() % size_of::<T>();
+    let new_len = bytes.len() - slop;
+    cast_slice_mut(&mut bytes[..new_len])
+}
+
+#[cfg_attr(not(feature = "program"), allow(unused))]
+impl State {
     #[cfg(feature = "program")]
     pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> DexResult {
         let instruction = MarketInstruction::unpack(input).ok_or(ProgramError::InvalidArgument)?;
@@ -1849,15 +4152,26 @@ impl State {
                 account_parser::InitializeMarketArgs::new(program_id, inner, accounts)?,
             )?,
             MarketInstruction::NewOrder(inner) => {
-                let new_order_v2 = inner.add_self_trade_behavior(SelfTradeBehavior::DecrementTake);
+                let new_order_v3 = inner
+                    .add_self_trade_behavior(SelfTradeBehavior::DecrementTake)
+                    .add_expiry(None);
                 account_parser::NewOrderArgs::with_parsed_args(
                     program_id,
-                    &new_order_v2,
+                    &new_order_v3,
                     accounts,
                     Self::process_new_order,
                 )?
             }
-            MarketInstruction::NewOrderV2(ref inner) => {
+            MarketInstruction::NewOrderV2(inner) => {
+                let new_order_v3 = inner.add_expiry(None);
+                account_parser::NewOrderArgs::with_parsed_args(
+                    program_id,
+                    &new_order_v3,
+                    accounts,
+                    Self::process_new_order,
+                )?
+            }
+            MarketInstruction::NewOrderV3(ref inner) => {
                 account_parser::NewOrderArgs::with_parsed_args(
                     program_id,
                     inner,
@@ -1914,6 +4228,138 @@ impl State {
                 accounts,
                 Self::process_sweep_fees,
             )?,
+            MarketInstruction::NewTriggerOrder(ref inner) => {
+                account_parser::NewTriggerOrderArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_new_trigger_order,
+                )?
+            }
+            MarketInstruction::NewOrdersBatch(ref inner) => {
+                let orders: Vec<NewOrderInstructionV3> = inner
+                    .orders
+                    .iter()
+                    .map(|order| order.clone().add_expiry(None))
+                    .collect();
+                account_parser::NewOrdersBatchArgs::with_parsed_args(
+                    program_id,
+                    &orders,
+                    accounts,
+                    Self::process_new_orders_batch,
+                )?
+            }
+            MarketInstruction::CancelAllOrders(side) => {
+                account_parser::CancelAllOrdersArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    side,
+                    Self::process_cancel_all_orders,
+                )?
+            }
+            MarketInstruction::ReplaceOrder(ref inner) => {
+                account_parser::ReplaceOrderArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_replace_order,
+                )?
+            }
+            MarketInstruction::UpdateFeeSchedule(fee_schedule) => {
+                account_parser::UpdateFeeScheduleArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    fee_schedule,
+                    Self::process_update_fee_schedule,
+                )?
+            }
+            MarketInstruction::UpdateMarketParams(ref inner) => {
+                account_parser::UpdateMarketParamsArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    inner,
+                    Self::process_update_market_params,
+                )?
+            }
+            MarketInstruction::SetMarketStatus(status) => {
+                account_parser::SetMarketStatusArgs::with_parsed_args(
+                    program_id,
+                    status,
+                    accounts,
+                    Self::process_set_market_status,
+                )?
+            }
+            MarketInstruction::CreateOpenOrders(ref inner) => {
+                account_parser::CreateOpenOrdersArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_create_open_orders,
+                )?
+            }
+            MarketInstruction::InitOpenOrders(delegate) => {
+                account_parser::InitOpenOrdersArgs::with_parsed_args(
+                    program_id,
+                    delegate,
+                    accounts,
+                    Self::process_init_open_orders,
+                )?
+            }
+            MarketInstruction::SetAutoSettle(enable) => {
+                account_parser::SetAutoSettleArgs::with_parsed_args(
+                    program_id,
+                    enable,
+                    accounts,
+                    Self::process_set_auto_settle,
+                )?
+            }
+            MarketInstruction::SettleFor => account_parser::SettleForArgs::with_parsed_args(
+                program_id,
+                accounts,
+                Self::process_settle_for,
+            )?,
+            MarketInstruction::InitTradeLog => account_parser::InitTradeLogArgs::with_parsed_args(
+                program_id,
+                accounts,
+                Self::process_init_trade_log,
+            )?,
+            MarketInstruction::InitMarketStats => {
+                account_parser::InitMarketStatsArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    Self::process_init_market_stats,
+                )?
+            }
+            MarketInstruction::SetMarketAuthority(market_authority) => {
+                account_parser::SetMarketAuthorityArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    market_authority,
+                    Self::process_set_market_authority,
+                )?
+            }
+            MarketInstruction::MigrateOpenOrders => {
+                account_parser::MigrateOpenOrdersArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    Self::process_migrate_open_orders,
+                )?
+            }
+            MarketInstruction::CloseOpenOrders => {
+                account_parser::CloseOpenOrdersArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    Self::process_close_open_orders,
+                )?
+            }
+            MarketInstruction::NewIcebergOrder(ref inner) => {
+                account_parser::NewIcebergOrderArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_new_iceberg_order,
+                )?
+            }
         };
         Ok(())
     }
@@ -1930,57 +4376,26 @@ impl State {
             vault_signer,
             spl_token_program,
             referrer,
+            signed_by_owner,
         } = args;
 
-        let native_coin_amount = open_orders.native_coin_free;
-        let native_pc_amount = open_orders.native_pc_free;
-
-        market.coin_deposits_total -= native_coin_amount;
-        market.pc_deposits_total -= native_pc_amount;
-
-        open_orders.native_coin_free = 0;
-        open_orders.native_pc_free = 0;
-
-        open_orders.native_coin_total = open_orders
-            .native_coin_total
-            .checked_sub(native_coin_amount)
-            .unwrap();
-        open_orders.native_pc_total = open_orders
-            .native_pc_total
-            .checked_sub(native_pc_amount)
-            .unwrap();
-
-        let token_infos: [(
-            u64,
-            account_parser::TokenAccount,
-            account_parser::TokenAccount,
-        ); 2] = [
-            (
-                native_coin_amount,
-                coin_wallet.token_account(),
-                coin_vault.token_account(),
-            ),
-            (
-                native_pc_amount,
-                pc_wallet.token_account(),
-                pc_vault.token_account(),
-            ),
-        ];
+        settle_free_funds(
+            market,
+            &mut open_orders,
+            coin_vault,
+            pc_vault,
+            coin_wallet,
+            pc_wallet,
+            vault_signer,
+            spl_token_program,
+        )?;
+        if !signed_by_owner {
+            return Ok(());
+        }
 
         let market_pubkey = market.pubkey();
         let vault_signer_seeds = gen_vault_signer_seeds(&market.vault_signer_nonce, &market_pubkey);
 
-        for &(token_amount, wallet_account, vault) in token_infos.iter() {
-            send_from_vault(
-                token_amount,
-                wallet_account,
-                vault,
-                spl_token_program,
-                vault_signer,
-                &vault_signer_seeds,
-            )?;
-        }
-
         match referrer {
             Some(referrer_pc_wallet) if open_orders.referrer_rebates_accrued > 0 => {
                 send_from_vault(
@@ -1999,7 +4414,46 @@ impl State {
         market.referrer_rebates_accrued -= open_orders.referrer_rebates_accrued;
         open_orders.referrer_rebates_accrued = 0;
 
-        Ok(())
+        Ok(())
+    }
+
+    #[cfg(feature = "program")]
+    fn process_settle_for(args: account_parser::SettleForArgs) -> DexResult {
+        let account_parser::SettleForArgs {
+            market,
+            mut open_orders,
+            coin_vault,
+            pc_vault,
+            coin_wallet,
+            pc_wallet,
+            vault_signer,
+            spl_token_program,
+        } = args;
+        // Referrer rebates stay accrued until the owner settles with a referrer
+        settle_free_funds(
+            market,
+            &mut open_orders,
+            coin_vault,
+            pc_vault,
+            coin_wallet,
+            pc_wallet,
+            vault_signer,
+            spl_token_program,
+        )
+    }
+
+    fn process_set_auto_settle(args: account_parser::SetAutoSettleArgs) -> DexResult {
+        let account_parser::SetAutoSettleArgs {
+            mut open_orders,
+            wallets,
+        } = args;
+        let wallets = wallets.map(|(coin_wallet, pc_wallet)| {
+            (
+                coin_wallet.token_account().inner().key.to_aligned_bytes(),
+                pc_wallet.token_account().inner().key.to_aligned_bytes(),
+            )
+        });
+        open_orders.set_settle_wallets(wallets)
     }
 
     fn process_cancel_order_by_client_id(
@@ -2012,18 +4466,16 @@ impl State {
             mut req_q,
             orders_owner: _,
         } = args;
-        let mut slot = None;
-        for (i, ith_client_order_id) in open_orders.client_order_ids.iter().enumerate() {
-            if *ith_client_order_id == client_order_id.get() && !open_orders.slot_is_free(i as u8) {
-                slot = Some(i);
-                break;
-            }
-        }
-        let expected_open_orders_slot = slot.ok_or(DexErrorCode::ClientIdNotFound)? as u8;
+        let expected_open_orders_slot = (0..open_orders.slot_count())
+            .find(|&slot| {
+                open_orders.client_order_id(slot) == client_order_id.get()
+                    && !open_orders.slot_is_free(slot)
+            })
+            .ok_or(DexErrorCode::ClientIdNotFound)?;
         let side = open_orders
             .slot_side(expected_open_orders_slot)
             .ok_or(DexErrorCode::ClientIdNotFound)?;
-        let ref order_id = open_orders.orders[expected_open_orders_slot as usize];
+        let ref order_id = open_orders.order_id(expected_open_orders_slot);
         let request = Request::new(RequestView::CancelOrder {
             cancel_id: req_q.gen_seq_num(),
             expected_owner: open_orders_address,
@@ -2032,9 +4484,40 @@ impl State {
             side,
             client_order_id: Some(client_order_id),
         });
-        req_q
-            .push_back(request)
-            .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        req_q.push_back(request)?;
+        Ok(())
+    }
+
+    fn process_cancel_all_orders(args: account_parser::CancelAllOrdersArgs) -> DexResult {
+        let account_parser::CancelAllOrdersArgs {
+            side,
+            open_orders,
+            open_orders_address,
+            mut req_q,
+            orders_owner: _,
+        } = args;
+        let mut slots = (0..open_orders.slot_count())
+            .filter_map(|slot| Some((slot, open_orders.slot_side(slot)?)))
+            .filter(|&(_, slot_side)| side.map_or(true, |side| side == slot_side))
+            .peekable();
+        if slots.peek().is_some() && req_q.full() {
+            Err(DexErrorCode::RequestQueueFull)?
+        }
+        // the orders that don't fit in the request queue are left for a later call
+        for (slot, slot_side) in slots {
+            if req_q.full() {
+                break;
+            }
+            let request = Request::new(RequestView::CancelOrder {
+                cancel_id: req_q.gen_seq_num(),
+                expected_owner: open_orders_address,
+                expected_owner_slot: slot,
+                order_id: &open_orders.order_id(slot),
+                side: slot_side,
+                client_order_id: None,
+            });
+            req_q.push_back(request)?;
+        }
         Ok(())
     }
 
@@ -2055,9 +4538,7 @@ impl State {
             side: instruction.side,
             client_order_id: None,
         });
-        req_q
-            .push_back(request)
-            .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        req_q.push_back(request)?;
         Ok(())
     }
 
@@ -2080,19 +4561,30 @@ impl State {
             let owner: [u64; 4] = event.owner;
             let owner_index: Result<usize, usize> = open_orders_accounts
                 .binary_search_by_key(&owner, |account_info| account_info.key.to_aligned_bytes());
-            let mut open_orders: RefMut<OpenOrders> = match owner_index {
+            let mut open_orders_ref = match owner_index {
                 Err(_) => break,
                 Ok(i) => {
                     market.load_orders_mut(&open_orders_accounts[i], None, program_id, None)?
                 }
             };
-
-            check_assert!(event.owner_slot < 128)?;
-            check_assert_eq!(&open_orders.slot_side(event.owner_slot), &Some(view.side()))?;
-            check_assert_eq!(
-                &open_orders.orders[event.owner_slot as usize],
-                &event.order_id
-            )?;
+            let mut open_orders = open_orders_ref.as_orders_mut();
+
+            let owner_slot = event.owner_slot();
+            check_assert!(owner_slot < open_orders.slot_count())?;
+            check_assert_eq!(&open_orders.slot_side(owner_slot), &Some(view.side()))?;
+            let slot_order_id = open_orders.order_id(owner_slot);
+            // the matching engine gives a resting order a new id when it reprices a post-only
+            // slide order, keeping its sequence number, or refills an iceberg order, keeping its
+            // price. Any other id belongs to an order that the one in the slot replaced, whose
+            // remaining events only settle its funds.
+            let slot_order = slot_order_id == event.order_id
+                || slot_order_id as u64 == event.order_id as u64
+                || (slot_order_id >> 64 == event.order_id >> 64
+                    && extract_seq_num_from_order_id(&event.order_id, view.side())
+                        > extract_seq_num_from_order_id(&slot_order_id, view.side()));
+            if slot_order {
+                *open_orders.order_id_mut(owner_slot) = event.order_id;
+            }
 
             match event.as_view()? {
                 EventView::Fill {
@@ -2124,14 +4616,14 @@ impl State {
                         }
                     };
                     if !maker {
-                        let referrer_rebate = fees::referrer_rebate(native_fee_or_rebate);
+                        let referrer_rebate = market
+                            .ext()
+                            .fee_schedule
+                            .referrer_rebate(native_fee_or_rebate);
                         open_orders.referrer_rebates_accrued += referrer_rebate;
                     }
-                    if let Some(client_id) = client_order_id {
-                        debug_assert_eq!(
-                            client_id.get(),
-                            open_orders.client_order_ids[owner_slot as usize]
-                        );
+                    if let (true, Some(client_id)) = (slot_order, client_order_id) {
+                        debug_assert_eq!(client_id.get(), open_orders.client_order_id(owner_slot));
                     }
                 }
                 EventView::Out {
@@ -2159,13 +4651,10 @@ impl State {
                             )?;
                         }
                     };
-                    if let Some(client_id) = client_order_id {
-                        debug_assert_eq!(
-                            client_id.get(),
-                            open_orders.client_order_ids[owner_slot as usize]
-                        );
+                    if let (true, Some(client_id)) = (slot_order, client_order_id) {
+                        debug_assert_eq!(client_id.get(), open_orders.client_order_id(owner_slot));
                     }
-                    if fully_out {
+                    if fully_out && slot_order {
                         open_orders.remove_order(owner_slot)?;
                     }
                 }
@@ -2183,17 +4672,237 @@ impl State {
             mut order_book_state,
             mut req_q,
             mut event_q,
+            mut trigger_q,
             limit,
         } = args;
-        order_book_state.process_requests(&mut req_q, &mut event_q, limit)
+        order_book_state.process_requests(&mut req_q, &mut event_q, trigger_q.as_mut(), limit)
     }
 
     #[cfg(feature = "program")]
     fn process_new_order(args: account_parser::NewOrderArgs) -> DexResult {
+        let (request, mut req_q) = Self::deposit_and_record_new_order(args, None, None)?;
+        req_q.push_back(request)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "program")]
+    fn process_new_trigger_order(args: account_parser::NewTriggerOrderArgs) -> DexResult {
+        let account_parser::NewTriggerOrderArgs {
+            trigger_price,
+            trigger_condition,
+            mut trigger_q,
+            new_order,
+        } = args;
+        let (request, _req_q) = Self::deposit_and_record_new_order(new_order, None, None)?;
+        trigger_q
+            .push_back(TriggerOrder::new(request, trigger_price, trigger_condition))
+            .map_err(|_| DexErrorCode::TriggerQueueFull)?;
+        // the new order may already be triggered at the last trade price
+        trigger_q.set_checked_price(None);
+        Ok(())
+    }
+
+    #[cfg(feature = "program")]
+    fn process_new_iceberg_order(args: account_parser::NewIcebergOrderArgs) -> DexResult {
+        let account_parser::NewIcebergOrderArgs {
+            display_qty,
+            new_order,
+        } = args;
+        let (request, mut req_q) =
+            Self::deposit_and_record_new_order(new_order, Some(display_qty), None)?;
+        req_q.push_back(request)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "program")]
+    fn process_replace_order(args: account_parser::ReplaceOrderArgs) -> DexResult {
+        let account_parser::ReplaceOrderArgs {
+            replaces,
+            orders,
+            exts,
+            mut new_order,
+        } = args;
+        // the request queue only has room for the replaced order on extended markets
+        if !new_order.market.is_extended() {
+            Err(DexErrorCode::MarketNotExtended)?
+        }
+        let instruction = new_order.instruction;
+        let open_orders_address = new_order.open_orders_address;
+        let open_orders = &new_order.open_orders;
+        let slot = (0..open_orders.slot_count()).find(|&slot| {
+            !open_orders.slot_is_free(slot)
+                && match replaces {
+                    OrderToReplace::OrderId(order_id) => open_orders.order_id(slot) == order_id,
+                    OrderToReplace::ClientId(client_id) => {
+                        client_id != 0 && open_orders.client_order_id(slot) == client_id
+                    }
+                }
+        });
+        let owner_slot = match (replaces, slot) {
+            (_, Some(slot)) => slot,
+            (OrderToReplace::OrderId(_), None) => Err(DexErrorCode::OrderNotFound)?,
+            (OrderToReplace::ClientId(_), None) => Err(DexErrorCode::ClientIdNotFound)?,
+        };
+        if open_orders.slot_side(owner_slot) != Some(instruction.side) {
+            Err(DexErrorCode::ReplacedOrderSideMismatch)?;
+        }
+
+        // the matching engine may have given the original a new id that its slot doesn't know
+        // about yet
+        let order_id = exts
+            .as_ref()
+            .and_then(|exts| exts.find(open_orders_address, owner_slot))
+            .map_or(open_orders.order_id(owner_slot), |ext| *ext.order_id());
+        let resting_order = orders
+            .find_by_key(&order_id)
+            .and_then(|handle| orders.get(handle)?.as_leaf())
+            .filter(|leaf| leaf.owner() == open_orders_address && leaf.owner_slot() == owner_slot);
+        let resting_ext = resting_order.and_then(|leaf| exts.as_ref()?.get(leaf));
+        let hidden_quantity = resting_ext.map_or(0, |ext| ext.hidden_quantity());
+        let same_price = resting_order.map(|leaf| leaf.price()) == Some(instruction.limit_price);
+        let amend_in_place = match resting_order {
+            Some(leaf) => {
+                same_price
+                    && instruction.order_type != OrderType::ImmediateOrCancel
+                    && instruction.order_type != OrderType::FillOrKill
+                    && resting_ext.and_then(|ext| ext.expiry()) == instruction.expiry
+                    && hidden_quantity == 0
+                    && instruction.max_qty.get() <= leaf.quantity()
+                    && instruction.client_id == leaf.client_order_id()
+            }
+            None => false,
+        };
+        // a refill would give the original a newer id at the price of the replacement, which
+        // ConsumeEvents couldn't tell apart from the replacement
+        if same_price && hidden_quantity > 0 {
+            Err(DexErrorCode::IcebergReplacedAtSamePrice)?
+        }
+
+        if amend_in_place {
+            // an amendment only reduces the original, so it has nothing to deposit
+            let request = Request::new(RequestView::NewOrder {
+                side: instruction.side,
+                order_type: instruction.order_type,
+                order_id: &order_id,
+                fee_tier: new_order.fee_tier,
+                self_trade_behavior: instruction.self_trade_behavior,
+                owner: open_orders_address,
+                owner_slot,
+                max_coin_qty: instruction.max_qty,
+                native_pc_qty_locked: None,
+                client_order_id: NonZeroU64::new(instruction.client_id),
+                expiry: instruction.expiry,
+                display_qty: None,
+                replaces: Some(ReplacedOrder {
+                    order_id,
+                    owner_slot,
+                    native_qty_credited: 0,
+                }),
+                triggered: false,
+            });
+            return new_order.req_q.push_back(request);
+        }
+
+        let native_qty_credited = resting_order.map_or(0, |leaf| {
+            let total_quantity = leaf.quantity() + hidden_quantity;
+            match instruction.side {
+                Side::Bid => total_quantity * leaf.price().get() * new_order.market.pc_lot_size,
+                Side::Ask => total_quantity * new_order.market.coin_lot_size,
+            }
+        });
+        let replaced_order = ReplacedOrder {
+            order_id,
+            owner_slot,
+            native_qty_credited,
+        };
+        let (request, mut req_q) =
+            Self::deposit_and_record_new_order(new_order, None, Some(replaced_order))?;
+        req_q.push_back(request)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "program")]
+    fn process_new_orders_batch(args: account_parser::NewOrdersBatchArgs) -> DexResult {
+        let account_parser::NewOrdersBatchArgs {
+            instructions,
+            mut market,
+            mut open_orders,
+            open_orders_address,
+            owner,
+            mut req_q,
+            coin_payer,
+            pc_payer,
+            coin_vault,
+            pc_vault,
+            spl_token_program,
+            fee_tier,
+        } = args;
+
+        let mut coin_deposit_amount = 0u64;
+        let mut pc_deposit_amount = 0u64;
+        let mut requests = Vec::with_capacity(instructions.len());
+        for instruction in instructions {
+            Self::check_order_supported(&market, instruction, None)?;
+            let (deposit_amount, native_pc_qty_locked) = Self::lock_new_order_funds(
+                &mut market,
+                &mut open_orders,
+                instruction,
+                fee_tier,
+                0,
+            )?;
+            match instruction.side {
+                Side::Bid => pc_deposit_amount += deposit_amount,
+                Side::Ask => coin_deposit_amount += deposit_amount,
+            };
+            requests.push(Self::record_new_order(
+                &mut req_q,
+                &mut open_orders,
+                open_orders_address,
+                instruction,
+                fee_tier,
+                native_pc_qty_locked,
+                None,
+                None,
+            )?);
+        }
+
+        if coin_deposit_amount > 0 {
+            Self::transfer_deposit(
+                coin_payer,
+                coin_vault.token_account(),
+                owner,
+                spl_token_program,
+                coin_deposit_amount,
+            )?;
+        }
+        if pc_deposit_amount > 0 {
+            Self::transfer_deposit(
+                pc_payer,
+                pc_vault.token_account(),
+                owner,
+                spl_token_program,
+                pc_deposit_amount,
+            )?;
+        }
+
+        for request in requests {
+            req_q.push_back(request)?;
+        }
+        Ok(())
+    }
+
+    // Locks the funds for a new order and records it in the OpenOrders account, returning the
+    // request to be matched along with the request queue that generated its order id.
+    #[cfg(feature = "program")]
+    fn deposit_and_record_new_order<'a, 'b>(
+        args: account_parser::NewOrderArgs<'a, 'b>,
+        display_qty: Option<NonZeroU64>,
+        replaces: Option<ReplacedOrder>,
+    ) -> DexResult<((Request, RequestExt), RequestQueue<'a>)> {
         let account_parser::NewOrderArgs {
             instruction,
-            market,
-            open_orders,
+            mut market,
+            mut open_orders,
             open_orders_address,
             mut req_q,
             payer,
@@ -2204,11 +4913,77 @@ impl State {
             fee_tier,
         } = args;
 
-        let deposit_amount;
-        let deposit_vault;
+        Self::check_order_supported(&market, instruction, display_qty)?;
+        let (deposit_amount, native_pc_qty_locked) = Self::lock_new_order_funds(
+            &mut market,
+            &mut open_orders,
+            instruction,
+            fee_tier,
+            replaces.map_or(0, |replaced_order| replaced_order.native_qty_credited),
+        )?;
+        // the replacement is only credited with as much as it locks
+        let replaces = replaces.map(|replaced_order| {
+            let native_qty_locked = match instruction.side {
+                Side::Bid => native_pc_qty_locked.unwrap().get(),
+                Side::Ask => instruction.max_qty.get() * market.coin_lot_size,
+            };
+            ReplacedOrder {
+                native_qty_credited: replaced_order.native_qty_credited.min(native_qty_locked),
+                ..replaced_order
+            }
+        });
+        let deposit_vault = match instruction.side {
+            Side::Bid => pc_vault.token_account(),
+            Side::Ask => coin_vault.token_account(),
+        };
+        Self::transfer_deposit(
+            payer,
+            deposit_vault,
+            owner,
+            spl_token_program,
+            deposit_amount,
+        )?;
+
+        let request = Self::record_new_order(
+            &mut req_q,
+            &mut open_orders,
+            open_orders_address,
+            instruction,
+            fee_tier,
+            native_pc_qty_locked,
+            display_qty,
+            replaces,
+        )?;
+        Ok((request, req_q))
+    }
+
+    // The books of legacy markets have no room for the expiry or hidden quantity of an order,
+    // nor for the slid order id of a post-only slide order.
+    fn check_order_supported(
+        market: &MarketState,
+        instruction: &NewOrderInstructionV3,
+        display_qty: Option<NonZeroU64>,
+    ) -> DexResult {
+        let needs_ext = instruction.expiry.is_some()
+            || display_qty.is_some()
+            || instruction.order_type == OrderType::PostOnlySlide;
+        if needs_ext && !market.is_extended() {
+            Err(DexErrorCode::MarketNotExtended)?
+        }
+        Ok(())
+    }
 
-        let native_pc_qty_locked;
-        match instruction.side {
+    // Locks the funds a new order needs, using funds already locked by the order it replaces
+    // up to `native_qty_credited`, then free funds in the OpenOrders account. Returns the
+    // amount that still has to be deposited and, for bids, the total pc locked.
+    fn lock_new_order_funds(
+        market: &mut MarketStateMut,
+        open_orders: &mut OpenOrders,
+        instruction: &NewOrderInstructionV3,
+        fee_tier: FeeTier,
+        native_qty_credited: u64,
+    ) -> DexResult<(u64, Option<NonZeroU64>)> {
+        Ok(match instruction.side {
             Side::Bid => {
                 let lock_qty_lots = instruction
                     .max_qty
@@ -2219,38 +4994,55 @@ impl State {
                     .checked_mul(market.pc_lot_size)
                     .ok_or(DexErrorCode::InsufficientFunds)?;
                 let lock_qty_native = native_lock_qty_before_fee
-                    .checked_add(fee_tier.taker_fee(native_lock_qty_before_fee))
+                    .checked_add(
+                        market
+                            .ext()
+                            .fee_schedule
+                            .taker_fee(fee_tier, native_lock_qty_before_fee),
+                    )
                     .ok_or(DexErrorCode::InsufficientFunds)?;
-                native_pc_qty_locked = Some(NonZeroU64::new(lock_qty_native).unwrap());
-                let free_qty_to_lock = lock_qty_native.min(open_orders.native_pc_free);
-                deposit_amount = lock_qty_native - free_qty_to_lock;
-                deposit_vault = pc_vault.token_account();
+                let qty_to_lock = lock_qty_native - native_qty_credited.min(lock_qty_native);
+                let free_qty_to_lock = qty_to_lock.min(open_orders.native_pc_free);
+                let deposit_amount = qty_to_lock - free_qty_to_lock;
                 open_orders.lock_free_pc(free_qty_to_lock);
                 open_orders.credit_locked_pc(deposit_amount);
                 market.pc_deposits_total = market
                     .pc_deposits_total
                     .checked_add(deposit_amount)
                     .unwrap();
+                (
+                    deposit_amount,
+                    Some(NonZeroU64::new(lock_qty_native).unwrap()),
+                )
             }
             Side::Ask => {
                 let lock_qty_native = instruction
                     .max_qty
                     .get()
-                    .checked_mul((*market).coin_lot_size)
+                    .checked_mul(market.coin_lot_size)
                     .ok_or(DexErrorCode::InsufficientFunds)?;
-                let free_qty_to_lock = lock_qty_native.min(open_orders.native_coin_free);
-                deposit_amount = lock_qty_native - free_qty_to_lock;
-                deposit_vault = coin_vault.token_account();
+                let qty_to_lock = lock_qty_native - native_qty_credited.min(lock_qty_native);
+                let free_qty_to_lock = qty_to_lock.min(open_orders.native_coin_free);
+                let deposit_amount = qty_to_lock - free_qty_to_lock;
                 open_orders.lock_free_coin(free_qty_to_lock);
                 open_orders.credit_locked_coin(deposit_amount);
-                native_pc_qty_locked = None;
                 market.coin_deposits_total = market
                     .coin_deposits_total
                     .checked_add(deposit_amount)
                     .unwrap();
+                (deposit_amount, None)
             }
-        };
+        })
+    }
 
+    #[cfg(feature = "program")]
+    fn transfer_deposit<'a, 'b>(
+        payer: account_parser::TokenAccount<'a, 'b>,
+        deposit_vault: account_parser::TokenAccount<'a, 'b>,
+        owner: account_parser::SignerAccount<'a, 'b>,
+        spl_token_program: account_parser::SplTokenProgram<'a, 'b>,
+        deposit_amount: u64,
+    ) -> DexResult {
         // pull balances from payer, signed by owner
         let deposit_instruction = spl_token::instruction::transfer(
             &spl_token::ID,
@@ -2281,14 +5073,33 @@ impl State {
             },
             _ => DexErrorCode::TransferFailed,
         })?;
+        Ok(())
+    }
 
-        // record the open order in the user account
+    // Records the open order in the user account and builds the request to be matched.
+    #[allow(clippy::too_many_arguments)]
+    fn record_new_order(
+        req_q: &mut RequestQueue,
+        open_orders: &mut OpenOrdersMut,
+        open_orders_address: &[u64; 4],
+        instruction: &NewOrderInstructionV3,
+        fee_tier: FeeTier,
+        native_pc_qty_locked: Option<NonZeroU64>,
+        display_qty: Option<NonZeroU64>,
+        replaces: Option<ReplacedOrder>,
+    ) -> DexResult<(Request, RequestExt)> {
         let order_id = req_q.gen_order_id(instruction.limit_price.get(), instruction.side);
-        let owner_slot = open_orders.add_order(order_id, instruction.side)?;
-        open_orders.client_order_ids[owner_slot as usize] = instruction.client_id;
+        let owner_slot = match replaces {
+            // events still queued for the original only settle its funds from now on
+            Some(replaced_order) => {
+                *open_orders.order_id_mut(replaced_order.owner_slot) = order_id;
+                replaced_order.owner_slot
+            }
+            None => open_orders.add_order(order_id, instruction.side)?,
+        };
+        *open_orders.client_order_id_mut(owner_slot) = instruction.client_id;
 
-        // add the request to the queue
-        let request = Request::new(RequestView::NewOrder {
+        Ok(Request::new(RequestView::NewOrder {
             side: instruction.side,
             order_type: instruction.order_type,
             order_id: &order_id,
@@ -2299,12 +5110,11 @@ impl State {
             max_coin_qty: instruction.max_qty,
             native_pc_qty_locked,
             client_order_id: NonZeroU64::new(instruction.client_id),
-        });
-
-        req_q
-            .push_back(request)
-            .map_err(|_| DexErrorCode::RequestQueueFull)?;
-        Ok(())
+            expiry: instruction.expiry,
+            display_qty,
+            replaces,
+            triggered: false,
+        }))
     }
 
     fn process_disable_market(args: account_parser::DisableMarketArgs) -> DexResult {
@@ -2316,6 +5126,216 @@ impl State {
         Ok(())
     }
 
+    #[cfg(feature = "program")]
+    fn process_create_open_orders(args: account_parser::CreateOpenOrdersArgs) -> DexResult {
+        let account_parser::CreateOpenOrdersArgs {
+            program_id,
+            index,
+            nonce,
+            delegate,
+            market,
+            open_orders_acc,
+            owner,
+            rent,
+            system_program,
+        } = args;
+        let space = OpenOrders::account_size(true);
+        let instruction = solana_sdk::system_instruction::create_account(
+            owner.inner().key,
+            open_orders_acc.key,
+            rent.minimum_balance(space),
+            space as u64,
+            program_id,
+        );
+        let market_key = market.pubkey();
+        let seeds = gen_open_orders_seeds(&market_key, owner.inner().key, &index, &nonce);
+        solana_sdk::program::invoke_signed(
+            &instruction,
+            &[
+                owner.inner().clone(),
+                open_orders_acc.clone(),
+                system_program.clone(),
+            ],
+            &[&seeds],
+        )?;
+
+        let mut open_orders =
+            market.init_orders_mut(open_orders_acc, owner.inner(), program_id, rent)?;
+        open_orders.as_orders_mut().set_delegate(delegate)
+    }
+
+    fn process_init_open_orders(args: account_parser::InitOpenOrdersArgs) -> DexResult {
+        let account_parser::InitOpenOrdersArgs {
+            delegate,
+            mut open_orders,
+        } = args;
+        open_orders.set_delegate(delegate)
+    }
+
+    fn process_migrate_open_orders(args: account_parser::MigrateOpenOrdersArgs) -> DexResult {
+        let account_parser::MigrateOpenOrdersArgs {
+            mut old_open_orders,
+            mut new_open_orders,
+            old_open_orders_acc,
+            owner,
+        } = args;
+        // Without open orders nothing on the book or in the queues refers to the old account
+        if !old_open_orders.all_slots_free()
+            || old_open_orders.native_coin_free != old_open_orders.native_coin_total
+            || old_open_orders.native_pc_free != old_open_orders.native_pc_total
+        {
+            Err(DexErrorCode::OrdersAccountNotEmpty)?
+        }
+        new_open_orders.native_coin_free += old_open_orders.native_coin_free;
+        new_open_orders.native_coin_total += old_open_orders.native_coin_total;
+        new_open_orders.native_pc_free += old_open_orders.native_pc_free;
+        new_open_orders.native_pc_total += old_open_orders.native_pc_total;
+        new_open_orders.referrer_rebates_accrued += old_open_orders.referrer_rebates_accrued;
+        old_open_orders.clear();
+
+        let mut orders_lamports = old_open_orders_acc.try_borrow_mut_lamports()?;
+        let mut owner_lamports = owner.inner().try_borrow_mut_lamports()?;
+        **owner_lamports = owner_lamports.checked_add(**orders_lamports).unwrap();
+        **orders_lamports = 0;
+        Ok(())
+    }
+
+    fn process_close_open_orders(args: account_parser::CloseOpenOrdersArgs) -> DexResult {
+        let account_parser::CloseOpenOrdersArgs {
+            mut open_orders,
+            open_orders_acc,
+            destination,
+        } = args;
+        if !open_orders.all_slots_free()
+            || open_orders.native_coin_total != 0
+            || open_orders.native_pc_total != 0
+            || open_orders.referrer_rebates_accrued != 0
+        {
+            Err(DexErrorCode::OrdersAccountNotEmpty)?
+        }
+        open_orders.clear();
+
+        let mut orders_lamports = open_orders_acc.try_borrow_mut_lamports()?;
+        let mut destination_lamports = destination.try_borrow_mut_lamports()?;
+        **destination_lamports = destination_lamports.checked_add(**orders_lamports).unwrap();
+        **orders_lamports = 0;
+        Ok(())
+    }
+
+    fn process_init_trade_log(args: account_parser::InitTradeLogArgs) -> DexResult {
+        let account_parser::InitTradeLogArgs {
+            mut market,
+            trade_log_acc,
+            authorization: _,
+        } = args;
+        let market_ext = market.ext_mut()?;
+        let (mut header, buf) = strip_header::<TradeLogHeader, TradeRecord>(trade_log_acc, true)?;
+        if header.account_flags != 0 {
+            Err(DexErrorCode::AlreadyInitialized)?
+        }
+        check_assert!(!buf.is_empty())?;
+        *header = TradeLogHeader {
+            account_flags: (AccountFlag::Initialized | AccountFlag::TradeLog).bits(),
+            head: 0,
+            count: 0,
+            seq_num: 0,
+        };
+        market_ext.trade_log = trade_log_acc.key.to_aligned_bytes();
+        Ok(())
+    }
+
+    fn process_init_market_stats(args: account_parser::InitMarketStatsArgs) -> DexResult {
+        let account_parser::InitMarketStatsArgs {
+            mut market,
+            stats_acc,
+            authorization: _,
+        } = args;
+        let market_address = market.own_address;
+        let market_ext = market.ext_mut()?;
+        let (mut stats, _) = strip_header::<MarketStats, u8>(stats_acc, true)?;
+        if stats.account_flags != 0 {
+            Err(DexErrorCode::AlreadyInitialized)?
+        }
+        *stats = Zeroable::zeroed();
+        stats.account_flags = (AccountFlag::Initialized | AccountFlag::MarketStats).bits();
+        stats.market = market_address;
+        market_ext.stats = stats_acc.key.to_aligned_bytes();
+        Ok(())
+    }
+
+    fn process_set_market_authority(args: account_parser::SetMarketAuthorityArgs) -> DexResult {
+        let account_parser::SetMarketAuthorityArgs {
+            market_authority,
+            mut market,
+            record,
+            authorization: _,
+        } = args;
+        match record {
+            Some(mut record) => record.authority = market_authority,
+            None => market.ext_mut()?.market_authority = market_authority,
+        }
+        Ok(())
+    }
+
+    fn process_set_market_status(args: account_parser::SetMarketStatusArgs) -> DexResult {
+        let account_parser::SetMarketStatusArgs {
+            status,
+            market,
+            authorization: _,
+        } = args;
+        let status_flags = AccountFlag::CancelOnly as u64 | AccountFlag::Paused as u64;
+        let status_flag = match status {
+            MarketStatus::Active => 0,
+            MarketStatus::CancelOnly => AccountFlag::CancelOnly as u64,
+            MarketStatus::Paused => AccountFlag::Paused as u64,
+        };
+        market.account_flags = (market.account_flags & !status_flags) | status_flag;
+        Ok(())
+    }
+
+    fn process_update_fee_schedule(args: account_parser::UpdateFeeScheduleArgs) -> DexResult {
+        let account_parser::UpdateFeeScheduleArgs {
+            fee_schedule,
+            mut market,
+            fee_authority: _,
+        } = args;
+        if !fee_schedule.is_valid() {
+            Err(DexErrorCode::InvalidFeeSchedule)?
+        }
+        market.ext_mut()?.fee_schedule = fee_schedule;
+        Ok(())
+    }
+
+    fn process_update_market_params(args: account_parser::UpdateMarketParamsArgs) -> DexResult {
+        let account_parser::UpdateMarketParamsArgs {
+            instruction,
+            mut market,
+            book_is_empty,
+            authorization: _,
+        } = args;
+        let &UpdateMarketParamsInstruction {
+            coin_lot_size,
+            pc_lot_size,
+            pc_dust_threshold,
+        } = instruction;
+        if coin_lot_size == 0 || pc_lot_size == 0 {
+            Err(DexErrorCode::InvalidMarketParams)?
+        }
+        if coin_lot_size != market.coin_lot_size || pc_lot_size != market.pc_lot_size {
+            if !book_is_empty {
+                Err(DexErrorCode::OrderBookNotEmpty)?
+            }
+            market.coin_lot_size = coin_lot_size;
+            market.pc_lot_size = pc_lot_size;
+            // the last trade price is in lots of the old sizes
+            if let Ok(market_ext) = market.ext_mut() {
+                market_ext.last_trade_price = 0;
+            }
+        }
+        market.pc_dust_threshold = pc_dust_threshold;
+        Ok(())
+    }
+
     #[cfg(feature = "program")]
     fn process_sweep_fees(args: account_parser::SweepFeesArgs) -> DexResult {
         let account_parser::SweepFeesArgs {
@@ -2348,31 +5368,54 @@ impl State {
             fee_rate_bps,
             vault_signer_nonce,
             pc_dust_threshold,
+            ref fee_config,
+            market_authority,
         } = args.instruction;
+        let (fee_authority, fee_schedule) = match fee_config {
+            Some(fee_config) => (fee_config.fee_authority, fee_config.fee_schedule),
+            None => ([0; 4], FeeSchedule::DEFAULT),
+        };
+        if !fee_schedule.is_valid() {
+            Err(DexErrorCode::InvalidFeeSchedule)?
+        }
 
         let market = args.get_market();
         let req_q = args.get_req_q();
         let event_q = args.get_event_q();
         let bids = args.get_bids();
         let asks = args.get_asks();
+        let trigger_q = args.get_trigger_q();
         let coin_vault = args.coin_vault_and_mint.get_account().inner();
         let coin_mint = args.coin_vault_and_mint.get_mint().inner();
         let pc_vault = args.pc_vault_and_mint.get_account().inner();
         let pc_mint = args.pc_vault_and_mint.get_mint().inner();
 
+        // Accounts sized for the original layout get a market without the extensions.
+        let extended = market.data_len() > size_of::<MarketState>() + 12;
         // initialize request queue
         let mut rq_data = req_q.try_borrow_mut_data()?;
         const RQ_HEADER_WORDS: usize = size_of::<RequestQueueHeader>() / size_of::<u64>();
         let rq_view = init_account_padding(&mut rq_data)?;
         let (rq_hdr_array, rq_buf_words) = mut_array_refs![rq_view, RQ_HEADER_WORDS; .. ;];
-        let rq_buf: &[Request] = remove_slop(cast_slice(rq_buf_words));
-        if rq_buf.is_empty() {
+        let rq_buf_bytes: &mut [u8] = cast_slice_mut(rq_buf_words);
+        let mut rq_flags = AccountFlag::Initialized | AccountFlag::RequestQueue;
+        let rq_capacity = if extended {
+            // the requests are followed by their extensions
+            rq_flags |= AccountFlag::Extended;
+            for byte in rq_buf_bytes.iter_mut() {
+                *byte = 0;
+            }
+            extended_request_queue_capacity(rq_buf_bytes.len())
+        } else {
+            rq_buf_bytes.len() / size_of::<Request>()
+        };
+        if rq_capacity == 0 {
             Err(DexErrorCode::RequestQueueEmpty)?
         }
         let rq_hdr: &mut RequestQueueHeader =
             try_cast_mut(rq_hdr_array).or(check_unreachable!())?;
         *rq_hdr = RequestQueueHeader {
-            account_flags: (AccountFlag::Initialized | AccountFlag::RequestQueue).bits(),
+            account_flags: rq_flags.bits(),
             head: 0,
             count: 0,
             next_seq_num: 0,
@@ -2403,23 +5446,78 @@ impl State {
             let (hdr_array, slab_words) = mut_array_refs![ob_view, OB_HEADER_WORDS; .. ;];
             let ob_hdr: &mut OrderBookStateHeader =
                 try_cast_mut(hdr_array).or(check_unreachable!())?;
+            let mut account_flags = AccountFlag::Initialized | *flag;
+            let slab_bytes: &mut [u8] = cast_slice_mut(slab_words);
+            check_assert!(slab_bytes.len() >= SLAB_HEADER_LEN)?;
+            let slab_len = if extended {
+                // the rest of the account holds the side table of the book's orders
+                account_flags |= AccountFlag::Extended;
+                extended_slab_len(slab_bytes.len())
+            } else {
+                slab_bytes.len()
+            };
+            let (slab_bytes, exts_bytes) = slab_bytes.split_at_mut(slab_len);
+            for byte in exts_bytes.iter_mut() {
+                *byte = 0;
+            }
             *ob_hdr = OrderBookStateHeader {
-                account_flags: (AccountFlag::Initialized | *flag).bits(),
+                account_flags: account_flags.bits(),
             };
-            let slab = Slab::new(cast_slice_mut(slab_words));
+            let slab = Slab::new(slab_bytes);
             slab.assert_minimum_capacity(100)?;
         }
+        // initialize trigger queue
+        if let Some(trigger_q) = trigger_q {
+            let mut tq_data = trigger_q.try_borrow_mut_data()?;
+            const TQ_HEADER_WORDS: usize = size_of::<TriggerQueueHeader>() / size_of::<u64>();
+            let tq_view = init_account_padding(&mut tq_data)?;
+            check_assert!(tq_view.len() > TQ_HEADER_WORDS)?;
+            let (tq_hdr_array, tq_buf_words) = mut_array_refs![tq_view, TQ_HEADER_WORDS; .. ;];
+            let tq_buf: &[TriggerOrder] = remove_slop(cast_slice(tq_buf_words));
+            check_assert!(!tq_buf.is_empty())?;
+            let tq_hdr: &mut TriggerQueueHeader =
+                try_cast_mut(tq_hdr_array).or(check_unreachable!())?;
+            *tq_hdr = TriggerQueueHeader {
+                account_flags: (AccountFlag::Initialized | AccountFlag::TriggerQueue).bits(),
+                head: 0,
+                count: 0,
+                checked_price: 0,
+            };
+        }
         info!("Initializing market...");
         // initialize market
         let mut market_data = market.try_borrow_mut_data()?;
-        let market_view = init_account_padding(&mut market_data)?;
+        let market_view: &mut [u8] = cast_slice_mut(init_account_padding(&mut market_data)?);
+        check_assert!(market_view.len() >= size_of::<MarketState>())?;
+        let (market_view, market_ext_view) = market_view.split_at_mut(size_of::<MarketState>());
         let market_hdr: &mut MarketState =
-            try_from_bytes_mut(cast_slice_mut(market_view)).or(check_unreachable!())?;
+            try_from_bytes_mut(market_view).or(check_unreachable!())?;
+        check_assert_eq!(extended, !market_ext_view.is_empty())?;
+        if !extended && (trigger_q.is_some() || fee_config.is_some() || market_authority.is_some())
+        {
+            Err(DexErrorCode::MarketNotExtended)?
+        }
+        let mut account_flags = AccountFlag::Initialized | AccountFlag::Market;
+        if extended {
+            account_flags |= AccountFlag::Extended;
+            let market_ext: &mut MarketStateExt =
+                try_from_bytes_mut(market_ext_view).or(check_unreachable!())?;
+            *market_ext = MarketStateExt {
+                last_trade_price: 0,
+                trigger_q: trigger_q.map_or([0; 4], |q| q.key.to_aligned_bytes()),
+
+                fee_authority,
+                fee_schedule,
+                market_authority: market_authority.unwrap_or([0; 4]),
+                trade_log: [0; 4],
+                stats: [0; 4],
+            };
+        }
         *market_hdr = MarketState {
             coin_lot_size,
             pc_lot_size,
             own_address: market.key.to_aligned_bytes(),
-            account_flags: (AccountFlag::Initialized | AccountFlag::Market).bits(),
+            account_flags: account_flags.bits(),
 
             coin_mint: coin_mint.key.to_aligned_bytes(),
             coin_vault: coin_vault.key.to_aligned_bytes(),
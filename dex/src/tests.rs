@@ -5,7 +5,7 @@ use bumpalo::{collections::Vec as BumpVec, vec as bump_vec, Bump};
 use rand::prelude::*;
 use safe_transmute::to_bytes::{transmute_to_bytes, transmute_to_bytes_mut};
 use solana_sdk::bpf_loader;
-use solana_sdk::clock::Epoch;
+use solana_sdk::clock::{Clock, Epoch};
 use solana_sdk::program_pack::Pack;
 use solana_sdk::rent::Rent;
 use solana_sdk::system_program;
@@ -13,10 +13,27 @@ use solana_sdk::sysvar;
 use solana_sdk::sysvar::Sysvar;
 use spl_token::state::{Account, AccountState, Mint};
 
-use instruction::{initialize_market, MarketInstruction, NewOrderInstructionV1};
-use matching::{OrderType, Side};
-use state::gen_vault_signer_key;
-use state::{MarketState, OpenOrders, State, ToAlignedBytes};
+use error::{DexErrorCode, DexResult};
+use fees::{FeeSchedule, FeeTier};
+use instruction::{
+    disable_authority, CreateOpenOrdersInstruction, FeeConfig, InitializeMarketInstruction,
+    MarketInstruction, MarketStatus, NewIcebergOrderInstruction, NewOrderInstructionV1,
+    NewOrderInstructionV2, NewOrderInstructionV3, NewOrdersBatchInstruction,
+    NewTriggerOrderInstruction, OrderToReplace, ReplaceOrderInstruction, SelfTradeBehavior,
+    UpdateMarketParamsInstruction,
+};
+use matching::{OrderExpiry, OrderType, Side, TriggerCondition};
+use simulation::{simulate_taker_order, SimulatedOrder, Simulation};
+use snapshot::{L2Snapshot, Level, MarketSnapshot, QueuedEvent, QueuedRequest, SNAPSHOT_VERSION};
+use state::{
+    find_open_orders_address, gen_vault_signer_key, load_order_book_side, read_request_queue,
+    strip_account_data_padding,
+};
+use state::{
+    Event, EventView, MarketAuthorityRecord, MarketState, MarketStateExt, MarketStats, OpenOrders,
+    OpenOrdersExt, Request, RequestQueueHeader, RequestView, State, ToAlignedBytes, TradeLogHeader,
+    TradeRecord,
+};
 
 use super::*;
 
@@ -35,6 +52,18 @@ struct MarketAccounts<'bump> {
     coin_mint: AccountInfo<'bump>,
     pc_mint: AccountInfo<'bump>,
     rent_sysvar: AccountInfo<'bump>,
+    spl_token_program: AccountInfo<'bump>,
+    vault_signer: AccountInfo<'bump>,
+    trigger_q: Option<AccountInfo<'bump>>,
+    clock_sysvar: AccountInfo<'bump>,
+}
+
+#[derive(Default)]
+struct MarketConfig {
+    legacy_layout: bool,
+    trigger_q: bool,
+    fee_config: Option<FeeConfig>,
+    market_authority: Option<[u64; 4]>,
 }
 
 fn allocate_dex_owned_account(unpadded_size: usize, bump: &Bump) -> &mut [u8] {
@@ -69,6 +98,22 @@ fn new_rent_sysvar_account<'bump>(
     account_info
 }
 
+fn new_clock_sysvar_account<'bump>(clock: Clock, bump: &'bump Bump) -> AccountInfo<'bump> {
+    let data = bump_vec![in bump; 0u8; size_of::<Clock>()].into_bump_slice_mut();
+    let mut account_info = AccountInfo::new(
+        &sysvar::clock::ID,
+        false,
+        false,
+        bump.alloc(0),
+        data,
+        &sysvar::ID,
+        false,
+        Epoch::default(),
+    );
+    clock.to_account_info(&mut account_info).unwrap();
+    account_info
+}
+
 fn new_sol_account<'bump, Gen: Rng>(
     rng: &mut Gen,
     lamports: u64,
@@ -86,6 +131,19 @@ fn new_sol_account<'bump, Gen: Rng>(
     )
 }
 
+fn new_signer_account<'bump>(key: &'bump Pubkey, bump: &'bump Bump) -> AccountInfo<'bump> {
+    AccountInfo::new(
+        key,
+        true,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    )
+}
+
 fn new_dex_owned_account<'bump, Gen: Rng>(
     rng: &mut Gen,
     unpadded_len: usize,
@@ -159,12 +217,30 @@ fn new_spl_token_program<'bump>(bump: &'bump Bump) -> AccountInfo<'bump> {
 }
 
 fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts<'bump> {
+    setup_market_with(rng, bump, MarketConfig::default())
+}
+
+fn setup_market_with<'bump, R: Rng>(
+    rng: &mut R,
+    bump: &'bump Bump,
+    config: MarketConfig,
+) -> MarketAccounts<'bump> {
     let program_id = random_pubkey(rng, bump);
-    let market = new_dex_owned_account(rng, size_of::<MarketState>(), program_id, bump);
+    let market_len = if config.legacy_layout {
+        size_of::<MarketState>()
+    } else {
+        size_of::<MarketState>() + size_of::<MarketStateExt>()
+    };
+    let market = new_dex_owned_account(rng, market_len, program_id, bump);
     let bids = new_dex_owned_account(rng, 1 << 23, program_id, bump);
     let asks = new_dex_owned_account(rng, 1 << 23, program_id, bump);
     let req_q = new_dex_owned_account(rng, 640, program_id, bump);
     let event_q = new_dex_owned_account(rng, 65536, program_id, bump);
+    let trigger_q = if config.trigger_q {
+        Some(new_dex_owned_account(rng, 8192, program_id, bump))
+    } else {
+        None
+    };
 
     let coin_mint = new_token_mint(rng, bump);
     let pc_mint = new_token_mint(rng, bump);
@@ -188,26 +264,19 @@ fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts
 
     let pc_dust_threshold = 5;
 
-    let init_instruction = initialize_market(
-        &market.key,
-        &program_id,
-        &coin_mint.key,
-        &pc_mint.key,
-        &coin_vault.key,
-        &pc_vault.key,
-        &bids.key,
-        &asks.key,
-        &req_q.key,
-        &event_q.key,
+    let init_data = MarketInstruction::InitializeMarket(InitializeMarketInstruction {
         coin_lot_size,
         pc_lot_size,
+        fee_rate_bps: 0,
         vault_signer_nonce,
         pc_dust_threshold,
-    )
-    .unwrap();
+        fee_config: config.fee_config,
+        market_authority: config.market_authority,
+    })
+    .pack();
 
     {
-        let accounts: &'bump [AccountInfo<'bump>] = bump_vec![in bump;
+        let mut accounts = vec![
             market.clone(),
             req_q.clone(),
             event_q.clone(),
@@ -217,9 +286,9 @@ fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts
             pc_vault.clone(),
             coin_mint.clone(),
             pc_mint.clone(),
-        ]
-        .into_bump_slice_mut();
-        State::process(&program_id, accounts, &init_instruction.data).unwrap();
+        ];
+        accounts.extend(trigger_q.clone());
+        State::process(&program_id, &accounts, &init_data).unwrap();
     }
 
     MarketAccounts {
@@ -233,9 +302,359 @@ fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts
         coin_mint,
         pc_mint,
         rent_sysvar,
+        spl_token_program: new_spl_token_program(bump),
+        vault_signer: AccountInfo::new(
+            vault_signer_pk,
+            false,
+            false,
+            bump.alloc(0),
+            &mut [],
+            &system_program::ID,
+            false,
+            Epoch::default(),
+        ),
+        trigger_q,
+        clock_sysvar: new_clock_sysvar_account(Clock::default(), bump),
+    }
+}
+
+struct Trader<'bump> {
+    owner: AccountInfo<'bump>,
+    orders: AccountInfo<'bump>,
+    coin: AccountInfo<'bump>,
+    pc: AccountInfo<'bump>,
+}
+
+fn new_trader<'bump, R: Rng>(
+    rng: &mut R,
+    accounts: &MarketAccounts<'bump>,
+    bump: &'bump Bump,
+) -> Trader<'bump> {
+    let owner = new_sol_account(rng, 1_000_000_000, bump);
+    let orders = new_dex_owned_account(rng, size_of::<OpenOrders>(), accounts.market.owner, bump);
+    let coin = new_token_account(rng, accounts.coin_mint.key, owner.key, bump);
+    let pc = new_token_account(rng, accounts.pc_mint.key, owner.key, bump);
+    Trader {
+        owner,
+        orders,
+        coin,
+        pc,
+    }
+}
+
+fn new_v2_trader<'bump, R: Rng>(
+    rng: &mut R,
+    accounts: &MarketAccounts<'bump>,
+    bump: &'bump Bump,
+) -> Trader<'bump> {
+    let mut trader = new_trader(rng, accounts, bump);
+    trader.orders = new_dex_owned_account(
+        rng,
+        size_of::<OpenOrders>() + size_of::<OpenOrdersExt>(),
+        accounts.market.owner,
+        bump,
+    );
+    trader
+}
+
+fn init_open_orders<'bump>(
+    accounts: &MarketAccounts<'bump>,
+    trader: &Trader<'bump>,
+    delegate: Option<[u64; 4]>,
+) -> DexResult {
+    State::process(
+        accounts.market.owner,
+        &[
+            trader.orders.clone(),
+            trader.owner.clone(),
+            accounts.market.clone(),
+            accounts.rent_sysvar.clone(),
+        ],
+        &MarketInstruction::InitOpenOrders(delegate).pack(),
+    )
+}
+
+fn create_open_orders<'bump>(
+    accounts: &MarketAccounts<'bump>,
+    trader: &Trader<'bump>,
+    open_orders: &AccountInfo<'bump>,
+    system_program_acc: &AccountInfo<'bump>,
+    index: u64,
+) -> DexResult {
+    let instruction = CreateOpenOrdersInstruction {
+        index,
+        delegate: None,
+    };
+    State::process(
+        accounts.market.owner,
+        &[
+            open_orders.clone(),
+            trader.owner.clone(),
+            accounts.market.clone(),
+            accounts.rent_sysvar.clone(),
+            system_program_acc.clone(),
+        ],
+        &MarketInstruction::CreateOpenOrders(instruction).pack(),
+    )
+}
+
+fn new_order_accounts<'bump>(
+    accounts: &MarketAccounts<'bump>,
+    trader: &Trader<'bump>,
+    side: Side,
+) -> Vec<AccountInfo<'bump>> {
+    let payer = match side {
+        Side::Bid => &trader.pc,
+        Side::Ask => &trader.coin,
+    };
+    vec![
+        accounts.market.clone(),
+        trader.orders.clone(),
+        accounts.req_q.clone(),
+        payer.clone(),
+        trader.owner.clone(),
+        accounts.coin_vault.clone(),
+        accounts.pc_vault.clone(),
+        accounts.spl_token_program.clone(),
+        accounts.rent_sysvar.clone(),
+    ]
+}
+
+fn new_limit_order(side: Side, price: u64, qty: u64) -> NewOrderInstructionV3 {
+    NewOrderInstructionV3 {
+        side,
+        limit_price: NonZeroU64::new(price).unwrap(),
+        max_qty: NonZeroU64::new(qty).unwrap(),
+        order_type: OrderType::Limit,
+        client_id: 0,
+        self_trade_behavior: SelfTradeBehavior::DecrementTake,
+        expiry: None,
     }
 }
 
+fn place_order<'bump>(
+    accounts: &MarketAccounts<'bump>,
+    trader: &Trader<'bump>,
+    order: NewOrderInstructionV3,
+) -> DexResult {
+    let instruction_accounts = new_order_accounts(accounts, trader, order.side);
+    let instruction_data = MarketInstruction::NewOrderV3(order).pack();
+    State::process(
+        accounts.market.owner,
+        &instruction_accounts,
+        &instruction_data,
+    )
+}
+
+fn replace_order<'bump>(
+    accounts: &MarketAccounts<'bump>,
+    trader: &Trader<'bump>,
+    replaces: OrderToReplace,
+    order: NewOrderInstructionV3,
+) -> DexResult {
+    let mut instruction_accounts = new_order_accounts(accounts, trader, order.side);
+    instruction_accounts.push(match order.side {
+        Side::Bid => accounts.bids.clone(),
+        Side::Ask => accounts.asks.clone(),
+    });
+    let instruction_data =
+        MarketInstruction::ReplaceOrder(ReplaceOrderInstruction { replaces, order }).pack();
+    State::process(
+        accounts.market.owner,
+        &instruction_accounts,
+        &instruction_data,
+    )
+}
+
+fn cancel_order_by_client_id<'bump>(
+    accounts: &MarketAccounts<'bump>,
+    trader: &Trader<'bump>,
+    client_id: u64,
+) -> DexResult {
+    State::process(
+        accounts.market.owner,
+        &[
+            accounts.market.clone(),
+            trader.orders.clone(),
+            accounts.req_q.clone(),
+            trader.owner.clone(),
+        ],
+        &MarketInstruction::CancelOrderByClientId(client_id).pack(),
+    )
+}
+
+fn cancel_all_orders<'bump>(
+    accounts: &MarketAccounts<'bump>,
+    trader: &Trader<'bump>,
+    side: Option<Side>,
+) -> DexResult {
+    State::process(
+        accounts.market.owner,
+        &[
+            accounts.market.clone(),
+            trader.orders.clone(),
+            accounts.req_q.clone(),
+            trader.owner.clone(),
+        ],
+        &MarketInstruction::CancelAllOrders(side).pack(),
+    )
+}
+
+fn match_orders(accounts: &MarketAccounts, limit: u16) -> DexResult {
+    match_orders_with(accounts, limit, &[accounts.clock_sysvar.clone()])
+}
+
+fn match_orders_with<'bump>(
+    accounts: &MarketAccounts<'bump>,
+    limit: u16,
+    extra_accounts: &[AccountInfo<'bump>],
+) -> DexResult {
+    let mut instruction_accounts = vec![
+        accounts.market.clone(),
+        accounts.req_q.clone(),
+        accounts.event_q.clone(),
+        accounts.bids.clone(),
+        accounts.asks.clone(),
+        accounts.coin_vault.clone(),
+        accounts.pc_vault.clone(),
+    ];
+    instruction_accounts.extend(accounts.trigger_q.clone());
+    instruction_accounts.extend_from_slice(extra_accounts);
+    let instruction_data = MarketInstruction::MatchOrders(limit).pack();
+    State::process(
+        accounts.market.owner,
+        &instruction_accounts,
+        &instruction_data,
+    )
+}
+
+fn consume_events<'bump>(
+    accounts: &MarketAccounts<'bump>,
+    traders: &[&Trader<'bump>],
+    limit: u16,
+) -> DexResult {
+    let mut instruction_accounts: Vec<AccountInfo> =
+        traders.iter().map(|trader| trader.orders.clone()).collect();
+    instruction_accounts.sort_by_key(|account| account.key.to_aligned_bytes());
+    instruction_accounts.dedup_by_key(|account| account.key.to_aligned_bytes());
+    instruction_accounts.extend(vec![
+        accounts.market.clone(),
+        accounts.event_q.clone(),
+        traders[0].coin.clone(),
+        traders[0].pc.clone(),
+    ]);
+    let instruction_data = MarketInstruction::ConsumeEvents(limit).pack();
+    State::process(
+        accounts.market.owner,
+        &instruction_accounts,
+        &instruction_data,
+    )
+}
+
+fn set_auto_settle<'bump>(accounts: &MarketAccounts<'bump>, trader: &Trader<'bump>) -> DexResult {
+    State::process(
+        accounts.market.owner,
+        &[
+            trader.orders.clone(),
+            trader.owner.clone(),
+            accounts.market.clone(),
+            trader.coin.clone(),
+            trader.pc.clone(),
+        ],
+        &MarketInstruction::SetAutoSettle(true).pack(),
+    )
+}
+
+fn settle_for<'bump>(
+    accounts: &MarketAccounts<'bump>,
+    trader: &Trader<'bump>,
+    coin_wallet: &AccountInfo<'bump>,
+    pc_wallet: &AccountInfo<'bump>,
+) -> DexResult {
+    State::process(
+        accounts.market.owner,
+        &[
+            accounts.market.clone(),
+            trader.orders.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            coin_wallet.clone(),
+            pc_wallet.clone(),
+            accounts.vault_signer.clone(),
+            accounts.spl_token_program.clone(),
+        ],
+        &MarketInstruction::SettleFor.pack(),
+    )
+}
+
+fn update_fee_schedule<'bump>(
+    accounts: &MarketAccounts<'bump>,
+    signer: &AccountInfo<'bump>,
+    fee_schedule: FeeSchedule,
+) -> DexResult {
+    State::process(
+        accounts.market.owner,
+        &[
+            accounts.market.clone(),
+            accounts.event_q.clone(),
+            signer.clone(),
+        ],
+        &MarketInstruction::UpdateFeeSchedule(fee_schedule).pack(),
+    )
+}
+
+fn market_snapshot(accounts: &MarketAccounts) -> MarketSnapshot {
+    MarketSnapshot::from_account_data(
+        &accounts.bids.try_borrow_data().unwrap(),
+        &accounts.asks.try_borrow_data().unwrap(),
+        &accounts.req_q.try_borrow_data().unwrap(),
+        &accounts.event_q.try_borrow_data().unwrap(),
+    )
+    .unwrap()
+}
+
+fn simulate(accounts: &MarketAccounts, order: &NewOrderInstructionV3) -> Simulation {
+    let dex_program_id = accounts.market.owner;
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    let bids_data = accounts.bids.try_borrow_data().unwrap();
+    let bids_words = strip_account_data_padding(&bids_data).unwrap();
+    let (_, bids) = load_order_book_side(&bids_words).unwrap();
+    let asks_data = accounts.asks.try_borrow_data().unwrap();
+    let asks_words = strip_account_data_padding(&asks_data).unwrap();
+    let (_, asks) = load_order_book_side(&asks_words).unwrap();
+    let fee_tier = FeeTier::Base;
+    let native_pc_qty = order.max_qty.get() * order.limit_price.get() * market.pc_lot_size;
+    let max_native_pc_qty_including_fees = match order.side {
+        Side::Bid => NonZeroU64::new(
+            native_pc_qty + market.ext().fee_schedule.taker_fee(fee_tier, native_pc_qty),
+        ),
+        Side::Ask => None,
+    };
+    let simulated_order = SimulatedOrder {
+        side: order.side,
+        limit_price: Some(order.limit_price),
+        max_coin_qty: order.max_qty,
+        max_native_pc_qty_including_fees,
+        fee_tier,
+    };
+    simulate_taker_order(bids, asks, &market, market.ext(), &simulated_order, None)
+}
+
+fn taker_fills(snapshot: &MarketSnapshot, trader: &Trader) -> u64 {
+    snapshot
+        .events
+        .iter()
+        .filter(|event| match event {
+            QueuedEvent::Fill {
+                maker: false,
+                owner,
+                ..
+            } => owner == trader.orders.key,
+            _ => false,
+        })
+        .count() as u64
+}
+
 #[test]
 fn test_initialize_market() {
     let mut rng = StdRng::seed_from_u64(0);
@@ -296,6 +715,7 @@ fn test_new_order() {
             accounts.asks.clone(),
             coin_account.clone(),
             pc_account.clone(),
+            accounts.clock_sysvar.clone(),
         ]
         .into_bump_slice(),
         &instruction_data,
@@ -341,6 +761,7 @@ fn test_new_order() {
             accounts.asks.clone(),
             coin_account.clone(),
             pc_account.clone(),
+            accounts.clock_sysvar.clone(),
         ]
         .into_bump_slice(),
         &instruction_data,
@@ -411,3 +832,1808 @@ fn test_new_order() {
         assert_eq!(open_orders_seller.native_pc_total, 399_120);
     }
 }
+
+#[test]
+fn test_legacy_market_layout() {
+    let mut rng = StdRng::seed_from_u64(2);
+    let bump = Bump::new();
+
+    let accounts = setup_market_with(
+        &mut rng,
+        &bump,
+        MarketConfig {
+            legacy_layout: true,
+            ..MarketConfig::default()
+        },
+    );
+    let dex_program_id = accounts.market.owner;
+    assert_eq!(
+        accounts.market.data_len(),
+        size_of::<MarketState>() + 12,
+        "a market sized for the original layout"
+    );
+    {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        assert!(!market.is_extended());
+        assert!(!market.ext().has_trigger_queue());
+    }
+
+    let buyer = new_trader(&mut rng, &accounts, &bump);
+    let seller = new_trader(&mut rng, &accounts, &bump);
+    place_order(&accounts, &buyer, new_limit_order(Side::Bid, 100_000, 5)).unwrap();
+    place_order(&accounts, &seller, new_limit_order(Side::Ask, 99_000, 4)).unwrap();
+    {
+        // the requests are queued back to back, as in request queues of existing markets
+        let data = accounts.req_q.try_borrow_data().unwrap();
+        let words = strip_account_data_padding(&data).unwrap();
+        let (_, requests) = read_request_queue(&words).unwrap();
+        assert_eq!(requests.len(), 2);
+        let request_bytes: &[u8] =
+            bytemuck::cast_slice(&words[size_of::<RequestQueueHeader>() / 8..]);
+        let request_len = size_of::<Request>();
+        assert_eq!(
+            &request_bytes[request_len..2 * request_len],
+            bytemuck::bytes_of(&requests[1].0)
+        );
+    }
+    match_orders(&accounts, 5).unwrap();
+
+    // the order book has no room for the expiry, hidden quantity or slid id of an order
+    let expiring_order = NewOrderInstructionV3 {
+        expiry: Some(OrderExpiry::Slot(10)),
+        ..new_limit_order(Side::Ask, 99_000, 1)
+    };
+    assert_eq!(
+        place_order(&accounts, &seller, expiring_order),
+        Err(DexErrorCode::MarketNotExtended.into())
+    );
+    let slide_order = NewOrderInstructionV3 {
+        order_type: OrderType::PostOnlySlide,
+        ..new_limit_order(Side::Ask, 99_000, 1)
+    };
+    assert_eq!(
+        place_order(&accounts, &seller, slide_order),
+        Err(DexErrorCode::MarketNotExtended.into())
+    );
+    let iceberg_data = MarketInstruction::NewIcebergOrder(NewIcebergOrderInstruction {
+        display_qty: NonZeroU64::new(1).unwrap(),
+        order: new_limit_order(Side::Ask, 99_000, 2),
+    })
+    .pack();
+    assert_eq!(
+        State::process(
+            dex_program_id,
+            &new_order_accounts(&accounts, &seller, Side::Ask),
+            &iceberg_data,
+        ),
+        Err(DexErrorCode::MarketNotExtended.into())
+    );
+    // nor has the request queue room for the order a replacement replaces
+    assert_eq!(
+        replace_order(
+            &accounts,
+            &buyer,
+            OrderToReplace::OrderId(0),
+            new_limit_order(Side::Bid, 100_000, 1)
+        ),
+        Err(DexErrorCode::MarketNotExtended.into())
+    );
+    {
+        // the default fee schedule applies, as on an extended market without a fee config
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        assert_eq!(market.referrer_rebates_accrued, 176);
+        assert_eq!(market.pc_fees_accrued, 584);
+    }
+
+    let authority = new_signer_account(&disable_authority::ID, &bump);
+    let status_data = MarketInstruction::SetMarketStatus(MarketStatus::CancelOnly).pack();
+    State::process(
+        dex_program_id,
+        &[accounts.market.clone(), authority.clone()],
+        &status_data,
+    )
+    .unwrap();
+    assert_eq!(
+        MarketState::load(&accounts.market, dex_program_id)
+            .unwrap()
+            .status(),
+        MarketStatus::CancelOnly
+    );
+
+    let trade_log = new_dex_owned_account(&mut rng, 4096, dex_program_id, &bump);
+    let init_log_data = MarketInstruction::InitTradeLog.pack();
+    assert_eq!(
+        State::process(
+            dex_program_id,
+            &[accounts.market.clone(), trade_log, authority],
+            &init_log_data,
+        ),
+        Err(DexErrorCode::MarketNotExtended.into())
+    );
+}
+
+#[test]
+fn test_order_expiry() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let seller = new_trader(&mut rng, &accounts, &bump);
+    let buyer = new_trader(&mut rng, &accounts, &bump);
+    let clock_at = |slot| {
+        let clock = Clock {
+            slot,
+            ..Clock::default()
+        };
+        new_clock_sysvar_account(clock, &bump)
+    };
+
+    let expiring_ask = NewOrderInstructionV3 {
+        expiry: Some(OrderExpiry::Slot(10)),
+        ..new_limit_order(Side::Ask, 100, 3)
+    };
+    place_order(&accounts, &seller, expiring_ask).unwrap();
+    match_orders_with(&accounts, 5, &[clock_at(5)]).unwrap();
+    assert_eq!(market_snapshot(&accounts).asks[0].quantity, 3);
+
+    // before its expiry the order still trades, and it can't be matched without a clock to tell
+    place_order(&accounts, &buyer, new_limit_order(Side::Bid, 100, 1)).unwrap();
+    match_orders_with(&accounts, 5, &[clock_at(9)]).unwrap();
+    place_order(&accounts, &buyer, new_limit_order(Side::Bid, 100, 1)).unwrap();
+    assert_eq!(
+        match_orders_with(&accounts, 5, &[]),
+        Err(DexErrorCode::ClockNotProvided.into())
+    );
+    match_orders_with(&accounts, 5, &[clock_at(9)]).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    assert_eq!(snapshot.asks[0].quantity, 1);
+    assert_eq!(taker_fills(&snapshot, &buyer), 2);
+
+    // once it has expired, it is taken off the book instead of being matched
+    place_order(&accounts, &buyer, new_limit_order(Side::Bid, 100, 1)).unwrap();
+    match_orders_with(&accounts, 5, &[clock_at(10)]).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    assert!(snapshot.asks.is_empty());
+    assert_eq!(snapshot.bids[0].quantity, 1);
+    assert_eq!(taker_fills(&snapshot, &buyer), 2);
+    assert!(snapshot.events.iter().any(|event| match event {
+        QueuedEvent::Out {
+            owner,
+            native_qty_unlocked,
+            ..
+        } => owner == seller.orders.key && *native_qty_unlocked == 1_000,
+        _ => false,
+    }));
+}
+
+#[test]
+fn test_iceberg_order_refill() {
+    let mut rng = StdRng::seed_from_u64(8);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let iceberg_seller = new_trader(&mut rng, &accounts, &bump);
+    let seller = new_trader(&mut rng, &accounts, &bump);
+    let buyer = new_trader(&mut rng, &accounts, &bump);
+
+    let iceberg_data = MarketInstruction::NewIcebergOrder(NewIcebergOrderInstruction {
+        display_qty: NonZeroU64::new(2).unwrap(),
+        order: new_limit_order(Side::Ask, 100, 5),
+    })
+    .pack();
+    State::process(
+        dex_program_id,
+        &new_order_accounts(&accounts, &iceberg_seller, Side::Ask),
+        &iceberg_data,
+    )
+    .unwrap();
+    place_order(&accounts, &seller, new_limit_order(Side::Ask, 100, 1)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    let shown: Vec<_> = snapshot
+        .asks
+        .iter()
+        .map(|order| (order.owner, order.quantity, order.hidden_quantity))
+        .collect();
+    assert_eq!(
+        shown,
+        vec![
+            (*iceberg_seller.orders.key, 2, 3),
+            (*seller.orders.key, 1, 0)
+        ]
+    );
+    let first_order_id = snapshot.asks[0].order_id;
+
+    // filling the shown quantity requeues the next slice behind the other order at the price
+    place_order(&accounts, &buyer, new_limit_order(Side::Bid, 101, 2)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    let shown: Vec<_> = snapshot
+        .asks
+        .iter()
+        .map(|order| (order.owner, order.quantity, order.hidden_quantity))
+        .collect();
+    assert_eq!(
+        shown,
+        vec![
+            (*seller.orders.key, 1, 0),
+            (*iceberg_seller.orders.key, 2, 1)
+        ]
+    );
+    assert_ne!(snapshot.asks[1].order_id, first_order_id);
+
+    // the last slice only holds what is left of the reserve
+    place_order(&accounts, &buyer, new_limit_order(Side::Bid, 101, 3)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    assert_eq!(snapshot.asks.len(), 1);
+    assert_eq!(snapshot.asks[0].owner, *iceberg_seller.orders.key);
+    assert_eq!(
+        (snapshot.asks[0].quantity, snapshot.asks[0].hidden_quantity),
+        (1, 0)
+    );
+}
+
+#[test]
+fn test_cancel_repriced_orders() {
+    let mut rng = StdRng::seed_from_u64(9);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let seller = new_trader(&mut rng, &accounts, &bump);
+    let maker = new_trader(&mut rng, &accounts, &bump);
+    let buyer = new_trader(&mut rng, &accounts, &bump);
+
+    place_order(&accounts, &seller, new_limit_order(Side::Ask, 100, 1)).unwrap();
+    let slide_bid = NewOrderInstructionV3 {
+        order_type: OrderType::PostOnlySlide,
+        client_id: 1,
+        ..new_limit_order(Side::Bid, 100, 1)
+    };
+    place_order(&accounts, &maker, slide_bid).unwrap();
+    let iceberg_data = MarketInstruction::NewIcebergOrder(NewIcebergOrderInstruction {
+        display_qty: NonZeroU64::new(2).unwrap(),
+        order: NewOrderInstructionV3 {
+            client_id: 2,
+            ..new_limit_order(Side::Ask, 110, 4)
+        },
+    })
+    .pack();
+    State::process(
+        dex_program_id,
+        &new_order_accounts(&accounts, &maker, Side::Ask),
+        &iceberg_data,
+    )
+    .unwrap();
+    place_order(&accounts, &buyer, new_limit_order(Side::Bid, 111, 3)).unwrap();
+    match_orders(&accounts, 10).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    assert_eq!(snapshot.bids[0].price, 99);
+    assert_eq!(snapshot.asks[0].hidden_quantity, 0);
+
+    // the open orders account still has the ids the orders were placed under
+    cancel_order_by_client_id(&accounts, &maker, 1).unwrap();
+    cancel_order_by_client_id(&accounts, &maker, 2).unwrap();
+    match_orders(&accounts, 10).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    assert!(snapshot.bids.is_empty());
+    assert!(snapshot.asks.is_empty());
+
+    consume_events(&accounts, &[&seller, &maker, &buyer], 100).unwrap();
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    let mut open_orders = market
+        .load_orders_mut(&maker.orders, None, dex_program_id, None)
+        .unwrap();
+    let slots = open_orders.as_orders_mut();
+    assert!((0..slots.slot_count()).all(|slot| slots.slot_side(slot).is_none()));
+    assert_eq!(open_orders.native_coin_free, open_orders.native_coin_total);
+    assert_eq!(open_orders.native_pc_free, open_orders.native_pc_total);
+}
+
+#[test]
+fn test_trigger_order_activation() {
+    let mut rng = StdRng::seed_from_u64(3);
+    let bump = Bump::new();
+
+    let accounts = setup_market_with(
+        &mut rng,
+        &bump,
+        MarketConfig {
+            trigger_q: true,
+            ..MarketConfig::default()
+        },
+    );
+    let dex_program_id = accounts.market.owner;
+    let seller = new_trader(&mut rng, &accounts, &bump);
+    let buyer = new_trader(&mut rng, &accounts, &bump);
+    let stop_buyer = new_trader(&mut rng, &accounts, &bump);
+
+    place_order(&accounts, &seller, new_limit_order(Side::Ask, 100, 2)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+
+    let instruction_data = MarketInstruction::NewTriggerOrder(NewTriggerOrderInstruction {
+        order: NewOrderInstructionV2 {
+            side: Side::Bid,
+            limit_price: NonZeroU64::new(100).unwrap(),
+            max_qty: NonZeroU64::new(1).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+        },
+        trigger_price: NonZeroU64::new(100).unwrap(),
+        trigger_condition: TriggerCondition::PriceAtOrAbove,
+    })
+    .pack();
+    let mut instruction_accounts = new_order_accounts(&accounts, &stop_buyer, Side::Bid);
+    instruction_accounts.push(accounts.trigger_q.clone().unwrap());
+    State::process(dex_program_id, &instruction_accounts, &instruction_data).unwrap();
+
+    // nothing has traded yet, so the stop order stays parked
+    match_orders(&accounts, 5).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    assert_eq!(snapshot.asks[0].quantity, 2);
+    assert!(snapshot.requests.is_empty());
+
+    // the trade at 100 activates it, and the next match fills it
+    place_order(&accounts, &buyer, new_limit_order(Side::Bid, 100, 1)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    assert_eq!(
+        MarketState::load(&accounts.market, dex_program_id)
+            .unwrap()
+            .ext()
+            .last_trade_price,
+        100
+    );
+    assert_eq!(market_snapshot(&accounts).requests.len(), 1);
+    match_orders(&accounts, 5).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    assert!(snapshot.asks.is_empty());
+    assert_eq!(taker_fills(&snapshot, &buyer), 1);
+    assert_eq!(taker_fills(&snapshot, &stop_buyer), 1);
+}
+
+#[test]
+fn test_trigger_order_activated_under_new_id() {
+    let mut rng = StdRng::seed_from_u64(23);
+    let bump = Bump::new();
+    let accounts = setup_market_with(
+        &mut rng,
+        &bump,
+        MarketConfig {
+            trigger_q: true,
+            ..MarketConfig::default()
+        },
+    );
+    let dex_program_id = accounts.market.owner;
+    let seller = new_trader(&mut rng, &accounts, &bump);
+    let buyer = new_trader(&mut rng, &accounts, &bump);
+    let stop_buyer = new_trader(&mut rng, &accounts, &bump);
+
+    place_order(&accounts, &seller, new_limit_order(Side::Ask, 100, 2)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    place_order(&accounts, &buyer, new_limit_order(Side::Bid, 100, 1)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    consume_events(&accounts, &[&seller, &buyer], 10).unwrap();
+
+    // the last trade price already meets the condition of the new order, so the next match
+    // activates it even though the price hasn't changed
+    let instruction_data = MarketInstruction::NewTriggerOrder(NewTriggerOrderInstruction {
+        order: NewOrderInstructionV2 {
+            side: Side::Bid,
+            limit_price: NonZeroU64::new(90).unwrap(),
+            max_qty: NonZeroU64::new(1).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 7,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+        },
+        trigger_price: NonZeroU64::new(100).unwrap(),
+        trigger_condition: TriggerCondition::PriceAtOrAbove,
+    })
+    .pack();
+    let mut instruction_accounts = new_order_accounts(&accounts, &stop_buyer, Side::Bid);
+    instruction_accounts.push(accounts.trigger_q.clone().unwrap());
+    State::process(dex_program_id, &instruction_accounts, &instruction_data).unwrap();
+    match_orders(&accounts, 5).unwrap();
+
+    let placed_order_id = {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        let mut open_orders = market
+            .load_orders_mut(&stop_buyer.orders, None, dex_program_id, None)
+            .unwrap();
+        open_orders.as_orders_mut().order_id(0)
+    };
+    let activated_order_id = match market_snapshot(&accounts).requests[..] {
+        [QueuedRequest::NewOrder { order_id, .. }] => order_id,
+        ref requests => panic!("unexpected requests: {:?}", requests),
+    };
+    assert_ne!(activated_order_id, placed_order_id);
+    assert_eq!(activated_order_id >> 64, placed_order_id >> 64);
+
+    // the order rests under its new id, and cancelling it by the id its open orders account
+    // still knows it by works before the events carrying the new id are consumed
+    match_orders(&accounts, 5).unwrap();
+    assert_eq!(
+        market_snapshot(&accounts).bids[0].order_id,
+        activated_order_id
+    );
+    cancel_order_by_client_id(&accounts, &stop_buyer, 7).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    assert!(market_snapshot(&accounts).bids.is_empty());
+    consume_events(&accounts, &[&stop_buyer], 10).unwrap();
+
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    let mut open_orders = market
+        .load_orders_mut(&stop_buyer.orders, None, dex_program_id, None)
+        .unwrap();
+    assert_eq!(open_orders.native_pc_free, open_orders.native_pc_total);
+    assert!(open_orders.as_orders_mut().slot_side(0).is_none());
+}
+
+#[test]
+fn test_set_market_authority() {
+    let mut rng = StdRng::seed_from_u64(4);
+    let bump = Bump::new();
+
+    let disable_authority = new_signer_account(&disable_authority::ID, &bump);
+    let market_authority = new_sol_account(&mut rng, 0, &bump);
+    let cancel_only_data = MarketInstruction::SetMarketStatus(MarketStatus::CancelOnly).pack();
+    let set_authority_data =
+        MarketInstruction::SetMarketAuthority(market_authority.key.to_aligned_bytes()).pack();
+
+    // a market with the original layout keeps its authority in a record
+    let legacy = setup_market_with(
+        &mut rng,
+        &bump,
+        MarketConfig {
+            legacy_layout: true,
+            ..MarketConfig::default()
+        },
+    );
+    let dex_program_id = legacy.market.owner;
+    let record = new_dex_owned_account(
+        &mut rng,
+        size_of::<MarketAuthorityRecord>(),
+        dex_program_id,
+        &bump,
+    );
+    assert!(State::process(
+        dex_program_id,
+        &[legacy.market.clone(), market_authority.clone()],
+        &cancel_only_data,
+    )
+    .is_err());
+    assert!(State::process(
+        dex_program_id,
+        &[
+            legacy.market.clone(),
+            market_authority.clone(),
+            record.clone()
+        ],
+        &set_authority_data,
+    )
+    .is_err());
+    State::process(
+        dex_program_id,
+        &[
+            legacy.market.clone(),
+            disable_authority.clone(),
+            record.clone(),
+        ],
+        &set_authority_data,
+    )
+    .unwrap();
+    State::process(
+        dex_program_id,
+        &[legacy.market.clone(), market_authority.clone(), record],
+        &cancel_only_data,
+    )
+    .unwrap();
+    assert_eq!(
+        MarketState::load(&legacy.market, dex_program_id)
+            .unwrap()
+            .status(),
+        MarketStatus::CancelOnly
+    );
+
+    // an extended market stores it in place
+    let extended = setup_market(&mut rng, &bump);
+    let dex_program_id = extended.market.owner;
+    State::process(
+        dex_program_id,
+        &[extended.market.clone(), disable_authority],
+        &set_authority_data,
+    )
+    .unwrap();
+    State::process(
+        dex_program_id,
+        &[extended.market.clone(), market_authority],
+        &cancel_only_data,
+    )
+    .unwrap();
+    assert_eq!(
+        MarketState::load(&extended.market, dex_program_id)
+            .unwrap()
+            .status(),
+        MarketStatus::CancelOnly
+    );
+}
+
+#[test]
+fn test_init_open_orders_delegate() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+
+    // the 128-slot layout keeps its original size and has no room for a delegate
+    assert_eq!(size_of::<OpenOrders>(), 3216);
+    let delegate = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let v1_trader = new_trader(&mut rng, &accounts, &bump);
+    assert_eq!(
+        init_open_orders(&accounts, &v1_trader, Some(delegate.key.to_aligned_bytes())),
+        Err(DexErrorCode::OrdersAccountNotV2.into())
+    );
+
+    let trader = new_v2_trader(&mut rng, &accounts, &bump);
+    init_open_orders(&accounts, &trader, Some(delegate.key.to_aligned_bytes())).unwrap();
+
+    // the delegate places orders on the owner's account, paying from its own wallets
+    let delegate_trader = Trader {
+        coin: new_token_account(&mut rng, accounts.coin_mint.key, delegate.key, &bump),
+        pc: new_token_account(&mut rng, accounts.pc_mint.key, delegate.key, &bump),
+        owner: delegate,
+        orders: trader.orders.clone(),
+    };
+    place_order(
+        &accounts,
+        &delegate_trader,
+        new_limit_order(Side::Bid, 100, 1),
+    )
+    .unwrap();
+
+    // but nobody else can
+    let stranger = new_trader(&mut rng, &accounts, &bump);
+    let stranger = Trader {
+        orders: trader.orders.clone(),
+        ..stranger
+    };
+    assert_eq!(
+        place_order(&accounts, &stranger, new_limit_order(Side::Bid, 100, 1)),
+        Err(DexErrorCode::WrongOrdersAccount.into())
+    );
+}
+
+#[test]
+fn test_settle_for_registered_wallets() {
+    let mut rng = StdRng::seed_from_u64(6);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+
+    // only the 512-slot layout can register wallets
+    let taker = new_trader(&mut rng, &accounts, &bump);
+    init_open_orders(&accounts, &taker, None).unwrap();
+    assert_eq!(
+        set_auto_settle(&accounts, &taker),
+        Err(DexErrorCode::OrdersAccountNotV2.into())
+    );
+
+    let maker = new_v2_trader(&mut rng, &accounts, &bump);
+    init_open_orders(&accounts, &maker, None).unwrap();
+    assert_eq!(
+        settle_for(&accounts, &maker, &maker.coin, &maker.pc),
+        Err(DexErrorCode::AutoSettleNotEnabled.into())
+    );
+    set_auto_settle(&accounts, &maker).unwrap();
+
+    place_order(&accounts, &maker, new_limit_order(Side::Ask, 100, 2)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    place_order(&accounts, &taker, new_limit_order(Side::Bid, 100, 1)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    consume_events(&accounts, &[&maker, &taker], 10).unwrap();
+    {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        let open_orders = market
+            .load_orders_mut(&maker.orders, None, dex_program_id, None)
+            .unwrap();
+        assert_eq!(open_orders.native_coin_free, 0);
+        assert_eq!(open_orders.native_coin_total, 1_000);
+        assert!(open_orders.native_pc_free > 0);
+    }
+
+    // anyone can settle, but only to the registered wallets
+    assert_eq!(
+        settle_for(&accounts, &maker, &taker.coin, &taker.pc),
+        Err(DexErrorCode::WrongSettleWallet.into())
+    );
+    settle_for(&accounts, &maker, &maker.coin, &maker.pc).unwrap();
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    let open_orders = market
+        .load_orders_mut(&maker.orders, None, dex_program_id, None)
+        .unwrap();
+    assert_eq!(open_orders.native_pc_free, 0);
+    assert_eq!(open_orders.native_pc_total, 0);
+    assert_eq!(open_orders.native_coin_total, 1_000);
+}
+
+#[test]
+fn test_queue_item_offsets() {
+    // the high byte of a widened owner slot lives in what used to be padding, so the fields
+    // an older consumer reads stay where they were
+    let owner = [1u64; 4];
+    let order_id = 7u128;
+    let event = Event::new(EventView::Out {
+        side: Side::Bid,
+        native_qty_unlocked: 0,
+        native_qty_still_locked: 0,
+        order_id: &order_id,
+        owner: &owner,
+        owner_slot: 0x0203,
+        client_order_id: None,
+    });
+    assert_eq!(bytemuck::bytes_of(&event)[1..4], [0x03, 0, 0x02]);
+    match event.as_view().unwrap() {
+        EventView::Out { owner_slot, .. } => assert_eq!(owner_slot, 0x0203),
+        _ => panic!(),
+    }
+
+    let (request, ext) = Request::new(RequestView::CancelOrder {
+        side: Side::Bid,
+        order_id: &order_id,
+        cancel_id: 0,
+        expected_owner_slot: 0x0203,
+        expected_owner: &owner,
+        client_order_id: None,
+    });
+    let bytes = bytemuck::bytes_of(&request);
+    assert_eq!((bytes[1], bytes[5]), (0x03, 0x02));
+    assert_eq!(ext, bytemuck::Zeroable::zeroed());
+    match request.as_view(&ext).unwrap() {
+        RequestView::CancelOrder {
+            expected_owner_slot,
+            ..
+        } => assert_eq!(expected_owner_slot, 0x0203),
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn test_orders_above_slot_255() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let maker = new_v2_trader(&mut rng, &accounts, &bump);
+    init_open_orders(&accounts, &maker, None).unwrap();
+    let taker = new_trader(&mut rng, &accounts, &bump);
+
+    // each ask takes the next slot at a better price than the ones before it
+    for slot in 0..300 {
+        let order = NewOrderInstructionV3 {
+            client_id: slot + 1,
+            ..new_limit_order(Side::Ask, 2_000 - slot, 1)
+        };
+        place_order(&accounts, &maker, order).unwrap();
+        match_orders(&accounts, 5).unwrap();
+    }
+    place_order(&accounts, &taker, new_limit_order(Side::Bid, 1_701, 1)).unwrap();
+    cancel_order_by_client_id(&accounts, &maker, 299).unwrap();
+    match_orders(&accounts, 5).unwrap();
+
+    let snapshot = market_snapshot(&accounts);
+    assert_eq!(snapshot.asks.len(), 298);
+    let maker_key = *maker.orders.key;
+    let out_slots: Vec<u16> = snapshot
+        .events
+        .iter()
+        .filter_map(|event| match *event {
+            QueuedEvent::Out {
+                owner, owner_slot, ..
+            } if owner == maker_key => Some(owner_slot),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(out_slots, vec![299, 298]);
+
+    consume_events(&accounts, &[&maker, &taker], 10).unwrap();
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    let mut open_orders = market
+        .load_orders_mut(&maker.orders, None, dex_program_id, None)
+        .unwrap();
+    let slots = open_orders.as_orders_mut();
+    assert_eq!(slots.slot_side(297), Some(Side::Ask));
+    assert_eq!(slots.slot_side(298), None);
+    assert_eq!(slots.slot_side(299), None);
+}
+
+#[test]
+fn test_fill_or_kill_order() {
+    let mut rng = StdRng::seed_from_u64(8);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+    let maker = new_trader(&mut rng, &accounts, &bump);
+    let taker = new_trader(&mut rng, &accounts, &bump);
+
+    for price in 100..103 {
+        place_order(&accounts, &maker, new_limit_order(Side::Ask, price, 1)).unwrap();
+        match_orders(&accounts, 5).unwrap();
+    }
+    let fill_or_kill = |price| NewOrderInstructionV3 {
+        order_type: OrderType::FillOrKill,
+        ..new_limit_order(Side::Bid, price, 3)
+    };
+
+    // the whole order doesn't fit under its limit price
+    place_order(&accounts, &taker, fill_or_kill(101)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    assert_eq!(snapshot.asks.len(), 3);
+    assert_eq!(taker_fills(&snapshot, &taker), 0);
+
+    // nor does it fill within the number of orders the crank is allowed to match
+    place_order(&accounts, &taker, fill_or_kill(102)).unwrap();
+    match_orders(&accounts, 2).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    assert_eq!(snapshot.asks.len(), 3);
+    assert_eq!(taker_fills(&snapshot, &taker), 0);
+    assert!(snapshot.requests.is_empty());
+
+    place_order(&accounts, &taker, fill_or_kill(102)).unwrap();
+    match_orders(&accounts, 3).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    assert!(snapshot.asks.is_empty());
+    assert!(taker_fills(&snapshot, &taker) > 0);
+}
+
+#[test]
+fn test_replace_order_in_full_account() {
+    let mut rng = StdRng::seed_from_u64(9);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let maker = new_trader(&mut rng, &accounts, &bump);
+    let other = new_trader(&mut rng, &accounts, &bump);
+    let taker = new_trader(&mut rng, &accounts, &bump);
+
+    for slot in 0..128 {
+        let order = NewOrderInstructionV3 {
+            client_id: slot + 1,
+            ..new_limit_order(Side::Ask, 2_000 - slot, 2)
+        };
+        place_order(&accounts, &maker, order).unwrap();
+        match_orders(&accounts, 5).unwrap();
+    }
+
+    let maker_balances = || {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        let open_orders = market
+            .load_orders_mut(&maker.orders, None, dex_program_id, None)
+            .unwrap();
+        (open_orders.native_coin_free, open_orders.native_coin_total)
+    };
+    let maker_slot = |slot| {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        let mut open_orders = market
+            .load_orders_mut(&maker.orders, None, dex_program_id, None)
+            .unwrap();
+        let slots = open_orders.as_orders_mut();
+        (slots.order_id(slot), slots.client_order_id(slot))
+    };
+    assert_eq!(maker_balances(), (0, 256_000));
+
+    // the replacement takes over slot 4 and only deposits the lot the original didn't lock
+    let replacement = NewOrderInstructionV3 {
+        client_id: 500,
+        ..new_limit_order(Side::Ask, 1_200, 3)
+    };
+    replace_order(&accounts, &maker, OrderToReplace::ClientId(5), replacement).unwrap();
+    assert_eq!(maker_balances(), (0, 257_000));
+    match_orders(&accounts, 5).unwrap();
+    consume_events(&accounts, &[&maker], 5).unwrap();
+    assert_eq!(maker_balances(), (0, 257_000));
+
+    let snapshot = market_snapshot(&accounts);
+    assert_eq!(snapshot.asks.len(), 128);
+    assert!(snapshot.asks.iter().all(|order| order.price != 1_996));
+    let replacement_id = maker_slot(4).0;
+    assert_eq!(maker_slot(4), (replacement_id, 500));
+    let resting = snapshot
+        .asks
+        .iter()
+        .find(|order| order.order_id == replacement_id)
+        .unwrap();
+    assert_eq!(
+        (resting.price, resting.quantity, resting.owner_slot),
+        (1_200, 3, 4)
+    );
+
+    // reducing the size at the same price amends the order in place and keeps its priority
+    place_order(&accounts, &other, new_limit_order(Side::Ask, 1_200, 1)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    let amendment = NewOrderInstructionV3 {
+        client_id: 500,
+        ..new_limit_order(Side::Ask, 1_200, 1)
+    };
+    replace_order(&accounts, &maker, OrderToReplace::ClientId(500), amendment).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    consume_events(&accounts, &[&maker, &other], 5).unwrap();
+    assert_eq!(maker_balances(), (2_000, 257_000));
+    assert_eq!(maker_slot(4), (replacement_id, 500));
+
+    place_order(&accounts, &taker, new_limit_order(Side::Bid, 1_200, 1)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    assert_eq!(snapshot.asks.len(), 128);
+    assert!(snapshot
+        .asks
+        .iter()
+        .all(|order| order.order_id != replacement_id));
+    assert!(snapshot
+        .asks
+        .iter()
+        .any(|order| order.owner == *other.orders.key && order.price == 1_200));
+
+    // the account stayed full throughout
+    assert_eq!(
+        place_order(&accounts, &maker, new_limit_order(Side::Ask, 1_000, 1)),
+        Err(DexErrorCode::TooManyOpenOrders.into())
+    );
+}
+
+#[test]
+fn test_cancel_all_orders_in_full_account() {
+    let mut rng = StdRng::seed_from_u64(10);
+    let bump = Bump::new();
+    let accounts = setup_market_with(
+        &mut rng,
+        &bump,
+        MarketConfig {
+            trigger_q: true,
+            ..MarketConfig::default()
+        },
+    );
+    let dex_program_id = accounts.market.owner;
+    let maker = new_trader(&mut rng, &accounts, &bump);
+    let other = new_trader(&mut rng, &accounts, &bump);
+
+    place_order(&accounts, &other, new_limit_order(Side::Ask, 1_000, 1)).unwrap();
+    for i in 0..63 {
+        place_order(&accounts, &maker, new_limit_order(Side::Bid, 100 - i, 1)).unwrap();
+        match_orders(&accounts, 5).unwrap();
+    }
+    for i in 0..64 {
+        place_order(&accounts, &maker, new_limit_order(Side::Ask, 200 + i, 1)).unwrap();
+        match_orders(&accounts, 5).unwrap();
+    }
+    let instruction_data = MarketInstruction::NewTriggerOrder(NewTriggerOrderInstruction {
+        order: NewOrderInstructionV2 {
+            side: Side::Ask,
+            limit_price: NonZeroU64::new(40).unwrap(),
+            max_qty: NonZeroU64::new(1).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+        },
+        trigger_price: NonZeroU64::new(50).unwrap(),
+        trigger_condition: TriggerCondition::PriceAtOrBelow,
+    })
+    .pack();
+    let mut instruction_accounts = new_order_accounts(&accounts, &maker, Side::Ask);
+    instruction_accounts.push(accounts.trigger_q.clone().unwrap());
+    State::process(dex_program_id, &instruction_accounts, &instruction_data).unwrap();
+    consume_events(&accounts, &[&maker], 200).unwrap();
+
+    // the request queue only has room for a few of the cancels at a time
+    cancel_all_orders(&accounts, &maker, None).unwrap();
+    let maker_key = *maker.orders.key;
+    let queued: Vec<_> = market_snapshot(&accounts)
+        .requests
+        .iter()
+        .map(|request| match *request {
+            QueuedRequest::CancelOrder {
+                owner, owner_slot, ..
+            } => (owner, owner_slot),
+            _ => panic!("unexpected request: {:?}", request),
+        })
+        .collect();
+    assert_eq!(
+        queued,
+        (0..4).map(|slot| (maker_key, slot)).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        cancel_all_orders(&accounts, &maker, None),
+        Err(DexErrorCode::RequestQueueFull.into())
+    );
+
+    let mut cancelled = 0;
+    for _ in 0..32 {
+        match_orders(&accounts, 5).unwrap();
+        cancelled += market_snapshot(&accounts)
+            .events
+            .iter()
+            .filter(|event| match event {
+                QueuedEvent::Out { owner, .. } => *owner == maker_key,
+                _ => false,
+            })
+            .count();
+        consume_events(&accounts, &[&maker], 10).unwrap();
+        cancel_all_orders(&accounts, &maker, None).unwrap();
+    }
+    assert_eq!(cancelled, 128);
+    let snapshot = market_snapshot(&accounts);
+    assert!(snapshot.requests.is_empty());
+    assert!(snapshot.bids.is_empty());
+    assert_eq!(snapshot.asks.len(), 1);
+    assert_eq!(snapshot.asks[0].owner, *other.orders.key);
+
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    let mut open_orders = market
+        .load_orders_mut(&maker.orders, None, dex_program_id, None)
+        .unwrap();
+    assert_eq!(open_orders.native_coin_free, open_orders.native_coin_total);
+    assert_eq!(open_orders.native_pc_free, open_orders.native_pc_total);
+    let slots = open_orders.as_orders_mut();
+    assert!((0..128).all(|slot| slots.slot_side(slot).is_none()));
+}
+
+#[test]
+fn test_update_fee_schedule() {
+    let mut rng = StdRng::seed_from_u64(11);
+    let bump = Bump::new();
+    let fee_authority = new_sol_account(&mut rng, 0, &bump);
+    let accounts = setup_market_with(
+        &mut rng,
+        &bump,
+        MarketConfig {
+            fee_config: Some(FeeConfig {
+                fee_authority: fee_authority.key.to_aligned_bytes(),
+                fee_schedule: FeeSchedule::DEFAULT,
+            }),
+            ..MarketConfig::default()
+        },
+    );
+    let dex_program_id = accounts.market.owner;
+    let maker = new_trader(&mut rng, &accounts, &bump);
+    let taker = new_trader(&mut rng, &accounts, &bump);
+    let one_pct = FeeSchedule {
+        taker_fee_bps: [100; 7],
+        maker_rebate_bps: [0; 7],
+        ..FeeSchedule::DEFAULT
+    };
+
+    place_order(&accounts, &maker, new_limit_order(Side::Ask, 1_000, 10)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    place_order(&accounts, &taker, new_limit_order(Side::Bid, 1_000, 1)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+
+    // fills still in the event queue are settled at the old rates
+    assert_eq!(
+        update_fee_schedule(&accounts, &fee_authority, one_pct),
+        Err(DexErrorCode::EventQueueNotEmpty.into())
+    );
+    consume_events(&accounts, &[&maker, &taker], 10).unwrap();
+    assert_eq!(
+        update_fee_schedule(&accounts, &taker.owner, one_pct),
+        Err(DexErrorCode::WrongSigner.into())
+    );
+    let overpaid_referrers = FeeSchedule {
+        referrer_rebate_pct: 101,
+        ..one_pct
+    };
+    assert_eq!(
+        update_fee_schedule(&accounts, &fee_authority, overpaid_referrers),
+        Err(DexErrorCode::InvalidFeeSchedule.into())
+    );
+    update_fee_schedule(&accounts, &fee_authority, one_pct).unwrap();
+    assert_eq!(
+        MarketState::load(&accounts.market, dex_program_id)
+            .unwrap()
+            .ext()
+            .fee_schedule,
+        one_pct
+    );
+
+    place_order(&accounts, &taker, new_limit_order(Side::Bid, 1_000, 1)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    let taker_fees: Vec<u64> = market_snapshot(&accounts)
+        .events
+        .iter()
+        .filter_map(|event| match *event {
+            QueuedEvent::Fill {
+                maker: false,
+                native_fee_or_rebate,
+                ..
+            } => Some(native_fee_or_rebate),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(taker_fees, vec![10]);
+}
+
+#[test]
+fn test_simulate_taker_order() {
+    let mut rng = StdRng::seed_from_u64(12);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+    let maker = new_trader(&mut rng, &accounts, &bump);
+    let taker = new_trader(&mut rng, &accounts, &bump);
+
+    for &(side, price, qty) in &[
+        (Side::Ask, 101, 3),
+        (Side::Ask, 103, 5),
+        (Side::Ask, 107, 7),
+        (Side::Bid, 97, 4),
+        (Side::Bid, 89, 9),
+    ] {
+        place_order(&accounts, &maker, new_limit_order(side, price, qty)).unwrap();
+        match_orders(&accounts, 5).unwrap();
+    }
+    consume_events(&accounts, &[&maker], 10).unwrap();
+
+    // the taker fee is rounded up at every resting order the engine matches against
+    for order in vec![
+        NewOrderInstructionV3 {
+            order_type: OrderType::ImmediateOrCancel,
+            ..new_limit_order(Side::Bid, 107, 12)
+        },
+        NewOrderInstructionV3 {
+            order_type: OrderType::ImmediateOrCancel,
+            ..new_limit_order(Side::Ask, 80, 11)
+        },
+    ] {
+        let simulation = simulate(&accounts, &order);
+        place_order(&accounts, &taker, order).unwrap();
+        match_orders(&accounts, 10).unwrap();
+        let taker_key = *taker.orders.key;
+        let (mut native_qty_paid, mut native_qty_received, mut native_taker_fee) = (0, 0, 0);
+        for event in market_snapshot(&accounts).events {
+            if let QueuedEvent::Fill {
+                maker: false,
+                owner,
+                native_qty_paid: paid,
+                native_qty_received: received,
+                native_fee_or_rebate,
+                ..
+            } = event
+            {
+                assert_eq!(owner, taker_key);
+                native_qty_paid += paid;
+                native_qty_received += received;
+                native_taker_fee += native_fee_or_rebate;
+            }
+        }
+        let (native_coin_qty, native_pc_qty) = match simulation.side {
+            Side::Bid => (native_qty_received, native_qty_paid),
+            Side::Ask => (native_qty_paid, native_qty_received),
+        };
+        assert_eq!(simulation.native_coin_qty, native_coin_qty);
+        assert_eq!(simulation.native_pc_qty_including_fees(), native_pc_qty);
+        assert_eq!(simulation.native_taker_fee, native_taker_fee);
+        consume_events(&accounts, &[&maker, &taker], 10).unwrap();
+    }
+}
+
+#[test]
+fn test_new_orders_batch() {
+    let mut rng = StdRng::seed_from_u64(13);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let maker = new_trader(&mut rng, &accounts, &bump);
+
+    let ladder = [
+        (Side::Bid, 100, 2),
+        (Side::Bid, 90, 3),
+        (Side::Ask, 120, 1),
+        (Side::Ask, 130, 4),
+    ];
+    let orders = ladder
+        .iter()
+        .enumerate()
+        .map(|(i, &(side, price, qty))| NewOrderInstructionV2 {
+            side,
+            limit_price: NonZeroU64::new(price).unwrap(),
+            max_qty: NonZeroU64::new(qty).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: i as u64 + 1,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+        })
+        .collect();
+    let instruction_accounts = [
+        accounts.market.clone(),
+        maker.orders.clone(),
+        accounts.req_q.clone(),
+        maker.coin.clone(),
+        maker.pc.clone(),
+        maker.owner.clone(),
+        accounts.coin_vault.clone(),
+        accounts.pc_vault.clone(),
+        accounts.spl_token_program.clone(),
+        accounts.rent_sysvar.clone(),
+    ];
+    State::process(
+        dex_program_id,
+        &instruction_accounts,
+        &MarketInstruction::NewOrdersBatch(NewOrdersBatchInstruction { orders }).pack(),
+    )
+    .unwrap();
+
+    {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        let mut open_orders = market
+            .load_orders_mut(&maker.orders, None, dex_program_id, None)
+            .unwrap();
+        // both bids lock their pc plus the taker fee, rounded up
+        assert_eq!(open_orders.native_pc_total, 201 + 271);
+        assert_eq!(open_orders.native_coin_total, 5_000);
+        assert_eq!(open_orders.native_pc_free, 0);
+        assert_eq!(open_orders.native_coin_free, 0);
+        let slots = open_orders.as_orders_mut();
+        for (slot, &(side, _, _)) in ladder.iter().enumerate() {
+            assert_eq!(slots.slot_side(slot as u16), Some(side));
+            assert_eq!(slots.client_order_id(slot as u16), slot as u64 + 1);
+        }
+    }
+
+    match_orders(&accounts, 10).unwrap();
+    let l2 = market_snapshot(&accounts).l2();
+    let levels = |levels: &[Level]| -> Vec<(u64, u64)> {
+        levels
+            .iter()
+            .map(|level| (level.price, level.quantity))
+            .collect()
+    };
+    assert_eq!(levels(&l2.bids), vec![(100, 2), (90, 3)]);
+    assert_eq!(levels(&l2.asks), vec![(120, 1), (130, 4)]);
+}
+
+#[test]
+fn test_post_only_slide() {
+    let mut rng = StdRng::seed_from_u64(14);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let seller = new_trader(&mut rng, &accounts, &bump);
+    let maker = new_trader(&mut rng, &accounts, &bump);
+    let other = new_trader(&mut rng, &accounts, &bump);
+
+    place_order(&accounts, &seller, new_limit_order(Side::Ask, 100, 2)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+
+    // a crossing post-only order is dropped, a sliding one rests a tick behind the best offer
+    let post_only_bid = NewOrderInstructionV3 {
+        order_type: OrderType::PostOnly,
+        client_id: 1,
+        ..new_limit_order(Side::Bid, 105, 1)
+    };
+    place_order(&accounts, &maker, post_only_bid).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    let slide_bid = NewOrderInstructionV3 {
+        order_type: OrderType::PostOnlySlide,
+        client_id: 2,
+        ..new_limit_order(Side::Bid, 105, 2)
+    };
+    place_order(&accounts, &maker, slide_bid).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    assert_eq!(snapshot.bids.len(), 1);
+    assert_eq!((snapshot.bids[0].price, snapshot.bids[0].quantity), (99, 2));
+    assert!(snapshot
+        .events
+        .iter()
+        .all(|event| matches!(event, QueuedEvent::Out { .. })));
+
+    // the open orders account learns the new id and gets back what the lower price frees up
+    consume_events(&accounts, &[&maker], 10).unwrap();
+    {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        let mut open_orders = market
+            .load_orders_mut(&maker.orders, None, dex_program_id, None)
+            .unwrap();
+        assert_eq!(open_orders.native_pc_total, 106 + 211);
+        assert_eq!(open_orders.native_pc_free, 106 + 211 - 198);
+        let slots = open_orders.as_orders_mut();
+        assert_eq!(slots.slot_side(0), None);
+        assert_eq!(slots.order_id(1), snapshot.bids[0].order_id);
+    }
+
+    // asks slide up to a tick above the best bid
+    let slide_ask = NewOrderInstructionV3 {
+        order_type: OrderType::PostOnlySlide,
+        ..new_limit_order(Side::Ask, 95, 1)
+    };
+    place_order(&accounts, &other, slide_ask).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    let other_asks: Vec<u64> = snapshot
+        .asks
+        .iter()
+        .filter(|order| order.owner == *other.orders.key)
+        .map(|order| order.price)
+        .collect();
+    assert_eq!(other_asks, vec![100]);
+    assert_eq!(snapshot.bids.len(), 1);
+}
+
+#[test]
+fn test_update_market_params() {
+    let mut rng = StdRng::seed_from_u64(15);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let maker = new_trader(&mut rng, &accounts, &bump);
+    let authority = new_signer_account(&disable_authority::ID, &bump);
+    let update_market_params = |coin_lot_size, pc_lot_size, pc_dust_threshold| {
+        let instruction = UpdateMarketParamsInstruction {
+            coin_lot_size,
+            pc_lot_size,
+            pc_dust_threshold,
+        };
+        State::process(
+            dex_program_id,
+            &[
+                accounts.market.clone(),
+                accounts.req_q.clone(),
+                accounts.bids.clone(),
+                accounts.asks.clone(),
+                authority.clone(),
+            ],
+            &MarketInstruction::UpdateMarketParams(instruction).pack(),
+        )
+    };
+
+    let order = NewOrderInstructionV3 {
+        client_id: 1,
+        ..new_limit_order(Side::Ask, 100, 1)
+    };
+    place_order(&accounts, &maker, order).unwrap();
+    match_orders(&accounts, 5).unwrap();
+
+    // the dust threshold can change under a resting order, the lot sizes can't
+    update_market_params(1_000, 1, 10).unwrap();
+    assert_eq!(
+        update_market_params(100, 1, 10),
+        Err(DexErrorCode::OrderBookNotEmpty.into())
+    );
+    assert_eq!(
+        MarketState::load(&accounts.market, dex_program_id)
+            .unwrap()
+            .pc_dust_threshold,
+        10
+    );
+
+    cancel_order_by_client_id(&accounts, &maker, 1).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    assert_eq!(
+        update_market_params(0, 1, 10),
+        Err(DexErrorCode::InvalidMarketParams.into())
+    );
+    update_market_params(100, 1, 10).unwrap();
+    {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        assert_eq!((market.coin_lot_size, market.pc_lot_size), (100, 1));
+    }
+
+    // new orders are locked in the new lots
+    consume_events(&accounts, &[&maker], 5).unwrap();
+    place_order(&accounts, &maker, new_limit_order(Side::Ask, 100, 3)).unwrap();
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    let open_orders = market
+        .load_orders_mut(&maker.orders, None, dex_program_id, None)
+        .unwrap();
+    assert_eq!(open_orders.native_coin_total, 1_000);
+    assert_eq!(open_orders.native_coin_free, 700);
+}
+
+#[test]
+fn test_market_status() {
+    let mut rng = StdRng::seed_from_u64(16);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let maker = new_trader(&mut rng, &accounts, &bump);
+    let taker = new_trader(&mut rng, &accounts, &bump);
+    let authority = new_signer_account(&disable_authority::ID, &bump);
+    let set_market_status = |status| {
+        State::process(
+            dex_program_id,
+            &[accounts.market.clone(), authority.clone()],
+            &MarketInstruction::SetMarketStatus(status).pack(),
+        )
+        .unwrap()
+    };
+    let ask = |client_id| NewOrderInstructionV3 {
+        client_id,
+        ..new_limit_order(Side::Ask, 100, 2)
+    };
+
+    place_order(&accounts, &maker, ask(1)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    place_order(&accounts, &taker, new_limit_order(Side::Bid, 100, 1)).unwrap();
+
+    // cancel-only markets still match what was queued before, cancel and consume events
+    set_market_status(MarketStatus::CancelOnly);
+    assert_eq!(
+        place_order(&accounts, &maker, ask(2)),
+        Err(DexErrorCode::MarketIsCancelOnly.into())
+    );
+    match_orders(&accounts, 5).unwrap();
+    assert_eq!(taker_fills(&market_snapshot(&accounts), &taker), 1);
+    consume_events(&accounts, &[&maker, &taker], 10).unwrap();
+    place_order(&accounts, &taker, new_limit_order(Side::Bid, 100, 1)).unwrap_err();
+
+    // paused markets don't cancel or match either
+    set_market_status(MarketStatus::Paused);
+    assert_eq!(
+        cancel_order_by_client_id(&accounts, &maker, 1),
+        Err(DexErrorCode::MarketIsPaused.into())
+    );
+    assert_eq!(
+        match_orders(&accounts, 5),
+        Err(DexErrorCode::MarketIsPaused.into())
+    );
+
+    set_market_status(MarketStatus::CancelOnly);
+    cancel_order_by_client_id(&accounts, &maker, 1).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    assert!(market_snapshot(&accounts).asks.is_empty());
+
+    set_market_status(MarketStatus::Active);
+    place_order(&accounts, &maker, ask(3)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    assert_eq!(market_snapshot(&accounts).asks.len(), 1);
+}
+
+#[test]
+fn test_cancel_trigger_order_in_cancel_only_market() {
+    let mut rng = StdRng::seed_from_u64(22);
+    let bump = Bump::new();
+    let accounts = setup_market_with(
+        &mut rng,
+        &bump,
+        MarketConfig {
+            trigger_q: true,
+            ..MarketConfig::default()
+        },
+    );
+    let dex_program_id = accounts.market.owner;
+    let stop_buyer = new_trader(&mut rng, &accounts, &bump);
+    let authority = new_signer_account(&disable_authority::ID, &bump);
+
+    let instruction_data = MarketInstruction::NewTriggerOrder(NewTriggerOrderInstruction {
+        order: NewOrderInstructionV2 {
+            side: Side::Bid,
+            limit_price: NonZeroU64::new(100).unwrap(),
+            max_qty: NonZeroU64::new(1).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 7,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+        },
+        trigger_price: NonZeroU64::new(100).unwrap(),
+        trigger_condition: TriggerCondition::PriceAtOrAbove,
+    })
+    .pack();
+    let mut instruction_accounts = new_order_accounts(&accounts, &stop_buyer, Side::Bid);
+    instruction_accounts.push(accounts.trigger_q.clone().unwrap());
+    State::process(dex_program_id, &instruction_accounts, &instruction_data).unwrap();
+
+    State::process(
+        dex_program_id,
+        &[accounts.market.clone(), authority.clone()],
+        &MarketInstruction::SetMarketStatus(MarketStatus::CancelOnly).pack(),
+    )
+    .unwrap();
+    cancel_order_by_client_id(&accounts, &stop_buyer, 7).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    let stop_buyer_key = *stop_buyer.orders.key;
+    let snapshot = market_snapshot(&accounts);
+    assert!(snapshot.requests.is_empty());
+    assert!(snapshot.events.iter().any(|event| match event {
+        QueuedEvent::Out {
+            owner,
+            client_order_id,
+            ..
+        } => *owner == stop_buyer_key && *client_order_id == 7,
+        _ => false,
+    }));
+    consume_events(&accounts, &[&stop_buyer], 10).unwrap();
+
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    let mut open_orders = market
+        .load_orders_mut(&stop_buyer.orders, None, dex_program_id, None)
+        .unwrap();
+    assert_eq!(open_orders.native_pc_free, open_orders.native_pc_total);
+    assert!(open_orders.as_orders_mut().slot_side(0).is_none());
+}
+
+#[test]
+fn test_close_open_orders() {
+    let mut rng = StdRng::seed_from_u64(17);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let maker = new_trader(&mut rng, &accounts, &bump);
+    let destination = new_sol_account(&mut rng, 0, &bump);
+    let close_open_orders = || {
+        State::process(
+            dex_program_id,
+            &[
+                maker.orders.clone(),
+                maker.owner.clone(),
+                destination.clone(),
+                accounts.market.clone(),
+            ],
+            &MarketInstruction::CloseOpenOrders.pack(),
+        )
+    };
+
+    let order = NewOrderInstructionV3 {
+        client_id: 1,
+        ..new_limit_order(Side::Ask, 100, 1)
+    };
+    place_order(&accounts, &maker, order).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    assert_eq!(
+        close_open_orders(),
+        Err(DexErrorCode::OrdersAccountNotEmpty.into())
+    );
+
+    // cancelled but unsettled funds keep the account open too
+    cancel_order_by_client_id(&accounts, &maker, 1).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    consume_events(&accounts, &[&maker], 5).unwrap();
+    assert_eq!(
+        close_open_orders(),
+        Err(DexErrorCode::OrdersAccountNotEmpty.into())
+    );
+
+    State::process(
+        dex_program_id,
+        &[
+            accounts.market.clone(),
+            maker.orders.clone(),
+            maker.owner.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            maker.coin.clone(),
+            maker.pc.clone(),
+            accounts.vault_signer.clone(),
+            accounts.spl_token_program.clone(),
+        ],
+        &MarketInstruction::SettleFunds.pack(),
+    )
+    .unwrap();
+    close_open_orders().unwrap();
+    assert_eq!(destination.lamports(), 100_000_000);
+    assert_eq!(maker.orders.lamports(), 0);
+    assert!(place_order(&accounts, &maker, new_limit_order(Side::Ask, 100, 1)).is_err());
+}
+
+#[test]
+fn test_create_open_orders_at_derived_address() {
+    let mut rng = StdRng::seed_from_u64(18);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let mut trader = new_trader(&mut rng, &accounts, &bump);
+    let system_program_acc = new_signer_account(&system_program::ID, &bump);
+
+    let (address, _) =
+        find_open_orders_address(accounts.market.key, trader.owner.key, 0, dex_program_id);
+    assert_ne!(
+        find_open_orders_address(accounts.market.key, trader.owner.key, 1, dex_program_id).0,
+        address
+    );
+    // stands in for the account the system program creates at the derived address
+    let derived_orders = AccountInfo::new(
+        bump.alloc(address),
+        false,
+        true,
+        bump.alloc(100_000_000),
+        allocate_dex_owned_account(size_of::<OpenOrders>() + size_of::<OpenOrdersExt>(), &bump),
+        dex_program_id,
+        false,
+        Epoch::default(),
+    );
+    assert_eq!(
+        create_open_orders(&accounts, &trader, &derived_orders, &system_program_acc, 1),
+        Err(DexErrorCode::WrongOrdersAccount.into())
+    );
+    assert_eq!(
+        create_open_orders(&accounts, &trader, &trader.orders, &system_program_acc, 0),
+        Err(DexErrorCode::WrongOrdersAccount.into())
+    );
+    create_open_orders(&accounts, &trader, &derived_orders, &system_program_acc, 0).unwrap();
+    assert!(
+        create_open_orders(&accounts, &trader, &derived_orders, &system_program_acc, 0).is_err()
+    );
+
+    trader.orders = derived_orders;
+    place_order(&accounts, &trader, new_limit_order(Side::Ask, 100, 1)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    let snapshot = market_snapshot(&accounts);
+    assert_eq!(snapshot.asks[0].owner, address);
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    let mut open_orders = market
+        .load_orders_mut(&trader.orders, Some(&trader.owner), dex_program_id, None)
+        .unwrap();
+    assert_eq!(open_orders.as_orders_mut().slot_count(), 512);
+}
+
+#[test]
+fn test_trade_log() {
+    let mut rng = StdRng::seed_from_u64(19);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let maker = new_trader(&mut rng, &accounts, &bump);
+    let taker = new_trader(&mut rng, &accounts, &bump);
+    let authority = new_signer_account(&disable_authority::ID, &bump);
+    // room for four trades
+    let trade_log = new_dex_owned_account(
+        &mut rng,
+        size_of::<TradeLogHeader>() + 4 * size_of::<TradeRecord>(),
+        dex_program_id,
+        &bump,
+    );
+    State::process(
+        dex_program_id,
+        &[
+            accounts.market.clone(),
+            trade_log.clone(),
+            authority.clone(),
+        ],
+        &MarketInstruction::InitTradeLog.pack(),
+    )
+    .unwrap();
+    let clock_at = |slot| {
+        let clock = Clock {
+            slot,
+            ..Clock::default()
+        };
+        new_clock_sysvar_account(clock, &bump)
+    };
+
+    place_order(&accounts, &maker, new_limit_order(Side::Ask, 100, 10)).unwrap();
+    assert_eq!(
+        match_orders(&accounts, 5),
+        Err(DexErrorCode::WrongTradeLogAccount.into())
+    );
+    assert_eq!(
+        match_orders_with(&accounts, 5, &[trade_log.clone()]),
+        Err(DexErrorCode::ClockNotProvided.into())
+    );
+    for slot in 10..15 {
+        place_order(&accounts, &taker, new_limit_order(Side::Bid, 100, 1)).unwrap();
+        match_orders_with(&accounts, 5, &[trade_log.clone(), clock_at(slot)]).unwrap();
+    }
+
+    // the oldest trade has been overwritten
+    let data = trade_log.try_borrow_data().unwrap();
+    let words = strip_account_data_padding(&data).unwrap();
+    let (header_words, record_words) = words.split_at(size_of::<TradeLogHeader>() / 8);
+    let header: &TradeLogHeader = bytemuck::from_bytes(bytemuck::cast_slice(header_words));
+    let records: &[TradeRecord] = bytemuck::cast_slice(record_words);
+    assert_eq!(header.seq_num(), 5);
+    let mut cursor = header.cursor(records, 0).unwrap();
+    assert_eq!(cursor.missed(), 1);
+    let trades: Vec<(u64, u64, u64, u64, Side)> = cursor
+        .by_ref()
+        .map(|(seq_num, record)| {
+            (
+                seq_num,
+                record.price,
+                record.quantity,
+                record.slot,
+                record.taker_side().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        trades,
+        (1..5)
+            .map(|seq_num| (seq_num, 100, 1, seq_num + 10, Side::Bid))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(cursor.seq_num(), 5);
+}
+
+#[test]
+fn test_market_stats() {
+    let mut rng = StdRng::seed_from_u64(20);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let maker = new_trader(&mut rng, &accounts, &bump);
+    let taker = new_trader(&mut rng, &accounts, &bump);
+    let authority = new_signer_account(&disable_authority::ID, &bump);
+    let stats = new_dex_owned_account(&mut rng, size_of::<MarketStats>(), dex_program_id, &bump);
+    State::process(
+        dex_program_id,
+        &[accounts.market.clone(), stats.clone(), authority.clone()],
+        &MarketInstruction::InitMarketStats.pack(),
+    )
+    .unwrap();
+    let clock_at = |unix_timestamp| {
+        let clock = Clock {
+            slot: unix_timestamp as u64 / 10,
+            unix_timestamp,
+            ..Clock::default()
+        };
+        new_clock_sysvar_account(clock, &bump)
+    };
+
+    let hour = 3600;
+    let start = 100 * hour;
+    place_order(&accounts, &maker, new_limit_order(Side::Ask, 100, 2)).unwrap();
+    place_order(&accounts, &maker, new_limit_order(Side::Ask, 110, 3)).unwrap();
+    place_order(&accounts, &taker, new_limit_order(Side::Bid, 110, 2)).unwrap();
+    match_orders_with(&accounts, 5, &[stats.clone(), clock_at(start)]).unwrap();
+    place_order(&accounts, &taker, new_limit_order(Side::Bid, 110, 3)).unwrap();
+    match_orders_with(&accounts, 5, &[stats.clone(), clock_at(start + 25 * hour)]).unwrap();
+
+    let market_stats = MarketStats::load(&stats, dex_program_id, accounts.market.key).unwrap();
+    assert_eq!(market_stats.last_trade_price, 110);
+    assert_eq!(
+        market_stats.last_trade_slot,
+        (start + 25 * hour) as u64 / 10
+    );
+    assert_eq!(market_stats.last_trade_unix_timestamp, start + 25 * hour);
+    assert_eq!(market_stats.native_coin_volume_total, 5_000);
+    assert_eq!(market_stats.native_pc_volume_total, 2 * 100 + 3 * 110);
+    // the first trade's price held for 25 hours
+    assert_eq!(market_stats.price_time_total, 100 * 25 * hour as u64);
+    assert_eq!(
+        market_stats.price_time_total_at(start + 26 * hour),
+        100 * 25 * hour as u64 + 110 * hour as u64
+    );
+    // the first trade has dropped out of the 24 hour window
+    assert_eq!(market_stats.volume_24h(start + 25 * hour), (3_000, 330));
+    assert_eq!(market_stats.volume_24h(start + 23 * hour), (2_000, 200));
+
+    let other_market = new_sol_account(&mut rng, 0, &bump);
+    assert_eq!(
+        MarketStats::load(&stats, dex_program_id, other_market.key).err(),
+        Some(DexErrorCode::WrongMarketStatsAccount.into())
+    );
+}
+
+#[test]
+fn test_market_snapshot() {
+    let mut rng = StdRng::seed_from_u64(21);
+    let bump = Bump::new();
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let iceberg_seller = new_trader(&mut rng, &accounts, &bump);
+    let seller = new_trader(&mut rng, &accounts, &bump);
+    let buyer = new_trader(&mut rng, &accounts, &bump);
+
+    let iceberg_data = MarketInstruction::NewIcebergOrder(NewIcebergOrderInstruction {
+        display_qty: NonZeroU64::new(2).unwrap(),
+        order: new_limit_order(Side::Ask, 100, 5),
+    })
+    .pack();
+    State::process(
+        dex_program_id,
+        &new_order_accounts(&accounts, &iceberg_seller, Side::Ask),
+        &iceberg_data,
+    )
+    .unwrap();
+    place_order(&accounts, &seller, new_limit_order(Side::Ask, 100, 1)).unwrap();
+    place_order(&accounts, &seller, new_limit_order(Side::Ask, 101, 4)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    place_order(&accounts, &buyer, new_limit_order(Side::Bid, 90, 3)).unwrap();
+    place_order(&accounts, &buyer, new_limit_order(Side::Bid, 95, 2)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    consume_events(&accounts, &[&buyer], 10).unwrap();
+    place_order(&accounts, &buyer, new_limit_order(Side::Bid, 100, 1)).unwrap();
+    match_orders(&accounts, 5).unwrap();
+    // left in the request queue
+    place_order(&accounts, &buyer, new_limit_order(Side::Bid, 90, 1)).unwrap();
+
+    let snapshot = market_snapshot(&accounts);
+    assert_eq!(snapshot.version, SNAPSHOT_VERSION);
+    let asks: Vec<_> = snapshot
+        .asks
+        .iter()
+        .map(|order| {
+            (
+                order.owner,
+                order.price,
+                order.quantity,
+                order.hidden_quantity,
+            )
+        })
+        .collect();
+    assert_eq!(
+        asks,
+        vec![
+            (*iceberg_seller.orders.key, 100, 1, 3),
+            (*seller.orders.key, 100, 1, 0),
+            (*seller.orders.key, 101, 4, 0),
+        ]
+    );
+    let bids: Vec<_> = snapshot
+        .bids
+        .iter()
+        .map(|order| (order.owner, order.price, order.quantity))
+        .collect();
+    assert_eq!(
+        bids,
+        vec![(*buyer.orders.key, 95, 2), (*buyer.orders.key, 90, 3)]
+    );
+    match snapshot.requests[..] {
+        [QueuedRequest::NewOrder {
+            side: Side::Bid,
+            max_coin_qty: 1,
+            owner,
+            ..
+        }] => assert_eq!(owner, *buyer.orders.key),
+        _ => panic!("unexpected requests: {:?}", snapshot.requests),
+    }
+    // the maker's and taker's fills, then the taker's filled order leaving the book
+    let events: Vec<_> = snapshot
+        .events
+        .iter()
+        .map(|event| match *event {
+            QueuedEvent::Fill {
+                owner,
+                maker,
+                native_qty_received,
+                ..
+            } => (owner, Some(maker), native_qty_received),
+            QueuedEvent::Out {
+                owner,
+                native_qty_unlocked,
+                ..
+            } => (owner, None, native_qty_unlocked),
+        })
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            (*iceberg_seller.orders.key, Some(true), 100),
+            (*buyer.orders.key, Some(false), 1_000),
+            (*buyer.orders.key, None, 0),
+        ]
+    );
+
+    // hidden quantity is left out of the price levels
+    let l2 = snapshot.l2();
+    assert_eq!(
+        l2.asks,
+        vec![
+            Level {
+                price: 100,
+                quantity: 2
+            },
+            Level {
+                price: 101,
+                quantity: 4
+            },
+        ]
+    );
+    assert_eq!(
+        l2.bids,
+        vec![
+            Level {
+                price: 95,
+                quantity: 2
+            },
+            Level {
+                price: 90,
+                quantity: 3
+            },
+        ]
+    );
+
+    assert_eq!(
+        MarketSnapshot::from_bytes(&snapshot.to_bytes()),
+        Some(snapshot.clone())
+    );
+    assert_eq!(L2Snapshot::from_bytes(&l2.to_bytes()), Some(l2));
+    let mut other_version = snapshot.to_bytes();
+    other_version[..4].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+    assert_eq!(MarketSnapshot::from_bytes(&other_version), None);
+}